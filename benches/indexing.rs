@@ -0,0 +1,73 @@
+//! Criterion benchmarks of the indexing pipeline on generated blocks. Run with
+//! `cargo bench`, and `korndex bench` for a sample of real blocks.
+
+use bitcoin::consensus::serialize;
+use bitcoin::Network;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use korndex::bench::{self, ScratchIndex};
+use korndex::blockscan;
+use korndex::indexes::{IndexKind, IndexSet};
+use korndex::store::{Backend, Durability};
+use korndex::txindex::TxIndexEntry;
+use korndex::IndexerOptions;
+
+const BLOCKS: usize = 50;
+const TXS_PER_BLOCK: usize = 200;
+
+fn scan_block(c: &mut Criterion) {
+    let blocks = bench::synthetic_blocks(Network::Regtest, 3, 2000);
+    let raw_block = serialize(&blocks[2]);
+    let mut group = c.benchmark_group("scan_block");
+    group.throughput(Throughput::Bytes(raw_block.len() as u64));
+    group.bench_function("2000_txs", |b| {
+        b.iter(|| blockscan::scan_block(&raw_block).unwrap())
+    });
+    group.finish();
+}
+
+fn txindex_entry(c: &mut Criterion) {
+    let entry = TxIndexEntry {
+        block_height: 850_000,
+        position_in_block: 1234,
+        fee: 4_500,
+        vsize: 141,
+        byte_range: Some((812_345, 222)),
+    };
+    let encoded = entry.encode();
+    c.bench_function("txindex_entry/encode", |b| b.iter(|| entry.encode()));
+    c.bench_function("txindex_entry/decode", |b| {
+        b.iter(|| TxIndexEntry::decode(&encoded).unwrap())
+    });
+}
+
+fn connect_blocks(c: &mut Criterion) {
+    let blocks = bench::synthetic_blocks(Network::Regtest, BLOCKS, TXS_PER_BLOCK);
+    let transactions: usize = blocks.iter().map(|block| block.txdata.len()).sum();
+    let mut group = c.benchmark_group("connect_blocks");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(transactions as u64));
+    let txid_only: IndexSet = [IndexKind::Txid, IndexKind::Utxo].into_iter().collect();
+    for (name, indexes) in [("all", None), ("txid", Some(txid_only))] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || ScratchIndex::create(Backend::Lmdb, Network::Regtest).unwrap(),
+                |index| {
+                    let options = IndexerOptions {
+                        indexes,
+                        durability: Durability::Async,
+                        ..IndexerOptions::default()
+                    };
+                    // Returned to be dropped, and the index deleted, outside
+                    // the measurement
+                    let report = index.run(&blocks, options).unwrap();
+                    (index, report)
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scan_block, txindex_entry, connect_blocks);
+criterion_main!(benches);
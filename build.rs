@@ -0,0 +1,6 @@
+fn main() {
+    // Generates the gRPC service of `src/grpc.rs`
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/korndex.proto")
+        .expect("Failed to compile the protobuf schema");
+}
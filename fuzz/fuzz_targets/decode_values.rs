@@ -0,0 +1,43 @@
+//! Decodes arbitrary bytes as each kind of index value. A corrupt or
+//! truncated value must be an error, never a panic, and whatever decodes must
+//! decode to the same entry again once re-encoded.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use korndex::analytics::{self, ValueHistogram};
+use korndex::blockstats::BlockStats;
+use korndex::coinbase::CoinbaseEntry;
+use korndex::locktime::LocktimeCounts;
+use korndex::scripthash::ScriptHashEntry;
+use korndex::scripttypes::ScriptTypeCounts;
+use korndex::spent::SpendEntry;
+use korndex::txindex::TxIndexEntry;
+use korndex::utxo::UtxoEntry;
+use korndex::witness::WitnessEntry;
+
+macro_rules! roundtrip {
+    ($data:expr, $decode:expr) => {
+        if let Ok(entry) = $decode($data) {
+            let again = $decode(&entry.encode()[..]).expect("re-encoded entry decodes");
+            assert_eq!(format!("{:?}", entry), format!("{:?}", again));
+        }
+    };
+}
+
+fuzz_target!(|data: &[u8]| {
+    roundtrip!(data, TxIndexEntry::decode);
+    roundtrip!(data, UtxoEntry::decode);
+    roundtrip!(data, SpendEntry::decode);
+    roundtrip!(data, ScriptHashEntry::decode);
+    roundtrip!(data, BlockStats::decode);
+    roundtrip!(data, CoinbaseEntry::decode);
+    roundtrip!(data, LocktimeCounts::decode);
+    roundtrip!(data, ScriptTypeCounts::decode);
+    roundtrip!(data, WitnessEntry::decode);
+    roundtrip!(data, |data| ValueHistogram::decode(
+        data,
+        &analytics::DEFAULT_VALUE_BUCKETS
+    ));
+});
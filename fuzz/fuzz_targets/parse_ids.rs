@@ -0,0 +1,29 @@
+//! Parses arbitrary strings as the ids, outpoints, addresses and descriptors
+//! clients hand to serve mode and the CLI.
+
+#![no_main]
+
+use bitcoin::{BlockHash, Network, OutPoint, Txid};
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+use korndex::{rescan, scripthash};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = Txid::from_str(input);
+    let _ = BlockHash::from_str(input);
+    let _ = OutPoint::from_str(input);
+    for network in [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ] {
+        let _ = scripthash::parse_script(network, input);
+    }
+    // Few derivation indexes, deriving is not what is fuzzed
+    let _ = rescan::scripts(input, 0, 1);
+});
@@ -0,0 +1,29 @@
+//! Parses arbitrary input as a REST API path and as a line of Electrum
+//! protocol requests, the way the serve mode listeners do before touching the
+//! index.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde_json::Value;
+
+use korndex::electrum;
+use korndex::rest::Route;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = Route::parse(input.split('?').next().unwrap_or_default());
+    match serde_json::from_str::<Value>(input.trim()) {
+        Ok(Value::Array(requests)) => {
+            for request in &requests {
+                let _ = electrum::parse_request(request);
+            }
+        }
+        Ok(request) => {
+            let _ = electrum::parse_request(&request);
+        }
+        Err(_) => {}
+    }
+});
@@ -0,0 +1,25 @@
+//! Scans arbitrary bytes as a serialized block. Malformed blocks must be an
+//! error, never a panic, and blocks rust-bitcoin deserializes must scan to
+//! the same transactions.
+
+#![no_main]
+
+use bitcoin::consensus::deserialize_partial;
+use bitcoin::Block;
+use libfuzzer_sys::fuzz_target;
+
+use korndex::blockscan::scan_block;
+
+fuzz_target!(|data: &[u8]| {
+    let scanned = scan_block(data);
+    let Ok((block, _)) = deserialize_partial::<Block>(data) else {
+        return;
+    };
+    let scanned = scanned.expect("rust-bitcoin deserializes the block");
+    assert_eq!(scanned.len(), block.txdata.len());
+    for (tx, scanned) in block.txdata.iter().zip(&scanned) {
+        assert_eq!(scanned.txid, tx.compute_txid());
+        assert_eq!(scanned.wtxid, tx.compute_wtxid());
+        assert_eq!(scanned.size, tx.total_size());
+    }
+});
@@ -0,0 +1,53 @@
+/* C interface to a korndex index, built with `cargo build --release
+ * --features ffi` as libkorndex.so. */
+
+#ifndef KORNDEX_H
+#define KORNDEX_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct KorndexIndex KorndexIndex;
+
+typedef struct {
+    /* Txid in internal byte order */
+    uint8_t txid[32];
+    int32_t block_height;
+    uint64_t position_in_block;
+    /* Fee in satoshis, 0 for the coinbase */
+    uint64_t fee;
+    /* Virtual size in vbytes */
+    uint32_t vsize;
+    /* The consensus-serialized transaction, NULL if the index was opened
+     * without the node's data directory */
+    uint8_t *tx;
+    size_t tx_len;
+} KorndexResult;
+
+/* Opens the LMDB index below index_dir for network ("mainnet", "testnet",
+ * "regtest" or "signet") read-only. Pass the node's data directory as datadir
+ * to also read transactions, or NULL. Returns NULL on error. */
+KorndexIndex *korndex_open(const char *index_dir, const char *network, const char *datadir);
+
+void korndex_close(KorndexIndex *index);
+
+/* Looks up a transaction by its hex txid or witness transaction id. Returns 0
+ * and stores the result in *result if found, 1 if not, and the CLI's exit
+ * code for other errors. */
+int korndex_lookup_txid(const KorndexIndex *index, const char *txid, KorndexResult **result);
+
+void korndex_free_result(KorndexResult *result);
+
+/* Message of the last error on the calling thread, valid until the next call
+ * on it. */
+const char *korndex_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KORNDEX_H */
@@ -0,0 +1,170 @@
+//! Access control for serve mode: optional API keys, per-client token bucket
+//! rate limits and a cap on live connections, so a public deployment can't be
+//! flooded with expensive lookups.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::KorndexError;
+
+/// Clients tracked before those whose buckets have refilled are forgotten.
+const MAX_CLIENTS: usize = 100_000;
+
+/// Why a request was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// No API key, or one that is not configured
+    Unauthorized,
+    /// The client's bucket is empty
+    RateLimited,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per client IP, refilled at `rate` tokens per second up to
+/// `burst`. Each request takes a token.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `client`, false if it is empty.
+    pub fn allow(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// The API keys and rate limits requests are checked against. Without either
+/// every request is let through.
+#[derive(Default)]
+pub struct AccessControl {
+    api_keys: Option<HashSet<String>>,
+    limiter: Option<RateLimiter>,
+}
+
+impl AccessControl {
+    pub fn new(api_keys: Option<HashSet<String>>, limiter: Option<RateLimiter>) -> Self {
+        AccessControl { api_keys, limiter }
+    }
+
+    /// Reads API keys from `path`, one per line. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn load_api_keys(path: &Path) -> Result<HashSet<String>, KorndexError> {
+        let keys: HashSet<String> = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+        if keys.is_empty() {
+            return Err(KorndexError::Config(format!(
+                "No API keys in {}",
+                path.display()
+            )));
+        }
+        Ok(keys)
+    }
+
+    /// Checks a request from `client` carrying `api_key`. Requests without a
+    /// known client address are not rate limited.
+    pub fn check(&self, client: Option<IpAddr>, api_key: Option<&str>) -> Result<(), Denied> {
+        if let Some(keys) = &self.api_keys {
+            if !api_key.is_some_and(|key| keys.contains(key)) {
+                return Err(Denied::Unauthorized);
+            }
+        }
+        match (&self.limiter, client) {
+            (Some(limiter), Some(client)) if !limiter.allow(client) => Err(Denied::RateLimited),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The API key carried by a request header, in `X-API-Key` or as an
+/// `Authorization: Bearer` token.
+pub fn header_api_key(name: &str, value: &str) -> Option<String> {
+    let value = value.trim();
+    if name.eq_ignore_ascii_case("X-API-Key") {
+        Some(value.to_owned())
+    } else if name.eq_ignore_ascii_case("Authorization") {
+        value
+            .strip_prefix("Bearer ")
+            .map(|key| key.trim().to_owned())
+    } else {
+        None
+    }
+}
+
+/// Caps the connections the Electrum and WebSocket servers hold open at
+/// once, each of which takes a thread.
+pub struct ConnectionLimit {
+    live: AtomicUsize,
+    max: usize,
+}
+
+/// A connection counted by a [`ConnectionLimit`] until dropped.
+pub struct Connection<'a> {
+    limit: &'a ConnectionLimit,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit {
+            live: AtomicUsize::new(0),
+            max: max.max(1),
+        }
+    }
+
+    /// Counts a new connection, none if `max` are already open.
+    pub fn acquire(&self) -> Option<Connection<'_>> {
+        self.live
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                (live < self.max).then_some(live + 1)
+            })
+            .ok()
+            .map(|_| Connection { limit: self })
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.limit.live.fetch_sub(1, Ordering::AcqRel);
+    }
+}
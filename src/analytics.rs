@@ -0,0 +1,113 @@
+use bitcoin::Block;
+
+use crate::store::{height_key, KvStore, Table};
+use crate::KorndexError;
+
+/// Bucket boundaries in satoshis used unless the build sets others: below the
+/// dust limit of a P2PKH output, then every power of ten from 10k sats to
+/// 1000 BTC.
+pub const DEFAULT_VALUE_BUCKETS: [u64; 9] = [
+    546,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+    10_000_000_000,
+    100_000_000_000,
+];
+
+/// Size of a bucket's encoded output count and value.
+const BUCKET_SIZE: usize = 12;
+
+/// Checks that `bounds` are bucket boundaries: at least one, strictly
+/// increasing and above 0.
+pub fn check_bounds(bounds: &[u64]) -> Result<(), KorndexError> {
+    if bounds.is_empty() || bounds[0] == 0 || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(KorndexError::InvalidInput(
+            "Value buckets need increasing boundaries above 0 sats".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Outputs and their value per value bucket, of a block or range of blocks.
+/// Boundaries `b` split values into `b.len() + 1` buckets, bucket `i` holding
+/// values from `b[i - 1]` up to but excluding `b[i]`. A block's histogram is
+/// encoded as a big-endian u32 count and u64 value per bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueHistogram {
+    /// Output count and total value in satoshis of each bucket
+    pub buckets: Vec<(u64, u64)>,
+}
+
+impl ValueHistogram {
+    pub fn empty(bounds: &[u64]) -> Self {
+        ValueHistogram {
+            buckets: vec![(0, 0); bounds.len() + 1],
+        }
+    }
+
+    /// Sorts the outputs of every transaction of `block` into the buckets of
+    /// `bounds`, the coinbase's included.
+    pub fn of_block(block: &Block, bounds: &[u64]) -> Self {
+        let mut histogram = ValueHistogram::empty(bounds);
+        for output in block.txdata.iter().flat_map(|tx| &tx.output) {
+            let value = output.value.to_sat();
+            let bucket = &mut histogram.buckets[bounds.partition_point(|bound| *bound <= value)];
+            bucket.0 += 1;
+            bucket.1 += value;
+        }
+        histogram
+    }
+
+    /// Adds the counts of `other`, a histogram with the same buckets.
+    pub fn add(&mut self, other: &ValueHistogram) {
+        for (bucket, added) in self.buckets.iter_mut().zip(&other.buckets) {
+            bucket.0 += added.0;
+            bucket.1 += added.1;
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BUCKET_SIZE * self.buckets.len());
+        for (count, value) in self.buckets.iter() {
+            buf.extend_from_slice(&(*count as u32).to_be_bytes());
+            buf.extend_from_slice(&value.to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8], bounds: &[u64]) -> Result<Self, KorndexError> {
+        if data.len() != BUCKET_SIZE * (bounds.len() + 1) {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid value histogram length {}",
+                data.len()
+            )));
+        }
+        let buckets = data
+            .chunks_exact(BUCKET_SIZE)
+            .map(|bucket| {
+                Ok((
+                    u32::from_be_bytes(bucket[0..4].try_into()?) as u64,
+                    u64::from_be_bytes(bucket[4..12].try_into()?),
+                ))
+            })
+            .collect::<Result<_, KorndexError>>()?;
+        Ok(ValueHistogram { buckets })
+    }
+}
+
+/// Value histogram of the indexed block at `block_height`, whose buckets are
+/// split at `bounds`.
+pub fn read_histogram(
+    store: &dyn KvStore,
+    block_height: i32,
+    bounds: &[u64],
+) -> Result<Option<ValueHistogram>, KorndexError> {
+    match store.get(Table::ValueHistograms, &height_key(block_height))? {
+        Some(data) => Ok(Some(ValueHistogram::decode(&data, bounds)?)),
+        None => Ok(None),
+    }
+}
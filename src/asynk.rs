@@ -0,0 +1,201 @@
+//! Async lookups for tokio services, built with the `async` feature. Every
+//! lookup runs the blocking reads on tokio's blocking thread pool.
+
+use bitcoin::block::Header;
+use bitcoin::{BlockHash, OutPoint, ScriptBuf, Txid};
+use std::sync::Arc;
+use std::thread;
+use std::{io, panic};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+
+use crate::scripthash::ScriptHashEntry;
+use crate::spent::SpendEntry;
+use crate::txindex::TxIndexEntry;
+use crate::utxo::UtxoEntry;
+use crate::watch::{BlockFeed, POLL_INTERVAL};
+use crate::{shutdown, EmbeddedIndex, KorndexError, QueryHandle, TransactionLookup};
+
+/// Blocks a range stream reads ahead of its consumer.
+const STREAM_READ_AHEAD: usize = 16;
+
+/// A cloneable handle to an index shared by async tasks. Like a connection
+/// pool, at most `max_readers` lookups read the index at a time and the others
+/// wait for a free slot without blocking their task.
+#[derive(Clone)]
+pub struct Index {
+    index: Arc<EmbeddedIndex>,
+    readers: Arc<Semaphore>,
+}
+
+impl Index {
+    pub fn new(index: EmbeddedIndex, max_readers: usize) -> Self {
+        Index {
+            index: Arc::new(index),
+            readers: Arc::new(Semaphore::new(max_readers)),
+        }
+    }
+
+    /// Runs `f` with a query handle on the blocking thread pool once a reader
+    /// slot is free.
+    pub async fn with_query<T, F>(&self, f: F) -> Result<T, KorndexError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&QueryHandle) -> Result<T, KorndexError> + Send + 'static,
+    {
+        let permit = self.readers.clone().acquire_owned().await.unwrap();
+        let index = self.index.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f(&index.query())
+        });
+        match task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(KorndexError::Io(io::Error::other(e))),
+        }
+    }
+
+    /// See [`QueryHandle::locate`].
+    pub async fn locate(
+        &self,
+        id: Txid,
+    ) -> Result<Option<(Txid, bool, TxIndexEntry)>, KorndexError> {
+        self.with_query(move |query| query.locate(&id)).await
+    }
+
+    /// See [`QueryHandle::transaction`].
+    pub async fn transaction(&self, id: Txid) -> Result<Option<TransactionLookup>, KorndexError> {
+        self.with_query(move |query| query.transaction(&id)).await
+    }
+
+    /// See [`QueryHandle::address_history`].
+    pub async fn address_history(
+        &self,
+        script: ScriptBuf,
+    ) -> Result<Vec<(Txid, ScriptHashEntry)>, KorndexError> {
+        self.with_query(move |query| query.address_history(&script))
+            .await
+    }
+
+    /// See [`QueryHandle::utxos`].
+    pub async fn utxos(
+        &self,
+        script: ScriptBuf,
+    ) -> Result<Vec<(OutPoint, UtxoEntry)>, KorndexError> {
+        self.with_query(move |query| query.utxos(&script)).await
+    }
+
+    /// See [`QueryHandle::spend`].
+    pub async fn spend(
+        &self,
+        outpoint: OutPoint,
+    ) -> Result<Option<(Txid, SpendEntry)>, KorndexError> {
+        self.with_query(move |query| query.spend(&outpoint)).await
+    }
+
+    /// See [`QueryHandle::header`].
+    pub async fn header(&self, block_height: i32) -> Result<Option<Header>, KorndexError> {
+        self.with_query(move |query| query.header(block_height))
+            .await
+    }
+
+    /// Streams the height and txids of every indexed block from `start` to
+    /// `end` inclusive, read on the blocking thread pool a little ahead of the
+    /// consumer. The stream ends early after an error, or when dropped.
+    pub fn block_txids(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> impl Stream<Item = Result<(i32, Vec<Txid>), KorndexError>> {
+        self.scan(start, end, |query, block_height| {
+            query.block_txids(block_height)
+        })
+    }
+
+    /// Streams the silent payments tweaks of the blocks from `start` to `end`
+    /// inclusive with their block height, a block at a time.
+    pub fn tweaks(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> impl Stream<Item = Result<(i32, Vec<Vec<u8>>), KorndexError>> {
+        self.scan(start, end, |query, block_height| {
+            let tweaks = query.tweaks(block_height, block_height)?;
+            Ok(Some(tweaks.into_iter().map(|(_, tweak)| tweak).collect()))
+        })
+    }
+
+    /// Streams the height and hash of every block indexed from now on, in
+    /// chain order, including those a reorg connects in place of blocks
+    /// already streamed. Polls the index without holding a reader slot, and
+    /// stops when the stream is dropped or shutdown is requested.
+    pub fn new_blocks(&self) -> impl Stream<Item = Result<(i32, BlockHash), KorndexError>> {
+        let (tx, rx) = mpsc::channel(STREAM_READ_AHEAD);
+        let index = self.index.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut feed = match BlockFeed::new(index.kv()) {
+                Ok(feed) => feed,
+                Err(e) => {
+                    let _ = tx.blocking_send(Err(e));
+                    return;
+                }
+            };
+            while !tx.is_closed() && !shutdown::requested() {
+                let blocks = match feed.poll(index.kv()) {
+                    Ok(blocks) => blocks,
+                    Err(e) => {
+                        let _ = tx.blocking_send(Err(e));
+                        return;
+                    }
+                };
+                for block in blocks {
+                    if tx.blocking_send(Ok(block)).is_err() {
+                        return;
+                    }
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+
+    /// Streams `read` of every height from `start` to `end` inclusive that it
+    /// has a value for, holding a reader slot while the stream is read.
+    fn scan<T, F>(
+        &self,
+        start: i32,
+        end: i32,
+        read: F,
+    ) -> impl Stream<Item = Result<(i32, T), KorndexError>>
+    where
+        T: Send + 'static,
+        F: Fn(&QueryHandle, i32) -> Result<Option<T>, KorndexError> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(STREAM_READ_AHEAD);
+        let (index, readers) = (self.index.clone(), self.readers.clone());
+        tokio::spawn(async move {
+            let Ok(permit) = readers.acquire_owned().await else {
+                return;
+            };
+            tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let query = index.query();
+                for block_height in start..=end {
+                    let item = match read(&query, block_height) {
+                        Ok(Some(value)) => Ok((block_height, value)),
+                        Ok(None) => continue,
+                        Err(e) => Err(e),
+                    };
+                    let failed = item.is_err();
+                    // Fails once the stream is dropped
+                    if tx.blocking_send(item).is_err() || failed {
+                        return;
+                    }
+                }
+            });
+        });
+        ReceiverStream::new(rx)
+    }
+}
@@ -0,0 +1,302 @@
+//! Indexing benchmarks over a canned set of blocks, for `korndex bench` and
+//! the criterion suite in `benches/`. Blocks are connected one at a time into
+//! a scratch index, as `korndex sync` does, and every write to the store is
+//! timed.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::deserialize;
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::FromHex;
+use bitcoin::script::Builder;
+use bitcoin::transaction;
+use bitcoin::{
+    Amount, Block, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, WPubkeyHash,
+    Witness,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::store::{Backend, Batch, Durability, KvStore, Table};
+use crate::{Indexer, IndexerOptions, KorndexError, TxIndexStore};
+
+/// Initial LMDB map size of a scratch index.
+const MAP_SIZE: usize = 1 << 30;
+
+/// Reads the blocks in `dir`, one serialized block per file in file name
+/// order. Files ending in `.hex` hold the block hex encoded.
+pub fn load_sample(dir: &Path) -> Result<Vec<Block>, KorndexError> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    let mut blocks = Vec::with_capacity(paths.len());
+    for path in paths {
+        let data = fs::read(&path)?;
+        let data = match path.extension().is_some_and(|ext| ext == "hex") {
+            true => Vec::<u8>::from_hex(String::from_utf8(data)?.trim())?,
+            false => data,
+        };
+        let block: Block = deserialize(&data).map_err(|e| {
+            KorndexError::InvalidInput(format!("{} is not a block: {}", path.display(), e).into())
+        })?;
+        blocks.push(block);
+    }
+    if blocks.is_empty() {
+        return Err(KorndexError::Config(format!(
+            "No blocks in {}",
+            dir.display()
+        )));
+    }
+    Ok(blocks)
+}
+
+/// A chain of `count` blocks on top of the genesis block of `network`, each
+/// with a coinbase paying `txs_per_block` outputs and a segwit transaction
+/// spending each output of the previous block's coinbase. The blocks are not
+/// mined, which the index doesn't check.
+pub fn synthetic_blocks(network: Network, count: usize, txs_per_block: usize) -> Vec<Block> {
+    let genesis = genesis_block(network);
+    let script = |height: usize, index: usize| {
+        let hash = sha256::Hash::hash(&[height.to_le_bytes(), index.to_le_bytes()].concat());
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_slice(&hash.to_byte_array()[..20]).unwrap())
+    };
+    let mut blocks = vec![genesis];
+    for height in 1..count {
+        let prev = blocks.last().unwrap();
+        let coinbase = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                // BIP34 height, which also keeps the coinbase txids unique
+                script_sig: Builder::new().push_int(height as i64).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: (0..txs_per_block)
+                .map(|index| TxOut {
+                    value: Amount::from_sat(100_000),
+                    script_pubkey: script(height, index),
+                })
+                .collect(),
+        };
+        let mut txdata = vec![coinbase];
+        // Genesis pays a single output that can't be spent
+        if height > 1 {
+            let prev_coinbase = prev.txdata[0].compute_txid();
+            txdata.extend((0..txs_per_block).map(|index| Transaction {
+                version: transaction::Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(prev_coinbase, index as u32),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::from_slice(&[vec![0x30; 72], vec![0x02; 33]]),
+                }],
+                output: vec![TxOut {
+                    value: Amount::from_sat(99_000),
+                    script_pubkey: script(height, txs_per_block + index),
+                }],
+            }));
+        }
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: prev.header.merkle_root,
+                time: prev.header.time + 600,
+                bits: prev.header.bits,
+                nonce: 0,
+            },
+            txdata,
+        };
+        if let Some(root) = block.compute_merkle_root() {
+            block.header.merkle_root = root;
+        }
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Throughput and write latency of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub elapsed: Duration,
+    /// Time each block spent writing to the store, its batches and the commit
+    pub commits: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn blocks_per_sec(&self) -> f64 {
+        self.blocks as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn txs_per_sec(&self) -> f64 {
+        self.transactions as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Commit latency at `percentile`, between 0 and 100.
+    pub fn commit_latency(&self, percentile: f64) -> Duration {
+        let mut commits = self.commits.clone();
+        commits.sort_unstable();
+        let rank = (percentile / 100.0 * commits.len() as f64).ceil() as usize;
+        commits
+            .get(rank.clamp(1, commits.len().max(1)) - 1)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// A store timing the batches written since the last commit along with the
+/// commit itself.
+struct TimedStore {
+    inner: Box<dyn KvStore>,
+    pending: Mutex<Duration>,
+    commits: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl KvStore for TimedStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        self.inner.get(table, key)
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        self.inner.get_many(table, keys)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.inner.iter_prefix(table, prefix, f)
+    }
+
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.inner.iter_dups(table, key, f)
+    }
+
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        self.inner.count_dups(table, key)
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        let start = Instant::now();
+        self.inner.put_batch(batch)?;
+        *self.pending.lock().unwrap() += start.elapsed();
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), KorndexError> {
+        let start = Instant::now();
+        self.inner.commit()?;
+        let written = std::mem::take(&mut *self.pending.lock().unwrap());
+        self.commits.lock().unwrap().push(written + start.elapsed());
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        self.inner.clear(table)
+    }
+
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        self.inner.compact()
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        self.inner.set_durability(durability)
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        self.inner.disk_size()
+    }
+
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        self.inner.fill()
+    }
+}
+
+/// An index in a fresh directory below the system's temporary directory,
+/// deleted again when dropped.
+pub struct ScratchIndex {
+    /// Taken on drop, to close the store before its directory is deleted
+    index: Option<TxIndexStore>,
+    commits: Arc<Mutex<Vec<Duration>>>,
+    dir: PathBuf,
+}
+
+impl ScratchIndex {
+    pub fn create(backend: Backend, network: Network) -> Result<Self, KorndexError> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "korndex-bench-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let index = TxIndexStore::open(backend, &dir, network, MAP_SIZE)?.map_store(|inner| {
+            Box::new(TimedStore {
+                inner,
+                pending: Mutex::new(Duration::ZERO),
+                commits: commits.clone(),
+            })
+        });
+        Ok(ScratchIndex {
+            index: Some(index),
+            commits,
+            dir,
+        })
+    }
+
+    /// The scratch index's store.
+    pub fn kv(&self) -> &dyn KvStore {
+        self.index.as_ref().unwrap().kv()
+    }
+
+    /// Connects `blocks` in order, which must start with the genesis block of
+    /// the index's network.
+    pub fn run(
+        &self,
+        blocks: &[Block],
+        options: IndexerOptions,
+    ) -> Result<BenchReport, KorndexError> {
+        let index = self.index.as_ref().unwrap();
+        index.kv().set_durability(options.durability)?;
+        let indexer = Indexer::without_kernel(index, options);
+        self.commits.lock().unwrap().clear();
+        let start = Instant::now();
+        for block in blocks {
+            indexer.connect_block(block)?;
+        }
+        Ok(BenchReport {
+            blocks: blocks.len(),
+            transactions: blocks.iter().map(|block| block.txdata.len()).sum(),
+            elapsed: start.elapsed(),
+            commits: self.commits.lock().unwrap().clone(),
+        })
+    }
+}
+
+impl Drop for ScratchIndex {
+    fn drop(&mut self) {
+        drop(self.index.take());
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            tracing::warn!("Failed to delete {}: {}", self.dir.display(), e);
+        }
+    }
+}
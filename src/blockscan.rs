@@ -0,0 +1,162 @@
+use bitcoin::consensus::encode::Error;
+use bitcoin::hashes::{sha256d, Hash, HashEngine};
+use bitcoin::{TxOut, Txid, Wtxid};
+
+/// A transaction located and hashed in its serialized block without being
+/// deserialized.
+#[derive(Debug, Clone, Copy)]
+pub struct ScannedTx {
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    /// Offset of the serialized transaction within the block
+    pub offset: usize,
+    /// Serialized size with the witness
+    pub size: usize,
+    /// Serialized size without the witness
+    pub base_size: usize,
+    /// Sum of the output values in satoshis
+    pub output_value: u64,
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl ScannedTx {
+    pub fn weight(&self) -> usize {
+        self.base_size * 3 + self.size
+    }
+
+    /// Virtual size in vbytes.
+    pub fn vsize(&self) -> u32 {
+        self.weight().div_ceil(4) as u32
+    }
+
+    /// Whether the transaction is serialized with a witness.
+    pub fn is_segwit(&self) -> bool {
+        self.size != self.base_size
+    }
+
+    /// Offset and length within the block, as stored in the txindex.
+    pub fn byte_range(&self) -> (u32, u32) {
+        (self.offset as u32, self.size as u32)
+    }
+
+    /// Fee paid if the inputs spend `prevouts`, 0 for the coinbase which has
+    /// none.
+    pub fn fee(&self, prevouts: &[TxOut]) -> u64 {
+        let inputs: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+        inputs.saturating_sub(self.output_value)
+    }
+}
+
+/// Locates and hashes every transaction of `raw_block` by walking its
+/// consensus encoding, hashing the transactions' bytes in place instead of
+/// allocating their scripts and witnesses.
+pub fn scan_block(raw_block: &[u8]) -> Result<Vec<ScannedTx>, Error> {
+    let mut reader = Reader {
+        data: raw_block,
+        pos: 0,
+    };
+    reader.skip(80)?;
+    let n_tx = reader.compact_size()?;
+    // Every transaction takes at least 60 bytes, don't trust the count further
+    let mut txs = Vec::with_capacity(n_tx.min(raw_block.len() / 60));
+    for _ in 0..n_tx {
+        txs.push(scan_tx(&mut reader)?);
+    }
+    Ok(txs)
+}
+
+fn scan_tx(reader: &mut Reader) -> Result<ScannedTx, Error> {
+    let start = reader.pos;
+    reader.skip(4)?;
+    // The segwit marker and flag, a zero input count otherwise
+    let segwit = reader.data.get(reader.pos..reader.pos + 2) == Some(&[0, 1]);
+    if segwit {
+        reader.skip(2)?;
+    }
+    let body_start = reader.pos;
+    let inputs = reader.compact_size()?;
+    for _ in 0..inputs {
+        reader.skip(36)?;
+        let script_len = reader.compact_size()?;
+        reader.skip(script_len)?;
+        reader.skip(4)?;
+    }
+    let mut output_value = 0u64;
+    let outputs = reader.compact_size()?;
+    for _ in 0..outputs {
+        output_value = output_value.saturating_add(reader.u64()?);
+        let script_len = reader.compact_size()?;
+        reader.skip(script_len)?;
+    }
+    let body_end = reader.pos;
+    if segwit {
+        for _ in 0..inputs {
+            for _ in 0..reader.compact_size()? {
+                let item_len = reader.compact_size()?;
+                reader.skip(item_len)?;
+            }
+        }
+    }
+    let locktime = reader.pos;
+    reader.skip(4)?;
+    let end = reader.pos;
+
+    let data = reader.data;
+    // The txid commits to the transaction without marker, flag and witness
+    let mut engine = sha256d::Hash::engine();
+    engine.input(&data[start..start + 4]);
+    engine.input(&data[body_start..body_end]);
+    engine.input(&data[locktime..end]);
+    let txid = Txid::from_engine(engine);
+    let wtxid = match segwit {
+        true => Wtxid::hash(&data[start..end]),
+        false => Wtxid::from_raw_hash(txid.to_raw_hash()),
+    };
+    Ok(ScannedTx {
+        txid,
+        wtxid,
+        offset: start,
+        size: end - start,
+        base_size: 4 + (body_end - body_start) + 4,
+        output_value,
+        inputs,
+        outputs,
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(Error::ParseFailed("Truncated block"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), Error> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn compact_size(&mut self) -> Result<usize, Error> {
+        let value = match self.take(1)?[0] {
+            0xfd => u16::from_le_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            0xfe => u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            0xff => u64::from_le_bytes(self.take(8)?.try_into().unwrap()),
+            n => n as u64,
+        };
+        usize::try_from(value).map_err(|_| Error::ParseFailed("Oversized compact size"))
+    }
+}
@@ -0,0 +1,202 @@
+use bitcoin::params::Params;
+use bitcoin::{Amount, Network, TxOut};
+
+use crate::blockscan::ScannedTx;
+use crate::store::{height_key, KvStore, Table};
+use crate::KorndexError;
+
+pub const ENTRY_SIZE: usize = 72;
+
+/// Aggregates of a block's transactions, like those of Bitcoin Core's
+/// `getblockstats`. Encoded big-endian in field order into a fixed 72-byte
+/// value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    /// Transactions, including the coinbase
+    pub txs: u32,
+    /// Serialized size in bytes
+    pub size: u32,
+    pub weight: u32,
+    /// Inputs, without the coinbase's
+    pub inputs: u32,
+    /// Outputs, without the coinbase's
+    pub outputs: u32,
+    /// Transactions serialized with a witness
+    pub segwit_txs: u32,
+    /// Value of the outputs, without the coinbase's, in satoshis
+    pub total_out: u64,
+    /// Value of the coinbase's outputs, the subsidy and fees the miner claimed
+    pub coinbase_out: u64,
+    /// Fees paid in satoshis
+    pub total_fee: u64,
+    /// Lowest, median and highest fee rate of the non-coinbase transactions
+    /// in sat/vB, 0 for a block with only the coinbase
+    pub min_fee_rate: u64,
+    pub median_fee_rate: u64,
+    pub max_fee_rate: u64,
+}
+
+impl BlockStats {
+    /// Stats of the serialized block `raw_block`, whose transactions are
+    /// `scanned` and whose non-coinbase transactions spend `spent_outputs`.
+    pub fn compute(raw_block: &[u8], scanned: &[ScannedTx], spent_outputs: &[Vec<TxOut>]) -> Self {
+        // The header and transaction count weigh four units per byte, like
+        // any data outside a witness
+        let tx_bytes: usize = scanned.iter().map(|tx| tx.size).sum();
+        let tx_weight: usize = scanned.iter().map(ScannedTx::weight).sum();
+        let mut stats = BlockStats {
+            txs: scanned.len() as u32,
+            size: raw_block.len() as u32,
+            weight: ((raw_block.len() - tx_bytes) * 4 + tx_weight) as u32,
+            segwit_txs: scanned.iter().filter(|tx| tx.is_segwit()).count() as u32,
+            coinbase_out: scanned.first().map_or(0, |coinbase| coinbase.output_value),
+            ..BlockStats::default()
+        };
+        let mut fee_rates = Vec::with_capacity(scanned.len().saturating_sub(1));
+        for (tx, prevouts) in scanned.iter().skip(1).zip(spent_outputs) {
+            let fee = tx.fee(prevouts);
+            stats.inputs += tx.inputs as u32;
+            stats.outputs += tx.outputs as u32;
+            stats.total_out += tx.output_value;
+            stats.total_fee += fee;
+            fee_rates.push(fee / tx.vsize() as u64);
+        }
+        fee_rates.sort_unstable();
+        if let (Some(min), Some(max)) = (fee_rates.first(), fee_rates.last()) {
+            stats.min_fee_rate = *min;
+            stats.median_fee_rate = fee_rates[fee_rates.len() / 2];
+            stats.max_fee_rate = *max;
+        }
+        stats
+    }
+
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        let counts = [
+            self.txs,
+            self.size,
+            self.weight,
+            self.inputs,
+            self.outputs,
+            self.segwit_txs,
+        ];
+        for (i, count) in counts.iter().enumerate() {
+            buf[4 * i..4 * i + 4].copy_from_slice(&count.to_be_bytes());
+        }
+        let amounts = [
+            self.total_out,
+            self.coinbase_out,
+            self.total_fee,
+            self.min_fee_rate,
+            self.median_fee_rate,
+            self.max_fee_rate,
+        ];
+        for (i, amount) in amounts.iter().enumerate() {
+            buf[24 + 8 * i..32 + 8 * i].copy_from_slice(&amount.to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() != ENTRY_SIZE {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid block stats length {}",
+                data.len()
+            )));
+        }
+        let count = |i: usize| u32::from_be_bytes(data[4 * i..4 * i + 4].try_into().unwrap());
+        let amount =
+            |i: usize| u64::from_be_bytes(data[24 + 8 * i..32 + 8 * i].try_into().unwrap());
+        Ok(BlockStats {
+            txs: count(0),
+            size: count(1),
+            weight: count(2),
+            inputs: count(3),
+            outputs: count(4),
+            segwit_txs: count(5),
+            total_out: amount(0),
+            coinbase_out: amount(1),
+            total_fee: amount(2),
+            min_fee_rate: amount(3),
+            median_fee_rate: amount(4),
+            max_fee_rate: amount(5),
+        })
+    }
+}
+
+/// Totals of [`BlockStats`] over a range of blocks, whose counts outgrow the
+/// `u32` of a single block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStatsTotal {
+    pub blocks: u64,
+    /// Transactions, including each block's coinbase
+    pub txs: u64,
+    pub size: u64,
+    pub weight: u64,
+    pub inputs: u64,
+    pub outputs: u64,
+    pub segwit_txs: u64,
+    pub total_out: u64,
+    pub coinbase_out: u64,
+    pub total_fee: u64,
+    /// Lowest and highest fee rate of the blocks with transactions besides
+    /// the coinbase, and the median of their medians
+    pub min_fee_rate: u64,
+    pub median_fee_rate: u64,
+    pub max_fee_rate: u64,
+}
+
+impl BlockStatsTotal {
+    pub fn combine(blocks: &[BlockStats]) -> Self {
+        let mut total = BlockStatsTotal::default();
+        for block in blocks {
+            total.blocks += 1;
+            total.txs += block.txs as u64;
+            total.size += block.size as u64;
+            total.weight += block.weight as u64;
+            total.inputs += block.inputs as u64;
+            total.outputs += block.outputs as u64;
+            total.segwit_txs += block.segwit_txs as u64;
+            total.total_out += block.total_out;
+            total.coinbase_out += block.coinbase_out;
+            total.total_fee += block.total_fee;
+        }
+        let paying: Vec<&BlockStats> = blocks.iter().filter(|block| block.txs > 1).collect();
+        let mut medians: Vec<u64> = paying.iter().map(|block| block.median_fee_rate).collect();
+        medians.sort_unstable();
+        if !medians.is_empty() {
+            total.min_fee_rate = paying.iter().map(|block| block.min_fee_rate).min().unwrap();
+            total.median_fee_rate = medians[medians.len() / 2];
+            total.max_fee_rate = paying.iter().map(|block| block.max_fee_rate).max().unwrap();
+        }
+        total
+    }
+
+    /// Average fee of the non-coinbase transactions in satoshis.
+    pub fn avg_fee(&self) -> u64 {
+        self.total_fee
+            .checked_div(self.txs.saturating_sub(self.blocks))
+            .unwrap_or(0)
+    }
+}
+
+/// Block subsidy at `block_height` on `network`, halving every
+/// `subsidy_halving_interval` blocks.
+pub fn subsidy(network: Network, block_height: i32) -> Amount {
+    let halvings = block_height as u32 / Params::new(network).subsidy_halving_interval;
+    match halvings {
+        0..64 => Amount::from_sat(Amount::from_int_btc(50).to_sat() >> halvings),
+        _ => Amount::ZERO,
+    }
+}
+
+/// Stats of the indexed block at `block_height`.
+pub fn read_block_stats(
+    store: &dyn KvStore,
+    block_height: i32,
+) -> Result<Option<BlockStats>, KorndexError> {
+    match store.get(Table::BlockStats, &height_key(block_height))? {
+        Some(data) => Ok(Some(BlockStats::decode(&data)?)),
+        None => Ok(None),
+    }
+}
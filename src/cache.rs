@@ -0,0 +1,155 @@
+//! In-memory caches of serve mode, so hot explorer queries don't read and
+//! deserialize the same multi-megabyte blocks over and over. Entries are keyed
+//! by block hash, a reorg leaves the replaced blocks to age out.
+
+use bitcoin::block::Header;
+use bitcoin::{Block, BlockHash, Transaction, TxOut};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::KorndexError;
+
+/// Blocks kept by default, a few hundred megabytes for full blocks.
+pub const DEFAULT_BLOCKS: usize = 32;
+
+/// Transactions kept by default.
+pub const DEFAULT_TXS: usize = 10_000;
+
+struct Entries<K, V> {
+    /// Values with the tick they were last used at
+    values: HashMap<K, (V, u64)>,
+    /// Keys by the tick they were last used at, least recently used first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+/// A least recently used cache of at most `capacity` entries, shared across
+/// threads. A capacity of 0 caches nothing.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: Mutex<Entries<K, V>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// The value cached for `key`, which becomes the most recently used.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            values,
+            order,
+            tick,
+        } = &mut *entries;
+        let (value, used) = values.get_mut(key)?;
+        *tick += 1;
+        order.remove(&*used);
+        order.insert(*tick, key.clone());
+        *used = *tick;
+        Some(value.clone())
+    }
+
+    /// Caches `value` for `key`, evicting the least recently used entry if
+    /// the cache is full.
+    pub fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            values,
+            order,
+            tick,
+        } = &mut *entries;
+        *tick += 1;
+        if let Some((_, used)) = values.insert(key.clone(), (value, *tick)) {
+            order.remove(&used);
+        }
+        order.insert(*tick, key);
+        while values.len() > self.capacity {
+            let Some((_, evicted)) = order.pop_first() else {
+                break;
+            };
+            values.remove(&evicted);
+        }
+    }
+}
+
+/// A deserialized block with the outputs its transactions spend.
+pub struct CachedBlock {
+    pub block: Block,
+    /// Outputs spent by each non-coinbase transaction, in input order
+    pub spent_outputs: Vec<Vec<TxOut>>,
+}
+
+impl CachedBlock {
+    /// The transaction at `position` and the outputs it spends.
+    pub fn get(&self, position: usize) -> Result<(&Transaction, &[TxOut]), KorndexError> {
+        let prevouts = match position {
+            0 => Some(&[][..]),
+            _ => self
+                .spent_outputs
+                .get(position - 1)
+                .map(|prevouts| &prevouts[..]),
+        };
+        match (self.block.txdata.get(position), prevouts) {
+            (Some(tx), Some(prevouts)) => Ok((tx, prevouts)),
+            _ => Err(KorndexError::Corrupt(format!(
+                "Position {} is outside block {} of {} transactions",
+                position,
+                self.block.block_hash(),
+                self.block.txdata.len()
+            ))),
+        }
+    }
+
+    /// The transaction at `position` with its prevouts.
+    pub fn tx(&self, position: usize) -> Result<CachedTx, KorndexError> {
+        let (tx, prevouts) = self.get(position)?;
+        Ok(CachedTx {
+            tx: tx.clone(),
+            prevouts: prevouts.to_vec(),
+            header: self.block.header,
+        })
+    }
+}
+
+/// A confirmed transaction with its prevouts and the header of its block.
+pub struct CachedTx {
+    pub tx: Transaction,
+    /// Empty for a coinbase
+    pub prevouts: Vec<TxOut>,
+    pub header: Header,
+}
+
+/// Blocks by hash and transactions by block hash and position, each with its
+/// own capacity.
+pub struct QueryCache {
+    pub blocks: LruCache<BlockHash, Arc<CachedBlock>>,
+    pub txs: LruCache<(BlockHash, usize), Arc<CachedTx>>,
+}
+
+impl QueryCache {
+    pub fn new(blocks: usize, txs: usize) -> Self {
+        QueryCache {
+            blocks: LruCache::new(blocks),
+            txs: LruCache::new(txs),
+        }
+    }
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        QueryCache::new(DEFAULT_BLOCKS, DEFAULT_TXS)
+    }
+}
@@ -0,0 +1,784 @@
+//! Parsing of the values korndex's commands take, and the text they print.
+
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::{BlockHash, Network, OutPoint, Script, Txid};
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::analytics::ValueHistogram;
+use crate::bench::BenchReport;
+use crate::locktime::LocktimeCounts;
+use crate::scripthash::Direction;
+use crate::scripttypes::{ScriptType, ScriptTypeCounts};
+use crate::store::KvStore;
+use crate::{blockstats, coinbase, graph, json, stats};
+use crate::{BuildEstimate, KorndexError, QueryHandle};
+
+/// Most txids listed when a txid prefix is ambiguous.
+const MAX_LISTED_MATCHES: usize = 20;
+
+/// How a transaction is printed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// Location summary, and the decoded transaction with `--with-tx`
+    Text,
+    /// Consensus-serialized transaction
+    Hex,
+    /// Esplora-style JSON with inputs, outputs, amounts and scripts
+    Json,
+}
+
+pub fn parse_txid(txid: &str, raw: bool) -> Result<Txid, KorndexError> {
+    if raw {
+        Ok(Txid::from_byte_array(<[u8; 32]>::from_hex(txid)?))
+    } else {
+        Ok(Txid::from_str(txid)?)
+    }
+}
+
+/// Parses a txid, or finds the one indexed txid starting with a shorter
+/// prefix.
+pub fn resolve_txid(query: &QueryHandle, txid: &str, raw: bool) -> Result<Txid, KorndexError> {
+    if txid.len() == 64 {
+        return parse_txid(txid, raw);
+    }
+    if !raw {
+        tracing::info!(
+            "Scanning the txindex for the prefix, pass --raw with a prefix of the raw bytes for a range lookup"
+        );
+    }
+    let matches = query.txids_with_prefix(txid, raw, MAX_LISTED_MATCHES + 1)?;
+    match matches.as_slice() {
+        [] => Err(KorndexError::NotFound(format!(
+            "No indexed transaction starts with {}",
+            txid
+        ))),
+        [found] => Ok(*found),
+        _ => {
+            let mut listed: Vec<String> = matches
+                .iter()
+                .take(MAX_LISTED_MATCHES)
+                .map(Txid::to_string)
+                .collect();
+            if matches.len() > MAX_LISTED_MATCHES {
+                listed.push("...".to_owned());
+            }
+            Err(KorndexError::InvalidInput(
+                format!(
+                    "Prefix {} is ambiguous, it matches {}",
+                    txid,
+                    listed.join(", ")
+                )
+                .into(),
+            ))
+        }
+    }
+}
+
+/// Parses an inclusive `<start>..<end>` height range.
+pub fn parse_range(range: &str) -> Result<(i32, i32), KorndexError> {
+    let Some((start, end)) = range.split_once("..") else {
+        return Err(KorndexError::InvalidInput(
+            format!("Invalid range {}, expected <start>..<end>", range).into(),
+        ));
+    };
+    Ok((start.parse()?, end.parse()?))
+}
+
+/// Resolves a block given by height or hash to its height.
+pub fn parse_block(query: &QueryHandle, block: &str) -> Result<i32, KorndexError> {
+    match block.parse::<i32>() {
+        Ok(height) => Ok(height),
+        Err(_) => query
+            .block_height(&BlockHash::from_str(block)?)?
+            .ok_or_else(|| KorndexError::NotFound(format!("Block {} not found", block))),
+    }
+}
+
+/// Parses a Unix timestamp or an RFC 3339 date and time.
+pub fn parse_time(time: &str) -> Result<u32, KorndexError> {
+    if let Ok(timestamp) = time.parse::<u32>() {
+        return Ok(timestamp);
+    }
+    let secs = humantime::parse_rfc3339_weak(time)?
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| KorndexError::InvalidInput(format!("{} is before 1970", time).into()))?
+        .as_secs();
+    u32::try_from(secs).map_err(|_| {
+        KorndexError::InvalidInput(format!("{} is too far in the future", time).into())
+    })
+}
+
+pub fn query_transaction(
+    query: &QueryHandle,
+    network: Network,
+    id: &Txid,
+    format: Format,
+    with_tx: bool,
+) -> Result<(), KorndexError> {
+    if matches!(format, Format::Text) && !with_tx {
+        let Some((txid, via_wtxid, entry)) = query.locate(id)? else {
+            return Err(query.txid_not_found(id));
+        };
+        if via_wtxid {
+            println!("Witness Transaction ID: {}", id);
+        }
+        println!(
+            "Transaction ID: {}, Block Height: {}, Block Location: {}, Fee: {} sat, Fee Rate: {:.2} sat/vB",
+            txid,
+            entry.block_height,
+            entry.position_in_block,
+            entry.fee,
+            entry.fee_rate()
+        );
+        for other in query.locations(&txid)? {
+            if other.block_height != entry.block_height {
+                println!(
+                    "Also at Block Height: {}, Block Location: {}",
+                    other.block_height, other.position_in_block
+                );
+            }
+        }
+        return Ok(());
+    }
+    let Some(found) = query.transaction(id)? else {
+        return Err(query.txid_not_found(id));
+    };
+    match format {
+        Format::Text => {
+            if found.via_wtxid {
+                println!("Witness Transaction ID: {}", id);
+            }
+            println!(
+                "Transaction ID: {}, Block Location: {}, Fee: {} sat, Fee Rate: {:.2} sat/vB",
+                found.txid,
+                found.entry.position_in_block,
+                found.entry.fee,
+                found.entry.fee_rate()
+            );
+            println!("Full transaction: {:#?}", found.tx);
+        }
+        Format::Hex => println!("{}", serialize_hex(&found.tx)),
+        Format::Json => {
+            let prevouts = query.prevouts(&found)?;
+            let json = json::tx_json(
+                &found.tx,
+                &prevouts,
+                network,
+                found.entry.block_height,
+                &found.header,
+            );
+            println!("{}", json);
+        }
+    }
+    Ok(())
+}
+
+pub fn query_batch(
+    query: &QueryHandle,
+    network: Network,
+    path: &Path,
+    raw: bool,
+    format: Format,
+) -> Result<(), KorndexError> {
+    let input: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    let mut ids = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match parse_txid(line, raw) {
+            Ok(id) => ids.push(id),
+            Err(e) => println!("{}", json!({ "id": line, "error": e.to_string() })),
+        }
+    }
+
+    let (found, missing) = query.transactions(&ids)?;
+    for found in found {
+        let value = match format {
+            Format::Hex => json!({
+                "txid": found.txid.to_string(),
+                "block_height": found.entry.block_height,
+                "position_in_block": found.entry.position_in_block,
+                "fee": found.entry.fee,
+                "vsize": found.entry.vsize,
+                "hex": serialize_hex(&found.tx),
+            }),
+            Format::Text | Format::Json => {
+                let prevouts = query.prevouts(&found)?;
+                json::tx_json(
+                    &found.tx,
+                    &prevouts,
+                    network,
+                    found.entry.block_height,
+                    &found.header,
+                )
+            }
+        };
+        println!("{}", value);
+    }
+    for id in &missing {
+        let error = query.txid_not_found(id);
+        println!(
+            "{}",
+            json!({ "id": id.to_string(), "error": error.to_string() })
+        );
+    }
+    if let Some(id) = missing.first() {
+        return Err(KorndexError::NotFound(format!(
+            "{} of the transactions were not found, starting with {}",
+            missing.len(),
+            id
+        )));
+    }
+    Ok(())
+}
+
+pub fn query_address(query: &QueryHandle, script: &Script) -> Result<(), KorndexError> {
+    for (txid, entry) in query.address_history(script)? {
+        let direction = match entry.direction {
+            Direction::Output => "Output",
+            Direction::Input => "Input",
+        };
+        println!(
+            "Transaction ID: {}, Block Height: {}, {}: {}",
+            txid, entry.block_height, direction, entry.index
+        );
+    }
+    Ok(())
+}
+
+pub fn query_utxos(query: &QueryHandle, script: &Script) -> Result<(), KorndexError> {
+    let utxos = query.utxos(script)?;
+    for (outpoint, utxo) in utxos.iter() {
+        println!(
+            "Outpoint: {}, Value: {}, Block Height: {}, Coinbase: {}",
+            outpoint,
+            utxo.value.to_sat(),
+            utxo.block_height,
+            utxo.is_coinbase
+        );
+    }
+    let balance: u64 = utxos.iter().map(|(_, utxo)| utxo.value.to_sat()).sum();
+    println!("Balance: {} sat in {} outputs", balance, utxos.len());
+    Ok(())
+}
+
+pub fn query_spend(query: &QueryHandle, outpoint: &OutPoint) -> Result<(), KorndexError> {
+    let Some((txid, entry)) = query.spend(outpoint)? else {
+        println!(
+            "Outpoint {} has not been spent in the indexed blocks",
+            outpoint
+        );
+        return Ok(());
+    };
+    println!(
+        "Outpoint: {}, Spent by Transaction ID: {}, Block Height: {}, Input: {}",
+        outpoint, txid, entry.block_height, entry.input_index
+    );
+    Ok(())
+}
+
+pub fn query_tweaks(query: &QueryHandle, start: i32, end: i32) -> Result<(), KorndexError> {
+    for (block_height, tweak) in query.tweaks(start, end)? {
+        println!(
+            "Block Height: {}, Tweak: {}",
+            block_height,
+            tweak.to_lower_hex_string()
+        );
+    }
+    Ok(())
+}
+
+pub fn query_block(query: &QueryHandle, block: &str) -> Result<(), KorndexError> {
+    let block_height = parse_block(query, block)?;
+    let txids = query
+        .block_txids(block_height)?
+        .ok_or_else(|| KorndexError::NotFound(format!("Block {} is not indexed", block)))?;
+    for (position, txid) in txids.iter().enumerate() {
+        println!(
+            "Transaction ID: {}, Block Height: {}, Block Location: {}",
+            txid, block_height, position
+        );
+    }
+    Ok(())
+}
+
+pub fn query_coinbase(
+    query: &QueryHandle,
+    network: Network,
+    block: &str,
+    format: Format,
+) -> Result<(), KorndexError> {
+    let block_height = parse_block(query, block)?;
+    let (txid, entry) = query
+        .coinbase(block_height)?
+        .ok_or_else(|| KorndexError::NotFound(format!("Block {} is not indexed", block)))?;
+    let subsidy = blockstats::subsidy(network, block_height).to_sat();
+    let confirmations = query
+        .best_height()?
+        .map_or(0, |best_height| best_height - block_height + 1);
+    let mature = confirmations >= coinbase::COINBASE_MATURITY;
+    let commitment = entry
+        .witness_commitment
+        .map(|commitment| commitment.to_lower_hex_string());
+    if let Format::Json = format {
+        println!(
+            "{}",
+            json!({
+                "txid": txid.to_string(),
+                "height": block_height,
+                "subsidy": subsidy,
+                "fees": entry.fees,
+                "output_value": entry.output_value,
+                "mature": mature,
+                "script_sig": entry.script_sig.as_bytes().to_lower_hex_string(),
+                "tags": entry.tags(),
+                "witness_commitment": commitment,
+            })
+        );
+        return Ok(());
+    }
+    println!(
+        "Transaction ID: {}, Block Height: {}, Subsidy: {}, Fees: {}, Output Value: {}, Mature: {}, ScriptSig: {}, Tags: {}, Witness Commitment: {}",
+        txid,
+        block_height,
+        subsidy,
+        entry.fees,
+        entry.output_value,
+        mature,
+        entry.script_sig.as_bytes().to_lower_hex_string(),
+        entry.tags().join(" | "),
+        commitment.as_deref().unwrap_or("none")
+    );
+    Ok(())
+}
+
+pub fn query_witness(
+    query: &QueryHandle,
+    start: i32,
+    end: i32,
+    min_size: u32,
+) -> Result<(), KorndexError> {
+    for (block_height, txid, entry) in query.witness_data(start, end, min_size)? {
+        println!(
+            "Transaction ID: {}, Block Height: {}, Input: {}, Kind: {}, Item: {}, Size: {}",
+            txid,
+            block_height,
+            entry.input_index,
+            entry.kind.name(),
+            entry.item_index,
+            entry.size
+        );
+    }
+    Ok(())
+}
+
+pub fn query_height_at(query: &QueryHandle, time: u32) -> Result<(), KorndexError> {
+    let (block_height, median_time) = query.height_at(time)?.ok_or_else(|| {
+        KorndexError::NotFound(format!("No indexed block was the tip at {}", time))
+    })?;
+    let header = query
+        .header(block_height)?
+        .ok_or_else(|| KorndexError::Corrupt(format!("No header for block {}", block_height)))?;
+    println!(
+        "Block Height: {}, Block Hash: {}, Median Time Past: {}",
+        block_height,
+        header.block_hash(),
+        median_time
+    );
+    Ok(())
+}
+
+pub fn query_proof(query: &QueryHandle, txid: &Txid) -> Result<(), KorndexError> {
+    let merkle_block = query
+        .merkle_proof(txid)?
+        .ok_or_else(|| query.txid_not_found(txid))?;
+    println!("{}", serialize_hex(&merkle_block));
+    Ok(())
+}
+
+pub fn query_block_stats(
+    query: &QueryHandle,
+    network: Network,
+    start: i32,
+    end: i32,
+    format: Format,
+) -> Result<(), KorndexError> {
+    let blocks = query.block_stats(start, end)?;
+    if blocks.is_empty() {
+        return Err(KorndexError::NotFound(format!(
+            "No blocks between heights {} and {} are indexed",
+            start, end
+        )));
+    }
+    let stats: Vec<_> = blocks.iter().map(|(_, stats)| *stats).collect();
+    let total = blockstats::BlockStatsTotal::combine(&stats);
+    let subsidy: u64 = blocks
+        .iter()
+        .map(|(block_height, _)| blockstats::subsidy(network, *block_height).to_sat())
+        .sum();
+    if let Format::Json = format {
+        let mut object = json!({
+            "blocks": blocks.len(),
+            "txs": total.txs,
+            "total_size": total.size,
+            "total_weight": total.weight,
+            "ins": total.inputs,
+            "outs": total.outputs,
+            "swtxs": total.segwit_txs,
+            "total_out": total.total_out,
+            "totalfee": total.total_fee,
+            "avgfee": total.avg_fee(),
+            "subsidy": subsidy,
+            "minfeerate": total.min_fee_rate,
+            "medianfeerate": total.median_fee_rate,
+            "maxfeerate": total.max_fee_rate,
+        });
+        if start == end {
+            object["height"] = json!(start);
+        } else {
+            object["start_height"] = json!(start);
+            object["end_height"] = json!(end);
+        }
+        println!("{}", object);
+        return Ok(());
+    }
+    let blocks = match start == end {
+        true => format!("Block Height: {}", start),
+        false => format!(
+            "Block Heights: {}..{}, Blocks: {}",
+            start,
+            end,
+            blocks.len()
+        ),
+    };
+    println!(
+        "{}, Transactions: {}, Size: {}, Weight: {}, Inputs: {}, Outputs: {}, Total Out: {}, Total Fee: {}, Subsidy: {}, Fee Rate: {}/{}/{} sat/vB (min/median/max)",
+        blocks,
+        total.txs,
+        total.size,
+        total.weight,
+        total.inputs,
+        total.outputs,
+        total.total_out,
+        total.total_fee,
+        subsidy,
+        total.min_fee_rate,
+        total.median_fee_rate,
+        total.max_fee_rate
+    );
+    Ok(())
+}
+
+pub fn query_graph(
+    query: &QueryHandle,
+    txid: &Txid,
+    depth: u32,
+    direction: graph::Direction,
+) -> Result<(), KorndexError> {
+    let entries = query
+        .graph(txid, depth, direction)?
+        .ok_or_else(|| query.txid_not_found(txid))?;
+    for entry in entries {
+        println!(
+            "Transaction ID: {}, Depth: {}, Via: {}",
+            entry.txid, entry.depth, entry.via
+        );
+    }
+    Ok(())
+}
+
+pub fn query_filter(query: &QueryHandle, block: &str) -> Result<(), KorndexError> {
+    let block_height = parse_block(query, block)?;
+    let (filter, header) = query.filter(block_height)?;
+    println!(
+        "Block Height: {}, Filter: {}, Filter Header: {}",
+        block_height,
+        filter.to_lower_hex_string(),
+        header.map_or("unavailable on a pruned index".to_owned(), |h| h
+            .to_string())
+    );
+    Ok(())
+}
+
+pub fn stats_script_types(
+    query: &QueryHandle,
+    start: i32,
+    end: i32,
+    per_block: bool,
+) -> Result<(), KorndexError> {
+    let blocks = query.script_types(start, end)?;
+    if per_block {
+        for (block_height, counts) in blocks {
+            let counts: Vec<String> = ScriptType::ALL
+                .iter()
+                .map(|kind| format!("{}: {}", kind.name(), counts.get(*kind)))
+                .collect();
+            println!("Block Height: {}, {}", block_height, counts.join(", "));
+        }
+        return Ok(());
+    }
+    let mut total = ScriptTypeCounts::default();
+    for (_, counts) in blocks.iter() {
+        total += *counts;
+    }
+    println!(
+        "Block Heights: {}..{}, Blocks: {}, Outputs: {}",
+        start,
+        end,
+        blocks.len(),
+        total.total()
+    );
+    for kind in ScriptType::ALL {
+        let share = match total.total() {
+            0 => 0.0,
+            outputs => 100.0 * total.get(kind) as f64 / outputs as f64,
+        };
+        println!(
+            "Script Type: {}, Outputs: {}, Share: {:.2}%",
+            kind.name(),
+            total.get(kind),
+            share
+        );
+    }
+    Ok(())
+}
+
+pub fn stats_value_distribution(
+    query: &QueryHandle,
+    start: i32,
+    end: i32,
+    per_block: bool,
+) -> Result<(), KorndexError> {
+    let (bounds, blocks) = query.value_distribution(start, end)?;
+    let labels = value_bucket_labels(&bounds);
+    if per_block {
+        for (block_height, histogram) in blocks {
+            let counts: Vec<String> = labels
+                .iter()
+                .zip(&histogram.buckets)
+                .map(|(label, (count, _))| format!("{}: {}", label, count))
+                .collect();
+            println!("Block Height: {}, {}", block_height, counts.join(", "));
+        }
+        return Ok(());
+    }
+    let mut total = ValueHistogram::empty(&bounds);
+    for (_, histogram) in blocks.iter() {
+        total.add(histogram);
+    }
+    let outputs: u64 = total.buckets.iter().map(|(count, _)| count).sum();
+    println!(
+        "Block Heights: {}..{}, Blocks: {}, Outputs: {}",
+        start,
+        end,
+        blocks.len(),
+        outputs
+    );
+    for (label, (count, value)) in labels.iter().zip(&total.buckets) {
+        let share = match outputs {
+            0 => 0.0,
+            outputs => 100.0 * *count as f64 / outputs as f64,
+        };
+        println!(
+            "Value Bucket: {} sats, Outputs: {}, Value: {}, Share: {:.2}%",
+            label, count, value, share
+        );
+    }
+    Ok(())
+}
+
+pub fn stats_locktime(
+    query: &QueryHandle,
+    start: i32,
+    end: i32,
+    per_block: bool,
+) -> Result<(), KorndexError> {
+    let blocks = query.locktimes(start, end)?;
+    if per_block {
+        for (block_height, counts) in blocks {
+            print_locktime_counts(&format!("Block Height: {}", block_height), &counts);
+        }
+        return Ok(());
+    }
+    let mut total = LocktimeCounts::default();
+    for (_, counts) in blocks.iter() {
+        total += *counts;
+    }
+    let prefix = format!(
+        "Block Heights: {}..{}, Blocks: {}",
+        start,
+        end,
+        blocks.len()
+    );
+    print_locktime_counts(&prefix, &total);
+    Ok(())
+}
+
+fn print_locktime_counts(prefix: &str, counts: &LocktimeCounts) {
+    let share = |count: u64, total: u64| match total {
+        0 => 0.0,
+        total => 100.0 * count as f64 / total as f64,
+    };
+    println!(
+        "{}, Transactions: {}, Height Locked: {}, Time Locked: {}, RBF: {} ({:.2}%), Inputs: {}, CSV Height Locked: {}, CSV Time Locked: {} ({:.2}%)",
+        prefix,
+        counts.txs,
+        counts.height_locked_txs,
+        counts.time_locked_txs,
+        counts.rbf_txs,
+        share(counts.rbf_txs, counts.txs),
+        counts.inputs,
+        counts.csv_height_inputs,
+        counts.csv_time_inputs,
+        share(counts.csv_height_inputs + counts.csv_time_inputs, counts.inputs)
+    );
+}
+
+/// Labels of the buckets split at `bounds`, like `546..10000`.
+fn value_bucket_labels(bounds: &[u64]) -> Vec<String> {
+    let mut labels = Vec::with_capacity(bounds.len() + 1);
+    labels.push(format!("0..{}", bounds[0]));
+    for pair in bounds.windows(2) {
+        labels.push(format!("{}..{}", pair[0], pair[1]));
+    }
+    labels.push(format!("{}..", bounds[bounds.len() - 1]));
+    labels
+}
+
+pub fn print_estimate(estimate: &BuildEstimate) {
+    let Some((start, end)) = estimate.heights else {
+        println!("Heights: none to index, the index is up to date");
+        return;
+    };
+    const GIB: f64 = (1u64 << 30) as f64;
+    println!(
+        "Heights: {}..={}, Sampled Blocks: {}",
+        start, end, estimate.samples
+    );
+    println!(
+        "Block Data: {} bytes, Transactions: {}",
+        estimate.block_bytes, estimate.transactions
+    );
+    println!(
+        "Index Growth: {} bytes ({:.1} GiB), Existing Index: {} bytes",
+        estimate.index_bytes,
+        estimate.index_bytes as f64 / GIB,
+        estimate.existing_bytes
+    );
+    println!(
+        "Duration: {}",
+        humantime::format_duration(Duration::from_secs(estimate.duration.as_secs()))
+    );
+    println!(
+        "Map Size: {} bytes, pass --db-map-size {}",
+        estimate.map_size,
+        (estimate.map_size as f64 / GIB).ceil() as u64
+    );
+}
+
+pub fn print_stats(store: &dyn KvStore) -> Result<(), KorndexError> {
+    let stats = stats::collect(store)?;
+    if let Some(version) = stats.schema_version {
+        println!("Schema Version: {}", version);
+    }
+    match stats.best {
+        Some(best) => println!(
+            "Heights: {}..={}, Tip: {}",
+            stats.start_height,
+            best.height,
+            BlockHash::from_byte_array(best.hash)
+        ),
+        None => println!("Heights: none indexed"),
+    }
+    if let Some(end_height) = stats.end_height {
+        println!("End Height: {}, not following the tip", end_height);
+    }
+    if let Some(times) = stats.build_times {
+        let format =
+            |time| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(time));
+        println!(
+            "Created: {}, Last Indexed: {}",
+            format(times.created),
+            format(times.updated)
+        );
+    }
+    println!("Indexes: {}", stats.indexes);
+    if !stats.compression.is_empty() {
+        let tables: Vec<String> = stats
+            .compression
+            .iter()
+            .map(|compression| {
+                let dictionary = match compression.dictionary {
+                    Some(_) => " with a dictionary",
+                    None => "",
+                };
+                format!(
+                    "{} (level {}{})",
+                    compression.table.name(),
+                    compression.level,
+                    dictionary
+                )
+            })
+            .collect();
+        println!("Compressed Tables: {}", tables.join(", "));
+    }
+    if let Some(shards) = stats.txindex_shards {
+        println!("Txindex Shards: {}", shards);
+    }
+    print!("Disk Size: {} bytes", stats.disk_size);
+    match stats.fill {
+        Some((used, capacity)) => println!(
+            ", Used: {} of {} bytes ({:.1}%)",
+            used,
+            capacity,
+            used as f64 * 100.0 / capacity as f64
+        ),
+        None => println!(),
+    }
+    for table in stats.tables {
+        println!(
+            "Table: {}, Entries: {}, Key Bytes: {}, Value Bytes: {}",
+            table.table.name(),
+            table.entries,
+            table.key_bytes,
+            table.value_bytes
+        );
+    }
+    Ok(())
+}
+
+pub fn print_bench_report(report: &BenchReport) {
+    println!(
+        "Blocks: {}, Transactions: {}, Elapsed: {:.2}s",
+        report.blocks,
+        report.transactions,
+        report.elapsed.as_secs_f64()
+    );
+    println!(
+        "Blocks/s: {:.1}, Transactions/s: {:.1}",
+        report.blocks_per_sec(),
+        report.txs_per_sec()
+    );
+    let millis = |percentile: f64| report.commit_latency(percentile).as_secs_f64() * 1000.0;
+    println!(
+        "Commit latency: p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+        millis(50.0),
+        millis(90.0),
+        millis(99.0),
+        millis(100.0)
+    );
+}
@@ -0,0 +1,133 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::{ScriptBuf, Transaction, TxOut};
+
+use crate::blockscan::ScannedTx;
+use crate::store::{height_key, KvStore, Table};
+use crate::KorndexError;
+
+/// Confirmations a coinbase needs before its outputs can be spent, counting
+/// its own block.
+pub const COINBASE_MATURITY: i32 = 100;
+
+/// Prefix of the BIP141 witness commitment output's scriptPubKey, OP_RETURN
+/// pushing 36 bytes starting with 0xaa21a9ed.
+const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// Length of the fixed fields preceding the scriptSig.
+const HEADER_SIZE: usize = 17;
+
+/// What a block's coinbase claimed. Encoded as the big-endian output value and
+/// fees, a commitment flag, the 32-byte commitment if flagged, and the
+/// scriptSig. The subsidy follows from the height and network, see
+/// [`crate::blockstats::subsidy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinbaseEntry {
+    /// Value of the coinbase's outputs in satoshis
+    pub output_value: u64,
+    /// Fees paid by the block's other transactions in satoshis
+    pub fees: u64,
+    /// The BIP141 commitment to the block's witness data
+    pub witness_commitment: Option<[u8; 32]>,
+    /// The coinbase's scriptSig, with the BIP34 height and any pool tag
+    pub script_sig: ScriptBuf,
+}
+
+impl CoinbaseEntry {
+    /// The coinbase entry of the serialized block `raw_block`, whose
+    /// transactions are `scanned` and whose non-coinbase transactions spend
+    /// `spent_outputs`.
+    pub fn compute(
+        raw_block: &[u8],
+        scanned: &[ScannedTx],
+        spent_outputs: &[Vec<TxOut>],
+    ) -> Result<Self, KorndexError> {
+        let first = scanned
+            .first()
+            .ok_or_else(|| KorndexError::Corrupt("Block without a coinbase".to_owned()))?;
+        let coinbase: Transaction =
+            deserialize(&raw_block[first.offset..first.offset + first.size])?;
+        let fees = scanned
+            .iter()
+            .skip(1)
+            .zip(spent_outputs)
+            .map(|(tx, prevouts)| tx.fee(prevouts))
+            .sum();
+        // The last matching output is the commitment if there are several
+        let witness_commitment = coinbase
+            .output
+            .iter()
+            .rev()
+            .map(|output| output.script_pubkey.as_bytes())
+            .find(|script| script.len() >= 38 && script.starts_with(&WITNESS_COMMITMENT_PREFIX))
+            .map(|script| script[6..38].try_into().unwrap());
+        let input = coinbase
+            .input
+            .first()
+            .ok_or_else(|| KorndexError::Corrupt("Coinbase without an input".to_owned()))?;
+        Ok(CoinbaseEntry {
+            output_value: first.output_value,
+            fees,
+            witness_commitment,
+            script_sig: input.script_sig.clone(),
+        })
+    }
+
+    /// Printable ASCII runs of at least four characters in the scriptSig,
+    /// where pools put their tags.
+    pub fn tags(&self) -> Vec<String> {
+        self.script_sig
+            .as_bytes()
+            .split(|byte| !byte.is_ascii_graphic() && *byte != b' ')
+            .filter(|run| run.len() >= 4)
+            .map(|run| String::from_utf8_lossy(run).trim().to_owned())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let script = self.script_sig.as_bytes();
+        let mut buf = Vec::with_capacity(HEADER_SIZE + 32 + script.len());
+        buf.extend_from_slice(&self.output_value.to_be_bytes());
+        buf.extend_from_slice(&self.fees.to_be_bytes());
+        buf.push(self.witness_commitment.is_some() as u8);
+        if let Some(commitment) = &self.witness_commitment {
+            buf.extend_from_slice(commitment);
+        }
+        buf.extend_from_slice(script);
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        let invalid =
+            || KorndexError::Corrupt(format!("Invalid coinbase entry length {}", data.len()));
+        if data.len() < HEADER_SIZE {
+            return Err(invalid());
+        }
+        let (witness_commitment, script) = match data[16] {
+            0 => (None, &data[HEADER_SIZE..]),
+            _ => {
+                let commitment = data
+                    .get(HEADER_SIZE..HEADER_SIZE + 32)
+                    .ok_or_else(invalid)?;
+                (Some(commitment.try_into()?), &data[HEADER_SIZE + 32..])
+            }
+        };
+        Ok(CoinbaseEntry {
+            output_value: u64::from_be_bytes(data[0..8].try_into()?),
+            fees: u64::from_be_bytes(data[8..16].try_into()?),
+            witness_commitment,
+            script_sig: ScriptBuf::from(script.to_vec()),
+        })
+    }
+}
+
+/// Coinbase entry of the indexed block at `block_height`.
+pub fn read_coinbase(
+    store: &dyn KvStore,
+    block_height: i32,
+) -> Result<Option<CoinbaseEntry>, KorndexError> {
+    match store.get(Table::Coinbase, &height_key(block_height))? {
+        Some(data) => Ok(Some(CoinbaseEntry::decode(&data)?)),
+        None => Ok(None),
+    }
+}
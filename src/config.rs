@@ -0,0 +1,66 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::KorndexError;
+
+/// Name of the config file looked for in the working directory.
+pub const DEFAULT_FILE: &str = "korndex.toml";
+
+/// Settings read from `korndex.toml`. Every setting is optional and only
+/// fills in options given neither on the command line nor in the
+/// environment.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub datadir: Option<String>,
+    pub network: Option<String>,
+    pub index_dir: Option<PathBuf>,
+    /// Storage backend, as for `--backend`
+    pub backend: Option<String>,
+    /// Initial LMDB map size in GiB
+    pub db_map_size: Option<usize>,
+    pub serve: ServeConfig,
+}
+
+/// The `[serve]` table.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    /// Address the REST API listens on, or `unix:<path>`
+    pub bind: Option<String>,
+    /// Address the Electrum server listens on
+    pub electrum: Option<String>,
+    /// Address the WebSocket server listens on
+    pub websocket: Option<String>,
+    /// Requests per second each client IP may make to any of the servers
+    pub rate_limit: Option<f64>,
+    /// Requests a client may make at once above the rate limit
+    pub rate_burst: Option<u32>,
+    /// File of the API keys the REST API and WebSocket server accept, one per
+    /// line
+    pub api_keys: Option<PathBuf>,
+    /// REST requests handled at a time, and Electrum and WebSocket
+    /// connections open at once
+    pub max_requests: Option<usize>,
+    /// Deserialized blocks kept in memory
+    pub block_cache: Option<usize>,
+    /// Transactions kept in memory
+    pub tx_cache: Option<usize>,
+    /// PEM certificate chain the listeners accept TLS connections with
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub tls_key: Option<PathBuf>,
+    /// Refuse settings that need DNS or outbound connections
+    pub onion: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, KorndexError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            KorndexError::Config(format!("Failed to read {}: {}", path.display(), e))
+        })?;
+        toml::from_str(&contents)
+            .map_err(|e| KorndexError::Config(format!("Invalid {}: {}", path.display(), e)))
+    }
+}
@@ -0,0 +1,271 @@
+//! Crash-consistency testing, built with the `crash-test` feature. `korndex
+//! crash-test` runs builds in child processes that abort before a random write
+//! to the index, and checks after every crash that `korndex verify` passes and
+//! that the next build resumes without losing indexed blocks. The builds are
+//! killed, the machine is not, so this covers process crashes rather than
+//! power loss.
+
+use bitcoin::Network;
+use std::ffi::OsString;
+use std::path::Path;
+#[cfg(feature = "crash-test")]
+use std::process::Command;
+#[cfg(feature = "crash-test")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::store::{Backend, KvStore};
+#[cfg(feature = "crash-test")]
+use crate::store::{Batch, Durability, Table};
+use crate::KorndexError;
+#[cfg(feature = "crash-test")]
+use crate::{meta, TxIndexStore};
+
+/// Environment variable holding the number of the write to the index a build
+/// aborts before, counting batches, commits and cleared tables from 1.
+pub const CRASH_AT_ENV: &str = "KORNDEX_CRASH_AT";
+
+/// Settings of a crash test run.
+#[derive(Debug, Clone)]
+pub struct CrashTestOptions {
+    /// Most builds to crash
+    pub rounds: usize,
+    /// Crash points are drawn from the first this many writes of a build
+    pub max_writes: u64,
+    /// Seed of the crash points, to reproduce a run
+    pub seed: u64,
+    /// Blocks per batch of the builds, fewer make for more commits to crash
+    pub batch_blocks: usize,
+    /// Height the index stops at instead of following the tip
+    pub end_height: Option<i32>,
+}
+
+/// Makes `store` abort the process before the write named by
+/// [`CRASH_AT_ENV`], if it is set.
+#[cfg(feature = "crash-test")]
+pub(crate) fn arm(store: Box<dyn KvStore>) -> Result<Box<dyn KvStore>, KorndexError> {
+    let Some(crash_at) = std::env::var_os(CRASH_AT_ENV) else {
+        return Ok(store);
+    };
+    let crash_at: u64 = crash_at
+        .to_str()
+        .and_then(|crash_at| crash_at.parse().ok())
+        .filter(|crash_at| *crash_at > 0)
+        .ok_or_else(|| {
+            KorndexError::Config(format!("{} must be a positive number", CRASH_AT_ENV))
+        })?;
+    tracing::warn!(
+        "Crash test: aborting before write {} to the index",
+        crash_at
+    );
+    Ok(Box::new(CrashingStore {
+        inner: store,
+        crash_at,
+        writes: AtomicU64::new(0),
+    }))
+}
+
+#[cfg(not(feature = "crash-test"))]
+pub(crate) fn arm(store: Box<dyn KvStore>) -> Result<Box<dyn KvStore>, KorndexError> {
+    Ok(store)
+}
+
+/// A store aborting the process before its `crash_at`th write.
+#[cfg(feature = "crash-test")]
+struct CrashingStore {
+    inner: Box<dyn KvStore>,
+    crash_at: u64,
+    writes: AtomicU64,
+}
+
+#[cfg(feature = "crash-test")]
+impl CrashingStore {
+    fn write(&self, what: &str) {
+        let write = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if write == self.crash_at {
+            tracing::warn!("Crash test: aborting before write {} ({})", write, what);
+            std::process::abort();
+        }
+    }
+}
+
+#[cfg(feature = "crash-test")]
+impl KvStore for CrashingStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        self.inner.get(table, key)
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        self.inner.get_many(table, keys)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.inner.iter_prefix(table, prefix, f)
+    }
+
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.inner.iter_dups(table, key, f)
+    }
+
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        self.inner.count_dups(table, key)
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        self.write("batch");
+        self.inner.put_batch(batch)
+    }
+
+    fn commit(&self) -> Result<(), KorndexError> {
+        self.write("commit");
+        self.inner.commit()
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        self.write("clear");
+        self.inner.clear(table)
+    }
+
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        self.inner.compact()
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        self.inner.set_durability(durability)
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        self.inner.disk_size()
+    }
+
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        self.inner.fill()
+    }
+}
+
+/// splitmix64, enough to spread crash points without another dependency.
+#[cfg(feature = "crash-test")]
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Best indexed height of the index below `index_dir`, `None` while it is
+/// empty or not created yet.
+#[cfg(feature = "crash-test")]
+fn best_height(
+    backend: Backend,
+    index_dir: &Path,
+    network: Network,
+) -> Result<Option<i32>, KorndexError> {
+    let index = match TxIndexStore::open_read_only(backend, index_dir, network) {
+        Ok(index) => index,
+        // Not created yet, or crashed before the network was stamped
+        Err(KorndexError::Config(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(meta::read_best_block(index.kv())?.map(|best| best.height))
+}
+
+/// Crashes up to `options.rounds` builds of the index below `index_dir`,
+/// which should hold no index yet. `global_args` are the arguments every
+/// child process starts with, selecting the node, network and index. Returns
+/// the number of builds crashed before one completed.
+#[cfg(feature = "crash-test")]
+pub fn run(
+    global_args: &[OsString],
+    backend: Backend,
+    index_dir: &Path,
+    network: Network,
+    options: &CrashTestOptions,
+) -> Result<usize, KorndexError> {
+    let exe = std::env::current_exe()?;
+    let mut random = options.seed;
+    let mut best = best_height(backend, index_dir, network)?;
+    if best.is_some() {
+        tracing::warn!("Crash testing an existing index, the last build leaves it as it is");
+    }
+    println!("Seed: {}", options.seed);
+    for round in 1..=options.rounds {
+        let crash_at = 1 + next_random(&mut random) % options.max_writes.max(1);
+        let mut build = Command::new(&exe);
+        build
+            .args(global_args)
+            .args(["build", "--resume", "--batch-blocks"])
+            .arg(options.batch_blocks.to_string())
+            .env(CRASH_AT_ENV, crash_at.to_string());
+        if let Some(end_height) = options.end_height {
+            build.arg("--end-height").arg(end_height.to_string());
+        }
+        let status = build.status()?;
+        // An aborted child exits through a signal, without an exit code
+        let completed = match status.code() {
+            Some(0) => true,
+            None => false,
+            Some(code) => {
+                return Err(KorndexError::Corrupt(format!(
+                    "Round {}: the build after the previous crash failed with exit code {}",
+                    round, code
+                )))
+            }
+        };
+
+        let verified = Command::new(&exe)
+            .args(global_args)
+            .arg("verify")
+            .env_remove(CRASH_AT_ENV)
+            .status()?;
+        if !verified.success() {
+            return Err(KorndexError::Corrupt(format!(
+                "Round {}: the index failed verification after crashing before write {}, rerun with --seed {}",
+                round, crash_at, options.seed
+            )));
+        }
+        let height = best_height(backend, index_dir, network)?;
+        if height < best {
+            return Err(KorndexError::Corrupt(format!(
+                "Round {}: the best indexed height went back from {:?} to {:?}",
+                round, best, height
+            )));
+        }
+        best = height;
+        println!(
+            "Round: {}, Crash At Write: {}, Crashed: {}, Best Height: {:?}",
+            round, crash_at, !completed, best
+        );
+        if completed {
+            println!("Build completed after {} crashes", round - 1);
+            return Ok(round - 1);
+        }
+    }
+    println!(
+        "Crashed {} builds, the index is consistent at height {:?}",
+        options.rounds, best
+    );
+    Ok(options.rounds)
+}
+
+#[cfg(not(feature = "crash-test"))]
+pub fn run(
+    _global_args: &[OsString],
+    _backend: Backend,
+    _index_dir: &Path,
+    _network: Network,
+    _options: &CrashTestOptions,
+) -> Result<usize, KorndexError> {
+    Err(KorndexError::Config(
+        "korndex was built without crash testing, rebuild with --features crash-test".to_owned(),
+    ))
+}
@@ -0,0 +1,185 @@
+//! Free disk space checks for builds. A build warns up front when the disk
+//! looks too small for the blocks it is about to index, warns again as the
+//! space runs low, and pauses before a commit that would leave less than the
+//! minimum free space until the operator frees some. A full disk would fail
+//! the commit, which leaves the index consistent but ends the build.
+
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::store::KvStore;
+use crate::{shutdown, KorndexError};
+
+/// Default free space a build leaves on the index's disk, in bytes.
+pub const DEFAULT_MIN_FREE: u64 = 1 << 30;
+
+/// Multiples of the minimum free space a build warns at as the space runs
+/// out, once each.
+const WARN_LEVELS: [u64; 3] = [8, 4, 2];
+
+/// How often a paused build checks the free space again.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a paused build repeats that it is waiting.
+const REMIND_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Bytes unprivileged processes can still write to the file system holding
+/// `path`, `None` where this is unknown.
+#[cfg(unix)]
+pub fn available(path: &Path) -> Result<Option<u64>, KorndexError> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::other)?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a plain C struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available(_path: &Path) -> Result<Option<u64>, KorndexError> {
+    Ok(None)
+}
+
+/// Bytes `store` occupies: the used part of an LMDB map, or the size of its
+/// files.
+pub(crate) fn index_size(store: &dyn KvStore) -> Result<u64, KorndexError> {
+    match store.fill()? {
+        Some((used, _)) => Ok(used),
+        None => store.disk_size(),
+    }
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / (1u64 << 30) as f64
+}
+
+/// Watches the free space on the disk of an index during a build.
+pub(crate) struct SpaceMonitor<'a> {
+    dir: &'a Path,
+    store: &'a dyn KvStore,
+    /// Free space commits leave, 0 to never pause
+    min_free: u64,
+    /// Size of the index before the build, to project its growth
+    start_size: u64,
+    /// Levels of [`WARN_LEVELS`] warned about
+    warned: usize,
+    warned_projection: bool,
+}
+
+impl<'a> SpaceMonitor<'a> {
+    pub(crate) fn new(
+        dir: &'a Path,
+        store: &'a dyn KvStore,
+        min_free: u64,
+    ) -> Result<Self, KorndexError> {
+        Ok(SpaceMonitor {
+            dir,
+            store,
+            min_free,
+            start_size: index_size(store)?,
+            warned: 0,
+            warned_projection: false,
+        })
+    }
+
+    /// Warns if `projected` more bytes of index would not fit on the disk
+    /// with the minimum free space left.
+    pub(crate) fn preflight(&mut self, projected: u64) -> Result<(), KorndexError> {
+        let Some(free) = available(self.dir)? else {
+            return Ok(());
+        };
+        tracing::info!(
+            "The build adds about {:.1} GiB to the index, {:.1} GiB are free on {}",
+            gib(projected),
+            gib(free),
+            self.dir.display()
+        );
+        if free < projected.saturating_add(self.min_free) {
+            self.warned_projection = true;
+            tracing::warn!(
+                "The build needs about {:.1} GiB but only {:.1} GiB are free on {}, it will pause when less than {:.1} GiB are left",
+                gib(projected),
+                gib(free),
+                self.dir.display(),
+                gib(self.min_free)
+            );
+        }
+        Ok(())
+    }
+
+    /// Waits until committing about `batch_bytes` leaves the minimum free
+    /// space, warning as the space runs low. `indexed` blocks have been
+    /// committed so far and `remaining` are left after this batch. Returns
+    /// `false` if shutdown was requested while paused.
+    pub(crate) fn wait_for(
+        &mut self,
+        batch_bytes: u64,
+        indexed: usize,
+        remaining: usize,
+    ) -> Result<bool, KorndexError> {
+        let Some(mut free) = available(self.dir)? else {
+            return Ok(true);
+        };
+        self.warn(free, indexed, remaining)?;
+        let needed = self.min_free.saturating_add(batch_bytes);
+        if self.min_free == 0 || free >= needed {
+            return Ok(true);
+        }
+        let mut reminded: Option<Instant> = None;
+        while free < needed {
+            if shutdown::requested() {
+                return Ok(false);
+            }
+            if !reminded.is_some_and(|at| at.elapsed() < REMIND_INTERVAL) {
+                tracing::warn!(
+                    "Only {:.2} GiB free on {}, the build is paused until {:.2} GiB are free",
+                    gib(free),
+                    self.dir.display(),
+                    gib(needed)
+                );
+                reminded = Some(Instant::now());
+            }
+            thread::sleep(POLL_INTERVAL);
+            free = available(self.dir)?.unwrap_or(u64::MAX);
+        }
+        tracing::info!(
+            "{:.2} GiB free on {} again, continuing the build",
+            gib(free),
+            self.dir.display()
+        );
+        Ok(true)
+    }
+
+    fn warn(&mut self, free: u64, indexed: usize, remaining: usize) -> Result<(), KorndexError> {
+        let passed = WARN_LEVELS
+            .iter()
+            .take_while(|level| free < self.min_free.saturating_mul(**level))
+            .count();
+        if passed > self.warned {
+            self.warned = passed;
+            tracing::warn!("Only {:.1} GiB free on {}", gib(free), self.dir.display());
+        }
+        if self.warned_projection || indexed == 0 || remaining == 0 {
+            return Ok(());
+        }
+        // The blocks left are assumed to grow the index as fast as those so
+        // far, which undercounts on the way up a growing chain
+        let grown = index_size(self.store)?.saturating_sub(self.start_size);
+        let projected = (grown / indexed as u64).saturating_mul(remaining as u64);
+        if free < projected.saturating_add(self.min_free) {
+            self.warned_projection = true;
+            tracing::warn!(
+                "At its current rate the index grows by another {:.1} GiB, more than the {:.1} GiB free on {}",
+                gib(projected),
+                gib(free),
+                self.dir.display()
+            );
+        }
+        Ok(())
+    }
+}
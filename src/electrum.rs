@@ -0,0 +1,455 @@
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::Txid;
+use libbitcoinkernel_sys::ChainstateManager;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::access::{AccessControl, ConnectionLimit, Denied};
+use crate::headers;
+use crate::indexes::IndexKind;
+use crate::kernel;
+use crate::listener::{Listener, Stream};
+use crate::meta;
+use crate::scripthash;
+use crate::store::{KvStore, Table};
+use crate::tls::{self, TlsConfig};
+use crate::txindex::{self, TxIndexEntry};
+use crate::utxo;
+use crate::{KorndexError, QueryHandle};
+
+const PROTOCOL_VERSION: &str = "1.4";
+
+/// Most headers returned by a single `blockchain.block.headers` call.
+const MAX_HEADERS: usize = 2016;
+
+/// How often idle connections check for a new tip to notify subscribers.
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Server<'a> {
+    chainman: &'a ChainstateManager,
+    store: &'a dyn KvStore,
+    access: &'a AccessControl,
+}
+
+/// Per-connection subscription state.
+#[derive(Default)]
+struct Session {
+    /// Rate limited by, none on a Unix domain socket
+    client: Option<IpAddr>,
+    headers_subscribed: bool,
+    tip_height: Option<i32>,
+    /// Subscribed scripthashes and the last status sent for each
+    scripthashes: HashMap<[u8; 32], Option<String>>,
+}
+
+/// Serves the Electrum protocol on `bind`, rate limiting each request by
+/// `access` and turning away connections beyond `connections`. Electrum
+/// clients cannot send API keys.
+pub fn serve(
+    bind: &str,
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    tls: Option<&TlsConfig>,
+    access: &AccessControl,
+    connections: &ConnectionLimit,
+) -> Result<(), KorndexError> {
+    let indexes = meta::built_indexes(store)?;
+    for kind in [IndexKind::Txid, IndexKind::Address, IndexKind::Utxo] {
+        indexes.require(kind)?;
+    }
+    let listener = Listener::bind(bind)?;
+    let server = Server {
+        chainman,
+        store,
+        access,
+    };
+    tracing::info!("Serving Electrum protocol on {}", bind);
+
+    thread::scope(|s| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept Electrum connection: {}", e);
+                    continue;
+                }
+            };
+            let Some(connection) = connections.acquire() else {
+                tracing::warn!("Refused an Electrum connection, too many are open");
+                continue;
+            };
+            let server = &server;
+            s.spawn(move || {
+                let _connection = connection;
+                let connection = tls::accept(tls, stream);
+                if let Err(e) = connection.and_then(|stream| server.handle_connection(stream)) {
+                    tracing::debug!("Electrum connection closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+impl Server<'_> {
+    fn handle_connection(&self, stream: Stream) -> Result<(), KorndexError> {
+        stream.set_read_timeout(Some(NOTIFY_INTERVAL))?;
+        // Writes go through the reader, a TLS session cannot be split
+        let mut reader = BufReader::new(stream);
+        let mut session = Session {
+            client: reader.get_ref().peer_ip(),
+            ..Session::default()
+        };
+        let mut line = String::new();
+        loop {
+            // A timed out read keeps any partial line in `line`
+            match reader.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) if line.ends_with('\n') => {
+                    let response = self.handle_line(&mut session, line.trim());
+                    line.clear();
+                    writeln!(reader.get_mut(), "{}", response)?;
+                }
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+            for notification in self.notifications(&mut session)? {
+                writeln!(reader.get_mut(), "{}", notification)?;
+            }
+        }
+    }
+
+    fn handle_line(&self, session: &mut Session, line: &str) -> Value {
+        match serde_json::from_str::<Value>(line) {
+            Ok(Value::Array(requests)) => Value::Array(
+                requests
+                    .iter()
+                    .map(|request| self.handle_request(session, request))
+                    .collect(),
+            ),
+            Ok(request) => self.handle_request(session, &request),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": e.to_string() },
+            }),
+        }
+    }
+
+    fn handle_request(&self, session: &mut Session, request: &Value) -> Value {
+        let (id, call) = parse_request(request);
+        let call = match self.access.check(session.client, None) {
+            Ok(()) => call,
+            Err(Denied::RateLimited) => {
+                Err(KorndexError::InvalidInput("Rate limit exceeded".into()))
+            }
+            Err(Denied::Unauthorized) => Err(KorndexError::InvalidInput(
+                "Missing or unknown API key".into(),
+            )),
+        };
+        match call.and_then(|call| self.call(session, call)) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": 1, "message": e.to_string() },
+            }),
+        }
+    }
+
+    fn call(&self, session: &mut Session, call: Call) -> Result<Value, KorndexError> {
+        match call {
+            Call::ServerVersion => Ok(json!([
+                concat!("korndex ", env!("CARGO_PKG_VERSION")),
+                PROTOCOL_VERSION
+            ])),
+            Call::Ping => Ok(Value::Null),
+            Call::Banner => Ok(json!("korndex")),
+            Call::DonationAddress => Ok(json!("")),
+            Call::Peers => Ok(json!([])),
+            Call::Features => Ok(json!({
+                "genesis_hash": kernel::block_hash(self.chainman, 0)?.map(|hash| hash.to_string()),
+                "hosts": {},
+                "protocol_max": PROTOCOL_VERSION,
+                "protocol_min": PROTOCOL_VERSION,
+                "pruning": null,
+                "server_version": concat!("korndex ", env!("CARGO_PKG_VERSION")),
+                "hash_function": "sha256",
+            })),
+            Call::HeadersSubscribe => {
+                let tip_height = self.tip_height()?;
+                session.headers_subscribed = true;
+                session.tip_height = Some(tip_height);
+                self.header_notification(tip_height)
+            }
+            Call::BlockHeader(height) => Ok(json!(self.header_hex(height)?)),
+            Call::BlockHeaders { start, count } => {
+                let tip_height = self.tip_height()?;
+                let end = start.saturating_add(count as i32 - 1).min(tip_height);
+                let headers = (start..=end)
+                    .map(|height| self.header_hex(height))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(json!({ "count": headers.len(), "hex": headers.concat(), "max": MAX_HEADERS }))
+            }
+            Call::GetHistory(scripthash) => {
+                let history = self
+                    .history(&scripthash)?
+                    .into_iter()
+                    .map(|(height, txid)| json!({ "tx_hash": txid.to_string(), "height": height }))
+                    .collect();
+                Ok(Value::Array(history))
+            }
+            Call::GetBalance(scripthash) => {
+                let confirmed: u64 = utxo::unspent(self.store, &scripthash)?
+                    .iter()
+                    .map(|(_, utxo)| utxo.value.to_sat())
+                    .sum();
+                Ok(json!({ "confirmed": confirmed, "unconfirmed": 0 }))
+            }
+            Call::ListUnspent(scripthash) => {
+                let unspent = utxo::unspent(self.store, &scripthash)?
+                    .into_iter()
+                    .map(|(outpoint, utxo)| {
+                        json!({
+                            "tx_hash": outpoint.txid.to_string(),
+                            "tx_pos": outpoint.vout,
+                            "height": utxo.block_height,
+                            "value": utxo.value.to_sat(),
+                        })
+                    })
+                    .collect();
+                Ok(Value::Array(unspent))
+            }
+            Call::Subscribe(scripthash) => {
+                let status = self.status(&scripthash)?;
+                session.scripthashes.insert(scripthash, status.clone());
+                Ok(json!(status))
+            }
+            Call::Unsubscribe(scripthash) => {
+                Ok(json!(session.scripthashes.remove(&scripthash).is_some()))
+            }
+            Call::GetMempool => Ok(json!([])),
+            Call::TransactionGet(txid) => Ok(json!(self.transaction_hex(&txid)?)),
+            Call::RelayFee => Ok(json!(0.00001)),
+            Call::EstimateFee => Ok(json!(-1)),
+            Call::FeeHistogram => Ok(json!([])),
+        }
+    }
+
+    /// Notifications for a session's subscriptions, sent whenever the indexed
+    /// tip moves.
+    fn notifications(&self, session: &mut Session) -> Result<Vec<Value>, KorndexError> {
+        if !session.headers_subscribed && session.scripthashes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tip_height = self.tip_height()?;
+        if session.tip_height == Some(tip_height) {
+            return Ok(Vec::new());
+        }
+        session.tip_height = Some(tip_height);
+
+        let mut notifications = Vec::new();
+        if session.headers_subscribed {
+            notifications.push(json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.headers.subscribe",
+                "params": [self.header_notification(tip_height)?],
+            }));
+        }
+        for (scripthash, last_status) in session.scripthashes.iter_mut() {
+            let status = self.status(scripthash)?;
+            if status != *last_status {
+                let mut display = *scripthash;
+                display.reverse();
+                notifications.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [display.to_lower_hex_string(), status.clone()],
+                }));
+                *last_status = status;
+            }
+        }
+        Ok(notifications)
+    }
+
+    fn tip_height(&self) -> Result<i32, KorndexError> {
+        match meta::read_best_block(self.store)? {
+            Some(best) => Ok(best.height),
+            None => Err(KorndexError::NotFound("index is empty".to_owned())),
+        }
+    }
+
+    fn header_hex(&self, height: i32) -> Result<String, KorndexError> {
+        let header = headers::read_header(self.store, height)?
+            .ok_or_else(|| KorndexError::NotFound(format!("no block at height {}", height)))?;
+        Ok(serialize(&header).to_lower_hex_string())
+    }
+
+    fn header_notification(&self, height: i32) -> Result<Value, KorndexError> {
+        Ok(json!({ "height": height, "hex": self.header_hex(height)? }))
+    }
+
+    /// Confirmed transactions touching a script, in chain order, each listed
+    /// once.
+    fn history(&self, scripthash: &[u8; 32]) -> Result<Vec<(i32, Txid)>, KorndexError> {
+        let mut history: Vec<(i32, Txid)> = scripthash::history(self.store, scripthash)?
+            .into_iter()
+            .map(|(txid, entry)| (entry.block_height, txid))
+            .collect();
+        history.dedup();
+        Ok(history)
+    }
+
+    /// Electrum status hash of a script's history, `None` if it has none.
+    fn status(&self, scripthash: &[u8; 32]) -> Result<Option<String>, KorndexError> {
+        let history = self.history(scripthash)?;
+        if history.is_empty() {
+            return Ok(None);
+        }
+        let concatenated: String = history
+            .iter()
+            .map(|(height, txid)| format!("{}:{}:", txid, height))
+            .collect();
+        Ok(Some(
+            sha256::Hash::hash(concatenated.as_bytes())
+                .to_byte_array()
+                .to_lower_hex_string(),
+        ))
+    }
+
+    fn transaction_hex(&self, txid: &Txid) -> Result<String, KorndexError> {
+        let entry: TxIndexEntry = match self.store.get(Table::TxIndex, &txid.to_byte_array())? {
+            Some(data) => TxIndexEntry::decode(&data)?,
+            None => return Err(QueryHandle::new(None, self.store).txid_not_found(txid)),
+        };
+        let raw_block = self.read_block(entry.block_height)?;
+        Ok(serialize_hex(&txindex::read_transaction(
+            &raw_block, &entry,
+        )?))
+    }
+
+    /// Serialized block at `height`.
+    fn read_block(&self, height: i32) -> Result<Vec<u8>, KorndexError> {
+        let block_index = self
+            .chainman
+            .get_block_index_by_height(height)
+            .map_err(|_| KorndexError::NotFound(format!("no block at height {}", height)))?;
+        kernel::read_block_data(self.chainman, &block_index, height)
+    }
+}
+
+/// A call of the Electrum protocol with its parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Call {
+    ServerVersion,
+    Ping,
+    Banner,
+    DonationAddress,
+    Peers,
+    Features,
+    HeadersSubscribe,
+    BlockHeader(i32),
+    BlockHeaders {
+        start: i32,
+        /// At most [`MAX_HEADERS`]
+        count: usize,
+    },
+    GetHistory([u8; 32]),
+    GetBalance([u8; 32]),
+    ListUnspent([u8; 32]),
+    Subscribe([u8; 32]),
+    Unsubscribe([u8; 32]),
+    GetMempool,
+    TransactionGet(Txid),
+    RelayFee,
+    EstimateFee,
+    FeeHistogram,
+}
+
+impl Call {
+    /// Parses the call of `method` with `params`, rejecting unknown methods
+    /// and missing or malformed parameters.
+    pub fn parse(method: &str, params: &[Value]) -> Result<Self, KorndexError> {
+        Ok(match method {
+            "server.version" => Call::ServerVersion,
+            "server.ping" => Call::Ping,
+            "server.banner" => Call::Banner,
+            "server.donation_address" => Call::DonationAddress,
+            "server.peers.subscribe" => Call::Peers,
+            "server.features" => Call::Features,
+            "blockchain.headers.subscribe" => Call::HeadersSubscribe,
+            "blockchain.block.header" => Call::BlockHeader(param_height(params, 0)?),
+            "blockchain.block.headers" => Call::BlockHeaders {
+                start: param_height(params, 0)?,
+                count: param_u64(params, 1)?.min(MAX_HEADERS as u64) as usize,
+            },
+            "blockchain.scripthash.get_history" => Call::GetHistory(param_scripthash(params)?),
+            "blockchain.scripthash.get_balance" => Call::GetBalance(param_scripthash(params)?),
+            "blockchain.scripthash.listunspent" => Call::ListUnspent(param_scripthash(params)?),
+            "blockchain.scripthash.subscribe" => Call::Subscribe(param_scripthash(params)?),
+            "blockchain.scripthash.unsubscribe" => Call::Unsubscribe(param_scripthash(params)?),
+            "blockchain.scripthash.get_mempool" => Call::GetMempool,
+            "blockchain.transaction.get" => {
+                if params.get(1).and_then(Value::as_bool).unwrap_or(false) {
+                    return Err(KorndexError::InvalidInput(
+                        "verbose transactions are not supported".into(),
+                    ));
+                }
+                Call::TransactionGet(Txid::from_str(param_str(params, 0)?)?)
+            }
+            "blockchain.relayfee" => Call::RelayFee,
+            "blockchain.estimatefee" => Call::EstimateFee,
+            "mempool.get_fee_histogram" => Call::FeeHistogram,
+            _ => {
+                return Err(KorndexError::InvalidInput(
+                    format!("unknown method {}", method).into(),
+                ))
+            }
+        })
+    }
+}
+
+/// The id of a JSON-RPC `request`, `null` if it has none, and its call.
+pub fn parse_request(request: &Value) -> (Value, Result<Call, KorndexError>) {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = request["method"].as_str().unwrap_or_default();
+    let params = match &request["params"] {
+        Value::Array(params) => &params[..],
+        _ => &[],
+    };
+    (id, Call::parse(method, params))
+}
+
+fn param_str(params: &[Value], index: usize) -> Result<&str, KorndexError> {
+    params.get(index).and_then(Value::as_str).ok_or_else(|| {
+        KorndexError::InvalidInput(format!("missing string parameter {}", index).into())
+    })
+}
+
+fn param_u64(params: &[Value], index: usize) -> Result<u64, KorndexError> {
+    params.get(index).and_then(Value::as_u64).ok_or_else(|| {
+        KorndexError::InvalidInput(format!("missing integer parameter {}", index).into())
+    })
+}
+
+fn param_height(params: &[Value], index: usize) -> Result<i32, KorndexError> {
+    i32::try_from(param_u64(params, index)?).map_err(|_| {
+        KorndexError::InvalidInput(format!("parameter {} is not a block height", index).into())
+    })
+}
+
+/// Electrum scripthashes are the sha256 of the script in reversed byte order.
+fn param_scripthash(params: &[Value]) -> Result<[u8; 32], KorndexError> {
+    let mut scripthash = <[u8; 32]>::from_hex(param_str(params, 0)?)?;
+    scripthash.reverse();
+    Ok(scripthash)
+}
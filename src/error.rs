@@ -0,0 +1,105 @@
+use libbitcoinkernel_sys::KernelError;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors returned by korndex.
+#[derive(Debug, thiserror::Error)]
+pub enum KorndexError {
+    /// libbitcoinkernel failed to load or read the node's chain data
+    #[error("Kernel error: {0}")]
+    Kernel(String),
+    /// The index database failed
+    #[error("Storage error: {0}")]
+    Storage(BoxError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// A stored or read value could not be encoded or decoded
+    #[error("Serialization error: {0}")]
+    Serialization(BoxError),
+    /// The index contradicts itself or the node's block data
+    #[error("Corrupt index: {0}")]
+    Corrupt(String),
+    /// The index cannot be used with the given options or korndex version
+    #[error("{0}")]
+    Config(String),
+    /// A txid, address, outpoint or other argument could not be parsed
+    #[error("Invalid input: {0}")]
+    InvalidInput(BoxError),
+    /// Something looked up is not in the index or the active chain
+    #[error("{0}")]
+    NotFound(String),
+    /// The block at this height is no longer stored by the pruned node
+    #[error("Block {0} has been pruned by the node")]
+    Pruned(i32),
+}
+
+impl KorndexError {
+    /// Process exit code for the CLI, following sysexits(3).
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            KorndexError::NotFound(_) => 1,
+            KorndexError::InvalidInput(_) => 64,
+            KorndexError::Serialization(_) | KorndexError::Corrupt(_) => 65,
+            KorndexError::Kernel(_) | KorndexError::Pruned(_) => 69,
+            KorndexError::Storage(_) | KorndexError::Io(_) => 74,
+            KorndexError::Config(_) => 78,
+        }
+    }
+}
+
+impl From<KernelError> for KorndexError {
+    fn from(e: KernelError) -> Self {
+        KorndexError::Kernel(format!("{:?}", e))
+    }
+}
+
+/// Converts the listed error types into a boxed variant.
+macro_rules! impl_from {
+    ($variant:ident: $($(#[$cfg:meta])* $ty:ty),* $(,)?) => {
+        $(
+            $(#[$cfg])*
+            impl From<$ty> for KorndexError {
+                fn from(e: $ty) -> Self {
+                    KorndexError::$variant(Box::new(e))
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(Storage:
+    #[cfg(feature = "lmdb")] lmdb::Error,
+    #[cfg(feature = "redb")] redb::Error,
+    #[cfg(feature = "redb")] redb::DatabaseError,
+    #[cfg(feature = "redb")] redb::TransactionError,
+    #[cfg(feature = "redb")] redb::TableError,
+    #[cfg(feature = "redb")] redb::StorageError,
+    #[cfg(feature = "redb")] redb::CommitError,
+    #[cfg(feature = "redb")] redb::CompactionError,
+    #[cfg(feature = "rocksdb")] rocksdb::Error,
+    #[cfg(feature = "sqlite")] rusqlite::Error,
+    #[cfg(feature = "sqlite")] rusqlite::types::FromSqlError,
+);
+
+impl_from!(Serialization:
+    bincode::Error,
+    bitcoin::bip158::Error,
+    bitcoin::consensus::encode::Error,
+    bitcoin::hashes::FromSliceError,
+    serde_json::Error,
+    std::array::TryFromSliceError,
+    std::string::FromUtf8Error,
+    #[cfg(feature = "parquet")] arrow_schema::ArrowError,
+    #[cfg(feature = "parquet")] parquet::errors::ParquetError,
+);
+
+impl_from!(InvalidInput:
+    bitcoin::address::ParseError,
+    bitcoin::hex::HexToArrayError,
+    bitcoin::hex::HexToBytesError,
+    bitcoin::transaction::ParseOutPointError,
+    humantime::TimestampError,
+    miniscript::Error,
+    miniscript::descriptor::ConversionError,
+    std::num::ParseIntError,
+);
@@ -0,0 +1,381 @@
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::Txid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::headers;
+use crate::indexes::IndexKind;
+use crate::meta;
+use crate::scripthash::{Direction, ScriptHashEntry};
+use crate::spent::SpendEntry;
+use crate::store::{KvStore, Table};
+use crate::txindex::TxIndexEntry;
+use crate::utxo::UtxoEntry;
+use crate::KorndexError;
+
+/// An index exported one row per entry.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Index {
+    /// txid, block_height, position_in_block, fee, vsize
+    Txindex,
+    /// scripthash, block_height, position_in_block, direction, index
+    Scripthash,
+    /// txid, vout, block_height, position_in_block, input_index of the spend
+    Spent,
+    /// txid, vout, block_height, is_coinbase, value, script_pubkey
+    Utxo,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// Comma-separated values with a header row
+    Csv,
+    /// Apache Parquet, requires building with the `parquet` feature
+    Parquet,
+}
+
+/// What [`export`] writes.
+#[derive(Debug)]
+pub struct ExportOptions {
+    pub index: Index,
+    pub format: Format,
+    /// Only rows of blocks in this inclusive height range
+    pub heights: Option<(i32, i32)>,
+    /// Add the block_hash and block_time of each row's block
+    pub with_blocks: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ColumnType {
+    Int,
+    Text,
+    Bool,
+}
+
+#[derive(Debug)]
+enum Value {
+    Int(i64),
+    Text(String),
+    Bool(bool),
+}
+
+trait RowWriter {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<(), KorndexError>;
+
+    fn finish(self: Box<Self>) -> Result<(), KorndexError>;
+}
+
+/// Streams every entry of an index within the height range to `out`,
+/// returning the number of rows written.
+pub fn export(
+    store: &dyn KvStore,
+    options: &ExportOptions,
+    out: &Path,
+) -> Result<u64, KorndexError> {
+    let (table, kind) = match options.index {
+        Index::Txindex => (Table::TxIndex, IndexKind::Txid),
+        Index::Scripthash => (Table::ScriptHash, IndexKind::Address),
+        Index::Spent => (Table::Spent, IndexKind::Spent),
+        Index::Utxo => (Table::Utxo, IndexKind::Utxo),
+    };
+    meta::built_indexes(store)?.require(kind)?;
+    let columns = columns(options);
+    let mut writer: Box<dyn RowWriter> = match options.format {
+        Format::Csv => Box::new(CsvWriter::create(out, &columns)?),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => Box::new(parquet_writer::ParquetWriter::create(out, &columns)?),
+        #[cfg(not(feature = "parquet"))]
+        Format::Parquet => {
+            return Err(KorndexError::Config(
+                "korndex was built without Parquet support, rebuild with --features parquet"
+                    .to_owned(),
+            ))
+        }
+    };
+
+    // Block hash and time by height, for --with-blocks
+    let mut blocks: HashMap<i32, (String, u32)> = HashMap::new();
+    let mut rows = 0;
+    let mut result = Ok(());
+    store.iter_prefix(table, &[], &mut |key, value| {
+        let mut write = || -> Result<(), KorndexError> {
+            let (block_height, mut row) = decode_row(options.index, key, value)?;
+            if let Some((start, end)) = options.heights {
+                if block_height < start || block_height > end {
+                    return Ok(());
+                }
+            }
+            if options.with_blocks {
+                let (hash, time) = match blocks.get(&block_height) {
+                    Some(block) => block.clone(),
+                    None => {
+                        let header =
+                            headers::read_header(store, block_height)?.ok_or_else(|| {
+                                KorndexError::Corrupt(format!(
+                                    "No header for block {}",
+                                    block_height
+                                ))
+                            })?;
+                        let block = (header.block_hash().to_string(), header.time);
+                        blocks.insert(block_height, block.clone());
+                        block
+                    }
+                };
+                row.push(Value::Text(hash));
+                row.push(Value::Int(time as i64));
+            }
+            rows += 1;
+            writer.write_row(row)
+        };
+        result = write();
+        result.is_ok()
+    })?;
+    result?;
+    writer.finish()?;
+    Ok(rows)
+}
+
+fn columns(options: &ExportOptions) -> Vec<(&'static str, ColumnType)> {
+    use ColumnType::*;
+    let mut columns = match options.index {
+        Index::Txindex => vec![
+            ("txid", Text),
+            ("block_height", Int),
+            ("position_in_block", Int),
+            ("fee", Int),
+            ("vsize", Int),
+        ],
+        Index::Scripthash => vec![
+            ("scripthash", Text),
+            ("block_height", Int),
+            ("position_in_block", Int),
+            ("direction", Text),
+            ("index", Int),
+        ],
+        Index::Spent => vec![
+            ("txid", Text),
+            ("vout", Int),
+            ("block_height", Int),
+            ("position_in_block", Int),
+            ("input_index", Int),
+        ],
+        Index::Utxo => vec![
+            ("txid", Text),
+            ("vout", Int),
+            ("block_height", Int),
+            ("is_coinbase", Bool),
+            ("value", Int),
+            ("script_pubkey", Text),
+        ],
+    };
+    if options.with_blocks {
+        columns.push(("block_hash", Text));
+        columns.push(("block_time", Int));
+    }
+    columns
+}
+
+/// The block height of an entry and its row, without the block columns.
+fn decode_row(index: Index, key: &[u8], value: &[u8]) -> Result<(i32, Vec<Value>), KorndexError> {
+    // Keys of spent outpoints and UTXOs are a txid and a big-endian vout
+    let outpoint = |key: &[u8]| -> Result<(String, i64), KorndexError> {
+        if key.len() != 36 {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid outpoint key length {}",
+                key.len()
+            )));
+        }
+        let txid = Txid::from_slice(&key[..32])?;
+        let vout = u32::from_be_bytes(key[32..].try_into()?);
+        Ok((txid.to_string(), vout as i64))
+    };
+    match index {
+        Index::Txindex => {
+            let entry = TxIndexEntry::decode(value)?;
+            let row = vec![
+                Value::Text(Txid::from_slice(key)?.to_string()),
+                Value::Int(entry.block_height as i64),
+                Value::Int(entry.position_in_block as i64),
+                Value::Int(entry.fee as i64),
+                Value::Int(entry.vsize as i64),
+            ];
+            Ok((entry.block_height, row))
+        }
+        Index::Scripthash => {
+            let entry = ScriptHashEntry::decode(value)?;
+            let direction = match entry.direction {
+                Direction::Output => "output",
+                Direction::Input => "input",
+            };
+            let row = vec![
+                Value::Text(key.to_lower_hex_string()),
+                Value::Int(entry.block_height as i64),
+                Value::Int(entry.position_in_block as i64),
+                Value::Text(direction.to_owned()),
+                Value::Int(entry.index as i64),
+            ];
+            Ok((entry.block_height, row))
+        }
+        Index::Spent => {
+            let (txid, vout) = outpoint(key)?;
+            let entry = SpendEntry::decode(value)?;
+            let row = vec![
+                Value::Text(txid),
+                Value::Int(vout),
+                Value::Int(entry.block_height as i64),
+                Value::Int(entry.position_in_block as i64),
+                Value::Int(entry.input_index as i64),
+            ];
+            Ok((entry.block_height, row))
+        }
+        Index::Utxo => {
+            let (txid, vout) = outpoint(key)?;
+            let entry = UtxoEntry::decode(value)?;
+            let row = vec![
+                Value::Text(txid),
+                Value::Int(vout),
+                Value::Int(entry.block_height as i64),
+                Value::Bool(entry.is_coinbase),
+                Value::Int(entry.value.to_sat() as i64),
+                Value::Text(entry.script_pubkey.as_bytes().to_lower_hex_string()),
+            ];
+            Ok((entry.block_height, row))
+        }
+    }
+}
+
+/// Every exported value is a number, a boolean or hex, so nothing needs
+/// quoting.
+struct CsvWriter {
+    out: BufWriter<File>,
+}
+
+impl CsvWriter {
+    fn create(path: &Path, columns: &[(&str, ColumnType)]) -> Result<Self, KorndexError> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+        writeln!(out, "{}", names.join(","))?;
+        Ok(CsvWriter { out })
+    }
+}
+
+impl RowWriter for CsvWriter {
+    fn write_row(&mut self, row: Vec<Value>) -> Result<(), KorndexError> {
+        for (i, value) in row.into_iter().enumerate() {
+            if i > 0 {
+                self.out.write_all(b",")?;
+            }
+            match value {
+                Value::Int(value) => write!(self.out, "{}", value)?,
+                Value::Text(value) => self.out.write_all(value.as_bytes())?,
+                Value::Bool(value) => write!(self.out, "{}", value)?,
+            }
+        }
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), KorndexError> {
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use arrow_array::{ArrayRef, BooleanArray, Int64Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema, SchemaRef};
+    use parquet::arrow::ArrowWriter;
+    use std::fs::File;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::{ColumnType, RowWriter, Value};
+    use crate::KorndexError;
+
+    /// Rows buffered into each record batch.
+    const BATCH_ROWS: usize = 65_536;
+
+    pub struct ParquetWriter {
+        writer: ArrowWriter<File>,
+        schema: SchemaRef,
+        rows: Vec<Vec<Value>>,
+    }
+
+    impl ParquetWriter {
+        pub fn create(path: &Path, columns: &[(&str, ColumnType)]) -> Result<Self, KorndexError> {
+            let fields: Vec<Field> = columns
+                .iter()
+                .map(|(name, column_type)| {
+                    let data_type = match column_type {
+                        ColumnType::Int => DataType::Int64,
+                        ColumnType::Text => DataType::Utf8,
+                        ColumnType::Bool => DataType::Boolean,
+                    };
+                    Field::new(*name, data_type, false)
+                })
+                .collect();
+            let schema = Arc::new(Schema::new(fields));
+            let writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), None)?;
+            Ok(ParquetWriter {
+                writer,
+                schema,
+                rows: Vec::with_capacity(BATCH_ROWS),
+            })
+        }
+
+        fn flush(&mut self) -> Result<(), KorndexError> {
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+            let rows = std::mem::take(&mut self.rows);
+            let arrays: Vec<ArrayRef> = (0..self.schema.fields().len())
+                .map(|i| -> ArrayRef {
+                    match self.schema.field(i).data_type() {
+                        DataType::Int64 => Arc::new(Int64Array::from_iter_values(rows.iter().map(
+                            |row| match row[i] {
+                                Value::Int(value) => value,
+                                _ => unreachable!("column {} holds integers", i),
+                            },
+                        ))),
+                        DataType::Boolean => {
+                            Arc::new(BooleanArray::from_iter(rows.iter().map(|row| {
+                                match row[i] {
+                                    Value::Bool(value) => Some(value),
+                                    _ => unreachable!("column {} holds booleans", i),
+                                }
+                            })))
+                        }
+                        _ => Arc::new(StringArray::from_iter_values(rows.iter().map(
+                            |row| match row[i] {
+                                Value::Text(ref value) => value.as_str(),
+                                _ => unreachable!("column {} holds text", i),
+                            },
+                        ))),
+                    }
+                })
+                .collect();
+            let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+            self.writer.write(&batch)?;
+            Ok(())
+        }
+    }
+
+    impl RowWriter for ParquetWriter {
+        fn write_row(&mut self, row: Vec<Value>) -> Result<(), KorndexError> {
+            self.rows.push(row);
+            if self.rows.len() >= BATCH_ROWS {
+                self.flush()?;
+            }
+            Ok(())
+        }
+
+        fn finish(mut self: Box<Self>) -> Result<(), KorndexError> {
+            self.flush()?;
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! C interface to the index, built with the `ffi` feature and declared in
+//! `include/korndex.h`.
+
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::Txid;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::path::Path;
+use std::str::FromStr;
+use std::{ptr, slice};
+
+use crate::store::Backend;
+use crate::{EmbeddedIndex, KorndexError};
+
+/// Returned by [`korndex_lookup_txid`] for a transaction that was found.
+const FOUND: c_int = 0;
+
+thread_local! {
+    /// Message of the last error on this thread, for `korndex_last_error`
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// An index opened read-only, with the node's chainstate if a data directory
+/// was given.
+pub struct KorndexIndex(EmbeddedIndex);
+
+/// A transaction found by [`korndex_lookup_txid`], freed with
+/// [`korndex_free_result`].
+#[repr(C)]
+pub struct KorndexResult {
+    /// Txid in internal byte order
+    pub txid: [u8; 32],
+    pub block_height: i32,
+    pub position_in_block: u64,
+    /// Fee in satoshis, 0 for the coinbase
+    pub fee: u64,
+    /// Virtual size in vbytes
+    pub vsize: u32,
+    /// The consensus-serialized transaction, NULL if the index was opened
+    /// without the node's data directory
+    pub tx: *mut u8,
+    pub tx_len: usize,
+}
+
+fn set_last_error(e: &KorndexError) {
+    let message = CString::new(e.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// # Safety
+/// `s` must be NULL or a NUL-terminated string.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, KorndexError> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| KorndexError::InvalidInput(format!("{} is not UTF-8", name).into()))
+}
+
+/// Opens the LMDB index below `index_dir` for `network` read-only. Pass the
+/// node's data directory as `datadir` to also read transactions, or NULL.
+/// Returns NULL on error, see `korndex_last_error`.
+///
+/// # Safety
+/// The arguments must be NUL-terminated strings, `datadir` may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn korndex_open(
+    index_dir: *const c_char,
+    network: *const c_char,
+    datadir: *const c_char,
+) -> *mut KorndexIndex {
+    let open = || -> Result<KorndexIndex, KorndexError> {
+        let missing = || KorndexError::InvalidInput("index_dir and network are required".into());
+        let index_dir = str_arg(index_dir, "index_dir")?.ok_or_else(missing)?;
+        let network = str_arg(network, "network")?.ok_or_else(missing)?;
+        let datadir = str_arg(datadir, "datadir")?;
+        let index = EmbeddedIndex::open(Backend::Lmdb, Path::new(index_dir), network, datadir)?;
+        Ok(KorndexIndex(index))
+    };
+    match open() {
+        Ok(index) => Box::into_raw(Box::new(index)),
+        Err(e) => {
+            set_last_error(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Closes an index opened with [`korndex_open`].
+///
+/// # Safety
+/// `index` must be NULL or returned by `korndex_open`, and not used again.
+#[no_mangle]
+pub unsafe extern "C" fn korndex_close(index: *mut KorndexIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+/// Looks up a transaction by its hex txid or witness transaction id. Returns 0
+/// and stores the result in `*result` if found, 1 if not, and the CLI's exit
+/// code for other errors, see `korndex_last_error`.
+///
+/// # Safety
+/// `index` must be an open index, `txid` a NUL-terminated string and `result`
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn korndex_lookup_txid(
+    index: *const KorndexIndex,
+    txid: *const c_char,
+    result: *mut *mut KorndexResult,
+) -> c_int {
+    let index = &*index;
+    let lookup = || -> Result<KorndexResult, KorndexError> {
+        let txid = str_arg(txid, "txid")?
+            .ok_or_else(|| KorndexError::InvalidInput("txid is required".into()))?;
+        let txid = Txid::from_str(txid)?;
+        let query = index.0.query();
+        let not_found = || query.txid_not_found(&txid);
+        let (txid, _, entry) = query.locate(&txid)?.ok_or_else(not_found)?;
+        let tx = match index.0.has_chainstate() {
+            true => Some(serialize(
+                &query.transaction(&txid)?.ok_or_else(not_found)?.tx,
+            )),
+            false => None,
+        };
+        let (tx, tx_len) = match tx {
+            Some(tx) => {
+                let tx = Box::into_raw(tx.into_boxed_slice());
+                (tx as *mut u8, tx.len())
+            }
+            None => (ptr::null_mut(), 0),
+        };
+        Ok(KorndexResult {
+            txid: txid.to_byte_array(),
+            block_height: entry.block_height,
+            position_in_block: entry.position_in_block as u64,
+            fee: entry.fee,
+            vsize: entry.vsize,
+            tx,
+            tx_len,
+        })
+    };
+    match lookup() {
+        Ok(found) => {
+            *result = Box::into_raw(Box::new(found));
+            FOUND
+        }
+        Err(e) => {
+            set_last_error(&e);
+            e.exit_code() as c_int
+        }
+    }
+}
+
+/// Frees a result returned by [`korndex_lookup_txid`].
+///
+/// # Safety
+/// `result` must be NULL or returned by `korndex_lookup_txid`, and not used
+/// again.
+#[no_mangle]
+pub unsafe extern "C" fn korndex_free_result(result: *mut KorndexResult) {
+    if result.is_null() {
+        return;
+    }
+    let result = Box::from_raw(result);
+    if !result.tx.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(
+            result.tx,
+            result.tx_len,
+        )));
+    }
+}
+
+/// Message of the last error on the calling thread, valid until the next
+/// call on it.
+#[no_mangle]
+pub extern "C" fn korndex_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
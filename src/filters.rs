@@ -0,0 +1,72 @@
+use bitcoin::bip158::{self, BlockFilter, FilterHeader};
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, OutPoint, ScriptBuf, TxOut};
+use std::collections::HashMap;
+
+use crate::meta;
+use crate::store::{height_key, Batch, KvStore, Table};
+use crate::KorndexError;
+
+/// Builds the BIP158 basic filter for a block. `spent_outputs` holds the
+/// prevouts of each non-coinbase transaction.
+pub fn basic_filter(
+    block: &Block,
+    spent_outputs: &[Vec<TxOut>],
+) -> Result<BlockFilter, KorndexError> {
+    let prevout_scripts: HashMap<OutPoint, &ScriptBuf> = block
+        .txdata
+        .iter()
+        .skip(1)
+        .zip(spent_outputs)
+        .flat_map(|(tx, prevouts)| {
+            tx.input
+                .iter()
+                .zip(prevouts)
+                .map(|(input, prevout)| (input.previous_output, &prevout.script_pubkey))
+        })
+        .collect();
+    Ok(BlockFilter::new_script_filter(block, |outpoint| {
+        prevout_scripts
+            .get(outpoint)
+            .map(|script| (*script).clone())
+            .ok_or(bip158::Error::UtxoMissing(*outpoint))
+    })?)
+}
+
+/// Extends the filter header chain over `start..=end`. Headers depend on their
+/// predecessor, so this runs after the filters themselves have been written.
+pub fn connect_filter_headers(
+    store: &dyn KvStore,
+    start: i32,
+    end: i32,
+) -> Result<(), KorndexError> {
+    // Filter headers commit to every filter since genesis, which an index
+    // not starting at genesis does not have
+    if meta::read_start_height(store)?.is_some() {
+        return Ok(());
+    }
+    let mut prev_header = if start == 0 {
+        FilterHeader::all_zeros()
+    } else {
+        let data = store
+            .get(Table::FilterHeaders, &height_key(start - 1))?
+            .ok_or_else(|| {
+                KorndexError::Corrupt(format!("Missing filter header at height {}", start - 1))
+            })?;
+        FilterHeader::from_slice(&data)?
+    };
+    let mut batch = Batch::default();
+    for height in start..=end {
+        let filter = store
+            .get(Table::Filters, &height_key(height))?
+            .ok_or_else(|| KorndexError::Corrupt(format!("Missing filter at height {}", height)))?;
+        let header = BlockFilter::new(&filter).filter_header(&prev_header);
+        batch.put(
+            Table::FilterHeaders,
+            height_key(height).to_vec(),
+            header.to_byte_array().to_vec(),
+        );
+        prev_header = header;
+    }
+    store.put_batch(&batch)
+}
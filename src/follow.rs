@@ -0,0 +1,92 @@
+//! Following the chain tip once an index is built, through the kernel, RPC
+//! or ZMQ, with notifications for watched scripts on the side.
+
+use bitcoin::Network;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use crate::rpc::RpcClient;
+use crate::store::KvStore;
+use crate::{rpc_source, watch, zmq_feed, Indexer, KorndexError};
+
+/// How a followed index learns about new blocks, besides ZMQ.
+#[derive(Clone, Copy)]
+pub enum Tips<'a> {
+    /// Notified by the kernel
+    Kernel(&'a Receiver<()>),
+    /// Polled over RPC
+    Rpc(&'a RpcClient),
+}
+
+/// How an index follows the tip after its initial build.
+pub struct FollowOptions {
+    /// bitcoind's `rawblock` ZMQ endpoint, indexing the blocks it publishes
+    /// instead of learning about them from the [`Tips`]
+    pub zmq_block: Option<String>,
+    /// Time between tip checks when not notified
+    pub poll_interval: Duration,
+    pub watch: Option<WatchOptions>,
+}
+
+/// Where notifications for the watched scripts of a followed index go.
+pub struct WatchOptions {
+    /// The [`watch::Watchlist`] file
+    pub file: PathBuf,
+    /// URLs notifications are POSTed to
+    pub webhooks: Vec<String>,
+    /// Endpoint of a ZMQ PUB socket notifications are published on
+    pub zmq: Option<String>,
+    /// Highest derivation index of ranged descriptors
+    pub range: u32,
+}
+
+/// Keeps the index at the tip through ZMQ or `tips` until shutdown is
+/// requested, sending watch notifications from another thread meanwhile.
+pub fn follow(
+    indexer: &Indexer,
+    store: &dyn KvStore,
+    network: Network,
+    tips: Tips,
+    options: &FollowOptions,
+) -> Result<(), KorndexError> {
+    let Some(ref watch) = options.watch else {
+        return follow_tip(indexer, store, tips, options);
+    };
+    let watchlist = watch::Watchlist::new(watch.file.clone(), network, watch.range);
+    let mut sinks: Vec<watch::Sink> = watch
+        .webhooks
+        .iter()
+        .map(|url| watch::Sink::Webhook(url.clone()))
+        .collect();
+    if let Some(ref endpoint) = watch.zmq {
+        sinks.push(watch::Sink::zmq(endpoint)?);
+    }
+    let stop = &AtomicBool::new(false);
+    thread::scope(|s| {
+        let watcher = s.spawn(move || watch::run(store, watchlist, &sinks, stop));
+        let result = follow_tip(indexer, store, tips, options);
+        stop.store(true, Ordering::Relaxed);
+        if let Err(e) = watcher.join().unwrap() {
+            tracing::error!("Stopped watching for notifications: {}", e);
+        }
+        result
+    })
+}
+
+fn follow_tip(
+    indexer: &Indexer,
+    store: &dyn KvStore,
+    tips: Tips,
+    options: &FollowOptions,
+) -> Result<(), KorndexError> {
+    match (tips, options.zmq_block.as_deref()) {
+        (_, Some(endpoint)) => zmq_feed::follow(indexer, endpoint),
+        (Tips::Kernel(block_tips), None) => indexer.follow(block_tips, options.poll_interval),
+        (Tips::Rpc(client), None) => {
+            rpc_source::follow(indexer, store, client, options.poll_interval)
+        }
+    }
+}
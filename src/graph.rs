@@ -0,0 +1,108 @@
+use bitcoin::hashes::Hash;
+use bitcoin::Txid;
+use std::collections::{HashSet, VecDeque};
+
+use crate::store::{KvStore, Table};
+use crate::KorndexError;
+
+/// Which way [`walk`] follows the transaction graph.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Parents, the transactions whose outputs are spent
+    Ancestors,
+    /// Children, the transactions spending the outputs
+    Descendants,
+    Both,
+}
+
+/// A transaction reached by [`walk`].
+#[derive(Debug, Clone, Copy)]
+pub struct GraphEntry {
+    pub txid: Txid,
+    /// Steps from the starting transaction, negative for ancestors
+    pub depth: i32,
+    /// The transaction it was reached from
+    pub via: Txid,
+}
+
+/// Txids of the transactions whose outputs `txid` spends, in input order, or
+/// `None` if `txid` is not indexed. Empty for a coinbase.
+pub fn parents(store: &dyn KvStore, txid: &Txid) -> Result<Option<Vec<Txid>>, KorndexError> {
+    let Some(data) = store.get(Table::Parents, &txid.to_byte_array())? else {
+        return Ok(None);
+    };
+    let parents = data
+        .chunks_exact(32)
+        .map(Txid::from_slice)
+        .collect::<Result<_, _>>()?;
+    Ok(Some(parents))
+}
+
+/// Txids of the indexed transactions spending outputs of `txid`.
+pub fn children(store: &dyn KvStore, txid: &Txid) -> Result<Vec<Txid>, KorndexError> {
+    store
+        .get_dups(Table::Children, &txid.to_byte_array())?
+        .iter()
+        .map(|child| Ok(Txid::from_slice(child)?))
+        .collect()
+}
+
+/// The transactions up to `depth` steps from `txid` in `direction`,
+/// breadth-first, each once at its closest depth. Parents outside the index,
+/// e.g. below the start of a partial index, end the walk there. `None` if
+/// `txid` is not indexed.
+pub fn walk(
+    store: &dyn KvStore,
+    txid: &Txid,
+    depth: u32,
+    direction: Direction,
+) -> Result<Option<Vec<GraphEntry>>, KorndexError> {
+    if parents(store, txid)?.is_none() {
+        return Ok(None);
+    }
+    let mut entries = Vec::new();
+    if direction != Direction::Descendants {
+        entries.extend(walk_one_way(store, txid, depth, true)?);
+    }
+    if direction != Direction::Ancestors {
+        entries.extend(walk_one_way(store, txid, depth, false)?);
+    }
+    Ok(Some(entries))
+}
+
+fn walk_one_way(
+    store: &dyn KvStore,
+    txid: &Txid,
+    depth: u32,
+    ancestors: bool,
+) -> Result<Vec<GraphEntry>, KorndexError> {
+    let mut entries = Vec::new();
+    let mut seen = HashSet::from([*txid]);
+    let mut queue = VecDeque::from([(*txid, 0)]);
+    while let Some((current, steps)) = queue.pop_front() {
+        if steps == depth {
+            continue;
+        }
+        let next = match ancestors {
+            true => parents(store, &current)?.unwrap_or_default(),
+            false => children(store, &current)?,
+        };
+        for found in next {
+            if !seen.insert(found) {
+                continue;
+            }
+            let steps = steps + 1;
+            entries.push(GraphEntry {
+                txid: found,
+                depth: if ancestors {
+                    -(steps as i32)
+                } else {
+                    steps as i32
+                },
+                via: current,
+            });
+            queue.push_back((found, steps));
+        }
+    }
+    Ok(entries)
+}
@@ -0,0 +1,123 @@
+//! gRPC API for backend services, built with the `grpc` feature. The service
+//! is defined in `proto/korndex.proto`, and answered through an async
+//! [`Index`].
+
+use bitcoin::consensus::serialize;
+use bitcoin::Txid;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::asynk::Index;
+use crate::KorndexError;
+
+pub mod proto {
+    tonic::include_proto!("korndex");
+}
+
+use proto::korndex_server::{Korndex, KorndexServer};
+use proto::{Block, BlockRequest, BlockTxids, StreamNewBlocksRequest, Tx, TxLocation, TxRequest};
+
+impl From<KorndexError> for Status {
+    fn from(e: KorndexError) -> Self {
+        match e {
+            KorndexError::NotFound(_) | KorndexError::Pruned(_) => Status::not_found(e.to_string()),
+            KorndexError::InvalidInput(_) => Status::invalid_argument(e.to_string()),
+            KorndexError::Config(_) => Status::failed_precondition(e.to_string()),
+            _ => Status::internal(e.to_string()),
+        }
+    }
+}
+
+struct Service {
+    index: Index,
+}
+
+fn parse_txid(txid: &str) -> Result<Txid, Status> {
+    Txid::from_str(txid).map_err(|e| Status::invalid_argument(format!("Invalid txid: {}", e)))
+}
+
+#[tonic::async_trait]
+impl Korndex for Service {
+    async fn locate_tx(&self, request: Request<TxRequest>) -> Result<Response<TxLocation>, Status> {
+        let id = parse_txid(&request.into_inner().txid)?;
+        let (txid, via_wtxid, entry) = self
+            .index
+            .with_query(move |query| query.locate(&id)?.ok_or_else(|| query.txid_not_found(&id)))
+            .await?;
+        Ok(Response::new(TxLocation {
+            txid: txid.to_string(),
+            via_wtxid,
+            block_height: entry.block_height,
+            position: entry.position_in_block as u32,
+            fee: entry.fee,
+            vsize: entry.vsize,
+        }))
+    }
+
+    async fn get_tx(&self, request: Request<TxRequest>) -> Result<Response<Tx>, Status> {
+        let id = parse_txid(&request.into_inner().txid)?;
+        let found = self
+            .index
+            .with_query(move |query| {
+                query
+                    .transaction(&id)?
+                    .ok_or_else(|| query.txid_not_found(&id))
+            })
+            .await?;
+        Ok(Response::new(Tx {
+            txid: found.txid.to_string(),
+            raw: serialize(&found.tx),
+            block_height: found.entry.block_height,
+            block_hash: found.header.block_hash().to_string(),
+        }))
+    }
+
+    async fn get_block_txids(
+        &self,
+        request: Request<BlockRequest>,
+    ) -> Result<Response<BlockTxids>, Status> {
+        let height = request.into_inner().height;
+        let txids = self
+            .index
+            .with_query(move |query| query.block_txids(height))
+            .await?
+            .ok_or_else(|| Status::not_found(format!("Block {} is not indexed", height)))?;
+        Ok(Response::new(BlockTxids {
+            height,
+            txids: txids.iter().map(Txid::to_string).collect(),
+        }))
+    }
+
+    type StreamNewBlocksStream = Pin<Box<dyn Stream<Item = Result<Block, Status>> + Send>>;
+
+    async fn stream_new_blocks(
+        &self,
+        _request: Request<StreamNewBlocksRequest>,
+    ) -> Result<Response<Self::StreamNewBlocksStream>, Status> {
+        let blocks = self
+            .index
+            .new_blocks()
+            .map(|block| -> Result<Block, Status> {
+                let (height, hash) = block?;
+                Ok(Block {
+                    height,
+                    hash: hash.to_string(),
+                })
+            });
+        Ok(Response::new(Box::pin(blocks)))
+    }
+}
+
+/// Serves the gRPC API on `bind` until the server fails.
+pub async fn serve(bind: SocketAddr, index: Index) -> Result<(), KorndexError> {
+    tracing::info!("Serving gRPC API on {}", bind);
+    Server::builder()
+        .add_service(KorndexServer::new(Service { index }))
+        .serve(bind)
+        .await
+        .map_err(|e| KorndexError::Io(std::io::Error::other(e)))
+}
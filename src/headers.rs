@@ -0,0 +1,93 @@
+use bitcoin::block::Header;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+
+use crate::store::{height_key, Batch, KvStore, Table};
+use crate::{meta, KorndexError};
+
+/// Blocks whose timestamps make up the median time past, see BIP113.
+pub const MEDIAN_TIME_SPAN: i32 = 11;
+
+/// Header of the indexed block at `height`, stored as its 80-byte
+/// serialization.
+pub fn read_header(store: &dyn KvStore, height: i32) -> Result<Option<Header>, KorndexError> {
+    match store.get(Table::Headers, &height_key(height))? {
+        Some(data) => Ok(Some(deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Height of the indexed block with `hash`.
+pub fn read_height(store: &dyn KvStore, hash: &BlockHash) -> Result<Option<i32>, KorndexError> {
+    match store.get(Table::FilterHeights, &hash.to_byte_array())? {
+        Some(data) => Ok(Some(u32::from_be_bytes(data[..].try_into()?) as i32)),
+        None => Ok(None),
+    }
+}
+
+/// Adds the median time past of the blocks in `start..=end` to `batch`: the
+/// median timestamp of each block and the ten before it. The first blocks of a
+/// partial index use only the timestamps it has.
+pub fn write_median_times(
+    store: &dyn KvStore,
+    batch: &mut Batch,
+    start: i32,
+    end: i32,
+) -> Result<(), KorndexError> {
+    let mut times = Vec::new();
+    for height in (start - MEDIAN_TIME_SPAN + 1).max(0)..=end {
+        if let Some(header) = read_header(store, height)? {
+            times.push(header.time);
+        }
+        if height < start || times.is_empty() {
+            continue;
+        }
+        let window = &times[times.len().saturating_sub(MEDIAN_TIME_SPAN as usize)..];
+        let mut sorted = window.to_vec();
+        sorted.sort_unstable();
+        batch.put(
+            Table::MedianTimes,
+            height_key(height).to_vec(),
+            sorted[sorted.len() / 2].to_be_bytes().to_vec(),
+        );
+    }
+    Ok(())
+}
+
+/// Median time past of the indexed block at `height`.
+pub fn read_median_time(store: &dyn KvStore, height: i32) -> Result<Option<u32>, KorndexError> {
+    match store.get(Table::MedianTimes, &height_key(height))? {
+        Some(data) => Ok(Some(u32::from_be_bytes(data[..].try_into()?))),
+        None => Ok(None),
+    }
+}
+
+/// The highest indexed block whose median time past is at most `time`, the
+/// block that was the tip at `time`. Median time past never decreases along
+/// the chain, so this is a binary search over heights. `None` if `time` is
+/// before the first indexed block.
+pub fn height_at(store: &dyn KvStore, time: u32) -> Result<Option<i32>, KorndexError> {
+    let Some(best) = meta::read_best_block(store)? else {
+        return Ok(None);
+    };
+    let median_time = |height: i32| {
+        read_median_time(store, height)?.ok_or_else(|| {
+            KorndexError::Corrupt(format!("No median time past for block {}", height))
+        })
+    };
+    let (mut low, mut high) = (meta::read_start_height(store)?.unwrap_or(0), best.height);
+    if median_time(low)? > time {
+        return Ok(None);
+    }
+    // Invariant: the block at `low` qualifies
+    while low < high {
+        let middle = low + (high - low + 1) / 2;
+        if median_time(middle)? <= time {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    Ok(Some(low))
+}
@@ -0,0 +1,417 @@
+use bitcoin::constants::genesis_block;
+use bitcoin::Network;
+use libbitcoinkernel_sys::{ChainstateManager, Context};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::store::{self, Backend, Batch, KvStore, TableCompression};
+use crate::{crashtest, kernel, meta, migrate, KorndexError, QueryHandle};
+
+/// Lock file in the index directory, held by the process writing the index.
+const LOCK_FILE: &str = "LOCK";
+
+/// An opened, migrated index for a single network.
+///
+/// Only one process may write to an index at a time: [`TxIndexStore::open`]
+/// takes an exclusive lock on a lock file in the index directory, and refuses
+/// to open an index another process holds it for. Any number of processes can
+/// open an LMDB index with [`TxIndexStore::open_read_only`] alongside the
+/// writer. redb and RocksDB lock their files themselves and allow a single
+/// process of any kind, SQLite allows concurrent readers.
+pub struct TxIndexStore {
+    store: Box<dyn KvStore>,
+    /// The network's directory the store lives in
+    dir: PathBuf,
+    /// Released once the store is closed, as fields drop in order
+    _lock: Option<File>,
+}
+
+impl TxIndexStore {
+    /// Opens the index for `network` below `index_dir` with the selected
+    /// backend, creating and migrating it as needed. `map_size` is the initial
+    /// LMDB map size in bytes.
+    pub fn open(
+        backend: Backend,
+        index_dir: &Path,
+        network: Network,
+        map_size: usize,
+    ) -> Result<Self, KorndexError> {
+        let index_dir = index_dir.join(network_dir(network));
+        fs::create_dir_all(&index_dir)?;
+        let lock = lock_writer(&index_dir)?;
+        let store = open_store(backend, &index_dir, map_size)?;
+        let store = sharding(store, &index_dir, true, |dir| {
+            open_store(backend, dir, map_size)
+        })?;
+        migrate::migrate(&*store)?;
+        check_network(&*store, network, true)?;
+        let store = decompressing(store)?;
+        let store = crashtest::arm(store)?;
+        Ok(TxIndexStore {
+            store,
+            dir: index_dir,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Opens an existing index for `network` below `index_dir` for lookups
+    /// only. LMDB indexes are opened read-only, and nothing is migrated or
+    /// stamped.
+    pub fn open_read_only(
+        backend: Backend,
+        index_dir: &Path,
+        network: Network,
+    ) -> Result<Self, KorndexError> {
+        let index_dir = index_dir.join(network_dir(network));
+        if !index_dir.exists() {
+            return Err(KorndexError::Config(format!(
+                "No index at {}, build one first",
+                index_dir.display()
+            )));
+        }
+        let open = |dir: &Path| -> Result<Box<dyn KvStore>, KorndexError> {
+            match backend {
+                #[cfg(feature = "lmdb")]
+                Backend::Lmdb => Ok(Box::new(store::LmdbStore::open_read_only(
+                    &dir.join("lmdb"),
+                )?)),
+                _ => open_store(backend, dir, 0),
+            }
+        };
+        let store = sharding(open(&index_dir)?, &index_dir, false, open)?;
+        migrate::check(&*store)?;
+        if meta::read_network(&*store)?.is_none() {
+            return Err(KorndexError::Config(format!(
+                "No index at {}, build one first",
+                index_dir.display()
+            )));
+        }
+        check_network(&*store, network, false)?;
+        let store = decompressing(store)?;
+        Ok(TxIndexStore {
+            store,
+            dir: index_dir,
+            _lock: None,
+        })
+    }
+
+    /// Rewrites the index without the space left behind by deleted entries,
+    /// returning the bytes it then occupies on disk.
+    pub fn compact(self) -> Result<u64, KorndexError> {
+        self.store.compact()
+    }
+
+    /// The underlying key-value store.
+    pub fn kv(&self) -> &dyn KvStore {
+        &*self.store
+    }
+
+    /// The directory of the index's network the store lives in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Routes every access to the store through the store `wrap` returns
+    /// around it, like the timing store of the benchmarks.
+    pub(crate) fn map_store(self, wrap: impl FnOnce(Box<dyn KvStore>) -> Box<dyn KvStore>) -> Self {
+        TxIndexStore {
+            store: wrap(self.store),
+            dir: self.dir,
+            _lock: self._lock,
+        }
+    }
+
+    /// Keeps the entries of the last `hot_blocks` blocks written in memory,
+    /// see [`store::TieredStore`].
+    pub fn tiered(self, hot_blocks: usize) -> Self {
+        self.map_store(|inner| Box::new(store::TieredStore::new(inner, hot_blocks)))
+    }
+
+    /// Compresses the values of `tables` in a new index, see
+    /// [`store::CompressedStore`]. The tables are recorded in the index and
+    /// cannot change once it holds blocks, an index opened later compresses
+    /// them without being asked. Empty `tables` keep what the index recorded.
+    pub fn compress(self, tables: &[TableCompression]) -> Result<Self, KorndexError> {
+        if tables.is_empty() {
+            return Ok(self);
+        }
+        TableCompression::check(tables)?;
+        match meta::read_compression(self.kv())? {
+            Some(recorded) if recorded == tables => Ok(self),
+            Some(_) => Err(KorndexError::Config(
+                "The index was created with other --compress settings, they cannot change without rebuilding it".to_owned(),
+            )),
+            None if meta::read_best_block(self.kv())?.is_some() => Err(KorndexError::Config(
+                "The index was created without compression, compressing it needs a rebuild".to_owned(),
+            )),
+            None => {
+                let mut batch = Batch::default();
+                meta::write_compression(&mut batch, tables)?;
+                self.kv().put_batch(&batch)?;
+                let tables = tables.to_vec();
+                Ok(self.map_store(|inner| Box::new(store::CompressedStore::new(inner, &tables))))
+            }
+        }
+    }
+
+    /// Splits the txindex of a new index into `shards` stores of the same
+    /// `backend` that a build writes in parallel, see [`store::ShardedStore`].
+    /// The number of shards is recorded in the index and cannot change once
+    /// it holds blocks, an index opened later opens its shards without being
+    /// asked. 1 shard keeps what the index recorded.
+    pub fn shard_txindex(
+        self,
+        backend: Backend,
+        shards: usize,
+        map_size: usize,
+    ) -> Result<Self, KorndexError> {
+        store::check_shards(shards)?;
+        if shards == 1 {
+            return Ok(self);
+        }
+        match meta::read_txindex_shards(self.kv())? {
+            Some(recorded) if recorded == shards => Ok(self),
+            Some(recorded) => Err(KorndexError::Config(format!(
+                "The index was created with {} txindex shards, they cannot change without rebuilding it",
+                recorded
+            ))),
+            None if meta::read_best_block(self.kv())?.is_some() => Err(KorndexError::Config(
+                "The index was created without txindex shards, sharding it needs a rebuild".to_owned(),
+            )),
+            None => {
+                let shard_stores = (0..shards)
+                    .map(|shard| {
+                        let dir = shard_dir(&self.dir, shard);
+                        fs::create_dir_all(&dir)?;
+                        open_store(backend, &dir, map_size)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut batch = Batch::default();
+                meta::write_txindex_shards(&mut batch, shards)?;
+                self.kv().put_batch(&batch)?;
+                Ok(TxIndexStore {
+                    store: Box::new(store::ShardedStore::new(self.store, shard_stores)?),
+                    ..self
+                })
+            }
+        }
+    }
+
+    /// Lookups against this index, reading blocks through `chainman`.
+    pub fn query<'a>(&'a self, chainman: &'a ChainstateManager) -> QueryHandle<'a> {
+        QueryHandle::new(Some(chainman), self.kv())
+    }
+
+    /// Lookups that only need this index. Those reading transactions or undo
+    /// data fail.
+    pub fn index_query(&self) -> QueryHandle<'_> {
+        QueryHandle::new(None, self.kv())
+    }
+}
+
+/// An index opened read-only along with the chainstate of its node, for
+/// programs embedding korndex's lookups.
+pub struct EmbeddedIndex {
+    index: TxIndexStore,
+    /// Declared before the context so the chainstate is unloaded first
+    chainman: Option<ChainstateManager>,
+    _context: Option<Context>,
+}
+
+impl EmbeddedIndex {
+    /// Opens the index below `index_dir` for the network named `network`.
+    /// Lookups reading transactions need the node's data directory,
+    /// `datadir`.
+    pub fn open(
+        backend: Backend,
+        index_dir: &Path,
+        network: &str,
+        datadir: Option<&str>,
+    ) -> Result<Self, KorndexError> {
+        let (chain_type, network) = kernel::network(network)?;
+        let index = TxIndexStore::open_read_only(backend, index_dir, network)?;
+        let (chainman, context) = match datadir {
+            Some(datadir) => {
+                let context = kernel::create_context(chain_type, None)?;
+                let chainman = kernel::load_chainman(&context, datadir)?;
+                (Some(chainman), Some(context))
+            }
+            None => (None, None),
+        };
+        Ok(EmbeddedIndex {
+            index,
+            chainman,
+            _context: context,
+        })
+    }
+
+    pub fn has_chainstate(&self) -> bool {
+        self.chainman.is_some()
+    }
+
+    pub fn query(&self) -> QueryHandle<'_> {
+        QueryHandle::new(self.chainman.as_ref(), self.index.kv())
+    }
+
+    pub fn kv(&self) -> &dyn KvStore {
+        self.index.kv()
+    }
+}
+
+/// Takes the writer lock on the index in `index_dir`, held until the returned
+/// file is closed, and records this process's id in it. The operating system
+/// releases the lock of a process that crashed.
+fn lock_writer(index_dir: &Path) -> Result<File, KorndexError> {
+    let path = index_dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            return Err(KorndexError::Config(format!(
+                "The index at {} is being written by another korndex process (pid {}), \
+                 stop it first. Queries can read the index meanwhile",
+                index_dir.display(),
+                pid.trim()
+            )));
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", process::id())?;
+    Ok(file)
+}
+
+/// Name of the subdirectory holding the index for `network`, so indexes for
+/// different networks can share an index directory.
+fn network_dir(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "mainnet",
+        Network::Testnet => "testnet",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+        _ => "unknown",
+    }
+}
+
+/// Refuses to use an index built for another network or chain. With `stamp`,
+/// records the network and its genesis hash in an index lacking them.
+fn check_network(store: &dyn KvStore, network: Network, stamp: bool) -> Result<(), KorndexError> {
+    let name = network_dir(network);
+    let genesis_hash = genesis_block(network).block_hash();
+    let mut batch = Batch::default();
+    match meta::read_network(store)? {
+        Some(indexed) if indexed == name => {}
+        Some(indexed) => {
+            return Err(KorndexError::Config(format!(
+                "Index was built for {} but korndex is running on {}",
+                indexed, name
+            )))
+        }
+        None => meta::write_network(&mut batch, name),
+    }
+    match meta::read_genesis_hash(store)? {
+        Some(indexed) if indexed == genesis_hash => {}
+        Some(indexed) => {
+            return Err(KorndexError::Config(format!(
+                "Index was built for the chain starting at block {} but {} starts at {}",
+                indexed, name, genesis_hash
+            )))
+        }
+        None => meta::write_genesis_hash(&mut batch, &genesis_hash),
+    }
+    if stamp && !batch.ops.is_empty() {
+        store.put_batch(&batch)?;
+    }
+    Ok(())
+}
+
+/// Directory of one of the txindex shards of the index in `index_dir`.
+fn shard_dir(index_dir: &Path, shard: usize) -> PathBuf {
+    index_dir.join("txindex-shards").join(shard.to_string())
+}
+
+/// Wraps `store` in a [`store::ShardedStore`] if its index splits the txindex
+/// into shards, opening each below `index_dir` with `open`, and undoing what
+/// the shards committed past the index before a crash if `recover`.
+fn sharding(
+    store: Box<dyn KvStore>,
+    index_dir: &Path,
+    recover: bool,
+    open: impl Fn(&Path) -> Result<Box<dyn KvStore>, KorndexError>,
+) -> Result<Box<dyn KvStore>, KorndexError> {
+    let Some(shards) = meta::read_txindex_shards(&*store)? else {
+        return Ok(store);
+    };
+    let shard_stores = (0..shards)
+        .map(|shard| open(&shard_dir(index_dir, shard)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sharded = store::ShardedStore::new(store, shard_stores)?;
+    if recover {
+        sharded.recover()?;
+    }
+    Ok(Box::new(sharded))
+}
+
+/// Wraps `store` in a [`store::CompressedStore`] if its index was created
+/// with compressed tables.
+fn decompressing(store: Box<dyn KvStore>) -> Result<Box<dyn KvStore>, KorndexError> {
+    match meta::read_compression(&*store)? {
+        Some(tables) => Ok(Box::new(store::CompressedStore::new(store, &tables))),
+        None => Ok(store),
+    }
+}
+
+/// Opens the index in `index_dir` with the selected backend, creating it if
+/// needed.
+fn open_store(
+    backend: Backend,
+    index_dir: &Path,
+    map_size: usize,
+) -> Result<Box<dyn KvStore>, KorndexError> {
+    match backend {
+        #[cfg(feature = "lmdb")]
+        Backend::Lmdb => {
+            let path = index_dir.join("lmdb");
+            fs::create_dir_all(&path)?;
+            Ok(Box::new(store::LmdbStore::open(&path, map_size)?))
+        }
+        #[cfg(not(feature = "lmdb"))]
+        Backend::Lmdb => Err(KorndexError::Config(
+            "korndex was built without LMDB support, rebuild with --features lmdb".to_owned(),
+        )),
+        #[cfg(feature = "redb")]
+        Backend::Redb => Ok(Box::new(store::RedbStore::open(
+            &index_dir.join("index.redb"),
+        )?)),
+        #[cfg(not(feature = "redb"))]
+        Backend::Redb => Err(KorndexError::Config(
+            "korndex was built without redb support, rebuild with --features redb".to_owned(),
+        )),
+        #[cfg(feature = "rocksdb")]
+        Backend::Rocksdb => Ok(Box::new(store::RocksDbStore::open(
+            &index_dir.join("rocksdb"),
+        )?)),
+        #[cfg(not(feature = "rocksdb"))]
+        Backend::Rocksdb => Err(KorndexError::Config(
+            "korndex was built without RocksDB support, rebuild with --features rocksdb".to_owned(),
+        )),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Box::new(store::SqliteStore::open(
+            &index_dir.join("index.sqlite"),
+        )?)),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => Err(KorndexError::Config(
+            "korndex was built without SQLite support, rebuild with --features sqlite".to_owned(),
+        )),
+    }
+}
@@ -0,0 +1,1802 @@
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::{deserialize, deserialize_partial, serialize};
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, OutPoint, TxOut, Txid};
+use libbitcoinkernel_sys::ChainstateManager;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::analytics::{self, ValueHistogram};
+use crate::bench::ScratchIndex;
+use crate::blockstats::BlockStats;
+use crate::coinbase::CoinbaseEntry;
+use crate::diskspace::{self, SpaceMonitor};
+use crate::indexes::{IndexKind, IndexSet};
+use crate::locktime::LocktimeCounts;
+use crate::scripthash::{self, Direction, ScriptHashEntry};
+use crate::scripttypes::ScriptTypeCounts;
+use crate::spent::{self, SpendEntry};
+use crate::store::{height_key, Backend, Batch, Bytes, Durability, KvStore, Table};
+use crate::txindex::{self, DupPolicy, TxIndexEntry};
+use crate::utxo::UtxoEntry;
+use crate::{
+    blockscan, filters, headers, kernel, meta, progress, shutdown, silentpayments, undo, witness,
+    KorndexError, TxIndexStore,
+};
+
+/// Settings for building the index.
+#[derive(Clone, Debug)]
+pub struct IndexerOptions {
+    /// Do not index coinbase transactions
+    pub skip_coinbase: bool,
+    /// Continue a build that was interrupted or crashed from its last
+    /// checkpoint
+    pub resume: bool,
+    /// Most blocks committed in one batch
+    pub batch_blocks: usize,
+    /// Commit a batch once its serialized blocks reach this many bytes
+    pub batch_bytes: Option<usize>,
+    /// Commit a batch once it holds this many transactions
+    pub batch_txs: Option<usize>,
+    /// Commit a batch once its estimated memory use reaches this many bytes
+    pub max_batch_mem: Option<usize>,
+    /// Height a new index starts from instead of genesis
+    pub start_height: Option<i32>,
+    /// Height a new index stops at instead of following the tip
+    pub end_height: Option<i32>,
+    /// When committed batches are synced, every build ends with a sync
+    pub durability: Durability,
+    /// Indexes to build. `None` keeps those of an existing index and builds
+    /// every index for a new one
+    pub indexes: Option<IndexSet>,
+    /// Which location a txid repeated by a later coinbase keeps. `None`
+    /// keeps that of an existing index and uses [`DupPolicy::Latest`] for a
+    /// new one
+    pub dup_policy: Option<DupPolicy>,
+    /// Bucket boundaries in satoshis of the value histograms of a new
+    /// analytics index. `None` keeps those of an existing index and uses
+    /// [`analytics::DEFAULT_VALUE_BUCKETS`] for a new one
+    pub value_buckets: Option<Vec<u64>>,
+    /// Blocks read from disk ahead of the indexer, bounding the pipeline's
+    /// memory use. A deeper queue hides the latency of slow disks
+    pub prefetch_blocks: usize,
+    /// Read one block at a time in chain order instead of a window of blocks
+    /// in parallel, which makes a spinning disk seek back and forth
+    pub sequential_reads: bool,
+    /// Free space in bytes to leave on the index's disk, the build pauses
+    /// before commits that would leave less. 0 never pauses
+    pub min_free_space: u64,
+}
+
+impl Default for IndexerOptions {
+    fn default() -> Self {
+        IndexerOptions {
+            skip_coinbase: false,
+            resume: false,
+            batch_blocks: 1000,
+            batch_bytes: None,
+            batch_txs: None,
+            max_batch_mem: None,
+            start_height: None,
+            end_height: None,
+            durability: Durability::Full,
+            indexes: None,
+            dup_policy: None,
+            value_buckets: None,
+            prefetch_blocks: 64,
+            sequential_reads: false,
+            min_free_space: diskspace::DEFAULT_MIN_FREE,
+        }
+    }
+}
+
+impl IndexerOptions {
+    /// Whether a batch has reached any of the configured limits.
+    fn batch_full(&self, blocks: usize, bytes: usize, transactions: usize, mem: usize) -> bool {
+        blocks >= self.batch_blocks
+            || self.batch_bytes.is_some_and(|limit| bytes >= limit)
+            || self.batch_txs.is_some_and(|limit| transactions >= limit)
+            || self.max_batch_mem.is_some_and(|limit| mem >= limit)
+    }
+}
+
+#[derive(Clone)]
+struct BlockIndexInfo {
+    block_height: i32,
+}
+
+/// Blocks read in parallel. Early blocks are tiny, so reading them one at a
+/// time would leave the indexer waiting.
+const READ_WINDOW: usize = 64;
+
+/// Indexed batches waiting for the writer thread. Lets indexing run ahead
+/// while a commit syncs, and caps memory use when the disk falls behind.
+const WRITE_QUEUE: usize = 4;
+
+/// Blocks an operation indexes before it checks up front that the index's
+/// disk has room for them.
+const PREFLIGHT_MIN_BLOCKS: usize = 1000;
+
+/// Blocks read and indexed to project the growth of the index up front.
+const PREFLIGHT_SAMPLES: usize = 32;
+
+/// Memory of a batch's index writes per byte of its raw blocks, assumed until
+/// the first batch has been indexed and the actual ratio is known.
+const INITIAL_WRITE_RATIO: f64 = 4.0;
+
+/// A block as read from disk, before deserialization.
+struct RawBlock {
+    block_height: i32,
+    n_tx: usize,
+    data: Vec<u8>,
+    spent_outputs: Vec<Vec<TxOut>>,
+}
+
+impl RawBlock {
+    /// Approximate heap memory held by the block and its undo data.
+    fn mem_size(&self) -> usize {
+        let spent_outputs: usize = self
+            .spent_outputs
+            .iter()
+            .flatten()
+            .map(|output| mem::size_of::<TxOut>() + output.script_pubkey.len())
+            .sum();
+        self.data.capacity() + spent_outputs
+    }
+}
+
+/// Everything a single block contributes to the index databases.
+struct BlockWrites {
+    block_height: i32,
+    hash: [u8; 32],
+    transactions: usize,
+    puts: Vec<(Table, Bytes, Bytes)>,
+    /// Txid of the coinbase if it goes into the txid index. Only coinbase
+    /// transactions ever repeated a txid
+    coinbase_txid: Option<[u8; 32]>,
+    /// The txid index entry of another transaction with the coinbase's txid
+    /// that the block's entry replaces, restored when it is disconnected
+    replaced_txid: Option<Bytes>,
+    /// Outpoints whose UTXO entries the block deletes
+    spent_utxos: Vec<[u8; 36]>,
+}
+
+impl BlockWrites {
+    /// Approximate heap memory held by the writes.
+    fn mem_size(&self) -> usize {
+        let puts: usize = self
+            .puts
+            .iter()
+            .map(|(_, key, value)| key.capacity() + value.capacity())
+            .sum();
+        self.puts.capacity() * mem::size_of::<(Table, Bytes, Bytes)>()
+            + puts
+            + self.spent_utxos.capacity() * 36
+    }
+}
+
+/// Builds the index from the kernel's block data and keeps it at the tip.
+pub struct Indexer<'a> {
+    /// `None` for an indexer fed blocks through [`Indexer::connect_block`]
+    /// only
+    chainman: Option<&'a ChainstateManager>,
+    store: &'a dyn KvStore,
+    /// Directory of the store, whose disk space is watched
+    dir: &'a Path,
+    options: IndexerOptions,
+}
+
+impl<'a> Indexer<'a> {
+    pub fn new(
+        chainman: &'a ChainstateManager,
+        store: &'a TxIndexStore,
+        options: IndexerOptions,
+    ) -> Self {
+        Indexer {
+            chainman: Some(chainman),
+            store: store.kv(),
+            dir: store.dir(),
+            options,
+        }
+    }
+
+    /// An indexer without the node's chainstate, for blocks from other
+    /// sources handed to [`Indexer::connect_block`].
+    pub fn without_kernel(store: &'a TxIndexStore, options: IndexerOptions) -> Self {
+        Indexer {
+            chainman: None,
+            store: store.kv(),
+            dir: store.dir(),
+            options,
+        }
+    }
+
+    fn chainman(&self) -> Result<&'a ChainstateManager, KorndexError> {
+        self.chainman.ok_or_else(|| {
+            KorndexError::Config(
+                "Building from the node's block data needs its data directory".to_owned(),
+            )
+        })
+    }
+
+    /// The indexes to build: those of an existing index, or the selected ones
+    /// for a new index, which are recorded. Selecting others for an existing
+    /// index is an error.
+    fn indexes(&self) -> Result<IndexSet, KorndexError> {
+        let store = self.store;
+        let built = match meta::read_indexes(store)? {
+            Some(built) => built,
+            None if meta::read_best_block(store)?.is_some() => IndexSet::ALL,
+            None => {
+                let selected = self.options.indexes.unwrap_or(IndexSet::ALL);
+                let mut batch = Batch::default();
+                meta::write_indexes(&mut batch, selected)?;
+                store.put_batch(&batch)?;
+                return Ok(selected);
+            }
+        };
+        match self.options.indexes {
+            Some(selected) if selected != built => Err(KorndexError::Config(format!(
+                "The index was built with --index {}, change them with korndex backfill --index or korndex drop --index",
+                built
+            ))),
+            _ => Ok(built),
+        }
+    }
+
+    /// Which location a repeated txid keeps: the policy recorded for the
+    /// index, or the selected one, which is recorded the first time blocks
+    /// are indexed. Selecting another for an existing index is an error, as
+    /// its repeated txids were resolved with the recorded one.
+    fn dup_policy(&self) -> Result<DupPolicy, KorndexError> {
+        let selected = self.options.dup_policy;
+        match meta::read_dup_policy(self.store)? {
+            Some(recorded) if selected.is_some_and(|selected| selected != recorded) => {
+                Err(KorndexError::Config(format!(
+                    "The index was built with --dup-policy {}, it cannot change without rebuilding the index",
+                    recorded.name()
+                )))
+            }
+            Some(recorded) => Ok(recorded),
+            None => {
+                let dup_policy = selected.unwrap_or_default();
+                let mut batch = Batch::default();
+                meta::write_dup_policy(&mut batch, dup_policy)?;
+                self.store.put_batch(&batch)?;
+                Ok(dup_policy)
+            }
+        }
+    }
+
+    /// Bucket boundaries of the value histograms: those recorded for the index,
+    /// or the selected ones, which are recorded the first time the analytics
+    /// index is built. Empty if `indexes` leaves it out.
+    fn value_buckets(&self, indexes: IndexSet) -> Result<Vec<u64>, KorndexError> {
+        if !indexes.contains(IndexKind::Analytics) {
+            return Ok(Vec::new());
+        }
+        let selected = self.options.value_buckets.as_deref();
+        match meta::read_value_buckets(self.store)? {
+            Some(recorded) if selected.is_some_and(|selected| selected != recorded) => {
+                let recorded: Vec<String> = recorded.iter().map(u64::to_string).collect();
+                Err(KorndexError::Config(format!(
+                    "The analytics index was built with --value-buckets {}, change them with korndex drop --index analytics",
+                    recorded.join(",")
+                )))
+            }
+            Some(recorded) => Ok(recorded),
+            None => {
+                let bounds = selected.unwrap_or(&analytics::DEFAULT_VALUE_BUCKETS);
+                analytics::check_bounds(bounds)?;
+                let mut batch = Batch::default();
+                meta::write_value_buckets(&mut batch, bounds)?;
+                self.store.put_batch(&batch)?;
+                Ok(bounds.to_vec())
+            }
+        }
+    }
+
+    /// Indexes the blocks connected since the last build, rolling back any
+    /// that left the active chain first.
+    pub fn build(&self) -> Result<(), KorndexError> {
+        let (chainman, store, options) = (self.chainman()?, self.store, &self.options);
+        let _guard = shutdown::BuildGuard::enter();
+        let indexes = self.indexes()?;
+        let dup_policy = self.dup_policy()?;
+
+        let interrupted = meta::read_checkpoint(store)?;
+        match interrupted {
+            Some(checkpoint) if options.resume => tracing::info!(
+                "Resuming interrupted build at height {} of {}",
+                checkpoint.highest_height,
+                checkpoint.target_height
+            ),
+            Some(checkpoint) => {
+                return Err(KorndexError::Config(format!(
+                    "Found a build interrupted at height {} of {}, pass --resume to continue it",
+                    checkpoint.highest_height, checkpoint.target_height
+                )))
+            }
+            None => {}
+        }
+
+        let mut best_block = meta::read_best_block(store)?;
+        if let Some(best) = best_block {
+            let active_hash = kernel::block_hash(chainman, best.height)?.map(|h| h.to_byte_array());
+            if active_hash != Some(best.hash) {
+                tracing::info!(
+                    "Block at height {} is no longer in the active chain, rolling back",
+                    best.height
+                );
+                best_block = rollback(chainman, store, best)?;
+            }
+        }
+        let start_height = match best_block {
+            Some(best) => {
+                if options.start_height.is_some() {
+                    tracing::warn!("Ignoring --start-height, the index already exists");
+                }
+                tracing::info!("Resuming from last indexed height {}", best.height);
+                best.height + 1
+            }
+            None => first_indexed_height(chainman, store, options.start_height)?,
+        };
+        let end_height = end_height(store, best_block.is_none(), options.end_height)?;
+        if end_height.is_some_and(|end| end < start_height) {
+            if best_block.is_none() {
+                return Err(KorndexError::Config(format!(
+                    "The index would end at height {}, below its start at height {}",
+                    end_height.unwrap(),
+                    start_height
+                )));
+            }
+            tracing::debug!("Index already reaches its end height");
+            return Ok(());
+        }
+
+        // Walk the active chain from genesis upward, so every batch extends the
+        // indexed prefix of the chain, can be checkpointed, and appends to the
+        // height-keyed tables
+        let tip_height = match chainman.get_block_index_tip() {
+            Ok(tip) => tip.info()?.height,
+            Err(_) => -1,
+        };
+        let last_height = end_height.map_or(tip_height, |end| end.min(tip_height));
+        if last_height < start_height {
+            tracing::debug!("Index is already up to date");
+            return Ok(());
+        }
+        let block_indices: Vec<BlockIndexInfo> = (start_height..=last_height)
+            .map(|block_height| BlockIndexInfo { block_height })
+            .collect();
+        let lowest_height = interrupted.map_or(start_height, |checkpoint| checkpoint.lowest_height);
+        let mut best = None;
+        self.run(&block_indices, indexes, |blocks| {
+            let committed = commit_blocks(
+                store,
+                blocks,
+                indexes,
+                dup_policy,
+                lowest_height,
+                last_height,
+            )?;
+            best = Some(committed);
+            Ok(committed.height)
+        })?;
+
+        match best {
+            Some(best) if best.height == last_height => {
+                tracing::info!("Built index up to height {}!", last_height)
+            }
+            Some(best) => {
+                tracing::info!("Interrupted, index checkpointed at height {}", best.height)
+            }
+            None => tracing::info!("Interrupted before the first batch was committed"),
+        }
+        Ok(())
+    }
+
+    /// Extends an index that does not start at genesis downwards to
+    /// `to_height`, or to the lowest block the node stores. Batches are
+    /// committed from the top down, so an interrupted backfill leaves a
+    /// contiguous index and can simply be run again.
+    pub fn backfill(&self, to_height: Option<i32>) -> Result<(), KorndexError> {
+        let (chainman, store) = (self.chainman()?, self.store);
+        let _guard = shutdown::BuildGuard::enter();
+        let indexes = self.indexes()?;
+        let dup_policy = self.dup_policy()?;
+
+        if meta::read_checkpoint(store)?.is_some() {
+            return Err(KorndexError::Config(
+                "Found an interrupted build, finish it with --resume before backfilling".to_owned(),
+            ));
+        }
+        let Some(best) = meta::read_best_block(store)? else {
+            return Err(KorndexError::Config(
+                "The index is empty, build it before backfilling".to_owned(),
+            ));
+        };
+        let Some(start_height) = meta::read_start_height(store)? else {
+            tracing::info!("Index already starts at genesis");
+            return Ok(());
+        };
+
+        let first_stored = kernel::first_stored_height(chainman, start_height)?;
+        let target_height = match to_height {
+            Some(height) if height < first_stored => return Err(KorndexError::Pruned(height)),
+            Some(height) => height,
+            None => {
+                if first_stored > 0 {
+                    tracing::info!(
+                        "Node is pruned, backfilling down to height {}",
+                        first_stored
+                    );
+                }
+                first_stored
+            }
+        };
+        if target_height >= start_height {
+            tracing::info!("Index already starts at height {}", start_height);
+            return Ok(());
+        }
+
+        let block_indices: Vec<BlockIndexInfo> = (target_height..start_height)
+            .rev()
+            .map(|block_height| BlockIndexInfo { block_height })
+            .collect();
+        let mut lowest = start_height;
+        self.run(&block_indices, indexes, |mut blocks| {
+            blocks.reverse();
+            lowest = commit_backfill(store, blocks, indexes, dup_policy, best.height)?;
+            Ok(lowest)
+        })?;
+
+        if lowest == target_height {
+            tracing::info!("Backfilled index down to height {}!", target_height);
+        } else {
+            tracing::info!("Interrupted, index now starts at height {}", lowest);
+        }
+        Ok(())
+    }
+
+    /// Builds the indexes of `selected` an existing index was built without
+    /// over every indexed block, leaving those it has untouched. The new
+    /// entries are added to the blocks' undo records, so reorgs disconnect
+    /// them too. An interrupted run starts over when run again.
+    pub fn add_indexes(&self, selected: IndexSet) -> Result<(), KorndexError> {
+        let (chainman, store) = (self.chainman()?, self.store);
+        let _guard = shutdown::BuildGuard::enter();
+
+        if meta::read_checkpoint(store)?.is_some() {
+            return Err(KorndexError::Config(
+                "Found an interrupted build, finish it with --resume before adding indexes"
+                    .to_owned(),
+            ));
+        }
+        let Some(best) = meta::read_best_block(store)? else {
+            return Err(KorndexError::Config(
+                "The index is empty, select its indexes with korndex build --index".to_owned(),
+            ));
+        };
+        let built = meta::built_indexes(store)?;
+        let added = selected.difference(built);
+        if added.is_empty() {
+            tracing::info!("Index already has {}", selected);
+            return Ok(());
+        }
+        let start_height = meta::read_start_height(store)?.unwrap_or(0);
+        if kernel::first_stored_height(chainman, start_height)? > start_height {
+            return Err(KorndexError::Pruned(start_height));
+        }
+
+        tracing::info!(
+            "Adding {} to the index at heights {} to {}",
+            added,
+            start_height,
+            best.height
+        );
+        let block_indices: Vec<BlockIndexInfo> = (start_height..=best.height)
+            .map(|block_height| BlockIndexInfo { block_height })
+            .collect();
+        let dup_policy = self.dup_policy()?;
+        let mut reached = None;
+        self.run(&block_indices, added, |blocks| {
+            let height = commit_added(store, blocks, added, dup_policy)?;
+            reached = Some(height);
+            Ok(height)
+        })?;
+
+        if reached != Some(best.height) {
+            tracing::info!("Interrupted, run the backfill again to add {}", added);
+            return Ok(());
+        }
+        let mut batch = Batch::default();
+        meta::write_indexes(&mut batch, built.union(added))?;
+        store.put_batch(&batch)?;
+        store.commit()?;
+        tracing::info!("Added {} to the index!", added);
+        Ok(())
+    }
+
+    /// Reads and indexes the blocks at `block_indices` in the given order,
+    /// handing batches of consecutive blocks to `commit`, which returns the
+    /// height the index reached. Reading, indexing and writing run on separate
+    /// threads connected by bounded channels, so disk reads, hashing and
+    /// commits overlap. Every write goes through `commit` on the writer thread.
+    fn run(
+        &self,
+        block_indices: &[BlockIndexInfo],
+        indexes: IndexSet,
+        mut commit: impl FnMut(Vec<BlockWrites>) -> Result<i32, KorndexError> + Send,
+    ) -> Result<(), KorndexError> {
+        let (chainman, store, options) = (self.chainman()?, self.store, &self.options);
+        // Positions always refer to the block's full transaction list, so they
+        // stay valid as `block.txdata[position]` when the coinbase is skipped.
+        let first_position = if options.skip_coinbase { 1 } else { 0 };
+        let value_buckets = &self.value_buckets(indexes)?;
+        let (Some(first), Some(last)) = (block_indices.first(), block_indices.last()) else {
+            return Ok(());
+        };
+        let mut progress = progress::Progress::new(first.block_height, last.block_height);
+        store.set_durability(options.durability)?;
+        let mut space = SpaceMonitor::new(self.dir, store, options.min_free_space)?;
+        if block_indices.len() >= PREFLIGHT_MIN_BLOCKS {
+            let heights: Vec<i32> = block_indices
+                .iter()
+                .map(|block_info| block_info.block_height)
+                .collect();
+            space.preflight(project_growth(
+                chainman,
+                &heights,
+                PREFLIGHT_SAMPLES,
+                first_position,
+                indexes,
+                value_buckets,
+            )?)?;
+        }
+
+        let (raw_blocks_tx, raw_blocks) = crossbeam_channel::bounded::<
+            Result<RawBlock, KorndexError>,
+        >(options.prefetch_blocks.max(1));
+        let (batches_tx, batches) =
+            crossbeam_channel::bounded::<Result<Vec<BlockWrites>, KorndexError>>(WRITE_QUEUE);
+        thread::scope(|s| {
+            // Reader: block and undo data from disk, in the given order, up to
+            // `prefetch_blocks` ahead of the indexer while it hashes and the
+            // writer commits. Windows of blocks are read in parallel unless
+            // reads are sequential.
+            let window = match options.sequential_reads {
+                true => 1,
+                false => READ_WINDOW,
+            };
+            s.spawn(move || {
+                for window in block_indices.chunks(window) {
+                    if shutdown::requested() {
+                        break;
+                    }
+                    let raw_blocks: Vec<_> = window
+                        .par_iter()
+                        .map(|block_info| read_raw_block(chainman, block_info.block_height))
+                        .collect();
+                    for raw_block in raw_blocks {
+                        let failed = raw_block.is_err();
+                        if raw_blocks_tx.send(raw_block).is_err() || failed {
+                            return;
+                        }
+                    }
+                }
+            });
+            // Indexer: deserialize and index each batch's blocks in parallel
+            s.spawn(move || {
+                let mut write_ratio = INITIAL_WRITE_RATIO;
+                loop {
+                    let mut chunk = Vec::new();
+                    let (mut bytes, mut transactions, mut raw_mem) = (0, 0, 0);
+                    for raw_block in raw_blocks.iter() {
+                        // Blocks read before a failed one are dropped with it,
+                        // the build resumes from the last committed batch
+                        let raw_block = match raw_block {
+                            Ok(raw_block) => raw_block,
+                            Err(e) => {
+                                let _ = batches_tx.send(Err(e));
+                                return;
+                            }
+                        };
+                        bytes += raw_block.data.len();
+                        transactions += raw_block.n_tx;
+                        raw_mem += raw_block.mem_size();
+                        chunk.push(raw_block);
+                        let mem = (raw_mem as f64 * (1.0 + write_ratio)) as usize;
+                        if options.batch_full(chunk.len(), bytes, transactions, mem) {
+                            break;
+                        }
+                    }
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    let blocks: Result<Vec<BlockWrites>, KorndexError> = chunk
+                        .into_par_iter()
+                        .map(|raw_block| {
+                            index_block(raw_block, first_position, indexes, value_buckets)
+                        })
+                        .collect();
+                    if let Ok(ref blocks) = blocks {
+                        let write_mem: usize = blocks.iter().map(BlockWrites::mem_size).sum();
+                        write_ratio = write_mem as f64 / raw_mem.max(1) as f64;
+                    }
+                    if batches_tx.send(blocks).is_err() {
+                        break;
+                    }
+                }
+            });
+            // Writer: the only thread writing to the store. Returning drops
+            // the receiver, which stops the other stages.
+            let writer = thread::Builder::new()
+                .name("korndex-writer".to_owned())
+                .spawn_scoped(s, move || {
+                    let mut indexed = 0;
+                    for blocks in batches.iter() {
+                        let blocks = blocks?;
+                        let n_blocks = blocks.len();
+                        // Pause rather than fail the commit on a full disk
+                        let batch_bytes = blocks.iter().map(index_growth).sum();
+                        let remaining = block_indices.len() - indexed - n_blocks;
+                        if !space.wait_for(batch_bytes, indexed, remaining)? {
+                            break;
+                        }
+                        let transactions: usize =
+                            blocks.iter().map(|block| block.transactions).sum();
+                        let queue_depth = batches.len();
+                        let _span = tracing::info_span!(
+                            "commit_batch",
+                            first_height = blocks[0].block_height,
+                            last_height = blocks[n_blocks - 1].block_height,
+                            blocks = n_blocks,
+                            transactions,
+                            queue_depth,
+                        )
+                        .entered();
+                        let height = commit(blocks)?;
+                        indexed += n_blocks;
+                        progress.batch_committed(
+                            store,
+                            height,
+                            n_blocks,
+                            transactions,
+                            queue_depth,
+                        );
+                    }
+                    Ok::<_, KorndexError>(())
+                })?;
+            writer.join().expect("writer thread panicked")
+        })?;
+        store.commit()
+    }
+
+    /// Keeps the index at the kernel's tip, re-running the incremental build
+    /// whenever the kernel reports a new tip or `poll_interval` elapses.
+    pub fn follow(
+        &self,
+        block_tips: &Receiver<()>,
+        poll_interval: Duration,
+    ) -> Result<(), KorndexError> {
+        tracing::info!("Following the chain tip");
+        loop {
+            match block_tips.recv_timeout(poll_interval) {
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            // Several tips may have been connected while we were busy
+            while block_tips.try_recv().is_ok() {}
+            self.build()?;
+            if shutdown::requested() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Indexes a block announced by the node rather than read through the
+    /// kernel, resolving the outputs it spends from the UTXO index. A block
+    /// forking off the indexed chain first disconnects the indexed blocks above
+    /// the fork point. Blocks that are already indexed are ignored. An empty
+    /// index only accepts the genesis block.
+    pub fn connect_block(&self, block: &bitcoin::Block) -> Result<(), KorndexError> {
+        let store = self.store;
+        let _guard = shutdown::BuildGuard::enter();
+        let hash = block.block_hash();
+        let _span = tracing::info_span!("connect_block", %hash).entered();
+        if headers::read_height(store, &hash)?.is_some() {
+            return Ok(());
+        }
+        if let Some(end_height) = meta::read_end_height(store)? {
+            return Err(KorndexError::Config(format!(
+                "The index was built to end at height {}, it cannot follow the tip",
+                end_height
+            )));
+        }
+        // The outputs the block spends are resolved through the UTXO index
+        let indexes = self.indexes()?;
+        indexes.require(IndexKind::Utxo)?;
+        let prev_hash = block.header.prev_blockhash;
+        let Some(best) = meta::read_best_block(store)? else {
+            if prev_hash != BlockHash::all_zeros() {
+                return Err(KorndexError::Config(
+                    "The index is empty, build it before following the node".to_owned(),
+                ));
+            }
+            return self.commit_connected(block, 0, indexes);
+        };
+        let Some(prev_height) = headers::read_height(store, &prev_hash)? else {
+            return Err(KorndexError::Config(format!(
+                "Block {} does not connect to the index, catch up with korndex build",
+                hash
+            )));
+        };
+
+        if prev_height < best.height {
+            tracing::info!(
+                "Block {} forks off at height {}, disconnecting the blocks above",
+                hash,
+                prev_height
+            );
+            let mut batch = Batch::default();
+            for height in (prev_height + 1..=best.height).rev() {
+                let record = undo::read_undo(store, height)?.ok_or_else(|| {
+                    KorndexError::Corrupt(format!(
+                        "No undo record for height {}, the index needs to be rebuilt",
+                        height
+                    ))
+                })?;
+                disconnect_block(&mut batch, height, record, indexes);
+            }
+            let fork = meta::BestBlock {
+                height: prev_height,
+                hash: prev_hash.to_byte_array(),
+            };
+            meta::write_best_block(&mut batch, &fork)?;
+            store.put_batch(&batch)?;
+        }
+
+        self.commit_connected(block, prev_height + 1, indexes)
+    }
+
+    /// Indexes `block` at `block_height` on top of the indexed chain.
+    fn commit_connected(
+        &self,
+        block: &bitcoin::Block,
+        block_height: i32,
+        indexes: IndexSet,
+    ) -> Result<(), KorndexError> {
+        let store = self.store;
+        let hash = block.block_hash();
+        let raw_block = RawBlock {
+            block_height,
+            n_tx: block.txdata.len(),
+            data: serialize(block),
+            spent_outputs: spent_outputs(store, block)?,
+        };
+        let first_position = if self.options.skip_coinbase { 1 } else { 0 };
+        let value_buckets = self.value_buckets(indexes)?;
+        let dup_policy = self.dup_policy()?;
+        let writes = index_block(raw_block, first_position, indexes, &value_buckets)?;
+        commit_blocks(
+            store,
+            vec![writes],
+            indexes,
+            dup_policy,
+            block_height,
+            block_height,
+        )?;
+        store.commit()?;
+        tracing::info!("Indexed block {} at height {}", hash, block_height);
+        Ok(())
+    }
+}
+
+/// Bytes a B-tree store spends on an entry beyond its key and value, for node
+/// headers and partly filled pages, roughly.
+const ENTRY_OVERHEAD: u64 = 16;
+
+/// Room left in the suggested map size above the estimated index size.
+const MAP_HEADROOM: f64 = 1.25;
+
+/// The cost of a build extrapolated from a sample of the blocks it would
+/// index.
+#[derive(Debug, Clone)]
+pub struct BuildEstimate {
+    /// First and last height the build would index, `None` if there is
+    /// nothing to index
+    pub heights: Option<(i32, i32)>,
+    /// Blocks read and indexed for the estimate
+    pub samples: usize,
+    /// Block data the build would read, in bytes
+    pub block_bytes: u64,
+    pub transactions: u64,
+    /// Bytes the build would add to the index, undo records included
+    pub index_bytes: u64,
+    /// Bytes the index already holds
+    pub existing_bytes: u64,
+    /// Time the build would take on this machine
+    pub duration: Duration,
+    /// LMDB map size in bytes that holds the index after the build
+    pub map_size: u64,
+}
+
+/// Estimates a build of `store`, or of a new index if `None`, with `options`
+/// from `samples` blocks spread evenly over the heights it would index. The
+/// samples are read and indexed like in a build, and their entries written to
+/// a scratch index with the selected backend to time the writes. Nothing is
+/// written to `store`.
+pub fn estimate_build(
+    chainman: &ChainstateManager,
+    store: Option<&dyn KvStore>,
+    backend: Backend,
+    network: Network,
+    options: &IndexerOptions,
+    samples: usize,
+) -> Result<BuildEstimate, KorndexError> {
+    let best_block = store.map(meta::read_best_block).transpose()?.flatten();
+    let indexes = match store.map(meta::read_indexes).transpose()?.flatten() {
+        Some(built) => built,
+        None if best_block.is_some() => IndexSet::ALL,
+        None => options.indexes.unwrap_or(IndexSet::ALL),
+    };
+    let value_buckets = match store.map(meta::read_value_buckets).transpose()?.flatten() {
+        Some(recorded) => recorded,
+        None => options
+            .value_buckets
+            .clone()
+            .unwrap_or_else(|| analytics::DEFAULT_VALUE_BUCKETS.to_vec()),
+    };
+    let tip_height = match chainman.get_block_index_tip() {
+        Ok(tip) => tip.info()?.height,
+        Err(_) => -1,
+    };
+    let recorded_start = store.map(meta::read_start_height).transpose()?.flatten();
+    let start_height = match (best_block, recorded_start) {
+        (Some(best), _) => best.height + 1,
+        (None, Some(start_height)) => start_height,
+        (None, None) if tip_height < 0 => 0,
+        (None, None) => options
+            .start_height
+            .unwrap_or(0)
+            .max(kernel::first_stored_height(chainman, tip_height)?),
+    };
+    let end_height = match store.map(meta::read_end_height).transpose()?.flatten() {
+        Some(end_height) => Some(end_height),
+        None if best_block.is_none() => options.end_height,
+        None => None,
+    };
+    let last_height = end_height.map_or(tip_height, |end| end.min(tip_height));
+    let existing_bytes = match store {
+        Some(store) => diskspace::index_size(store)?,
+        None => 0,
+    };
+    let mut estimate = BuildEstimate {
+        heights: None,
+        samples: 0,
+        block_bytes: 0,
+        transactions: 0,
+        index_bytes: 0,
+        existing_bytes,
+        duration: Duration::ZERO,
+        map_size: (existing_bytes as f64 * MAP_HEADROOM) as u64,
+    };
+    if last_height < start_height {
+        return Ok(estimate);
+    }
+
+    let count = (last_height - start_height + 1) as usize;
+    let first_position = if options.skip_coinbase { 1 } else { 0 };
+    let scratch = ScratchIndex::create(backend, network)?;
+    scratch.kv().set_durability(options.durability)?;
+    let (mut indexing, mut writing) = (Duration::ZERO, Duration::ZERO);
+    for offset in spread(count, samples) {
+        if shutdown::requested() {
+            return Err(KorndexError::Config(
+                "Interrupted before the estimate was done".to_owned(),
+            ));
+        }
+        let block_height = start_height + offset as i32;
+        estimate.samples += 1;
+        let started = Instant::now();
+        let raw_block = read_raw_block(chainman, block_height)?;
+        estimate.block_bytes += raw_block.data.len() as u64;
+        estimate.transactions += raw_block.n_tx as u64;
+        let writes = index_block(raw_block, first_position, indexes, &value_buckets)?;
+        indexing += started.elapsed();
+
+        estimate.index_bytes += index_growth(&writes);
+        let mut batch = Batch::default();
+        for (table, key, value) in writes.puts {
+            batch.put(table, key, value);
+        }
+        let started = Instant::now();
+        scratch.kv().put_batch(&batch)?;
+        writing += started.elapsed();
+    }
+    let started = Instant::now();
+    scratch.kv().commit()?;
+    writing += started.elapsed();
+
+    // Reading and indexing run in parallel while a single writer commits, so
+    // the slower of the two sets the pace
+    let scale = count as f64 / estimate.samples as f64;
+    let threads = rayon::current_num_threads() as f64;
+    estimate.heights = Some((start_height, last_height));
+    estimate.block_bytes = (estimate.block_bytes as f64 * scale) as u64;
+    estimate.transactions = (estimate.transactions as f64 * scale) as u64;
+    estimate.index_bytes = (estimate.index_bytes as f64 * scale) as u64;
+    estimate.duration = indexing
+        .mul_f64(scale / threads)
+        .max(writing.mul_f64(scale));
+    estimate.map_size = ((existing_bytes + estimate.index_bytes) as f64 * MAP_HEADROOM) as u64;
+    Ok(estimate)
+}
+
+/// Offsets of up to `samples` of `count` elements spread evenly over them, the
+/// middle of each of `samples` equal stretches.
+fn spread(count: usize, samples: usize) -> impl Iterator<Item = usize> {
+    let samples = samples.clamp(1, count.max(1));
+    (0..samples.min(count)).map(move |sample| (2 * sample + 1) * count / (2 * samples))
+}
+
+/// Bytes `block` adds to the index. Every put goes into its table and into
+/// the block's undo record, which keeps the values of dup-sorted tables too.
+/// The UTXOs a block spends move from the UTXO table to its undo record.
+fn index_growth(block: &BlockWrites) -> u64 {
+    block
+        .puts
+        .iter()
+        .map(|(table, key, value)| {
+            let undo_value = if table.is_dup_sort() { value.len() } else { 0 };
+            (2 * key.len() + value.len() + undo_value) as u64 + 2 * ENTRY_OVERHEAD
+        })
+        .sum()
+}
+
+/// Bytes indexing the blocks at `heights` adds to the index, extrapolated
+/// from `samples` of them.
+fn project_growth(
+    chainman: &ChainstateManager,
+    heights: &[i32],
+    samples: usize,
+    first_position: usize,
+    indexes: IndexSet,
+    value_buckets: &[u64],
+) -> Result<u64, KorndexError> {
+    let (mut grown, mut sampled) = (0, 0);
+    for offset in spread(heights.len(), samples) {
+        let raw_block = read_raw_block(chainman, heights[offset])?;
+        grown += index_growth(&index_block(
+            raw_block,
+            first_position,
+            indexes,
+            value_buckets,
+        )?);
+        sampled += 1;
+    }
+    Ok(match sampled {
+        0 => 0,
+        _ => (grown as f64 * heights.len() as f64 / sampled as f64) as u64,
+    })
+}
+
+/// Writes a batch of consecutive blocks with their undo records, extends the
+/// filter header chain over them if filters are built and checkpoints the
+/// build.
+fn commit_blocks(
+    store: &dyn KvStore,
+    mut blocks: Vec<BlockWrites>,
+    indexes: IndexSet,
+    dup_policy: DupPolicy,
+    lowest_height: i32,
+    target_height: i32,
+) -> Result<meta::BestBlock, KorndexError> {
+    resolve_duplicates(store, &mut blocks, dup_policy)?;
+    let first_height = blocks[0].block_height;
+    let last = blocks.last().unwrap();
+    let best = meta::BestBlock {
+        height: last.block_height,
+        hash: last.hash,
+    };
+
+    let mut batch = Batch::default();
+    write_blocks(store, &mut batch, blocks, false)?;
+    store.put_batch(&batch)?;
+    if indexes.contains(IndexKind::Filters) {
+        filters::connect_filter_headers(store, first_height, best.height)?;
+    }
+
+    // Only record the new best block once the batch has been committed
+    let mut batch = Batch::default();
+    headers::write_median_times(store, &mut batch, first_height, best.height)?;
+    meta::write_best_block(&mut batch, &best)?;
+    meta::touch_build_times(store, &mut batch)?;
+    if best.height < target_height {
+        let checkpoint = meta::Checkpoint {
+            lowest_height,
+            highest_height: best.height,
+            tip_hash: best.hash,
+            target_height,
+        };
+        meta::write_checkpoint(&mut batch, &checkpoint)?;
+    } else {
+        meta::delete_checkpoint(&mut batch);
+    }
+    store.put_batch(&batch)?;
+    Ok(best)
+}
+
+/// Adds the writes of `blocks` to `batch`, each with the undo record that
+/// disconnects it again. With `extend_undo` the blocks are already indexed
+/// and their existing undo records are extended instead.
+fn write_blocks(
+    store: &dyn KvStore,
+    batch: &mut Batch,
+    blocks: Vec<BlockWrites>,
+    extend_undo: bool,
+) -> Result<(), KorndexError> {
+    // The spent UTXOs go into the undo records. Those created earlier in the
+    // batch are not in the store yet and are picked up as they are written.
+    // Coins below the start of a partial index are not there at all.
+    let keys: Vec<&[u8]> = blocks
+        .iter()
+        .flat_map(|block| &block.spent_utxos)
+        .map(|key| &key[..])
+        .collect();
+    let mut utxos: HashMap<Bytes, Bytes> = keys
+        .iter()
+        .zip(store.get_many(Table::Utxo, &keys)?)
+        .filter_map(|(key, value)| Some((Bytes::from(*key), value?.into())))
+        .collect();
+
+    // Every put and UTXO deletion, plus an undo record per block
+    let ops: usize = blocks
+        .iter()
+        .map(|block| block.puts.len() + block.spent_utxos.len() + 1)
+        .sum();
+    batch.ops.reserve(ops);
+    for block in blocks {
+        let mut entries = Vec::with_capacity(block.puts.len());
+        let mut deleted = Vec::with_capacity(block.spent_utxos.len());
+        for (table, key, value) in block.puts {
+            // A repeated txid's entry is replaced by the one it replaced
+            // instead of deleted
+            let replaced = match block.replaced_txid {
+                Some(ref previous)
+                    if table == Table::TxIndex
+                        && block.coinbase_txid.is_some_and(|txid| key[..] == txid) =>
+                {
+                    Some(previous.clone())
+                }
+                _ => None,
+            };
+            match replaced {
+                Some(previous) => deleted.push(undo::UndoEntry {
+                    table,
+                    key: key.clone(),
+                    value: Some(previous),
+                }),
+                None => entries.push(undo::UndoEntry {
+                    table,
+                    key: key.clone(),
+                    value: table.is_dup_sort().then(|| value.clone()),
+                }),
+            }
+            if table == Table::Utxo {
+                utxos.insert(key.clone(), value.clone());
+            }
+            batch.put(table, key, value);
+        }
+        for key in block.spent_utxos {
+            if let Some(value) = utxos.remove(&key[..]) {
+                deleted.push(undo::UndoEntry {
+                    table: Table::Utxo,
+                    key: key.into(),
+                    value: Some(value),
+                });
+            }
+            batch.delete(Table::Utxo, key, None);
+        }
+        let record = match extend_undo {
+            true => {
+                let mut record = undo::read_undo(store, block.block_height)?
+                    .filter(|record| record.hash == block.hash)
+                    .ok_or_else(|| {
+                        KorndexError::Config(format!(
+                            "Block {} is not the indexed one, catch up with korndex build first",
+                            block.block_height
+                        ))
+                    })?;
+                record.entries.extend(entries);
+                record.deleted.extend(deleted);
+                record
+            }
+            false => undo::UndoRecord {
+                hash: block.hash,
+                entries,
+                deleted,
+            },
+        };
+        undo::write_undo(batch, block.block_height, &record)?;
+    }
+    Ok(())
+}
+
+/// Resolves the txid index entries of coinbase transactions in `blocks` whose
+/// txid is already indexed or repeated within `blocks`. The entry of the
+/// location `dup_policy` keeps replaces the other one, which is restored if
+/// its block is disconnected, and [`DupPolicy::All`] also writes both
+/// locations to the duplicates table. `blocks` are consecutive and ascending,
+/// and the indexed blocks lie below them, or above them if `backfill`.
+fn resolve_duplicates(
+    store: &dyn KvStore,
+    blocks: &mut [BlockWrites],
+    dup_policy: DupPolicy,
+) -> Result<(), KorndexError> {
+    let txids: Vec<[u8; 32]> = blocks
+        .iter()
+        .filter_map(|block| block.coinbase_txid)
+        .collect();
+    let keys: Vec<&[u8]> = txids.iter().map(|txid| &txid[..]).collect();
+    // The entry each txid has so far, in the index or earlier in `blocks`
+    let mut located: HashMap<[u8; 32], Bytes> = txids
+        .iter()
+        .zip(store.get_many(Table::TxIndex, &keys)?)
+        .filter_map(|(txid, value)| Some((*txid, value?.into())))
+        .collect();
+    for block in blocks.iter_mut() {
+        let Some(txid) = block.coinbase_txid else {
+            continue;
+        };
+        let Some(position) = block
+            .puts
+            .iter()
+            .position(|(table, key, _)| *table == Table::TxIndex && key[..] == txid)
+        else {
+            continue;
+        };
+        let value = block.puts[position].2.clone();
+        let Some(previous) = located.get(&txid).cloned() else {
+            located.insert(txid, value);
+            continue;
+        };
+        let previous_height = TxIndexEntry::decode(&previous)?.block_height;
+        // Written before by a build that was interrupted
+        if previous_height == block.block_height {
+            continue;
+        }
+        tracing::info!(
+            "Coinbase txid {} at height {} repeats the one at height {}, keeping the {} location",
+            Txid::from_byte_array(txid),
+            block.block_height,
+            previous_height,
+            dup_policy.name()
+        );
+        if dup_policy == DupPolicy::All {
+            for (height, entry) in [(previous_height, &previous), (block.block_height, &value)] {
+                let key = txindex::duplicate_key(&txid, height);
+                block
+                    .puts
+                    .push((Table::Duplicates, key.into(), entry.clone()));
+            }
+        }
+        // Backfilled blocks lie below the indexed location
+        let later = block.block_height > previous_height;
+        let keep = match dup_policy {
+            DupPolicy::Earliest => !later,
+            DupPolicy::Latest | DupPolicy::All => later,
+        };
+        if keep {
+            block.replaced_txid = Some(previous);
+            located.insert(txid, value);
+        } else {
+            block.puts.remove(position);
+        }
+    }
+    Ok(())
+}
+
+/// Keys `blocks` write to `table` that already have an entry in `lookup`.
+fn existing_keys(
+    store: &dyn KvStore,
+    blocks: &[BlockWrites],
+    table: Table,
+    lookup: Table,
+) -> Result<HashSet<Vec<u8>>, KorndexError> {
+    let keys: Vec<&[u8]> = blocks
+        .iter()
+        .flat_map(|block| &block.puts)
+        .filter(|(t, ..)| *t == table)
+        .map(|(_, key, _)| &key[..])
+        .collect();
+    Ok(keys
+        .iter()
+        .zip(store.get_many(lookup, &keys)?)
+        .filter(|(_, value)| value.is_some())
+        .map(|(key, _)| key.to_vec())
+        .collect())
+}
+
+/// Writes the entries of the indexes `added` to an existing index for a batch
+/// of consecutive indexed blocks and extends their filter header chain if
+/// filters were added, returning the last height.
+fn commit_added(
+    store: &dyn KvStore,
+    mut blocks: Vec<BlockWrites>,
+    added: IndexSet,
+    dup_policy: DupPolicy,
+) -> Result<i32, KorndexError> {
+    resolve_duplicates(store, &mut blocks, dup_policy)?;
+    let first_height = blocks[0].block_height;
+    let last_height = blocks.last().unwrap().block_height;
+    // Headers, heights and txid lists are written with every block, and the
+    // indexed blocks have them already
+    let tables: Vec<Table> = added.iter().flat_map(IndexKind::tables).copied().collect();
+    for block in blocks.iter_mut() {
+        block.puts.retain(|(table, ..)| tables.contains(table));
+    }
+    let mut batch = Batch::default();
+    write_blocks(store, &mut batch, blocks, true)?;
+    meta::touch_build_times(store, &mut batch)?;
+    store.put_batch(&batch)?;
+    if added.contains(IndexKind::Filters) {
+        filters::connect_filter_headers(store, first_height, last_height)?;
+    }
+    Ok(last_height)
+}
+
+/// Height a fresh index starts from: `requested` or genesis, raised to the
+/// lowest block a pruned node still stores. Anything above genesis is recorded
+/// as the index's start height.
+fn first_indexed_height(
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    requested: Option<i32>,
+) -> Result<i32, KorndexError> {
+    if let Some(start_height) = meta::read_start_height(store)? {
+        return Ok(start_height);
+    }
+    let Ok(tip) = chainman.get_block_index_tip() else {
+        return Ok(0);
+    };
+    let first_stored = kernel::first_stored_height(chainman, tip.info()?.height)?;
+    let requested = requested.unwrap_or(0);
+    if first_stored > requested {
+        tracing::info!(
+            "Node is pruned, indexing the blocks it stores from height {}",
+            first_stored
+        );
+    }
+    let start_height = requested.max(first_stored);
+    if start_height > 0 {
+        let mut batch = Batch::default();
+        meta::write_start_height(&mut batch, start_height)?;
+        store.put_batch(&batch)?;
+    }
+    Ok(start_height)
+}
+
+/// The end height of the index: the recorded one, or for a new index the
+/// requested one, which is recorded.
+fn end_height(
+    store: &dyn KvStore,
+    new_index: bool,
+    requested: Option<i32>,
+) -> Result<Option<i32>, KorndexError> {
+    let recorded = meta::read_end_height(store)?;
+    match (recorded, requested) {
+        (Some(recorded), Some(requested)) if requested != recorded => {
+            tracing::warn!(
+                "Ignoring --to-height, the index already ends at height {}",
+                recorded
+            )
+        }
+        (None, Some(requested)) if new_index => {
+            let mut batch = Batch::default();
+            meta::write_end_height(&mut batch, requested)?;
+            store.put_batch(&batch)?;
+            return Ok(Some(requested));
+        }
+        (None, Some(_)) => tracing::warn!("Ignoring --to-height, the index already exists"),
+        _ => {}
+    }
+    Ok(recorded)
+}
+
+/// Writes a batch of consecutive blocks just below the index's start height
+/// with their undo records and lowers the start height to the first of them,
+/// returning it. Once the index reaches genesis its filter header chain, if
+/// built, is extended up to `best_height`.
+fn commit_backfill(
+    store: &dyn KvStore,
+    mut blocks: Vec<BlockWrites>,
+    indexes: IndexSet,
+    dup_policy: DupPolicy,
+    best_height: i32,
+) -> Result<i32, KorndexError> {
+    let first_height = blocks[0].block_height;
+    let last_height = blocks.last().unwrap().block_height;
+
+    // Outputs spent above the start height must stay out of the UTXO set
+    resolve_duplicates(store, &mut blocks, dup_policy)?;
+    let spent = existing_keys(store, &blocks, Table::Utxo, Table::Spent)?;
+    for block in blocks.iter_mut() {
+        block
+            .puts
+            .retain(|(table, key, _)| *table != Table::Utxo || !spent.contains(&key[..]));
+    }
+
+    let mut batch = Batch::default();
+    write_blocks(store, &mut batch, blocks, false)?;
+    if first_height == 0 {
+        meta::delete_start_height(&mut batch);
+    } else {
+        meta::write_start_height(&mut batch, first_height)?;
+    }
+    store.put_batch(&batch)?;
+    if first_height == 0 && indexes.contains(IndexKind::Filters) {
+        filters::connect_filter_headers(store, 0, best_height)?;
+    }
+    // The median times of the next blocks up were computed without the
+    // timestamps of these ones
+    let recompute_to = (last_height + headers::MEDIAN_TIME_SPAN - 1).min(best_height);
+    let mut batch = Batch::default();
+    headers::write_median_times(store, &mut batch, first_height, recompute_to)?;
+    meta::touch_build_times(store, &mut batch)?;
+    store.put_batch(&batch)?;
+    Ok(first_height)
+}
+
+/// Reads the serialized block at `block_height` and the outputs it spends.
+fn read_raw_block(
+    chainman: &ChainstateManager,
+    block_height: i32,
+) -> Result<RawBlock, KorndexError> {
+    let block_index = chainman.get_block_index_by_height(block_height)?;
+    let data = kernel::read_block_data(chainman, &block_index, block_height)?;
+    // The transaction count follows the 80-byte header
+    let count = data.get(80..).ok_or_else(|| {
+        KorndexError::Corrupt(format!("Block at height {} is truncated", block_height))
+    })?;
+    let (n_tx, _): (VarInt, usize) = deserialize_partial(count)?;
+    let n_tx = n_tx.0 as usize;
+    let spent_outputs = kernel::spent_outputs(chainman, &block_index, n_tx)?;
+    Ok(RawBlock {
+        block_height,
+        n_tx,
+        data,
+        spent_outputs,
+    })
+}
+
+/// Outputs spent by each non-coinbase transaction of `block`, in the shape
+/// of the kernel's undo data, from the UTXO index and the block's own earlier
+/// outputs.
+fn spent_outputs(
+    store: &dyn KvStore,
+    block: &bitcoin::Block,
+) -> Result<Vec<Vec<TxOut>>, KorndexError> {
+    let keys: Vec<[u8; 36]> = block
+        .txdata
+        .iter()
+        .skip(1)
+        .flat_map(|tx| &tx.input)
+        .map(|input| spent::outpoint_key(&input.previous_output))
+        .collect();
+    let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+    let mut stored = store.get_many(Table::Utxo, &key_refs)?.into_iter();
+
+    let mut created: HashMap<OutPoint, TxOut> = HashMap::new();
+    let mut spent_outputs = Vec::with_capacity(block.txdata.len().saturating_sub(1));
+    for (position, tx) in block.txdata.iter().enumerate() {
+        if position > 0 {
+            let mut prevouts = Vec::with_capacity(tx.input.len());
+            for input in tx.input.iter() {
+                let stored = stored.next().flatten();
+                let prevout = match created.remove(&input.previous_output) {
+                    Some(prevout) => prevout,
+                    None => {
+                        let data = stored.ok_or_else(|| {
+                            KorndexError::Corrupt(format!(
+                                "Output {} spent by block {} is not in the UTXO index",
+                                input.previous_output,
+                                block.block_hash()
+                            ))
+                        })?;
+                        let utxo = UtxoEntry::decode(&data)?;
+                        TxOut {
+                            value: utxo.value,
+                            script_pubkey: utxo.script_pubkey,
+                        }
+                    }
+                };
+                prevouts.push(prevout);
+            }
+            spent_outputs.push(prevouts);
+        }
+        let txid = tx.compute_txid();
+        for (vout, output) in tx.output.iter().enumerate() {
+            created.insert(OutPoint::new(txid, vout as u32), output.clone());
+        }
+    }
+    Ok(spent_outputs)
+}
+
+/// Scans a block and computes the entries it adds to each of `indexes`,
+/// deserializing it only for the indexes that need its scripts.
+fn index_block(
+    raw_block: RawBlock,
+    first_position: usize,
+    indexes: IndexSet,
+    value_buckets: &[u64],
+) -> Result<BlockWrites, KorndexError> {
+    let RawBlock {
+        block_height,
+        data,
+        spent_outputs,
+        ..
+    } = raw_block;
+    let _span = tracing::debug_span!("index_block", height = block_height).entered();
+    let scanned = blockscan::scan_block(&data)?;
+    if scanned.is_empty() {
+        return Err(KorndexError::Corrupt(format!(
+            "Block at height {} has no transactions, not even a coinbase",
+            block_height
+        )));
+    }
+    let txids: Vec<[u8; 32]> = scanned.iter().map(|tx| tx.txid.to_byte_array()).collect();
+    let header: Header = deserialize(&data[..80])?;
+    let hash = header.block_hash().to_byte_array();
+    // The coinbase has no entry in the undo data
+    let prevouts = |position: usize| {
+        position
+            .checked_sub(1)
+            .and_then(|i| spent_outputs.get(i))
+            .map_or(&[][..], |prevouts| &prevouts[..])
+    };
+
+    let mut puts = Vec::new();
+    let mut spent_utxos = Vec::new();
+    if indexes.contains(IndexKind::Txid) {
+        puts.reserve(2 * scanned.len());
+        for (position, tx) in scanned.iter().enumerate().skip(first_position) {
+            let v = TxIndexEntry {
+                position_in_block: position,
+                block_height,
+                fee: tx.fee(prevouts(position)),
+                vsize: tx.vsize(),
+                byte_range: Some(tx.byte_range()),
+            };
+            let txid = txids[position];
+            puts.push((Table::TxIndex, txid.into(), v.encode()));
+
+            // Non-segwit transactions have wtxid == txid and need no mapping
+            let wtxid = tx.wtxid.to_byte_array();
+            if wtxid != txid {
+                puts.push((Table::Wtxid, wtxid.into(), txid.into()));
+            }
+        }
+    }
+
+    let needs_block = [
+        IndexKind::Address,
+        IndexKind::Spent,
+        IndexKind::Utxo,
+        IndexKind::Filters,
+        IndexKind::Tweaks,
+        IndexKind::ScriptTypes,
+        IndexKind::Graph,
+        IndexKind::Witness,
+        IndexKind::Analytics,
+        IndexKind::Locktime,
+    ]
+    .into_iter()
+    .any(|kind| indexes.contains(kind));
+    if needs_block {
+        let block: bitcoin::Block = deserialize(&data)?;
+        index_scripts(
+            &block,
+            block_height,
+            &txids,
+            &spent_outputs,
+            indexes,
+            &mut puts,
+            &mut spent_utxos,
+        )?;
+        if indexes.contains(IndexKind::Analytics) {
+            puts.push((
+                Table::ValueHistograms,
+                height_key(block_height).into(),
+                ValueHistogram::of_block(&block, value_buckets)
+                    .encode()
+                    .into(),
+            ));
+        }
+    }
+
+    if indexes.contains(IndexKind::Coinbase) {
+        let coinbase = CoinbaseEntry::compute(&data, &scanned, &spent_outputs)?;
+        puts.push((
+            Table::Coinbase,
+            height_key(block_height).into(),
+            coinbase.encode().into(),
+        ));
+    }
+
+    if indexes.contains(IndexKind::BlockStats) {
+        let stats = BlockStats::compute(&data, &scanned, &spent_outputs);
+        puts.push((
+            Table::BlockStats,
+            height_key(block_height).into(),
+            stats.encode().into(),
+        ));
+    }
+
+    puts.push((
+        Table::FilterHeights,
+        hash.into(),
+        height_key(block_height).into(),
+    ));
+    puts.push((
+        Table::Headers,
+        height_key(block_height).into(),
+        Bytes::from(&data[..80]),
+    ));
+    puts.push((
+        Table::BlockTxids,
+        height_key(block_height).into(),
+        txids.concat().into(),
+    ));
+
+    Ok(BlockWrites {
+        block_height,
+        hash,
+        transactions: scanned.len(),
+        puts,
+        coinbase_txid: (indexes.contains(IndexKind::Txid) && first_position == 0).then(|| txids[0]),
+        replaced_txid: None,
+        spent_utxos,
+    })
+}
+
+/// Adds the entries of the indexes among `indexes` that are computed from the
+/// block's scripts, and the outpoints whose UTXO entries it deletes.
+fn index_scripts(
+    block: &bitcoin::Block,
+    block_height: i32,
+    txids: &[[u8; 32]],
+    spent_outputs: &[Vec<TxOut>],
+    indexes: IndexSet,
+    puts: &mut Vec<(Table, Bytes, Bytes)>,
+    spent_utxos: &mut Vec<[u8; 36]>,
+) -> Result<(), KorndexError> {
+    let (address, spent, utxo, graph) = (
+        indexes.contains(IndexKind::Address),
+        indexes.contains(IndexKind::Spent),
+        indexes.contains(IndexKind::Utxo),
+        indexes.contains(IndexKind::Graph),
+    );
+    // Sized up front, as growing by doubling would leave up to half of a large
+    // block's writes unused: a UTXO and a scripthash entry per output, a spent
+    // and a scripthash entry per input, and the per-block entries
+    let inputs: usize = block.txdata.iter().skip(1).map(|tx| tx.input.len()).sum();
+    let outputs: usize = block.txdata.iter().map(|tx| tx.output.len()).sum();
+    puts.reserve(2 * (inputs + outputs) + 5);
+    if utxo {
+        spent_utxos.reserve(inputs);
+    }
+
+    for (position, tx) in block.txdata.iter().enumerate() {
+        // The coinbase has no entry in the undo data
+        let prevouts = position
+            .checked_sub(1)
+            .and_then(|i| spent_outputs.get(i))
+            .map_or(&[][..], |prevouts| &prevouts[..]);
+        for (vout, output) in tx.output.iter().enumerate() {
+            // Provably unspendable outputs never enter the UTXO set
+            if utxo && !output.script_pubkey.is_op_return() {
+                let outpoint = OutPoint::new(Txid::from_byte_array(txids[position]), vout as u32);
+                let entry = UtxoEntry {
+                    block_height,
+                    is_coinbase: tx.is_coinbase(),
+                    value: output.value,
+                    script_pubkey: output.script_pubkey.clone(),
+                };
+                puts.push((
+                    Table::Utxo,
+                    spent::outpoint_key(&outpoint).into(),
+                    entry.encode(),
+                ));
+            }
+
+            if address {
+                let entry = ScriptHashEntry {
+                    block_height,
+                    position_in_block: position as u32,
+                    direction: Direction::Output,
+                    index: vout as u32,
+                };
+                puts.push((
+                    Table::ScriptHash,
+                    scripthash::script_hash(&output.script_pubkey).into(),
+                    entry.encode().into(),
+                ));
+            }
+        }
+
+        if !tx.is_coinbase() {
+            for (vin, input) in tx.input.iter().enumerate() {
+                let key = spent::outpoint_key(&input.previous_output);
+                if spent {
+                    let entry = SpendEntry {
+                        block_height,
+                        position_in_block: position as u32,
+                        input_index: vin as u32,
+                    };
+                    puts.push((Table::Spent, key.into(), entry.encode().into()));
+                }
+                if utxo {
+                    spent_utxos.push(key);
+                }
+            }
+        }
+
+        if graph {
+            let txid = txids[position];
+            let mut parents: Vec<[u8; 32]> = Vec::new();
+            let mut seen = HashSet::new();
+            for input in tx.input.iter().filter(|_| !tx.is_coinbase()) {
+                let parent = input.previous_output.txid.to_byte_array();
+                if seen.insert(parent) {
+                    parents.push(parent);
+                    puts.push((Table::Children, parent.into(), txid.into()));
+                }
+            }
+            // Written for the coinbase too, so every indexed transaction has
+            // an entry
+            puts.push((Table::Parents, txid.into(), parents.concat().into()));
+        }
+
+        if address {
+            for (vin, prevout) in prevouts.iter().enumerate() {
+                let entry = ScriptHashEntry {
+                    block_height,
+                    position_in_block: position as u32,
+                    direction: Direction::Input,
+                    index: vin as u32,
+                };
+                puts.push((
+                    Table::ScriptHash,
+                    scripthash::script_hash(&prevout.script_pubkey).into(),
+                    entry.encode().into(),
+                ));
+            }
+        }
+    }
+
+    if indexes.contains(IndexKind::Filters) {
+        let filter = filters::basic_filter(block, spent_outputs)?;
+        puts.push((
+            Table::Filters,
+            height_key(block_height).into(),
+            filter.content.into(),
+        ));
+    }
+
+    if indexes.contains(IndexKind::ScriptTypes) {
+        puts.push((
+            Table::ScriptTypes,
+            height_key(block_height).into(),
+            ScriptTypeCounts::of_block(block).encode().into(),
+        ));
+    }
+
+    if indexes.contains(IndexKind::Locktime) {
+        puts.push((
+            Table::Locktimes,
+            height_key(block_height).into(),
+            LocktimeCounts::of_block(block).encode().into(),
+        ));
+    }
+
+    if indexes.contains(IndexKind::Tweaks) {
+        let tweaks = silentpayments::block_tweaks(block, spent_outputs);
+        if !tweaks.is_empty() {
+            puts.push((
+                Table::Tweaks,
+                height_key(block_height).into(),
+                tweaks.concat().into(),
+            ));
+        }
+    }
+
+    if indexes.contains(IndexKind::Witness) {
+        let entries = witness::block_entries(block, spent_outputs);
+        if !entries.is_empty() {
+            puts.push((
+                Table::Witness,
+                height_key(block_height).into(),
+                entries
+                    .iter()
+                    .flat_map(|entry| entry.encode())
+                    .collect::<Vec<u8>>()
+                    .into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Disconnects indexed blocks from `best` downwards until the stored block hash
+/// matches the kernel's active chain again, returning the new best block.
+fn rollback(
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    best: meta::BestBlock,
+) -> Result<Option<meta::BestBlock>, KorndexError> {
+    let mut batch = Batch::default();
+    let indexes = meta::built_indexes(store)?;
+    let start_height = meta::read_start_height(store)?.unwrap_or(0);
+    let mut height = best.height;
+    let fork = loop {
+        if height < start_height {
+            break None;
+        }
+        let Some(record) = undo::read_undo(store, height)? else {
+            return Err(KorndexError::Corrupt(format!(
+                "No undo record for height {}, the index needs to be rebuilt",
+                height
+            )));
+        };
+        let active_hash = kernel::block_hash(chainman, height)?.map(|h| h.to_byte_array());
+        if active_hash == Some(record.hash) {
+            break Some(meta::BestBlock {
+                height,
+                hash: record.hash,
+            });
+        }
+        disconnect_block(&mut batch, height, record, indexes);
+        height -= 1;
+    };
+    match fork {
+        Some(ref fork) => meta::write_best_block(&mut batch, fork)?,
+        None => meta::delete_best_block(&mut batch),
+    }
+    store.put_batch(&batch)?;
+    match fork {
+        Some(ref fork) => tracing::info!("Rolled back to fork point at height {}", fork.height),
+        None => tracing::info!("No common block with the active chain, rolled back everything"),
+    }
+    Ok(fork)
+}
+
+/// Adds the writes undoing the block at `height` to `batch`. Entries of
+/// indexes dropped since the block was indexed are not restored.
+fn disconnect_block(batch: &mut Batch, height: i32, record: undo::UndoRecord, indexes: IndexSet) {
+    // Restore what the block deleted before removing what it wrote, as
+    // outputs created and spent in the same block are both
+    for entry in record.deleted.into_iter().rev() {
+        if !indexes.writes(entry.table) {
+            continue;
+        }
+        if let Some(value) = entry.value {
+            batch.put(entry.table, entry.key, value);
+        }
+    }
+    for entry in record.entries.into_iter().rev() {
+        batch.delete(entry.table, entry.key, entry.value);
+    }
+    // Filter headers and median times are computed after the block writes
+    // and have no undo entries of their own
+    batch.delete(Table::FilterHeaders, height_key(height), None);
+    batch.delete(Table::MedianTimes, height_key(height), None);
+    batch.delete(Table::Undo, height_key(height), None);
+}
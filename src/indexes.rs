@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::store::{Batch, KvStore, Table};
+use crate::{meta, KorndexError};
+
+/// An index that can be left out of a build with `--index`. Headers, block
+/// heights by hash, txid lists and undo records are always written.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Transactions by txid and witness transaction id
+    Txid,
+    /// Transactions paying to or spending from a script, for address lookups
+    Address,
+    /// The transaction spending each output
+    Spent,
+    /// Unspent outputs, needed to follow the tip through ZMQ
+    Utxo,
+    /// BIP158 compact block filters and their header chain
+    Filters,
+    /// Silent payments tweaks
+    Tweaks,
+    /// Per-block size, weight, transaction count and fee stats
+    #[value(name = "blockstats")]
+    BlockStats,
+    /// Per-block counts of outputs by script type
+    #[value(name = "scripttypes")]
+    ScriptTypes,
+    /// The parents and children of each transaction, for walking the
+    /// transaction graph
+    Graph,
+    /// Taproot annexes and large witness items, like inscription envelopes
+    Witness,
+    /// Each block's coinbase scriptSig, witness commitment and claimed fees
+    Coinbase,
+    /// Per-block histograms of output values
+    Analytics,
+    /// Per-block counts of lock time, RBF and relative lock time use
+    Locktime,
+}
+
+impl IndexKind {
+    pub const ALL: [IndexKind; 13] = [
+        IndexKind::Txid,
+        IndexKind::Address,
+        IndexKind::Spent,
+        IndexKind::Utxo,
+        IndexKind::Filters,
+        IndexKind::Tweaks,
+        IndexKind::BlockStats,
+        IndexKind::ScriptTypes,
+        IndexKind::Graph,
+        IndexKind::Witness,
+        IndexKind::Coinbase,
+        IndexKind::Analytics,
+        IndexKind::Locktime,
+    ];
+
+    /// Name as given to `--index`.
+    pub fn name(self) -> &'static str {
+        match self {
+            IndexKind::Txid => "txid",
+            IndexKind::Address => "address",
+            IndexKind::Spent => "spent",
+            IndexKind::Utxo => "utxo",
+            IndexKind::Filters => "filters",
+            IndexKind::Tweaks => "tweaks",
+            IndexKind::BlockStats => "blockstats",
+            IndexKind::ScriptTypes => "scripttypes",
+            IndexKind::Graph => "graph",
+            IndexKind::Witness => "witness",
+            IndexKind::Coinbase => "coinbase",
+            IndexKind::Analytics => "analytics",
+            IndexKind::Locktime => "locktime",
+        }
+    }
+
+    /// Tables holding this index.
+    pub fn tables(self) -> &'static [Table] {
+        match self {
+            IndexKind::Txid => &[Table::TxIndex, Table::Wtxid, Table::Duplicates],
+            IndexKind::Address => &[Table::ScriptHash],
+            IndexKind::Spent => &[Table::Spent],
+            IndexKind::Utxo => &[Table::Utxo],
+            IndexKind::Filters => &[Table::Filters, Table::FilterHeaders],
+            IndexKind::Tweaks => &[Table::Tweaks],
+            IndexKind::BlockStats => &[Table::BlockStats],
+            IndexKind::ScriptTypes => &[Table::ScriptTypes],
+            IndexKind::Graph => &[Table::Parents, Table::Children],
+            IndexKind::Witness => &[Table::Witness],
+            IndexKind::Coinbase => &[Table::Coinbase],
+            IndexKind::Analytics => &[Table::ValueHistograms],
+            IndexKind::Locktime => &[Table::Locktimes],
+        }
+    }
+}
+
+/// A set of [`IndexKind`]s, stored as a bitmask.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexSet(u16);
+
+impl IndexSet {
+    pub const ALL: IndexSet = IndexSet((1 << IndexKind::ALL.len()) - 1);
+
+    pub fn contains(self, kind: IndexKind) -> bool {
+        self.0 & 1 << kind as u16 != 0
+    }
+
+    /// Whether every index of `other` is in this set.
+    pub fn contains_all(self, other: IndexSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: IndexSet) -> IndexSet {
+        IndexSet(self.0 | other.0)
+    }
+
+    /// The indexes of this set not in `other`.
+    pub fn difference(self, other: IndexSet) -> IndexSet {
+        IndexSet(self.0 & !other.0)
+    }
+
+    /// Whether an index with these indexes writes to `table`, either for one
+    /// of them or because the table is always written.
+    pub fn writes(self, table: Table) -> bool {
+        IndexKind::ALL
+            .into_iter()
+            .all(|kind| self.contains(kind) || !kind.tables().contains(&table))
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = IndexKind> {
+        IndexKind::ALL
+            .into_iter()
+            .filter(move |kind| self.contains(*kind))
+    }
+
+    /// Fails with a message naming `kind` if it is not in the set, for
+    /// lookups needing an index the build left out.
+    pub fn require(self, kind: IndexKind) -> Result<(), KorndexError> {
+        if self.contains(kind) {
+            return Ok(());
+        }
+        Err(KorndexError::Config(format!(
+            "The {} index was not built, the index has {}",
+            kind.name(),
+            self
+        )))
+    }
+}
+
+impl FromIterator<IndexKind> for IndexSet {
+    fn from_iter<I: IntoIterator<Item = IndexKind>>(kinds: I) -> Self {
+        IndexSet(
+            kinds
+                .into_iter()
+                .fold(0, |set, kind| set | 1 << kind as u16),
+        )
+    }
+}
+
+impl fmt::Display for IndexSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no optional indexes");
+        }
+        let names: Vec<&str> = self.iter().map(IndexKind::name).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Deletes the indexes of `dropped` from the index in `store`, returning those
+/// it had. The freed space is reused by later writes, and only returned to the
+/// file system by [`KvStore::compact`].
+pub fn drop_indexes(store: &dyn KvStore, dropped: IndexSet) -> Result<IndexSet, KorndexError> {
+    if meta::read_checkpoint(store)?.is_some() {
+        return Err(KorndexError::Config(
+            "Found an interrupted build, finish it with --resume before dropping indexes"
+                .to_owned(),
+        ));
+    }
+    let built = meta::built_indexes(store)?;
+    let remaining = built.difference(dropped);
+    let dropping = built.difference(remaining);
+    if dropping.is_empty() {
+        return Ok(dropping);
+    }
+    // Recorded first, so an index left behind by a crash midway does not
+    // claim the half-deleted indexes
+    let mut batch = Batch::default();
+    meta::write_indexes(&mut batch, remaining)?;
+    if dropping.contains(IndexKind::Analytics) {
+        meta::delete_value_buckets(&mut batch);
+    }
+    store.put_batch(&batch)?;
+    for kind in dropping.iter() {
+        for table in kind.tables() {
+            store.clear(*table)?;
+        }
+    }
+    store.commit()?;
+    Ok(dropping)
+}
@@ -0,0 +1,118 @@
+use bitcoin::block::Header;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Address, Network, Script, Transaction, TxOut};
+use serde_json::{json, Value};
+
+use crate::txindex;
+
+/// Renders a confirmed transaction in Esplora's JSON shape. `prevouts` holds
+/// the outputs spent by its inputs and is empty for a coinbase.
+pub fn tx_json(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    network: Network,
+    block_height: i32,
+    header: &Header,
+) -> Value {
+    let status = json!({
+        "confirmed": true,
+        "block_height": block_height,
+        "block_hash": header.block_hash().to_string(),
+        "block_time": header.time,
+    });
+    tx_with_status(tx, prevouts, network, txindex::fee(tx, prevouts), status)
+}
+
+/// Renders a mempool transaction in Esplora's JSON shape, with the fee the
+/// node reported. `prevouts` may be empty if they are unknown.
+pub fn unconfirmed_tx_json(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    network: Network,
+    fee: u64,
+) -> Value {
+    tx_with_status(tx, prevouts, network, fee, json!({ "confirmed": false }))
+}
+
+fn tx_with_status(
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    network: Network,
+    fee: u64,
+    status: Value,
+) -> Value {
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            json!({
+                "txid": input.previous_output.txid.to_string(),
+                "vout": input.previous_output.vout,
+                "prevout": prevouts.get(i).map(|prevout| output_json(prevout, network)),
+                "scriptsig": input.script_sig.as_bytes().to_lower_hex_string(),
+                "scriptsig_asm": input.script_sig.to_asm_string(),
+                "witness": input
+                    .witness
+                    .iter()
+                    .map(|item| item.to_lower_hex_string())
+                    .collect::<Vec<_>>(),
+                "is_coinbase": tx.is_coinbase(),
+                "sequence": input.sequence.0,
+            })
+        })
+        .collect();
+    let vout: Vec<Value> = tx
+        .output
+        .iter()
+        .map(|output| output_json(output, network))
+        .collect();
+
+    json!({
+        "txid": tx.compute_txid().to_string(),
+        "version": tx.version.0,
+        "locktime": tx.lock_time.to_consensus_u32(),
+        "vin": vin,
+        "vout": vout,
+        "size": tx.total_size(),
+        "weight": tx.weight().to_wu(),
+        "fee": fee,
+        "status": status,
+    })
+}
+
+pub fn output_json(output: &TxOut, network: Network) -> Value {
+    let script = &output.script_pubkey;
+    json!({
+        "scriptpubkey": script.as_bytes().to_lower_hex_string(),
+        "scriptpubkey_asm": script.to_asm_string(),
+        "scriptpubkey_type": script_type(script),
+        "scriptpubkey_address": Address::from_script(script, network)
+            .ok()
+            .map(|address| address.to_string()),
+        "value": output.value.to_sat(),
+    })
+}
+
+/// Esplora's names for the standard output types.
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_p2tr() {
+        "v1_p2tr"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_empty() {
+        "empty"
+    } else {
+        "unknown"
+    }
+}
@@ -1,16 +1,20 @@
-use env_logger::Builder;
+use bitcoin::block::Header;
+use bitcoin::consensus::deserialize;
+use bitcoin::{Amount, BlockHash, Network, ScriptBuf, TxOut};
 use libbitcoinkernel_sys::{
-    ChainType, Context, ContextBuilder, KernelError, KernelNotificationInterfaceCallbackHolder,
-    LogCallback, Logger,
+    BlockIndex, BlockManagerOptions, ChainType, ChainstateLoadOptions, ChainstateManager,
+    ChainstateManagerOptions, Context, ContextBuilder, KernelError,
+    KernelNotificationInterfaceCallbackHolder, LogCallback, Logger,
 };
-use log::LevelFilter;
+use std::sync::mpsc::Sender;
 
-pub fn setup_logging() -> Result<Logger, KernelError> {
-    let mut builder = Builder::from_default_env();
-    builder.filter(None, LevelFilter::Info).init();
+use crate::KorndexError;
 
+/// Forwards the kernel's log messages to korndex's log under the
+/// `libbitcoinkernel` target.
+pub fn setup_logging() -> Result<Logger, KernelError> {
     let callback = |message: &str| {
-        log::info!(
+        tracing::info!(
             target: "libbitcoinkernel", 
             "{}", message.strip_suffix("\r\n").or_else(|| message.strip_suffix('\n')).unwrap_or(message));
     };
@@ -18,19 +22,205 @@ pub fn setup_logging() -> Result<Logger, KernelError> {
     Logger::new(LogCallback::new(callback))
 }
 
-pub fn create_context(network: ChainType) -> Context {
-    ContextBuilder::new()
-        .chain_type(network)
-        .unwrap()
+/// The kernel chain type and network named `name`, one of mainnet, testnet,
+/// regtest or signet.
+pub fn network(name: &str) -> Result<(ChainType, Network), KorndexError> {
+    match name.to_lowercase().as_str() {
+        "mainnet" => Ok((ChainType::MAINNET, Network::Bitcoin)),
+        "testnet" => Ok((ChainType::TESTNET, Network::Testnet)),
+        "regtest" => Ok((ChainType::REGTEST, Network::Regtest)),
+        "signet" => Ok((ChainType::SIGNET, Network::Signet)),
+        _ => Err(KorndexError::Config(format!(
+            "Invalid network type: {}",
+            name
+        ))),
+    }
+}
+
+/// Creates the kernel context. Every time the kernel connects a new tip a
+/// message is sent on `block_tips`, if given.
+pub fn create_context(
+    network: ChainType,
+    block_tips: Option<Sender<()>>,
+) -> Result<Context, KorndexError> {
+    Ok(ContextBuilder::new()
+        .chain_type(network)?
         .kn_callbacks(Box::new(KernelNotificationInterfaceCallbackHolder {
-            kn_block_tip: Box::new(|_state, _block_index| {}),
+            kn_block_tip: Box::new(move |_state, _block_index| {
+                if let Some(ref block_tips) = block_tips {
+                    let _ = block_tips.send(());
+                }
+            }),
             kn_header_tip: Box::new(|_state, _height, _timestamp, _presync| {}),
             kn_progress: Box::new(|_title, _progress, _resume_possible| {}),
             kn_warning: Box::new(|_warning| {}),
             kn_flush_error: Box::new(|_message| {}),
             kn_fatal_error: Box::new(|_message| {}),
-        }))
-        .unwrap()
-        .build()
-        .unwrap()
+        }))?
+        .build()?)
+}
+
+/// Loads the chainstate of the node with data directory `data_dir`.
+pub fn load_chainman(context: &Context, data_dir: &str) -> Result<ChainstateManager, KorndexError> {
+    let blocks_dir = data_dir.to_owned() + "/blocks";
+    let chainman = ChainstateManager::new(
+        ChainstateManagerOptions::new(context, data_dir)?,
+        BlockManagerOptions::new(context, &blocks_dir)?,
+        context,
+    )?;
+    chainman.load_chainstate(ChainstateLoadOptions::new())?;
+    Ok(chainman)
+}
+
+/// Imports the node's block files into the chainstate, unless `force` is
+/// false and its block index is already loaded past genesis. Importing walks
+/// every block file, which routine runs against a synced node do not need.
+pub fn import_blocks(chainman: &ChainstateManager, force: bool) -> Result<(), KorndexError> {
+    let tip_height = match chainman.get_block_index_tip() {
+        Ok(tip) => tip.info()?.height,
+        Err(_) => 0,
+    };
+    if !force && tip_height > 0 {
+        tracing::debug!(
+            "Block index loaded up to height {}, skipping block import",
+            tip_height
+        );
+        return Ok(());
+    }
+    tracing::info!("Importing blocks");
+    chainman.import_blocks()?;
+    Ok(())
+}
+
+/// Header of the active chain's block at `height`, or `None` if the active
+/// chain is shorter than that.
+pub fn block_header(
+    chainman: &ChainstateManager,
+    height: i32,
+) -> Result<Option<Header>, KorndexError> {
+    let Ok(block_index) = chainman.get_block_index_by_height(height) else {
+        return Ok(None);
+    };
+    let raw_block = read_block_data(chainman, &block_index, height)?;
+    Ok(Some(header(&raw_block)?))
+}
+
+/// The header of the serialized block `raw_block`.
+pub fn header(raw_block: &[u8]) -> Result<Header, KorndexError> {
+    let header = raw_block.get(..80).ok_or_else(|| {
+        KorndexError::Corrupt(format!(
+            "Block data of {} bytes is too short for a header",
+            raw_block.len()
+        ))
+    })?;
+    Ok(deserialize(header)?)
+}
+
+/// Lowest height of the active chain whose block timestamp is at or after
+/// `time`, or one past the tip if there is none. Block timestamps only roughly
+/// increase with height, so this is found by bisection and may be off by a
+/// few blocks around `time`.
+pub fn height_at_time(chainman: &ChainstateManager, time: u32) -> Result<i32, KorndexError> {
+    let Ok(tip) = chainman.get_block_index_tip() else {
+        return Ok(0);
+    };
+    let (mut low, mut high) = (0, tip.info()?.height + 1);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let header = block_header(chainman, middle)?
+            .ok_or_else(|| KorndexError::NotFound(format!("No block at height {}", middle)))?;
+        if header.time < time {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(low)
+}
+
+/// Serialized block of `block_index` at `height`.
+pub fn read_block_data(
+    chainman: &ChainstateManager,
+    block_index: &BlockIndex,
+    height: i32,
+) -> Result<Vec<u8>, KorndexError> {
+    // The kernel does not tell why a read failed, but blocks of the active
+    // chain are only missing from disk once the node pruned them
+    match chainman.read_block_data(block_index) {
+        Ok(block) => Ok(block.into()),
+        Err(_) => Err(KorndexError::Pruned(height)),
+    }
+}
+
+/// Lowest height from which the node still stores every block up to
+/// `tip_height`, 0 unless it is pruned. Pruning deletes the oldest block files
+/// first, so the stored blocks form a suffix of the chain.
+pub fn first_stored_height(
+    chainman: &ChainstateManager,
+    tip_height: i32,
+) -> Result<i32, KorndexError> {
+    let stored = |height: i32| -> Result<bool, KorndexError> {
+        let block_index = chainman.get_block_index_by_height(height)?;
+        Ok(chainman.read_block_data(&block_index).is_ok())
+    };
+    if stored(0)? {
+        return Ok(0);
+    }
+    // Binary search for the first stored block above the missing genesis
+    let (mut missing, mut present) = (0, tip_height);
+    while present - missing > 1 {
+        let middle = missing + (present - missing) / 2;
+        if stored(middle)? {
+            present = middle;
+        } else {
+            missing = middle;
+        }
+    }
+    Ok(present)
+}
+
+/// Hash of the active chain's block at `height`, or `None` if the active
+/// chain is shorter than that.
+pub fn block_hash(
+    chainman: &ChainstateManager,
+    height: i32,
+) -> Result<Option<BlockHash>, KorndexError> {
+    Ok(block_header(chainman, height)?.map(|header| header.block_hash()))
+}
+
+/// Outputs spent by each non-coinbase transaction of a block with at least
+/// `n_tx` transactions, in input order, read from the block's undo data.
+pub fn spent_outputs(
+    chainman: &ChainstateManager,
+    block_index: &BlockIndex,
+    n_tx: usize,
+) -> Result<Vec<Vec<TxOut>>, KorndexError> {
+    // Blocks with only a coinbase (like genesis) spend nothing and may not
+    // have undo data at all
+    if n_tx <= 1 {
+        return Ok(Vec::new());
+    }
+    let undo = chainman.read_undo_data(block_index)?;
+    if (undo.n_tx_undo as usize) < n_tx - 1 {
+        return Err(KorndexError::Corrupt(format!(
+            "Undo data of block {} covers {} transactions, the block has {} besides its coinbase",
+            block_index.info()?.height,
+            undo.n_tx_undo,
+            n_tx - 1
+        )));
+    }
+    (0..undo.n_tx_undo)
+        .map(|i| {
+            let n_prevouts = undo.get_get_transaction_undo_size(i as u64);
+            (0..n_prevouts)
+                .map(|j| {
+                    let coin = undo.get_prevout_by_index(i as u64, j)?;
+                    Ok(TxOut {
+                        value: Amount::from_sat(coin.get_value() as u64),
+                        script_pubkey: ScriptBuf::from(coin.get_script_pubkey().get()),
+                    })
+                })
+                .collect::<Result<Vec<_>, KorndexError>>()
+        })
+        .collect()
 }
@@ -0,0 +1,72 @@
+//! Transaction, address, spend, UTXO, silent payments and compact block
+//! filter indexes built from Bitcoin Core's block data through
+//! libbitcoinkernel.
+
+pub mod access;
+pub mod analytics;
+#[cfg(feature = "async")]
+pub mod asynk;
+pub mod bench;
+pub mod blockscan;
+pub mod blockstats;
+pub mod cache;
+pub mod cli;
+pub mod coinbase;
+pub mod config;
+pub mod crashtest;
+pub mod diskspace;
+pub mod electrum;
+mod error;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filters;
+pub mod follow;
+pub mod graph;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod headers;
+mod index_store;
+mod indexer;
+pub mod indexes;
+pub mod json;
+pub mod kernel;
+pub mod listener;
+pub mod locktime;
+pub mod logging;
+pub mod mempool;
+pub mod meta;
+mod migrate;
+pub mod p2p;
+mod progress;
+pub mod proof;
+#[cfg(feature = "python")]
+pub mod python;
+mod query;
+pub mod rescan;
+pub mod rest;
+pub mod rpc;
+pub mod rpc_source;
+pub mod scripthash;
+pub mod scripttypes;
+pub mod serve;
+pub mod shutdown;
+pub mod silentpayments;
+pub mod snapshot;
+pub mod spent;
+pub mod stats;
+pub mod store;
+pub mod tls;
+pub mod txindex;
+pub mod undo;
+pub mod utxo;
+pub mod verify;
+pub mod watch;
+pub mod websocket;
+pub mod witness;
+pub mod zmq_feed;
+
+pub use error::KorndexError;
+pub use index_store::{EmbeddedIndex, TxIndexStore};
+pub use indexer::{estimate_build, BuildEstimate, Indexer, IndexerOptions};
+pub use query::{QueryHandle, TransactionLookup};
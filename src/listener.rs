@@ -0,0 +1,139 @@
+//! Listening sockets of the servers: TCP addresses, or Unix domain sockets
+//! given as `unix:<path>`, e.g. for a Tor hidden service pointing at a socket
+//! file instead of a port.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::KorndexError;
+
+/// Prefix of a listen address naming a Unix domain socket.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Path of the Unix domain socket `bind` names, if it names one.
+pub fn unix_path(bind: &str) -> Option<&Path> {
+    bind.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+/// Fails for a listen or connect address that would need a DNS lookup, i.e.
+/// anything but an IP address with a port or a Unix domain socket.
+pub fn check_no_dns(addr: &str) -> Result<(), KorndexError> {
+    if unix_path(addr).is_some() || addr.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    Err(KorndexError::Config(format!(
+        "{} is not an IP address and port, --onion does not resolve host names",
+        addr
+    )))
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Listens on `bind`. A stale socket file left at a Unix socket's path by
+    /// a previous run is replaced.
+    pub fn bind(bind: &str) -> Result<Self, KorndexError> {
+        match unix_path(bind) {
+            #[cfg(unix)]
+            Some(path) => {
+                if path.exists() && UnixStream::connect(path).is_err() {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(KorndexError::Config(
+                "Unix domain sockets are not supported on this platform".to_owned(),
+            )),
+            None => Ok(Listener::Tcp(TcpListener::bind(bind)?)),
+        }
+    }
+
+    /// Waits for the next connection.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    /// Connections as they arrive, like [`TcpListener::incoming`].
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<Stream>> + '_ {
+        std::iter::repeat_with(|| self.accept())
+    }
+}
+
+/// An accepted connection, encrypted or not.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+        }
+    }
+
+    /// Address of the peer, none on a Unix domain socket.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Stream::Tcp(tcp) => tcp.peer_addr().ok().map(|addr| addr.ip()),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref().peer_addr().ok().map(|addr| addr.ip()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            #[cfg(unix)]
+            Stream::Unix(unix) => unix.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
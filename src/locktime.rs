@@ -0,0 +1,121 @@
+use bitcoin::transaction::Version;
+use bitcoin::Block;
+use std::ops::AddAssign;
+
+use crate::store::{height_key, KvStore, Table};
+use crate::KorndexError;
+
+pub const ENTRY_SIZE: usize = 28;
+
+/// Use of nLockTime and nSequence by the non-coinbase transactions of a block
+/// or range of blocks. A block's counts are encoded as a big-endian u32 per
+/// field, in field order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LocktimeCounts {
+    pub txs: u64,
+    /// Transactions locked to a block height
+    pub height_locked_txs: u64,
+    /// Transactions locked to a time
+    pub time_locked_txs: u64,
+    /// Transactions signaling BIP125 replaceability
+    pub rbf_txs: u64,
+    pub inputs: u64,
+    /// Inputs with a BIP68 relative lock time in blocks
+    pub csv_height_inputs: u64,
+    /// Inputs with a BIP68 relative lock time in units of 512 seconds
+    pub csv_time_inputs: u64,
+}
+
+impl LocktimeCounts {
+    pub fn of_block(block: &Block) -> Self {
+        let mut counts = LocktimeCounts::default();
+        for tx in block.txdata.iter().skip(1) {
+            counts.txs += 1;
+            if tx.lock_time.to_consensus_u32() != 0 {
+                match tx.lock_time.is_block_height() {
+                    true => counts.height_locked_txs += 1,
+                    false => counts.time_locked_txs += 1,
+                }
+            }
+            if tx.is_explicitly_rbf() {
+                counts.rbf_txs += 1;
+            }
+            counts.inputs += tx.input.len() as u64;
+            // BIP68 only applies to version 2 and later
+            if tx.version < Version::TWO {
+                continue;
+            }
+            for sequence in tx.input.iter().map(|input| input.sequence) {
+                if sequence.is_height_locked() {
+                    counts.csv_height_inputs += 1;
+                } else if sequence.is_time_locked() {
+                    counts.csv_time_inputs += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    fn fields(&self) -> [u64; 7] {
+        [
+            self.txs,
+            self.height_locked_txs,
+            self.time_locked_txs,
+            self.rbf_txs,
+            self.inputs,
+            self.csv_height_inputs,
+            self.csv_time_inputs,
+        ]
+    }
+
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        for (i, count) in self.fields().iter().enumerate() {
+            buf[4 * i..4 * i + 4].copy_from_slice(&(*count as u32).to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() != ENTRY_SIZE {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid locktime counts length {}",
+                data.len()
+            )));
+        }
+        let count =
+            |i: usize| u32::from_be_bytes(data[4 * i..4 * i + 4].try_into().unwrap()) as u64;
+        Ok(LocktimeCounts {
+            txs: count(0),
+            height_locked_txs: count(1),
+            time_locked_txs: count(2),
+            rbf_txs: count(3),
+            inputs: count(4),
+            csv_height_inputs: count(5),
+            csv_time_inputs: count(6),
+        })
+    }
+}
+
+impl AddAssign for LocktimeCounts {
+    fn add_assign(&mut self, other: LocktimeCounts) {
+        self.txs += other.txs;
+        self.height_locked_txs += other.height_locked_txs;
+        self.time_locked_txs += other.time_locked_txs;
+        self.rbf_txs += other.rbf_txs;
+        self.inputs += other.inputs;
+        self.csv_height_inputs += other.csv_height_inputs;
+        self.csv_time_inputs += other.csv_time_inputs;
+    }
+}
+
+/// Locktime counts of the indexed block at `block_height`.
+pub fn read_counts(
+    store: &dyn KvStore,
+    block_height: i32,
+) -> Result<Option<LocktimeCounts>, KorndexError> {
+    match store.get(Table::Locktimes, &height_key(block_height))? {
+        Some(data) => Ok(Some(LocktimeCounts::decode(&data)?)),
+        None => Ok(None),
+    }
+}
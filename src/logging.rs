@@ -0,0 +1,32 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::KorndexError;
+
+/// How log lines are written to stderr.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans
+    Json,
+}
+
+/// Installs the global subscriber. `filter` sets the level per module in
+/// `RUST_LOG` syntax, e.g. `info,korndex::indexer=debug,libbitcoinkernel=warn`.
+pub fn init(format: LogFormat, filter: &str) -> Result<(), KorndexError> {
+    let filter = EnvFilter::try_new(filter)
+        .map_err(|e| KorndexError::Config(format!("Invalid log filter {}: {}", filter, e)))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .try_init(),
+    };
+    result.map_err(|e| KorndexError::Config(format!("Failed to set up logging: {}", e)))
+}
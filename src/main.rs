@@ -1,159 +1,1546 @@
-use bitcoin::consensus::deserialize;
-use clap::Parser;
-use libbitcoinkernel_sys::{
-    BlockManagerOptions, ChainType, ChainstateLoadOptions, ChainstateManager,
-    ChainstateManagerOptions,
+use bitcoin::{Network, OutPoint};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use korndex::bench;
+use korndex::cache;
+use korndex::cli::{self, parse_block, parse_range, parse_time, parse_txid, resolve_txid, Format};
+use korndex::config::{self, Config};
+use korndex::crashtest::{self, CrashTestOptions};
+use korndex::diskspace;
+use korndex::follow::{self, FollowOptions, Tips, WatchOptions};
+use korndex::indexes::{self, IndexKind};
+use korndex::logging::{self, LogFormat};
+use korndex::rpc::{RpcAuth, RpcClient};
+use korndex::scripthash::parse_script;
+use korndex::serve::{ServeOptions, Servers};
+use korndex::store::{Backend, Durability, KvStore, TableCompression};
+use korndex::txindex::DupPolicy;
+use korndex::{
+    estimate_build, export, graph, kernel, p2p, rescan, rpc, rpc_source, shutdown, snapshot,
+    verify, Indexer, IndexerOptions, KorndexError, QueryHandle, TxIndexStore,
 };
-use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
-use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::Path;
-
-mod kernel;
+use libbitcoinkernel_sys::ChainstateManager;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, UNIX_EPOCH};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Data directory
-    #[arg(long)]
-    datadir: String,
+    /// Config file filling in options given neither here nor in the
+    /// environment [default: korndex.toml in the working directory, if any]
+    #[arg(long, env = "KORNDEX_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Data directory of the node, needed by every command but sync and those
+    /// answered by the index alone
+    #[arg(long, env = "KORNDEX_DATADIR")]
+    datadir: Option<String>,
 
     /// Network
-    #[arg(long)]
+    #[arg(long, env = "KORNDEX_NETWORK")]
     network: String,
+
+    /// Directory holding the index, one subdirectory per network
+    /// [default: <datadir>/korndex]
+    #[arg(long, env = "KORNDEX_INDEX_DIR")]
+    index_dir: Option<PathBuf>,
+
+    /// Storage backend for the index
+    #[arg(long, env = "KORNDEX_BACKEND", value_enum, default_value_t = Backend::Lmdb)]
+    backend: Backend,
+
+    /// Initial LMDB map size in GiB, grown automatically when the index fills it
+    #[arg(long, env = "KORNDEX_DB_MAP_SIZE", default_value_t = 10)]
+    db_map_size: usize,
+
+    /// Import the node's block files even if its block index is already
+    /// loaded, e.g. after copying in blocks from elsewhere
+    #[arg(long)]
+    import_blocks: bool,
+
+    /// Format of the log written to stderr
+    #[arg(long, env = "KORNDEX_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Log levels, optionally per module, e.g.
+    /// info,korndex::indexer=debug,libbitcoinkernel=warn
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    log_filter: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Build the transaction index from the node's block data
+    Build {
+        #[command(flatten)]
+        options: BuildOptions,
+    },
+    /// Serve an Esplora-compatible REST API from an existing index
+    Serve {
+        /// Address to listen on, or unix:<path> for a Unix domain socket
+        #[arg(long, env = "KORNDEX_BIND", default_value = "127.0.0.1:3000")]
+        bind: String,
+
+        /// Also serve the Electrum protocol on this address, e.g.
+        /// 127.0.0.1:50001 or unix:/run/korndex/electrum.sock
+        #[arg(long, env = "KORNDEX_ELECTRUM")]
+        electrum: Option<String>,
+
+        /// Also serve WebSocket notifications of new blocks, addresses and
+        /// confirmations on this address, e.g. 127.0.0.1:3001
+        #[arg(long, env = "KORNDEX_WEBSOCKET")]
+        websocket: Option<String>,
+
+        /// Also track the node's mempool through its RPC interface, so
+        /// transaction and address lookups return unconfirmed transactions
+        #[arg(long)]
+        mempool: bool,
+
+        /// Requests per second each client IP may make to the REST API, and
+        /// Electrum requests and WebSocket messages, unlimited if unset.
+        /// Clients over the limit get 429 responses or error replies
+        #[arg(long)]
+        rate_limit: Option<f64>,
+
+        /// Requests a client may make at once above the rate limit
+        #[arg(long, default_value_t = 20)]
+        rate_burst: u32,
+
+        /// Only answer REST requests and WebSocket handshakes carrying one of
+        /// the API keys listed in this file, one per line, in an X-API-Key or
+        /// Authorization: Bearer header. Electrum clients cannot send one, so
+        /// it does not work with --electrum
+        #[arg(long)]
+        api_keys: Option<PathBuf>,
+
+        /// REST requests handled at a time, others wait in line, and Electrum
+        /// and WebSocket connections open at once, others are refused
+        /// [default: number of CPUs]
+        #[arg(long)]
+        max_requests: Option<usize>,
+
+        /// Deserialized blocks the REST API keeps in memory for repeated
+        /// lookups, 0 to disable
+        #[arg(long, default_value_t = cache::DEFAULT_BLOCKS)]
+        block_cache: usize,
+
+        /// Transactions the REST API keeps in memory for repeated lookups, 0
+        /// to disable
+        #[arg(long, default_value_t = cache::DEFAULT_TXS)]
+        tx_cache: usize,
+
+        /// Accept only TLS connections on the REST, Electrum and WebSocket
+        /// listeners, with this PEM certificate chain. Needs the tls feature
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key of the --tls-cert certificate
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Refuse settings that would resolve host names or connect anywhere
+        /// but the local node, for serving as a Tor hidden service: listen
+        /// addresses must be IP addresses or unix:<path> sockets, --rpc-url an
+        /// IP address, and watch webhooks are rejected. korndex sends no
+        /// telemetry either way
+        #[arg(long)]
+        onion: bool,
+
+        #[command(flatten)]
+        options: BuildOptions,
+    },
+    /// Build the index from blocks downloaded from a peer over the P2P
+    /// network instead of the node's data directory
+    Sync {
+        /// Peer to download blocks from, e.g. 192.168.1.10:8333. It is trusted
+        /// to serve valid blocks, connect to a node you run
+        #[arg(long, env = "KORNDEX_PEER")]
+        peer: String,
+
+        /// Keep indexing the blocks the peer announces after catching up
+        #[arg(long)]
+        follow: bool,
+
+        /// Do not index coinbase transactions
+        #[arg(long)]
+        skip_coinbase: bool,
+
+        /// Which location the two txids repeated by mainnet coinbases before
+        /// BIP30 keep in the txid index, recorded in the index [default:
+        /// latest]
+        #[arg(long, value_enum)]
+        dup_policy: Option<DupPolicy>,
+
+        /// Indexes to build, comma-separated [default: all]. Needs utxo
+        #[arg(long, value_enum, value_delimiter = ',')]
+        index: Vec<IndexKind>,
+
+        #[command(flatten)]
+        batch: BatchOptions,
+    },
+    /// Measure indexing throughput and commit latency on a sample of blocks,
+    /// in a scratch index deleted afterwards
+    Bench {
+        /// Directory of serialized blocks, one per file in chain order by file
+        /// name, starting with the network's genesis block. Files ending in
+        /// .hex hold the block hex encoded [default: generated blocks]
+        #[arg(long)]
+        blocks_sample: Option<PathBuf>,
+
+        /// Blocks generated without --blocks-sample
+        #[arg(long, default_value_t = 200)]
+        generated_blocks: usize,
+
+        /// Transactions per generated block
+        #[arg(long, default_value_t = 500)]
+        generated_txs: usize,
+
+        /// Indexes to build, comma-separated [default: all]. Needs utxo
+        #[arg(long, value_enum, value_delimiter = ',')]
+        index: Vec<IndexKind>,
+
+        /// When committed blocks are synced to disk
+        #[arg(long, value_enum, default_value_t = Durability::Full)]
+        durability: Durability,
+    },
+    /// Crash builds of the index at random writes and check after each crash
+    /// that it verifies and resumes. Needs the crash-test feature, and an
+    /// index directory of its own
+    CrashTest {
+        /// Most builds to crash
+        #[arg(long, default_value_t = 20)]
+        rounds: usize,
+
+        /// Crash points are drawn from the first this many writes of a build
+        #[arg(long, default_value_t = 200)]
+        max_writes: u64,
+
+        /// Seed of the crash points, to reproduce a failed run [default:
+        /// random]
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Most blocks committed in one batch, fewer make for more commits
+        #[arg(long, default_value_t = 10)]
+        batch_blocks: usize,
+
+        /// Stop the index at this height instead of the node's tip
+        #[arg(long)]
+        end_height: Option<i32>,
+    },
+    /// Index the blocks below an index built with --start-height or on a
+    /// pruned node
+    Backfill {
+        /// Lowest height to index [default: genesis, or the lowest block a
+        /// pruned node stores]
+        #[arg(long, conflicts_with = "index")]
+        to_height: Option<i32>,
+
+        /// Instead of extending the index downwards, add these indexes to an
+        /// index built without them, leaving those it has untouched
+        #[arg(long, value_enum, value_delimiter = ',')]
+        index: Vec<IndexKind>,
+
+        /// Do not index coinbase transactions, as for the original build
+        #[arg(long)]
+        skip_coinbase: bool,
+
+        /// Which location a repeated coinbase txid keeps, must match the
+        /// original build [default: as recorded in the index]
+        #[arg(long, value_enum)]
+        dup_policy: Option<DupPolicy>,
+
+        /// Value histogram bucket boundaries in satoshis when adding the
+        /// analytics index, comma-separated [default: 546,10000,...,100000000000]
+        #[arg(long, value_delimiter = ',')]
+        value_buckets: Vec<u64>,
+
+        #[command(flatten)]
+        batch: BatchOptions,
+    },
+    /// List the transactions paying to or spending from the scripts of an
+    /// output descriptor, to rescan a watch-only wallet
+    Rescan {
+        /// Output descriptor, e.g. wpkh([d34db33f/84'/0'/0']xpub.../0/*)
+        #[arg(long)]
+        descriptor: String,
+
+        /// Lowest block height to scan, e.g. the wallet's birth height
+        #[arg(long, default_value_t = 0)]
+        start_height: i32,
+
+        /// Inclusive range of derivation indexes of a ranged descriptor as
+        /// <start>..<end>
+        #[arg(long, default_value = "0..999")]
+        range: String,
+    },
+    /// Delete indexes that are no longer needed from an existing index
+    Drop {
+        /// Indexes to delete, comma-separated
+        #[arg(long, value_enum, value_delimiter = ',', required = true)]
+        index: Vec<IndexKind>,
+    },
+    /// Return the space of deleted entries, e.g. after `korndex drop` or many
+    /// reorgs, to the file system. The index must not be in use
+    Compact,
+    /// Report the entries, covered heights and size of an existing index
+    Stats {
+        #[command(subcommand)]
+        command: Option<StatsCommand>,
+    },
+    /// Copy a built index to another machine through a compressed,
+    /// checksummed snapshot file
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Write the entries of an index to a CSV or Parquet file for analysis
+    Export {
+        /// Index to export
+        #[arg(long, value_enum, default_value_t = export::Index::Txindex)]
+        index: export::Index,
+
+        #[arg(long, value_enum, default_value_t = export::Format::Csv)]
+        format: export::Format,
+
+        /// File to write
+        #[arg(long)]
+        out: PathBuf,
+
+        /// Only export entries of blocks in this inclusive height range, as
+        /// <start>..<end>
+        #[arg(long)]
+        heights: Option<String>,
+
+        /// Add the hash and time of each entry's block
+        #[arg(long)]
+        with_blocks: bool,
+    },
+    /// Check an existing index against the node's block data
+    Verify {
+        /// Only check every Nth block instead of walking the whole index
+        #[arg(long)]
+        sample: Option<usize>,
+    },
+    /// Serve the gRPC API of proto/korndex.proto from an existing index,
+    /// alongside a process building or serving it. Transaction lookups need
+    /// --datadir
+    #[cfg(feature = "grpc")]
+    Grpc {
+        /// Address to listen on
+        #[arg(long, env = "KORNDEX_GRPC", default_value = "127.0.0.1:50051")]
+        bind: std::net::SocketAddr,
+
+        /// Most lookups reading the index at a time
+        #[arg(long, default_value_t = 16)]
+        max_readers: usize,
+    },
+    /// Walk the ancestors or descendants of a transaction through the graph
+    /// index
+    Graph {
+        /// Transaction id to start from
+        txid: String,
+
+        /// Most steps away from the transaction to follow
+        #[arg(long, default_value_t = 1)]
+        depth: u32,
+
+        /// Which relatives to list
+        #[arg(long, value_enum, default_value_t = graph::Direction::Both)]
+        direction: graph::Direction,
+
+        /// Interpret the txid as raw little-endian bytes rather than the usual
+        /// display order
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Look up a transaction in an existing index
+    #[command(args_conflicts_with_subcommands = true)]
+    Query {
+        /// Transaction id or witness transaction id to look up, or a unique
+        /// prefix of a txid
+        #[arg(conflicts_with = "batch")]
+        txid: Option<String>,
+
+        /// Look up every id listed in this file, one per line, or on stdin
+        /// for `-`. Prints one JSON object per line, with only the
+        /// consensus-serialized transaction for `--format hex`
+        #[arg(long)]
+        batch: Option<PathBuf>,
+
+        /// Interpret the id as raw little-endian bytes rather than the usual
+        /// display order
+        #[arg(long)]
+        raw: bool,
+
+        /// How to print the transaction
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+
+        /// Also print the decoded transaction with `--format text`, which
+        /// loads the node's chainstate instead of only reading the index
+        #[arg(long)]
+        with_tx: bool,
+
+        #[command(subcommand)]
+        command: Option<QueryCommand>,
+    },
+}
+
+impl Command {
+    /// Whether the command reads the node's block data, rather than only the
+    /// index.
+    fn needs_kernel(&self) -> bool {
+        match self {
+            Command::Stats { .. }
+            | Command::Export { .. }
+            | Command::Snapshot { .. }
+            | Command::Drop { .. }
+            | Command::Compact
+            | Command::Graph { .. }
+            | Command::Sync { .. }
+            | Command::Bench { .. }
+            | Command::CrashTest { .. } => false,
+            Command::Query {
+                command: Some(_), ..
+            } => false,
+            Command::Build { options } => options.source == Source::Kernel,
+            Command::Query { batch: Some(_), .. } => true,
+            Command::Query {
+                format, with_tx, ..
+            } => *with_tx || !matches!(format, Format::Text),
+            _ => true,
+        }
+    }
+}
+
+/// Where blocks are read from.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    /// The node's data directory, through libbitcoinkernel
+    Kernel,
+    /// bitcoind's RPC interface, for an index built from genesis with the utxo
+    /// index, which supplies the outputs the blocks spend
+    Rpc,
+}
+
+#[derive(clap::Args, Debug)]
+struct BuildOptions {
+    /// Where to read blocks from
+    #[arg(long, value_enum, default_value_t = Source::Kernel)]
+    source: Source,
+
+    #[command(flatten)]
+    rpc: RpcOptions,
+
+    /// Do not index coinbase transactions
+    #[arg(long)]
+    skip_coinbase: bool,
+
+    /// Which location the two txids repeated by mainnet coinbases before
+    /// BIP30 keep in the txid index, recorded in the index [default: latest]
+    #[arg(long, value_enum)]
+    dup_policy: Option<DupPolicy>,
+
+    /// Keep running and index new blocks as the node connects them
+    #[arg(long)]
+    follow: bool,
+
+    /// Seconds between tip checks in follow mode
+    #[arg(long, default_value_t = 10)]
+    poll_interval: u64,
+
+    /// While following the tip, keep the entries of this many of the most
+    /// recent blocks in memory and write them to the index once buried, so
+    /// reorgs rewrite them in memory. Other processes reading the index lag
+    /// up to twice as many blocks behind. 0 writes every block right away
+    #[arg(long, default_value_t = 0)]
+    hot_blocks: usize,
+
+    /// Compress the values of these tables of a new index with zstd, as
+    /// TABLE[=LEVEL] comma-separated, e.g. undo,filters=9,block_txids. Saves
+    /// disk space on tables with large values at the cost of CPU. Values are
+    /// compressed one by one, so not those of meta, txindex and the
+    /// dup-sorted scripthash and children tables, whose short fixed-size
+    /// entries would not shrink. Recorded in the index, it cannot change
+    /// afterwards
+    #[arg(long, value_parser = TableCompression::parse, value_delimiter = ',')]
+    compress: Vec<TableCompression>,
+
+    /// Compress a table's values with a zstd dictionary trained on typical
+    /// values, e.g. with `zstd --train`, as TABLE=PATH. Can be repeated
+    #[arg(long, value_parser = TableCompression::parse_dictionary, requires = "compress")]
+    compress_dict: Vec<(String, PathBuf)>,
+
+    /// Split the txindex of a new index into this many stores by the first
+    /// byte of the txid, up to 256, which a build writes and commits in
+    /// parallel. Recorded in the index, it cannot change afterwards
+    #[arg(long, default_value_t = 1)]
+    txindex_shards: usize,
+
+    /// Follow the tip by indexing the blocks bitcoind publishes on this ZMQ
+    /// rawblock endpoint, e.g. tcp://127.0.0.1:28332, instead of through the
+    /// kernel. Implies --follow
+    #[arg(long)]
+    zmq_block: Option<String>,
+
+    /// Continue a build that was interrupted or crashed from its last
+    /// checkpoint
+    #[arg(long)]
+    resume: bool,
+
+    /// Start a new index at this height instead of genesis, older blocks can
+    /// be added later with `korndex backfill`
+    #[arg(long, visible_alias = "from-height")]
+    start_height: Option<i32>,
+
+    /// End a new index at this height instead of following the tip. Lookups
+    /// outside the indexed range fail
+    #[arg(long, conflicts_with_all = ["follow", "zmq_block", "to_time"])]
+    to_height: Option<i32>,
+
+    /// Start a new index at the first block with a timestamp at or after this
+    /// time, as Unix seconds or RFC 3339, e.g. 2024-01-01T00:00:00Z
+    #[arg(long, value_parser = parse_time, conflicts_with = "start_height")]
+    from_time: Option<u32>,
+
+    /// End a new index at the last block with a timestamp at or before this
+    /// time, as Unix seconds or RFC 3339
+    #[arg(long, value_parser = parse_time, conflicts_with_all = ["follow", "zmq_block"])]
+    to_time: Option<u32>,
+
+    /// Indexes to build into a new index, comma-separated [default: all]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    index: Vec<IndexKind>,
+
+    /// Boundaries in satoshis splitting output values into the buckets of
+    /// the analytics index, comma-separated [default: 546,10000,...,100000000000]
+    #[arg(long, value_delimiter = ',')]
+    value_buckets: Vec<u64>,
+
+    /// While following the tip, send a notification for every transaction
+    /// funding or spending an address, hex scriptPubKey or descriptor listed
+    /// in this file, one per line. The file is reread when it changes. Needs
+    /// the address index
+    #[arg(long)]
+    watch_file: Option<PathBuf>,
+
+    /// POST watch notifications as JSON to this URL, can be repeated
+    #[arg(long, requires = "watch_file")]
+    watch_webhook: Vec<String>,
+
+    /// Publish watch notifications on a ZMQ PUB socket bound to this
+    /// endpoint, e.g. tcp://127.0.0.1:28350
+    #[arg(long, requires = "watch_file")]
+    watch_zmq: Option<String>,
+
+    /// Highest derivation index of ranged descriptors in the watch file
+    #[arg(long, default_value_t = 999)]
+    watch_range: u32,
+
+    /// Estimate the index size, build duration and LMDB map size from a
+    /// sample of the blocks the build would index, without building
+    #[arg(long, conflicts_with_all = ["follow", "zmq_block", "resume"])]
+    estimate: bool,
+
+    /// Blocks read and indexed for --estimate, more give a closer estimate
+    #[arg(long, default_value_t = 500, requires = "estimate")]
+    estimate_samples: usize,
+
+    #[command(flatten)]
+    batch: BatchOptions,
+}
+
+#[derive(clap::Args, Debug)]
+struct BatchOptions {
+    /// Most blocks committed in one batch
+    #[arg(long, default_value_t = 1000)]
+    batch_blocks: usize,
+
+    /// Commit a batch once its serialized blocks reach this many bytes
+    #[arg(long)]
+    batch_bytes: Option<usize>,
+
+    /// Commit a batch once it holds this many transactions
+    #[arg(long)]
+    batch_txs: Option<usize>,
+
+    /// Commit a batch once its estimated memory use reaches this many MiB.
+    /// The build holds a few batches at a time, so allow for several times
+    /// this
+    #[arg(long)]
+    max_batch_mem: Option<usize>,
+
+    /// When committed batches are synced to disk
+    #[arg(long, value_enum, default_value_t = Durability::Full)]
+    durability: Durability,
+
+    /// Blocks read from disk ahead of indexing. Raise it on a spinning disk
+    /// to hide its latency, at the cost of memory for the queued blocks
+    #[arg(long, default_value_t = 64)]
+    prefetch_blocks: usize,
+
+    /// Read blocks one at a time in chain order instead of several in
+    /// parallel, which is faster on a spinning disk
+    #[arg(long)]
+    sequential_reads: bool,
+
+    /// Pause the build while committing would leave less than this many MiB
+    /// free on the index's disk, until space is freed. 0 never pauses
+    #[arg(long, default_value_t = diskspace::DEFAULT_MIN_FREE / (1024 * 1024))]
+    min_free_space: u64,
+}
+
+impl BuildOptions {
+    fn follows(&self) -> bool {
+        self.follow || self.zmq_block.is_some()
+    }
+
+    /// Shards and compresses a new `index` as selected with --txindex-shards
+    /// and --compress, and keeps its most recent blocks in memory if following
+    /// the tip with --hot-blocks.
+    fn prepare(
+        &self,
+        index: TxIndexStore,
+        backend: Backend,
+        map_size: usize,
+    ) -> Result<TxIndexStore, KorndexError> {
+        let index = index
+            .shard_txindex(backend, self.txindex_shards, map_size)?
+            .compress(&self.compression()?)?;
+        Ok(match self.follows() && self.hot_blocks > 0 {
+            true => index.tiered(self.hot_blocks),
+            false => index,
+        })
+    }
+
+    /// The tables of --compress with the dictionaries of --compress-dict.
+    fn compression(&self) -> Result<Vec<TableCompression>, KorndexError> {
+        let mut tables = self.compress.clone();
+        TableCompression::load_dictionaries(&mut tables, &self.compress_dict)?;
+        Ok(tables)
+    }
+
+    fn follow_options(&self) -> FollowOptions {
+        FollowOptions {
+            zmq_block: self.zmq_block.clone(),
+            poll_interval: Duration::from_secs(self.poll_interval),
+            watch: self.watch_file.clone().map(|file| WatchOptions {
+                file,
+                webhooks: self.watch_webhook.clone(),
+                zmq: self.watch_zmq.clone(),
+                range: self.watch_range,
+            }),
+        }
+    }
+
+    fn indexer_options(&self) -> IndexerOptions {
+        IndexerOptions {
+            skip_coinbase: self.skip_coinbase,
+            dup_policy: self.dup_policy,
+            resume: self.resume,
+            start_height: self.start_height,
+            end_height: self.to_height,
+            indexes: (!self.index.is_empty()).then(|| self.index.iter().copied().collect()),
+            value_buckets: (!self.value_buckets.is_empty()).then(|| self.value_buckets.clone()),
+            ..self.batch.indexer_options()
+        }
+    }
+
+    /// [`BuildOptions::indexer_options`] with --from-time and --to-time
+    /// resolved to heights of the active chain.
+    fn indexer_options_at(
+        &self,
+        chainman: &ChainstateManager,
+    ) -> Result<IndexerOptions, KorndexError> {
+        let mut options = self.indexer_options();
+        if let Some(time) = self.from_time {
+            options.start_height = Some(kernel::height_at_time(chainman, time)?);
+        }
+        if let Some(time) = self.to_time {
+            options.end_height = Some(kernel::height_at_time(chainman, time + 1)? - 1);
+        }
+        Ok(options)
+    }
+}
+
+impl BatchOptions {
+    fn indexer_options(&self) -> IndexerOptions {
+        IndexerOptions {
+            batch_blocks: self.batch_blocks,
+            batch_bytes: self.batch_bytes,
+            batch_txs: self.batch_txs,
+            max_batch_mem: self.max_batch_mem.map(|mib| mib * 1024 * 1024),
+            durability: self.durability,
+            prefetch_blocks: self.prefetch_blocks,
+            sequential_reads: self.sequential_reads,
+            min_free_space: self.min_free_space * 1024 * 1024,
+            ..IndexerOptions::default()
+        }
+    }
+}
+
+/// How to reach bitcoind's RPC interface.
+#[derive(clap::Args, Debug)]
+struct RpcOptions {
+    /// URL of bitcoind's RPC server [default: localhost at the network's
+    /// default RPC port]
+    #[arg(long, env = "KORNDEX_RPC_URL")]
+    rpc_url: Option<String>,
+
+    /// RPC cookie file [default: .cookie in the node's data directory]
+    #[arg(long, env = "KORNDEX_RPC_COOKIE", conflicts_with = "rpc_user")]
+    rpc_cookie: Option<PathBuf>,
+
+    /// RPC user name, instead of the cookie file
+    #[arg(long, env = "KORNDEX_RPC_USER", requires = "rpc_password")]
+    rpc_user: Option<String>,
+
+    #[arg(long, env = "KORNDEX_RPC_PASSWORD", requires = "rpc_user")]
+    rpc_password: Option<String>,
+}
+
+impl RpcOptions {
+    fn client(&self, datadir: Option<&str>, network: Network) -> Result<RpcClient, KorndexError> {
+        let url = self
+            .rpc_url
+            .as_deref()
+            .unwrap_or_else(|| rpc::default_url(network));
+        let auth = match (&self.rpc_user, &self.rpc_password, &self.rpc_cookie, datadir) {
+            (Some(user), Some(password), ..) => RpcAuth::UserPass(user.clone(), password.clone()),
+            (.., Some(cookie), _) => RpcAuth::Cookie(cookie.clone()),
+            (.., Some(datadir)) => RpcAuth::Cookie(rpc::default_cookie(Path::new(datadir), network)),
+            _ => {
+                return Err(KorndexError::Config(
+                    "Pass --rpc-cookie or --rpc-user and --rpc-password, or --datadir to use its cookie file"
+                        .to_owned(),
+                ))
+            }
+        };
+        Ok(RpcClient::new(url, auth))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Write a snapshot of the index
+    Create {
+        /// Snapshot file to write
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Restore a snapshot into a new, empty index, with any backend
+    Restore {
+        /// Snapshot file to read
+        #[arg(long)]
+        from: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum QueryCommand {
+    /// List the transactions funding or spending an address
+    Address {
+        /// Address, or hex-encoded scriptPubKey
+        address: String,
+    },
+    /// List the unspent outputs of an address and its balance
+    Utxos {
+        /// Address, or hex-encoded scriptPubKey
+        address: String,
+    },
+    /// Find the transaction spending an output
+    Spend {
+        /// Outpoint as <txid>:<vout>
+        outpoint: String,
+    },
+    /// List the silent payments tweaks of a block or range of blocks
+    Tweaks {
+        /// Block height
+        #[arg(long, conflicts_with = "range", required_unless_present = "range")]
+        height: Option<i32>,
+
+        /// Inclusive height range as <start>..<end>
+        #[arg(long)]
+        range: Option<String>,
+    },
+    /// Show the BIP158 basic filter and filter header of a block
+    Filter {
+        /// Block hash or height
+        block: String,
+    },
+    /// List the txids of a block in block order
+    Block {
+        /// Block hash or height
+        block: String,
+    },
+    /// Show the size, weight, transaction count, fees and subsidy of a block,
+    /// like Bitcoin Core's `getblockstats`, or their totals over a range of
+    /// blocks. Prints JSON with `--format json`
+    #[command(name = "blockstats")]
+    BlockStats {
+        /// Block hash or height
+        #[arg(conflicts_with = "range", required_unless_present = "range")]
+        block: Option<String>,
+
+        /// Add up the blocks of this inclusive height range, as <start>..<end>
+        #[arg(long)]
+        range: Option<String>,
+    },
+    /// Show the coinbase of a block: its subsidy, the fees it claimed, its
+    /// scriptSig with any pool tag and its witness commitment. Prints JSON
+    /// with `--format json`
+    Coinbase {
+        /// Block hash or height
+        block: String,
+    },
+    /// List the taproot annexes and large witness items of a block or range
+    /// of blocks
+    Witness {
+        /// Block hash or height
+        #[arg(conflicts_with = "range", required_unless_present = "range")]
+        block: Option<String>,
+
+        /// Inclusive height range as <start>..<end>
+        #[arg(long)]
+        range: Option<String>,
+
+        /// Only list annexes and items of at least this many bytes
+        #[arg(long, default_value_t = 0)]
+        min_size: u32,
+    },
+    /// Find the block that was the chain tip at a point in time, judged by
+    /// median time past
+    HeightAt {
+        /// Unix timestamp or RFC 3339 date and time, e.g. 2024-01-01T00:00:00Z
+        #[arg(long)]
+        time: String,
+    },
+    /// Print a hex-encoded BIP37 merkle block proving a transaction's
+    /// inclusion, like Bitcoin Core's `gettxoutproof`
+    Proof {
+        /// Transaction id
+        txid: String,
+    },
 }
 
-struct TxIndex {
-    txid: String,
-    block_height: i32,
-    position_in_block: usize,
+#[derive(Subcommand, Debug)]
+enum StatsCommand {
+    /// Count the outputs each block created by script type, e.g. to track
+    /// taproot adoption
+    ScriptTypes {
+        /// Inclusive height range as <start>..<end>
+        #[arg(long)]
+        range: String,
+
+        /// Print the counts of every block instead of their totals
+        #[arg(long)]
+        per_block: bool,
+    },
+    /// Count the outputs each block created and their value by value bucket,
+    /// from dust to large outputs
+    ValueDistribution {
+        /// Inclusive height range as <start>..<end>
+        #[arg(long)]
+        range: String,
+
+        /// Print the histogram of every block instead of their totals
+        #[arg(long)]
+        per_block: bool,
+    },
+    /// Count the transactions using nLockTime or signaling RBF, and the
+    /// inputs with BIP68 relative lock times
+    Locktime {
+        /// Inclusive height range as <start>..<end>
+        #[arg(long)]
+        range: String,
+
+        /// Print the counts of every block instead of their totals
+        #[arg(long)]
+        per_block: bool,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TxIndexEntry {
-    block_height: i32,
-    position_in_block: usize,
+fn main() -> ExitCode {
+    match parse_args().and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
 
-#[derive(Clone)]
-struct BlockIndexInfo {
-    block_height: i32,
+/// Parses the command line, filling in options it and the environment leave
+/// out from the config file.
+fn parse_args() -> Result<Args, KorndexError> {
+    // A first pass only to find the config file
+    let matches = Args::command().ignore_errors(true).get_matches();
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(config::DEFAULT_FILE)).filter(|path| path.exists()),
+    };
+    let Some(path) = path else {
+        return Ok(Args::parse());
+    };
+    let config = Config::load(&path)?;
+    let matches = with_config(Args::command(), &config).get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.config = Some(path);
+    Ok(args)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let chain_type = match args.network.to_lowercase().as_str() {
-        "mainnet" => ChainType::MAINNET,
-        "testnet" => ChainType::TESTNET,
-        "regtest" => ChainType::REGTEST,
-        "signet" => ChainType::SIGNET,
-        _ => {
-            eprintln!("Invalid network type: {}", args.network);
-            std::process::exit(1);
+/// Makes the settings of `config` the defaults of the matching options.
+fn with_config(command: clap::Command, config: &Config) -> clap::Command {
+    let settings = [
+        ("datadir", config.datadir.clone()),
+        ("network", config.network.clone()),
+        (
+            "index_dir",
+            config
+                .index_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+        ),
+        ("backend", config.backend.clone()),
+        (
+            "db_map_size",
+            config.db_map_size.map(|size| size.to_string()),
+        ),
+    ];
+    let serve_settings = [
+        ("bind", config.serve.bind.clone()),
+        ("electrum", config.serve.electrum.clone()),
+        ("websocket", config.serve.websocket.clone()),
+        (
+            "rate_limit",
+            config.serve.rate_limit.map(|rate| rate.to_string()),
+        ),
+        (
+            "rate_burst",
+            config.serve.rate_burst.map(|burst| burst.to_string()),
+        ),
+        (
+            "api_keys",
+            config
+                .serve
+                .api_keys
+                .as_ref()
+                .map(|path| path.display().to_string()),
+        ),
+        (
+            "max_requests",
+            config.serve.max_requests.map(|max| max.to_string()),
+        ),
+        (
+            "block_cache",
+            config.serve.block_cache.map(|blocks| blocks.to_string()),
+        ),
+        ("tx_cache", config.serve.tx_cache.map(|txs| txs.to_string())),
+        (
+            "tls_cert",
+            config
+                .serve
+                .tls_cert
+                .as_ref()
+                .map(|path| path.display().to_string()),
+        ),
+        (
+            "tls_key",
+            config
+                .serve
+                .tls_key
+                .as_ref()
+                .map(|path| path.display().to_string()),
+        ),
+        ("onion", config.serve.onion.then(|| "true".to_owned())),
+    ];
+    let set_defaults = |mut command: clap::Command, settings: &[(&str, Option<String>)]| {
+        for (id, value) in settings {
+            if let Some(value) = value.clone() {
+                command = command.mut_arg(*id, |arg| arg.default_value(value).required(false));
+            }
         }
+        command
     };
-    let data_dir = args.datadir;
-    let blocks_dir = data_dir.clone() + "/blocks";
-    // Set up the kernel
-    let _ = kernel::setup_logging().unwrap();
-    let context = kernel::create_context(chain_type);
-    let chainman = ChainstateManager::new(
-        ChainstateManagerOptions::new(&context, &data_dir).unwrap(),
-        BlockManagerOptions::new(&context, &blocks_dir).unwrap(),
-        &context,
-    )
-    .unwrap();
-    chainman
-        .load_chainstate(ChainstateLoadOptions::new())
-        .unwrap();
-    chainman.import_blocks().unwrap();
-
-    // Create directory for the LMDB environment
-    let path = Path::new("./txindex");
-    fs::create_dir_all(path)?;
-
-    // Set up the LMDB environment
-    let env = Environment::new()
-        .set_max_dbs(10)
-        .set_map_size(10 * 1024 * 1024 * 1024) // Increase map size to 10 GB
-        .open(path)?;
-
-    // Create (or open) a database
-    let db = env.create_db(Some("txindex"), DatabaseFlags::empty())?;
-
-    // Collect block indices
-    let mut block_index_res = chainman.get_block_index_tip();
-    let mut block_indices = Vec::new();
-    while let Ok(ref block_index) = block_index_res {
-        let block_height = block_index.info().unwrap().clone().height;
-        block_indices.push(BlockIndexInfo { block_height });
-        block_index_res = block_index_res.unwrap().prev();
-    }
-
-    // Process blocks in parallel
-    let batch_size = 1000;
-    block_indices.par_chunks(batch_size).for_each(|chunk| {
-        let env = &env;
-        let db = db;
-
-        let tx_batch: Vec<TxIndex> = chunk
-            .par_iter()
-            .flat_map(|block_info| {
-                let block_index = chainman
-                    .get_block_index_by_height(block_info.block_height)
-                    .unwrap();
-                let raw_block: Vec<u8> = chainman.read_block_data(&block_index).unwrap().into();
-                let block: bitcoin::Block = deserialize(&raw_block).unwrap();
-
-                (0..block.txdata.len() - 1)
-                    .map(|i| {
-                        let txid = block.txdata[i + 1].compute_txid();
-                        TxIndex {
-                            txid: txid.to_string(),
-                            position_in_block: i,
-                            block_height: block_info.block_height,
-                        }
-                    })
-                    .collect::<Vec<TxIndex>>()
-            })
-            .collect();
-
-        let mut txn = env.begin_rw_txn().unwrap();
-        for entry in tx_batch.iter() {
-            let v = TxIndexEntry {
-                position_in_block: entry.position_in_block,
-                block_height: entry.block_height,
-            };
-            let serialized = bincode::serialize(&v).unwrap();
-            txn.put(db, &entry.txid, &serialized, WriteFlags::empty())
-                .unwrap();
+    set_defaults(command, &settings)
+        .mut_subcommand("serve", |serve| set_defaults(serve, &serve_settings))
+}
+
+fn run(args: Args) -> Result<(), KorndexError> {
+    let (chain_type, network) = kernel::network(&args.network)?;
+    logging::init(args.log_format, &args.log_filter)?;
+    let _ = kernel::setup_logging()?;
+    if let Some(ref config) = args.config {
+        tracing::info!("Using settings from {}", config.display());
+    }
+    if let Command::Bench {
+        blocks_sample,
+        generated_blocks,
+        generated_txs,
+        index: kinds,
+        durability,
+    } = args.command
+    {
+        let blocks = match blocks_sample {
+            Some(dir) => bench::load_sample(&dir)?,
+            None => bench::synthetic_blocks(network, generated_blocks, generated_txs),
+        };
+        let options = IndexerOptions {
+            indexes: (!kinds.is_empty()).then(|| kinds.into_iter().collect()),
+            durability,
+            ..IndexerOptions::default()
+        };
+        let report = bench::ScratchIndex::create(args.backend, network)?.run(&blocks, options)?;
+        cli::print_bench_report(&report);
+        return Ok(());
+    }
+    let index_dir = match (args.index_dir, args.datadir.as_deref()) {
+        (Some(index_dir), _) => index_dir,
+        (None, Some(datadir)) => Path::new(datadir).join("korndex"),
+        (None, None) => {
+            return Err(KorndexError::Config(
+                "Pass --index-dir, or --datadir to keep the index in the node's data directory"
+                    .to_owned(),
+            ))
         }
-        txn.commit().unwrap();
-    });
+    };
+    if let Command::CrashTest {
+        rounds,
+        max_writes,
+        seed,
+        batch_blocks,
+        end_height,
+    } = args.command
+    {
+        let mut global_args: Vec<OsString> = vec!["--network".into(), args.network.clone().into()];
+        if let Some(ref config) = args.config {
+            global_args.extend(["--config".into(), config.clone().into_os_string()]);
+        }
+        if let Some(ref datadir) = args.datadir {
+            global_args.extend(["--datadir".into(), datadir.into()]);
+        }
+        global_args.extend([
+            "--index-dir".into(),
+            index_dir.clone().into_os_string(),
+            "--backend".into(),
+            args.backend.to_possible_value().unwrap().get_name().into(),
+            "--db-map-size".into(),
+            args.db_map_size.to_string().into(),
+            "--log-filter".into(),
+            args.log_filter.clone().into(),
+        ]);
+        let options = CrashTestOptions {
+            rounds,
+            max_writes,
+            seed: seed
+                .unwrap_or_else(|| UNIX_EPOCH.elapsed().unwrap_or_default().as_nanos() as u64),
+            batch_blocks,
+            end_height,
+        };
+        crashtest::run(&global_args, args.backend, &index_dir, network, &options)?;
+        return Ok(());
+    }
+    shutdown::install()?;
+
+    let map_size = args.db_map_size * 1024 * 1024 * 1024;
+    if let Command::Snapshot { command } = args.command {
+        return run_snapshot(args.backend, &index_dir, network, map_size, command);
+    }
 
-    log::info!("Built index!");
+    #[cfg(feature = "grpc")]
+    if let Command::Grpc { bind, max_readers } = args.command {
+        let index = korndex::EmbeddedIndex::open(
+            args.backend,
+            &index_dir,
+            &args.network,
+            args.datadir.as_deref(),
+        )?;
+        let index = korndex::asynk::Index::new(index, max_readers);
+        return tokio::runtime::Runtime::new()?.block_on(korndex::grpc::serve(bind, index));
+    }
+
+    if let Command::Drop { .. } | Command::Compact = args.command {
+        let index = TxIndexStore::open(args.backend, &index_dir, network, map_size)?;
+        return run_maintenance(index, args.command);
+    }
 
-    // Example retrieval of a transaction's block location
+    if let Command::Sync {
+        peer,
+        follow,
+        skip_coinbase,
+        dup_policy,
+        index: kinds,
+        batch,
+    } = args.command
     {
-        let txid = "37d704c8550bf80213ed1b1c3b5798665c7274d67c707bc6e9d6eb4167d3b7f3".to_string();
-        let txn = env.begin_ro_txn()?;
-        if let Some(data) = txn.get(db, &txid).ok() {
-            let txindex: TxIndexEntry = bincode::deserialize(data)?;
-            println!(
-                "Transaction ID: {}, Block Location: {}",
-                &txid, txindex.position_in_block
+        let index = TxIndexStore::open(args.backend, &index_dir, network, map_size)?;
+        let options = IndexerOptions {
+            skip_coinbase,
+            dup_policy,
+            indexes: (!kinds.is_empty()).then(|| kinds.into_iter().collect()),
+            ..batch.indexer_options()
+        };
+        index.kv().set_durability(options.durability)?;
+        let indexer = Indexer::without_kernel(&index, options);
+        return p2p::sync(&indexer, index.kv(), network, &peer, follow);
+    }
+
+    if let Command::Build { ref options } = args.command {
+        if options.source == Source::Rpc && options.estimate {
+            return Err(KorndexError::Config(
+                "--estimate reads blocks through the kernel, it does not work with --source rpc"
+                    .to_owned(),
+            ));
+        }
+        if options.source == Source::Rpc {
+            return build_over_rpc(
+                args.backend,
+                &index_dir,
+                network,
+                map_size,
+                args.datadir.as_deref(),
+                options,
             );
-            let Ok(ref block_index) = chainman.get_block_index_by_height(txindex.block_height)
-            else {
-                todo!()
+        }
+    }
+
+    // Queries answered by the index alone skip loading the chainstate
+    if !args.command.needs_kernel() {
+        let index = TxIndexStore::open_read_only(args.backend, &index_dir, network)?;
+        return match args.command {
+            Command::Stats { command: None } => cli::print_stats(index.kv()),
+            Command::Stats {
+                command: Some(command),
+            } => run_stats(&index.index_query(), command),
+            command @ Command::Export { .. } => run_export(index.kv(), command),
+            command => run_query(&index.index_query(), network, command),
+        };
+    }
+
+    // Set up the kernel
+    let (block_tips_tx, block_tips) = mpsc::channel();
+    let context = kernel::create_context(chain_type, Some(block_tips_tx))?;
+    let datadir = args.datadir.as_deref().ok_or_else(|| {
+        KorndexError::Config(
+            "This command reads the node's data directory, pass --datadir".to_owned(),
+        )
+    })?;
+    let chainman = kernel::load_chainman(&context, datadir)?;
+
+    // Estimates leave the index as it is, or uncreated
+    if let Command::Build { ref options } = args.command {
+        if options.estimate {
+            let index = match TxIndexStore::open_read_only(args.backend, &index_dir, network) {
+                Ok(index) => Some(index),
+                Err(KorndexError::Config(_)) => None,
+                Err(e) => return Err(e),
+            };
+            let estimate = estimate_build(
+                &chainman,
+                index.as_ref().map(TxIndexStore::kv),
+                args.backend,
+                network,
+                &options.indexer_options_at(&chainman)?,
+                options.estimate_samples,
+            )?;
+            cli::print_estimate(&estimate);
+            return Ok(());
+        }
+    }
+
+    let index = TxIndexStore::open(args.backend, &index_dir, network, map_size)?;
+    let index = match args.command {
+        Command::Build { ref options } | Command::Serve { ref options, .. } => {
+            options.prepare(index, args.backend, map_size)?
+        }
+        _ => index,
+    };
+    let store = index.kv();
+
+    match args.command {
+        Command::Build { options } => {
+            kernel::import_blocks(&chainman, args.import_blocks)?;
+            let indexer = Indexer::new(&chainman, &index, options.indexer_options_at(&chainman)?);
+            indexer.build()?;
+            if options.follows() && !shutdown::requested() {
+                let follow_options = options.follow_options();
+                let tips = Tips::Kernel(&block_tips);
+                follow::follow(&indexer, store, network, tips, &follow_options)?;
+            }
+            Ok(())
+        }
+        Command::Serve {
+            bind,
+            electrum,
+            websocket,
+            mempool,
+            rate_limit,
+            rate_burst,
+            api_keys,
+            max_requests,
+            block_cache,
+            tx_cache,
+            tls_cert,
+            tls_key,
+            onion,
+            options,
+        } => {
+            if onion && !options.watch_webhook.is_empty() {
+                return Err(KorndexError::Config(
+                    "--onion does not send watch webhooks, use --watch-zmq instead".to_owned(),
+                ));
+            }
+            if options.source == Source::Rpc {
+                return Err(KorndexError::Config(
+                    "serve reads blocks through the kernel, --source rpc only works with build"
+                        .to_owned(),
+                ));
+            }
+            if options.estimate {
+                return Err(KorndexError::Config(
+                    "--estimate only works with build".to_owned(),
+                ));
+            }
+            let mempool = match mempool {
+                true => Some(options.rpc.client(Some(datadir), network)?),
+                false => None,
+            };
+            let servers = Servers::new(ServeOptions {
+                bind,
+                electrum,
+                websocket,
+                mempool,
+                rate_limit,
+                rate_burst,
+                api_keys,
+                max_requests,
+                block_cache,
+                tx_cache,
+                tls_cert,
+                tls_key,
+                onion,
+            })?;
+            let indexer = match options.follows() {
+                true => {
+                    kernel::import_blocks(&chainman, args.import_blocks)?;
+                    let indexer =
+                        Indexer::new(&chainman, &index, options.indexer_options_at(&chainman)?);
+                    indexer.build()?;
+                    Some(indexer)
+                }
+                false => None,
+            };
+            if shutdown::requested() {
+                return Ok(());
+            }
+            let follow_options = options.follow_options();
+            let follow = indexer.map(|indexer| {
+                move || {
+                    let tips = Tips::Kernel(&block_tips);
+                    follow::follow(&indexer, store, network, tips, &follow_options)
+                }
+            });
+            servers.run(&chainman, store, network, follow)
+        }
+        Command::Backfill {
+            to_height,
+            index: kinds,
+            skip_coinbase,
+            dup_policy,
+            value_buckets,
+            batch,
+        } => {
+            kernel::import_blocks(&chainman, args.import_blocks)?;
+            let options = IndexerOptions {
+                skip_coinbase,
+                dup_policy,
+                value_buckets: (!value_buckets.is_empty()).then_some(value_buckets),
+                ..batch.indexer_options()
+            };
+            let indexer = Indexer::new(&chainman, &index, options);
+            match kinds.is_empty() {
+                true => indexer.backfill(to_height),
+                false => indexer.add_indexes(kinds.into_iter().collect()),
+            }
+        }
+        Command::Rescan {
+            descriptor,
+            start_height,
+            range,
+        } => {
+            let (first, last) = parse_range(&range)?;
+            let scripts = rescan::scripts(&descriptor, first as u32, last as u32)?;
+            tracing::info!("Rescanning for {} scripts", scripts.len());
+            for found in rescan::rescan(&chainman, store, &scripts, start_height)? {
+                println!(
+                    "Transaction ID: {}, Block Height: {}, Block Location: {}",
+                    found.txid, found.block_height, found.position_in_block
+                );
+            }
+            Ok(())
+        }
+        Command::Stats { command: None } => cli::print_stats(store),
+        Command::Stats {
+            command: Some(command),
+        } => run_stats(&index.query(&chainman), command),
+        Command::Snapshot { .. } => unreachable!("snapshots do not load the kernel"),
+        #[cfg(feature = "grpc")]
+        Command::Grpc { .. } => unreachable!("the gRPC server opens its own index"),
+        Command::Drop { .. } | Command::Compact => {
+            unreachable!("index maintenance does not load the kernel")
+        }
+        Command::Sync { .. } | Command::Bench { .. } | Command::CrashTest { .. } => {
+            unreachable!("sync, bench and crash-test do not load the kernel")
+        }
+        command @ Command::Export { .. } => run_export(store, command),
+        Command::Verify { sample } => verify::verify(&chainman, store, sample),
+        command @ (Command::Query { .. } | Command::Graph { .. }) => {
+            run_query(&index.query(&chainman), network, command)
+        }
+    }
+}
+
+fn run_query(query: &QueryHandle, network: Network, command: Command) -> Result<(), KorndexError> {
+    match command {
+        Command::Query {
+            command: Some(QueryCommand::Address { address }),
+            ..
+        } => cli::query_address(query, &parse_script(network, &address)?),
+        Command::Query {
+            command: Some(QueryCommand::Utxos { address }),
+            ..
+        } => cli::query_utxos(query, &parse_script(network, &address)?),
+        Command::Query {
+            command: Some(QueryCommand::Spend { outpoint }),
+            ..
+        } => cli::query_spend(query, &OutPoint::from_str(&outpoint)?),
+        Command::Query {
+            command: Some(QueryCommand::Tweaks { height, range }),
+            ..
+        } => {
+            let (start, end) = match (height, range) {
+                (Some(height), _) => (height, height),
+                (None, Some(range)) => parse_range(&range)?,
+                (None, None) => unreachable!("clap requires --height or --range"),
+            };
+            cli::query_tweaks(query, start, end)
+        }
+        Command::Query {
+            command: Some(QueryCommand::Filter { block }),
+            ..
+        } => cli::query_filter(query, &block),
+        Command::Query {
+            command: Some(QueryCommand::Block { block }),
+            ..
+        } => cli::query_block(query, &block),
+        Command::Query {
+            command: Some(QueryCommand::BlockStats { block, range }),
+            format,
+            ..
+        } => {
+            let (start, end) = match (block, range) {
+                (Some(block), _) => {
+                    let block_height = parse_block(query, &block)?;
+                    (block_height, block_height)
+                }
+                (None, Some(range)) => parse_range(&range)?,
+                (None, None) => unreachable!("clap requires a block or --range"),
+            };
+            cli::query_block_stats(query, network, start, end, format)
+        }
+        Command::Query {
+            command: Some(QueryCommand::Coinbase { block }),
+            format,
+            ..
+        } => cli::query_coinbase(query, network, &block, format),
+        Command::Query {
+            command:
+                Some(QueryCommand::Witness {
+                    block,
+                    range,
+                    min_size,
+                }),
+            ..
+        } => {
+            let (start, end) = match (block, range) {
+                (Some(block), _) => {
+                    let block_height = parse_block(query, &block)?;
+                    (block_height, block_height)
+                }
+                (None, Some(range)) => parse_range(&range)?,
+                (None, None) => unreachable!("clap requires a block or --range"),
             };
-            let raw_block: Vec<u8> = chainman.read_block_data(&block_index).unwrap().into();
-            let block: bitcoin::Block = deserialize(&raw_block).unwrap();
-            let tx = &block.txdata[txindex.position_in_block];
-            println!("Full transaction: {:#?}", tx);
+            cli::query_witness(query, start, end, min_size)
+        }
+        Command::Query {
+            command: Some(QueryCommand::HeightAt { time }),
+            ..
+        } => cli::query_height_at(query, parse_time(&time)?),
+        Command::Query {
+            command: Some(QueryCommand::Proof { txid }),
+            raw,
+            ..
+        } => cli::query_proof(query, &parse_txid(&txid, raw)?),
+        Command::Query {
+            batch: Some(batch),
+            raw,
+            format,
+            ..
+        } => cli::query_batch(query, network, &batch, raw, format),
+        Command::Query {
+            txid: Some(txid),
+            raw,
+            format,
+            with_tx,
+            ..
+        } => {
+            let txid = resolve_txid(query, &txid, raw)?;
+            cli::query_transaction(query, network, &txid, format, with_tx)
         }
+        Command::Query { .. } => Err(KorndexError::InvalidInput(
+            "Specify a txid, --batch or a query subcommand".into(),
+        )),
+        Command::Graph {
+            txid,
+            depth,
+            direction,
+            raw,
+        } => cli::query_graph(query, &parse_txid(&txid, raw)?, depth, direction),
+        _ => unreachable!("only called for query commands"),
     }
+}
 
+fn run_snapshot(
+    backend: Backend,
+    index_dir: &Path,
+    network: Network,
+    map_size: usize,
+    command: SnapshotCommand,
+) -> Result<(), KorndexError> {
+    match command {
+        SnapshotCommand::Create { out } => {
+            let index = TxIndexStore::open_read_only(backend, index_dir, network)?;
+            let entries = snapshot::create(index.kv(), BufWriter::new(File::create(&out)?))?;
+            tracing::info!("Wrote {} entries to {}", entries, out.display());
+        }
+        SnapshotCommand::Restore { from } => {
+            let index = TxIndexStore::open(backend, index_dir, network, map_size)?;
+            let entries = snapshot::restore(index.kv(), BufReader::new(File::open(&from)?))?;
+            tracing::info!("Restored {} entries from {}", entries, from.display());
+        }
+    }
     Ok(())
 }
+
+/// Builds and optionally follows the index from blocks fetched over RPC,
+/// without loading the kernel.
+fn build_over_rpc(
+    backend: Backend,
+    index_dir: &Path,
+    network: Network,
+    map_size: usize,
+    datadir: Option<&str>,
+    options: &BuildOptions,
+) -> Result<(), KorndexError> {
+    let ranged = options.start_height.is_some()
+        || options.to_height.is_some()
+        || options.from_time.is_some()
+        || options.to_time.is_some();
+    if ranged || options.resume {
+        return Err(KorndexError::Config(
+            "--source rpc builds from genesis to the tip and commits every block, height and time ranges and --resume only work with the kernel".to_owned(),
+        ));
+    }
+    let client = options.rpc.client(datadir, network)?;
+    let index = TxIndexStore::open(backend, index_dir, network, map_size)?;
+    let index = options.prepare(index, backend, map_size)?;
+    let store = index.kv();
+    let indexer_options = options.indexer_options();
+    store.set_durability(indexer_options.durability)?;
+    let indexer = Indexer::without_kernel(&index, indexer_options);
+    rpc_source::sync(&indexer, store, &client)?;
+    if options.follows() && !shutdown::requested() {
+        let follow_options = options.follow_options();
+        follow::follow(
+            &indexer,
+            store,
+            network,
+            Tips::Rpc(&client),
+            &follow_options,
+        )?;
+    }
+    Ok(())
+}
+
+fn run_maintenance(index: TxIndexStore, command: Command) -> Result<(), KorndexError> {
+    match command {
+        Command::Drop { index: kinds } => {
+            let dropped = indexes::drop_indexes(index.kv(), kinds.into_iter().collect())?;
+            match dropped.is_empty() {
+                true => tracing::info!("The index has none of these indexes"),
+                false => tracing::info!(
+                    "Dropped {}, run korndex compact to reclaim the space",
+                    dropped
+                ),
+            }
+        }
+        Command::Compact => {
+            let before = index.kv().disk_size()?;
+            let after = index.compact()?;
+            tracing::info!(
+                "Compacted the index from {} to {} MiB",
+                before / (1024 * 1024),
+                after / (1024 * 1024)
+            );
+        }
+        _ => unreachable!("not an index maintenance command"),
+    }
+    Ok(())
+}
+
+fn run_export(store: &dyn KvStore, command: Command) -> Result<(), KorndexError> {
+    let Command::Export {
+        index,
+        format,
+        out,
+        heights,
+        with_blocks,
+    } = command
+    else {
+        unreachable!("only called for the export command");
+    };
+    let options = export::ExportOptions {
+        index,
+        format,
+        heights: heights.as_deref().map(parse_range).transpose()?,
+        with_blocks,
+    };
+    let rows = export::export(store, &options, &out)?;
+    tracing::info!("Exported {} rows to {}", rows, out.display());
+    Ok(())
+}
+
+fn run_stats(query: &QueryHandle, command: StatsCommand) -> Result<(), KorndexError> {
+    match command {
+        StatsCommand::ScriptTypes { range, per_block } => {
+            let (start, end) = parse_range(&range)?;
+            cli::stats_script_types(query, start, end, per_block)
+        }
+        StatsCommand::ValueDistribution { range, per_block } => {
+            let (start, end) = parse_range(&range)?;
+            cli::stats_value_distribution(query, start, end, per_block)
+        }
+        StatsCommand::Locktime { range, per_block } => {
+            let (start, end) = parse_range(&range)?;
+            cli::stats_locktime(query, start, end, per_block)
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::FromHex;
+use bitcoin::{Amount, Transaction, TxOut, Txid};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use crate::rpc::RpcClient;
+use crate::scripthash::{self, Direction, ScriptHashEntry};
+use crate::spent;
+use crate::store::{KvStore, Table};
+use crate::txindex::TxIndexEntry;
+use crate::utxo::UtxoEntry;
+use crate::{shutdown, KorndexError};
+
+/// Block height reported for unconfirmed transactions.
+pub const MEMPOOL_HEIGHT: i32 = -1;
+
+/// How often the node's mempool is read again.
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An unconfirmed transaction.
+#[derive(Clone, Debug)]
+pub struct MempoolTx {
+    pub tx: Transaction,
+    /// Fee in satoshis, as reported by the node
+    pub fee: u64,
+    /// Virtual size in vbytes
+    pub vsize: u32,
+    /// Outputs spent by the inputs, empty unless every one of them was found
+    /// in the UTXO index or the mempool
+    pub prevouts: Vec<TxOut>,
+    /// Script hashes the transaction funds or spends, with the direction and
+    /// vout or vin
+    scripts: Vec<([u8; 32], Direction, u32)>,
+}
+
+#[derive(Default)]
+struct State {
+    txs: HashMap<Txid, MempoolTx>,
+    /// Txids by witness transaction id
+    wtxids: HashMap<Txid, Txid>,
+    /// Txids of the transactions funding or spending each script, with the
+    /// direction and vout or vin
+    scripts: HashMap<[u8; 32], Vec<(Txid, Direction, u32)>>,
+}
+
+/// The node's unconfirmed transactions, held in memory and indexed by txid,
+/// witness transaction id and script. Kept in sync with the node by
+/// [`Mempool::run`]. Spends are only found for outputs in the UTXO index or
+/// the mempool.
+#[derive(Default)]
+pub struct Mempool {
+    state: RwLock<State>,
+}
+
+impl Mempool {
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Finds an unconfirmed transaction by txid or witness transaction id,
+    /// like [`crate::QueryHandle::locate`], with its block height set to
+    /// [`MEMPOOL_HEIGHT`].
+    pub fn locate(&self, id: &Txid) -> Option<(Txid, bool, TxIndexEntry)> {
+        let state = self.state.read().unwrap();
+        let (txid, via_wtxid) = match state.wtxids.get(id) {
+            Some(txid) if txid != id => (*txid, true),
+            _ => (*id, false),
+        };
+        let found = state.txs.get(&txid)?;
+        Some((
+            txid,
+            via_wtxid,
+            TxIndexEntry {
+                block_height: MEMPOOL_HEIGHT,
+                position_in_block: 0,
+                fee: found.fee,
+                vsize: found.vsize,
+                byte_range: None,
+            },
+        ))
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<MempoolTx> {
+        self.state.read().unwrap().txs.get(txid).cloned()
+    }
+
+    /// Unconfirmed transactions funding or spending the script with
+    /// `scripthash`, with their block height set to [`MEMPOOL_HEIGHT`].
+    pub fn history(&self, scripthash: &[u8; 32]) -> Vec<(Txid, ScriptHashEntry)> {
+        let state = self.state.read().unwrap();
+        let Some(appearances) = state.scripts.get(scripthash) else {
+            return Vec::new();
+        };
+        appearances
+            .iter()
+            .map(|&(txid, direction, index)| {
+                let entry = ScriptHashEntry {
+                    block_height: MEMPOOL_HEIGHT,
+                    position_in_block: 0,
+                    direction,
+                    index,
+                };
+                (txid, entry)
+            })
+            .collect()
+    }
+
+    /// Reads the node's mempool through `rpc` every few seconds until shutdown
+    /// is requested. Failed reads are logged and retried.
+    pub fn run(&self, rpc: &RpcClient, store: &dyn KvStore) {
+        while !shutdown::requested() {
+            if let Err(e) = self.sync(rpc, store) {
+                tracing::warn!("Failed to read the node's mempool: {}", e);
+            }
+            thread::sleep(SYNC_INTERVAL);
+        }
+    }
+
+    /// Adds the transactions the node's mempool gained since the last sync,
+    /// parents first, and removes those it lost to blocks, replacement or
+    /// eviction.
+    pub fn sync(&self, rpc: &RpcClient, store: &dyn KvStore) -> Result<(), KorndexError> {
+        let invalid = || KorndexError::Serialization("Unexpected getrawmempool result".into());
+        let result = rpc.call("getrawmempool", json!([true]))?;
+        let entries = result.as_object().ok_or_else(invalid)?;
+        let mut current = HashMap::with_capacity(entries.len());
+        for (txid, entry) in entries {
+            current.insert(Txid::from_str(txid)?, entry);
+        }
+
+        let gone: Vec<Txid> = {
+            let state = self.state.read().unwrap();
+            state
+                .txs
+                .keys()
+                .filter(|txid| !current.contains_key(txid))
+                .copied()
+                .collect()
+        };
+        for txid in gone.iter() {
+            self.remove(txid);
+        }
+
+        let mut added = Vec::new();
+        {
+            let state = self.state.read().unwrap();
+            for (txid, entry) in current.iter() {
+                if state.txs.contains_key(txid) {
+                    continue;
+                }
+                let ancestors = entry["ancestorcount"].as_u64().ok_or_else(invalid)?;
+                let fee = entry["fees"]["base"].as_f64().ok_or_else(invalid)?;
+                let vsize = entry["vsize"].as_u64().ok_or_else(invalid)?;
+                let fee = Amount::from_btc(fee).map_err(|_| invalid())?.to_sat();
+                added.push((ancestors, *txid, fee, vsize as u32));
+            }
+        }
+        added.sort_unstable_by_key(|(ancestors, ..)| *ancestors);
+        for (_, txid, fee, vsize) in added.iter().copied() {
+            // Gone from the node since getrawmempool
+            let Ok(Value::String(hex)) = rpc.call("getrawtransaction", json!([txid.to_string()]))
+            else {
+                continue;
+            };
+            let tx: Transaction = deserialize(&Vec::<u8>::from_hex(&hex)?)?;
+            self.insert(txid, tx, fee, vsize, store)?;
+        }
+        if !gone.is_empty() || !added.is_empty() {
+            tracing::debug!(
+                "Mempool has {} transactions, {} added and {} removed",
+                self.len(),
+                added.len(),
+                gone.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &self,
+        txid: Txid,
+        tx: Transaction,
+        fee: u64,
+        vsize: u32,
+        store: &dyn KvStore,
+    ) -> Result<(), KorndexError> {
+        let mut state = self.state.write().unwrap();
+        let mut scripts = Vec::new();
+        let mut prevouts = Vec::with_capacity(tx.input.len());
+        for (vin, input) in tx.input.iter().enumerate() {
+            let outpoint = &input.previous_output;
+            let prevout = match state.txs.get(&outpoint.txid) {
+                Some(parent) => parent.tx.output.get(outpoint.vout as usize).cloned(),
+                None => match store.get(Table::Utxo, &spent::outpoint_key(outpoint))? {
+                    Some(data) => {
+                        let utxo = UtxoEntry::decode(&data)?;
+                        Some(TxOut {
+                            value: utxo.value,
+                            script_pubkey: utxo.script_pubkey,
+                        })
+                    }
+                    None => None,
+                },
+            };
+            if let Some(ref prevout) = prevout {
+                let scripthash = scripthash::script_hash(&prevout.script_pubkey);
+                scripts.push((scripthash, Direction::Input, vin as u32));
+            }
+            prevouts.push(prevout);
+        }
+        for (vout, output) in tx.output.iter().enumerate() {
+            let scripthash = scripthash::script_hash(&output.script_pubkey);
+            scripts.push((scripthash, Direction::Output, vout as u32));
+        }
+
+        for &(scripthash, direction, index) in scripts.iter() {
+            state
+                .scripts
+                .entry(scripthash)
+                .or_default()
+                .push((txid, direction, index));
+        }
+        let wtxid = Txid::from_byte_array(tx.compute_wtxid().to_byte_array());
+        state.wtxids.insert(wtxid, txid);
+        let prevouts = prevouts.into_iter().collect::<Option<Vec<_>>>();
+        state.txs.insert(
+            txid,
+            MempoolTx {
+                tx,
+                fee,
+                vsize,
+                prevouts: prevouts.unwrap_or_default(),
+                scripts,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove(&self, txid: &Txid) {
+        let mut state = self.state.write().unwrap();
+        let Some(removed) = state.txs.remove(txid) else {
+            return;
+        };
+        let wtxid = Txid::from_byte_array(removed.tx.compute_wtxid().to_byte_array());
+        state.wtxids.remove(&wtxid);
+        for (scripthash, ..) in removed.scripts.iter() {
+            if let Some(appearances) = state.scripts.get_mut(scripthash) {
+                appearances.retain(|(other, ..)| other != txid);
+                if appearances.is_empty() {
+                    state.scripts.remove(scripthash);
+                }
+            }
+        }
+    }
+}
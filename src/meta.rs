@@ -0,0 +1,335 @@
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::indexes::{IndexKind, IndexSet};
+use crate::store::{Batch, KvStore, Table, TableCompression};
+use crate::txindex::DupPolicy;
+use crate::KorndexError;
+
+const BEST_BLOCK_KEY: &str = "best_block";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const NETWORK_KEY: &str = "network";
+const GENESIS_HASH_KEY: &str = "genesis_hash";
+const CHECKPOINT_KEY: &str = "checkpoint";
+const START_HEIGHT_KEY: &str = "start_height";
+const END_HEIGHT_KEY: &str = "end_height";
+const BUILD_TIMES_KEY: &str = "build_times";
+const INDEXES_KEY: &str = "indexes";
+const VALUE_BUCKETS_KEY: &str = "value_buckets";
+const COMPRESSION_KEY: &str = "compression";
+const TXINDEX_SHARDS_KEY: &str = "txindex_shards";
+const DUP_POLICY_KEY: &str = "dup_policy";
+const COMMIT_GENERATION_KEY: &str = "commit_generation";
+const SHARD_COMMIT_KEY: &str = "shard_commit";
+
+/// The last commit of a txindex shard, what it wrote beyond the commit of
+/// the index holding its undo records.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShardCommit {
+    /// Commits of the shard so far, ahead of those of the index if the
+    /// index's commit was lost
+    pub generation: u64,
+    /// Lowest and highest height of the blocks whose entries it wrote
+    pub heights: Option<(i32, i32)>,
+    /// Entries it deleted, with their values
+    pub deleted: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Version of the on-disk layout, see `migrate` for the changes between
+/// versions.
+pub const SCHEMA_VERSION: u32 = 22;
+
+/// The highest block whose transactions have been fully written to the index.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BestBlock {
+    pub height: i32,
+    pub hash: [u8; 32],
+}
+
+/// When blocks were first and last written to the index, as Unix timestamps.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BuildTimes {
+    pub created: u64,
+    pub updated: u64,
+}
+
+/// Progress of a build that has not reached its target yet, persisted with
+/// every committed batch and removed once the build completes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Checkpoint {
+    /// Height the build started from
+    pub lowest_height: i32,
+    /// Last height committed, whose hash is `tip_hash`
+    pub highest_height: i32,
+    pub tip_hash: [u8; 32],
+    /// Kernel tip height when the build started
+    pub target_height: i32,
+}
+
+pub fn read_best_block(store: &dyn KvStore) -> Result<Option<BestBlock>, KorndexError> {
+    match store.get(Table::Meta, BEST_BLOCK_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_best_block(batch: &mut Batch, best: &BestBlock) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(best)?;
+    batch.put(Table::Meta, BEST_BLOCK_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Whether `batch` records a best block, the last write of every change to
+/// the indexed chain.
+pub fn writes_best_block(batch: &Batch) -> bool {
+    batch
+        .ops
+        .iter()
+        .any(|op| op.table() == Table::Meta && op.key() == BEST_BLOCK_KEY.as_bytes())
+}
+
+pub fn delete_best_block(batch: &mut Batch) {
+    batch.delete(Table::Meta, BEST_BLOCK_KEY.as_bytes(), None);
+}
+
+pub fn read_checkpoint(store: &dyn KvStore) -> Result<Option<Checkpoint>, KorndexError> {
+    match store.get(Table::Meta, CHECKPOINT_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_checkpoint(batch: &mut Batch, checkpoint: &Checkpoint) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(checkpoint)?;
+    batch.put(Table::Meta, CHECKPOINT_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+pub fn delete_checkpoint(batch: &mut Batch) {
+    batch.delete(Table::Meta, CHECKPOINT_KEY.as_bytes(), None);
+}
+
+pub fn read_schema_version(store: &dyn KvStore) -> Result<Option<u32>, KorndexError> {
+    match store.get(Table::Meta, SCHEMA_VERSION_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_schema_version(batch: &mut Batch, version: u32) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&version)?;
+    batch.put(Table::Meta, SCHEMA_VERSION_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Network the index was built for.
+pub fn read_network(store: &dyn KvStore) -> Result<Option<String>, KorndexError> {
+    match store.get(Table::Meta, NETWORK_KEY.as_bytes())? {
+        Some(data) => Ok(Some(String::from_utf8(data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_network(batch: &mut Batch, network: &str) {
+    batch.put(Table::Meta, NETWORK_KEY.as_bytes(), network.as_bytes());
+}
+
+/// Genesis block hash of the chain the index was built for. Indexes stamped
+/// before it was recorded have only their network name.
+pub fn read_genesis_hash(store: &dyn KvStore) -> Result<Option<BlockHash>, KorndexError> {
+    match store.get(Table::Meta, GENESIS_HASH_KEY.as_bytes())? {
+        Some(data) => Ok(Some(BlockHash::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_genesis_hash(batch: &mut Batch, hash: &BlockHash) {
+    batch.put(
+        Table::Meta,
+        GENESIS_HASH_KEY.as_bytes(),
+        hash.to_byte_array().to_vec(),
+    );
+}
+
+/// Lowest indexed height of an index that does not cover the chain from
+/// genesis, because the node had pruned the blocks below it or the build was
+/// given a start height. `None` if the index starts at genesis.
+pub fn read_start_height(store: &dyn KvStore) -> Result<Option<i32>, KorndexError> {
+    match store.get(Table::Meta, START_HEIGHT_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_start_height(batch: &mut Batch, height: i32) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&height)?;
+    batch.put(Table::Meta, START_HEIGHT_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+pub fn delete_start_height(batch: &mut Batch) {
+    batch.delete(Table::Meta, START_HEIGHT_KEY.as_bytes(), None);
+}
+
+/// Highest height of an index built with an end height, which later builds
+/// and following never extend. `None` if the index follows the tip.
+pub fn read_end_height(store: &dyn KvStore) -> Result<Option<i32>, KorndexError> {
+    match store.get(Table::Meta, END_HEIGHT_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_end_height(batch: &mut Batch, height: i32) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&height)?;
+    batch.put(Table::Meta, END_HEIGHT_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+pub fn read_build_times(store: &dyn KvStore) -> Result<Option<BuildTimes>, KorndexError> {
+    match store.get(Table::Meta, BUILD_TIMES_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Records that blocks are being written now, and that the index was created
+/// now if it has no build times yet.
+pub fn touch_build_times(store: &dyn KvStore, batch: &mut Batch) -> Result<(), KorndexError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let created = read_build_times(store)?.map_or(now, |times| times.created);
+    let serialized = bincode::serialize(&BuildTimes {
+        created,
+        updated: now,
+    })?;
+    batch.put(Table::Meta, BUILD_TIMES_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Optional indexes the index was built with. `None` for a new index, and
+/// for indexes built before the selection was recorded, which have them all.
+pub fn read_indexes(store: &dyn KvStore) -> Result<Option<IndexSet>, KorndexError> {
+    match store.get(Table::Meta, INDEXES_KEY.as_bytes())? {
+        // A single byte before there were more than eight indexes
+        Some(data) if data.len() == 1 => Ok(Some(
+            IndexKind::ALL
+                .into_iter()
+                .filter(|kind| data[0] as u16 & 1 << *kind as u16 != 0)
+                .collect(),
+        )),
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+/// [`read_indexes`], counting an existing index without a recorded selection
+/// as having every index.
+pub fn built_indexes(store: &dyn KvStore) -> Result<IndexSet, KorndexError> {
+    Ok(read_indexes(store)?.unwrap_or(IndexSet::ALL))
+}
+
+pub fn write_indexes(batch: &mut Batch, indexes: IndexSet) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&indexes)?;
+    batch.put(Table::Meta, INDEXES_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Which location a repeated txid keeps, recorded when the index is first
+/// built.
+pub fn read_dup_policy(store: &dyn KvStore) -> Result<Option<DupPolicy>, KorndexError> {
+    match store.get(Table::Meta, DUP_POLICY_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_dup_policy(batch: &mut Batch, dup_policy: DupPolicy) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&dup_policy)?;
+    batch.put(Table::Meta, DUP_POLICY_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Bucket boundaries of the value histograms, recorded when the analytics
+/// index is first built.
+pub fn read_value_buckets(store: &dyn KvStore) -> Result<Option<Vec<u64>>, KorndexError> {
+    match store.get(Table::Meta, VALUE_BUCKETS_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_value_buckets(batch: &mut Batch, bounds: &[u64]) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(bounds)?;
+    batch.put(Table::Meta, VALUE_BUCKETS_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+pub fn delete_value_buckets(batch: &mut Batch) {
+    batch.delete(Table::Meta, VALUE_BUCKETS_KEY.as_bytes(), None);
+}
+
+/// Tables whose values are compressed, recorded when the index is created.
+/// `None` for an index storing every value as it is.
+pub fn read_compression(
+    store: &dyn KvStore,
+) -> Result<Option<Vec<TableCompression>>, KorndexError> {
+    match store.get(Table::Meta, COMPRESSION_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_compression(
+    batch: &mut Batch,
+    tables: &[TableCompression],
+) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(tables)?;
+    batch.put(Table::Meta, COMPRESSION_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Number of shards the txindex is split into, recorded when the index is
+/// created. `None` for an index keeping the txindex with the other tables.
+pub fn read_txindex_shards(store: &dyn KvStore) -> Result<Option<usize>, KorndexError> {
+    match store.get(Table::Meta, TXINDEX_SHARDS_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize::<u32>(&data)? as usize)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_txindex_shards(batch: &mut Batch, shards: usize) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&(shards as u32))?;
+    batch.put(Table::Meta, TXINDEX_SHARDS_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+/// Commits of an index split into txindex shards so far, see [`ShardCommit`].
+pub fn read_commit_generation(store: &dyn KvStore) -> Result<u64, KorndexError> {
+    match store.get(Table::Meta, COMMIT_GENERATION_KEY.as_bytes())? {
+        Some(data) => Ok(bincode::deserialize(&data)?),
+        None => Ok(0),
+    }
+}
+
+pub fn write_commit_generation(batch: &mut Batch, generation: u64) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(&generation)?;
+    batch.put(Table::Meta, COMMIT_GENERATION_KEY.as_bytes(), serialized);
+    Ok(())
+}
+
+pub fn read_shard_commit(store: &dyn KvStore) -> Result<Option<ShardCommit>, KorndexError> {
+    match store.get(Table::Meta, SHARD_COMMIT_KEY.as_bytes())? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_shard_commit(batch: &mut Batch, commit: &ShardCommit) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(commit)?;
+    batch.put(Table::Meta, SHARD_COMMIT_KEY.as_bytes(), serialized);
+    Ok(())
+}
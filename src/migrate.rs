@@ -0,0 +1,271 @@
+use serde::Deserialize;
+
+use crate::headers;
+use crate::indexes::{IndexKind, IndexSet};
+use crate::meta::{self, SCHEMA_VERSION};
+use crate::store::{Batch, KvStore, Table};
+use crate::txindex::{DupPolicy, TxIndexEntry};
+use crate::KorndexError;
+
+/// Upgrades an index by one schema version.
+struct Migration {
+    /// What the new version changes
+    change: &'static str,
+    /// Adds the new version's writes to the batch, which is written together
+    /// with the new version, or writes them in batches of its own when they
+    /// are too many to hold at once, in which case they must be safe to
+    /// repeat. `None` when the old layout lacks data that can only be
+    /// recovered by re-reading every block
+    upgrade: Option<fn(&dyn KvStore, &mut Batch) -> Result<(), KorndexError>>,
+}
+
+/// `MIGRATIONS[i]` upgrades an index from schema version `i + 1`. Changing the
+/// on-disk layout means bumping [`SCHEMA_VERSION`] and appending an entry here.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize - 1] = [
+    Migration {
+        change: "txindex keyed by raw 32-byte txids instead of hex strings",
+        upgrade: None,
+    },
+    Migration {
+        change: "scripthash index and generic undo records",
+        upgrade: None,
+    },
+    Migration {
+        change: "spent-outpoint index",
+        upgrade: None,
+    },
+    Migration {
+        change: "silent payments tweak index",
+        upgrade: None,
+    },
+    Migration {
+        change: "wtxid to txid mapping",
+        upgrade: None,
+    },
+    Migration {
+        change: "BIP158 basic filter index",
+        upgrade: None,
+    },
+    Migration {
+        change: "per-block txid lists",
+        upgrade: None,
+    },
+    Migration {
+        change: "UTXO set index and undo records restoring deleted entries",
+        upgrade: None,
+    },
+    Migration {
+        change: "block header index",
+        upgrade: None,
+    },
+    Migration {
+        change: "median time past index",
+        upgrade: Some(add_median_times),
+    },
+    Migration {
+        change: "fee and virtual size in txindex entries",
+        upgrade: None,
+    },
+    Migration {
+        change: "varint-encoded txindex entries",
+        upgrade: Some(reencode_txindex),
+    },
+    Migration {
+        change: "transaction byte ranges in txindex entries",
+        upgrade: Some(keep_txindex),
+    },
+    Migration {
+        change: "block stats index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "output script type index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "transaction graph index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "witness data index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "coinbase index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "output value histogram index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "lock time and sequence index",
+        upgrade: Some(record_indexes),
+    },
+    Migration {
+        change: "duplicate txid locations and the recorded --dup-policy",
+        upgrade: Some(record_dup_policy),
+    },
+];
+
+/// Indexes added after the selection of indexes was first recorded, which an
+/// index without a recorded selection was built without.
+const ADDED_INDEXES: [IndexKind; 7] = [
+    IndexKind::BlockStats,
+    IndexKind::ScriptTypes,
+    IndexKind::Graph,
+    IndexKind::Witness,
+    IndexKind::Coinbase,
+    IndexKind::Analytics,
+    IndexKind::Locktime,
+];
+
+/// Computes the median time past of every indexed block from the header index.
+fn add_median_times(store: &dyn KvStore, batch: &mut Batch) -> Result<(), KorndexError> {
+    let Some(best) = meta::read_best_block(store)? else {
+        return Ok(());
+    };
+    let start_height = meta::read_start_height(store)?.unwrap_or(0);
+    headers::write_median_times(store, batch, start_height, best.height)
+}
+
+/// A txindex entry as bincode serialized it up to schema version 12, with a
+/// 64-bit `usize`.
+#[derive(Deserialize)]
+struct BincodeTxIndexEntry {
+    block_height: i32,
+    position_in_block: u64,
+    fee: u64,
+    vsize: u32,
+}
+
+const BINCODE_TXINDEX_ENTRY_SIZE: usize = 24;
+
+/// Entries [`reencode_txindex`] rewrites in one batch, give or take those of
+/// one key prefix.
+const REENCODE_BATCH_ENTRIES: usize = 100_000;
+
+/// Rewrites every txindex entry in the varint encoding, in batches of its own
+/// so a mainnet txindex is neither held in memory nor written in a single
+/// transaction. The txids are read by their first two bytes, a few thousand
+/// at a time. Entries already rewritten are no longer 24 bytes, so an upgrade
+/// interrupted part way resumes where it stopped. Backends storing the entry's
+/// fields in columns, like SQLite, already hand out the new encoding and are
+/// left alone.
+fn reencode_txindex(store: &dyn KvStore, _batch: &mut Batch) -> Result<(), KorndexError> {
+    let mut chunk = Batch::default();
+    for prefix in 0..=u16::MAX {
+        let mut result = Ok(());
+        store.iter_prefix(Table::TxIndex, &prefix.to_be_bytes(), &mut |key, value| {
+            if value.len() != BINCODE_TXINDEX_ENTRY_SIZE {
+                return true;
+            }
+            match bincode::deserialize::<BincodeTxIndexEntry>(value) {
+                Ok(old) => {
+                    let entry = TxIndexEntry {
+                        block_height: old.block_height,
+                        position_in_block: old.position_in_block as usize,
+                        fee: old.fee,
+                        vsize: old.vsize,
+                        byte_range: None,
+                    };
+                    chunk.put(Table::TxIndex, key.to_vec(), entry.encode());
+                    true
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    false
+                }
+            }
+        })?;
+        result?;
+        if chunk.ops.len() >= REENCODE_BATCH_ENTRIES || prefix == u16::MAX {
+            store.put_batch(&chunk)?;
+            chunk = Batch::default();
+        }
+    }
+    Ok(())
+}
+
+/// Existing txindex entries stay without a byte range, lookups of them fall
+/// back to deserializing the whole block.
+fn keep_txindex(_store: &dyn KvStore, _batch: &mut Batch) -> Result<(), KorndexError> {
+    Ok(())
+}
+
+/// Records the indexes of an index built before the selection was recorded,
+/// every index but [`ADDED_INDEXES`], so those count as not built. They can
+/// be added with `korndex backfill --index`.
+fn record_indexes(store: &dyn KvStore, batch: &mut Batch) -> Result<(), KorndexError> {
+    if meta::read_indexes(store)?.is_none() {
+        let added = ADDED_INDEXES.into_iter().collect();
+        meta::write_indexes(batch, IndexSet::ALL.difference(added))?;
+    }
+    Ok(())
+}
+
+/// Records the default policy an index built before the policy was recorded
+/// kept repeated txids with, unless it holds no blocks yet.
+fn record_dup_policy(store: &dyn KvStore, batch: &mut Batch) -> Result<(), KorndexError> {
+    if meta::read_dup_policy(store)?.is_none() && meta::read_best_block(store)?.is_some() {
+        meta::write_dup_policy(batch, DupPolicy::Latest)?;
+    }
+    Ok(())
+}
+
+/// Fails unless the index is at [`SCHEMA_VERSION`], for opening it without
+/// migrating.
+pub fn check(store: &dyn KvStore) -> Result<(), KorndexError> {
+    match meta::read_schema_version(store)? {
+        Some(SCHEMA_VERSION) => Ok(()),
+        Some(version) => Err(KorndexError::Config(format!(
+            "Index has schema version {} but this korndex expects {}, run korndex build to migrate it",
+            version, SCHEMA_VERSION
+        ))),
+        None => Err(KorndexError::Config(
+            "Index has no schema version, run korndex build first".to_owned(),
+        )),
+    }
+}
+
+/// Brings the index up to [`SCHEMA_VERSION`], stamping a fresh index and
+/// upgrading an old one in place where possible. Fails without touching the
+/// index if any step needs a rebuild or the index is newer than this korndex.
+pub fn migrate(store: &dyn KvStore) -> Result<(), KorndexError> {
+    let version = match meta::read_schema_version(store)? {
+        Some(version) => version,
+        None if store.is_empty(Table::TxIndex)? => {
+            let mut batch = Batch::default();
+            meta::write_schema_version(&mut batch, SCHEMA_VERSION)?;
+            return store.put_batch(&batch);
+        }
+        // Indexes from before schema versioning
+        None => 1,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(KorndexError::Config(format!(
+            "Index has schema version {} but this korndex only supports up to {}, upgrade korndex",
+            version, SCHEMA_VERSION
+        )));
+    }
+
+    let pending = &MIGRATIONS[version as usize - 1..];
+    if let Some(migration) = pending.iter().find(|m| m.upgrade.is_none()) {
+        return Err(KorndexError::Config(format!(
+            "Index has schema version {} but this korndex expects {} and cannot upgrade it in place ({}), rebuild the index",
+            version, SCHEMA_VERSION, migration.change
+        )));
+    }
+    for (to, migration) in (version + 1..).zip(pending) {
+        tracing::info!(
+            "Migrating index to schema version {}: {}",
+            to,
+            migration.change
+        );
+        let mut batch = Batch::default();
+        (migration.upgrade.unwrap())(store, &mut batch)?;
+        meta::write_schema_version(&mut batch, to)?;
+        store.put_batch(&batch)?;
+    }
+    Ok(())
+}
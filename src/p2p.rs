@@ -0,0 +1,326 @@
+//! Builds and follows the index from full blocks downloaded from a single peer
+//! over the Bitcoin P2P network, so korndex can run without access to the
+//! node's data directory. The peer is trusted: headers are checked to connect
+//! and to meet their own target, blocks to match their headers, but nothing
+//! is validated against consensus rules. Connect to a node you run.
+
+use bitcoin::block::Header;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin::p2p::{Address, ServiceFlags};
+use bitcoin::{Block, BlockHash, Network};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::indexes::IndexKind;
+use crate::store::KvStore;
+use crate::{headers, meta, shutdown, Indexer, KorndexError};
+
+/// Blocks requested from the peer at a time.
+const BLOCKS_IN_FLIGHT: usize = 16;
+/// How long the peer has to answer a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often a wait for the peer checks for a shutdown request.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Largest message accepted from the peer, as in Bitcoin Core.
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+/// Size of a message header: magic, command, payload length and checksum.
+const MESSAGE_HEADER_SIZE: usize = 24;
+/// Protocol version announced to the peer, which supports `sendheaders` and
+/// witness blocks.
+const PROTOCOL_VERSION: u32 = 70016;
+
+/// A connection to a peer, answering its pings while waiting for replies.
+struct Peer {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    network: Network,
+    addr: SocketAddr,
+}
+
+/// Indexes the blocks of `peer`'s best chain, e.g. 192.168.1.10:8333, through
+/// `indexer`, disconnecting indexed blocks it reorganized away. With `follow`
+/// the peer's new blocks are indexed as it announces them, until shutdown is
+/// requested. Needs the UTXO index, which supplies the outputs the blocks
+/// spend, and an index built from genesis.
+pub fn sync(
+    indexer: &Indexer,
+    store: &dyn KvStore,
+    network: Network,
+    peer: &str,
+    follow: bool,
+) -> Result<(), KorndexError> {
+    if meta::read_start_height(store)?.is_some() {
+        return Err(KorndexError::Config(
+            "Syncing over P2P needs an index built from genesis".to_owned(),
+        ));
+    }
+    let mut peer = Peer::connect(peer, network)?;
+    if meta::read_best_block(store)?.is_none() {
+        let genesis = genesis_block(network);
+        for block in peer.blocks(&[genesis.block_hash()])? {
+            indexer.connect_block(&block)?;
+        }
+    }
+    meta::built_indexes(store)?.require(IndexKind::Utxo)?;
+
+    while !shutdown::requested() {
+        let headers = peer.headers(locator(store)?)?;
+        if headers.is_empty() {
+            if !follow {
+                tracing::info!("Index is at the tip of {}", peer.addr);
+                return Ok(());
+            }
+            peer.wait_for_announcement()?;
+            continue;
+        }
+        check_headers(store, &headers)?;
+        let hashes: Vec<BlockHash> = headers.iter().map(Header::block_hash).collect();
+        for chunk in hashes.chunks(BLOCKS_IN_FLIGHT) {
+            for block in peer.blocks(chunk)? {
+                indexer.connect_block(&block)?;
+            }
+            if shutdown::requested() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hashes of indexed blocks from the best block down, dense at the top and
+/// exponentially sparser below, ending at genesis, for `getheaders`.
+fn locator(store: &dyn KvStore) -> Result<Vec<BlockHash>, KorndexError> {
+    let Some(best) = meta::read_best_block(store)? else {
+        return Ok(Vec::new());
+    };
+    let mut hashes = vec![BlockHash::from_byte_array(best.hash)];
+    let (mut height, mut step) = (best.height, 1);
+    while height > 0 {
+        height = (height - step).max(0);
+        let header = headers::read_header(store, height)?
+            .ok_or_else(|| KorndexError::Corrupt(format!("No header for block {}", height)))?;
+        hashes.push(header.block_hash());
+        if hashes.len() > 10 {
+            step *= 2;
+        }
+    }
+    Ok(hashes)
+}
+
+/// Checks that `headers` form a chain from an indexed block, each meeting its
+/// own proof of work target.
+fn check_headers(store: &dyn KvStore, headers: &[Header]) -> Result<(), KorndexError> {
+    let first = &headers[0];
+    if headers::read_height(store, &first.prev_blockhash)?.is_none() {
+        return Err(KorndexError::Corrupt(format!(
+            "Headers from the peer start at {}, which does not connect to the index",
+            first.block_hash()
+        )));
+    }
+    let mut prev_hash = first.prev_blockhash;
+    for header in headers {
+        if header.prev_blockhash != prev_hash {
+            return Err(KorndexError::Corrupt(format!(
+                "Header {} from the peer does not connect to the previous one",
+                header.block_hash()
+            )));
+        }
+        prev_hash = header.validate_pow(header.target()).map_err(|_| {
+            KorndexError::Corrupt(format!(
+                "Header {} from the peer has invalid proof of work",
+                header.block_hash()
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+fn p2p_error(message: String) -> KorndexError {
+    KorndexError::Io(io::Error::other(message))
+}
+
+impl Peer {
+    /// Connects to `addr` and completes the version handshake.
+    fn connect(addr: &str, network: Network) -> Result<Self, KorndexError> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            KorndexError::InvalidInput(format!("Cannot resolve peer {}", addr).into())
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, RESPONSE_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        let mut peer = Peer {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            network,
+            addr,
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let local = peer.writer.local_addr()?;
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            Address::new(&addr, ServiceFlags::NONE),
+            Address::new(&local, ServiceFlags::NONE),
+            timestamp as u64 ^ std::process::id() as u64,
+            format!("/korndex:{}/", env!("CARGO_PKG_VERSION")),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        version.relay = false;
+        peer.send(NetworkMessage::Version(version))?;
+
+        let (mut services, mut acked) = (None, false);
+        while services.is_none() || !acked {
+            match peer.receive(RESPONSE_TIMEOUT)? {
+                Some(NetworkMessage::Version(version)) => {
+                    services = Some(version.services);
+                    peer.send(NetworkMessage::Verack)?;
+                }
+                Some(NetworkMessage::Verack) => acked = true,
+                Some(_) => {}
+                None => {
+                    return Err(p2p_error(format!(
+                        "{} did not complete the handshake",
+                        addr
+                    )))
+                }
+            }
+        }
+        let services = services.unwrap();
+        if !services.has(ServiceFlags::NETWORK | ServiceFlags::WITNESS) {
+            return Err(KorndexError::Config(format!(
+                "{} does not serve witness blocks, it offers {}",
+                addr, services
+            )));
+        }
+        // Announce new blocks with their headers rather than an inv
+        peer.send(NetworkMessage::SendHeaders)?;
+        tracing::info!("Connected to {}", addr);
+        Ok(peer)
+    }
+
+    fn send(&mut self, message: NetworkMessage) -> Result<(), KorndexError> {
+        let raw = RawNetworkMessage::new(self.network.magic(), message);
+        self.writer.write_all(&serialize(&raw))?;
+        Ok(())
+    }
+
+    /// The next message from the peer, answering pings, or `None` if none
+    /// arrived within `timeout` or shutdown was requested.
+    fn receive(&mut self, timeout: Duration) -> Result<Option<NetworkMessage>, KorndexError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Wait for a message to start in short steps, to notice shutdown
+            self.reader
+                .get_ref()
+                .set_read_timeout(Some(POLL_INTERVAL))?;
+            loop {
+                match self.reader.fill_buf() {
+                    Ok([]) => return Err(p2p_error(format!("{} disconnected", self.addr))),
+                    Ok(_) => break,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        if shutdown::requested() || Instant::now() >= deadline {
+                            return Ok(None);
+                        }
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            // Then allow the whole message to take a while, blocks are large
+            self.reader
+                .get_ref()
+                .set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+            let mut data = vec![0u8; MESSAGE_HEADER_SIZE];
+            self.reader.read_exact(&mut data)?;
+            let size = u32::from_le_bytes(data[16..20].try_into()?) as usize;
+            if size > MAX_MESSAGE_SIZE {
+                return Err(p2p_error(format!(
+                    "{} sent a message of {} bytes",
+                    self.addr, size
+                )));
+            }
+            data.resize(MESSAGE_HEADER_SIZE + size, 0);
+            self.reader.read_exact(&mut data[MESSAGE_HEADER_SIZE..])?;
+            let raw: RawNetworkMessage = deserialize(&data)?;
+            if *raw.magic() != self.network.magic() {
+                return Err(p2p_error(format!("{} is on another network", self.addr)));
+            }
+            match raw.into_payload() {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                message => return Ok(Some(message)),
+            }
+        }
+    }
+
+    /// Up to 2000 headers of the peer's best chain following the last block of
+    /// `locator` it has.
+    fn headers(&mut self, locator: Vec<BlockHash>) -> Result<Vec<Header>, KorndexError> {
+        let request = GetHeadersMessage::new(locator, BlockHash::all_zeros());
+        self.send(NetworkMessage::GetHeaders(request))?;
+        loop {
+            match self.receive(RESPONSE_TIMEOUT)? {
+                Some(NetworkMessage::Headers(headers)) => return Ok(headers),
+                Some(_) => {}
+                None if shutdown::requested() => return Ok(Vec::new()),
+                None => return Err(p2p_error(format!("{} sent no headers", self.addr))),
+            }
+        }
+    }
+
+    /// Downloads the blocks with `hashes`, returned in the same order.
+    fn blocks(&mut self, hashes: &[BlockHash]) -> Result<Vec<Block>, KorndexError> {
+        let request = hashes.iter().map(|hash| Inventory::WitnessBlock(*hash));
+        self.send(NetworkMessage::GetData(request.collect()))?;
+        let mut received = HashMap::new();
+        while received.len() < hashes.len() {
+            match self.receive(RESPONSE_TIMEOUT)? {
+                Some(NetworkMessage::Block(block)) => {
+                    let hash = block.block_hash();
+                    if !hashes.contains(&hash) {
+                        continue;
+                    }
+                    if !block.check_merkle_root() || !block.check_witness_commitment() {
+                        return Err(KorndexError::Corrupt(format!(
+                            "Block {} from {} does not match its header",
+                            hash, self.addr
+                        )));
+                    }
+                    received.insert(hash, block);
+                }
+                Some(NetworkMessage::NotFound(_)) => {
+                    return Err(p2p_error(format!(
+                        "{} does not have the requested blocks, it may be pruned",
+                        self.addr
+                    )))
+                }
+                Some(_) => {}
+                None if shutdown::requested() => return Ok(Vec::new()),
+                None => return Err(p2p_error(format!("{} sent no blocks", self.addr))),
+            }
+        }
+        Ok(hashes
+            .iter()
+            .map(|hash| received.remove(hash).unwrap())
+            .collect())
+    }
+
+    /// Waits until the peer announces a block or shutdown is requested.
+    fn wait_for_announcement(&mut self) -> Result<(), KorndexError> {
+        while !shutdown::requested() {
+            match self.receive(POLL_INTERVAL)? {
+                Some(NetworkMessage::Headers(_)) | Some(NetworkMessage::Inv(_)) => return Ok(()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+use crate::store::KvStore;
+
+/// Minimum time between progress lines.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Logs periodic progress lines during a build, which walks from
+/// `start_height` towards `target_height` in either direction.
+pub struct Progress {
+    started: Instant,
+    last_log: Instant,
+    start_height: i32,
+    target_height: i32,
+    blocks: u64,
+    transactions: u64,
+}
+
+impl Progress {
+    pub fn new(start_height: i32, target_height: i32) -> Self {
+        let now = Instant::now();
+        Progress {
+            started: now,
+            last_log: now,
+            start_height,
+            target_height,
+            blocks: 0,
+            transactions: 0,
+        }
+    }
+
+    /// Records a committed batch ending at `height`, with `queue_depth`
+    /// batches waiting behind it, logging progress if the last line is old
+    /// enough or the build is done.
+    pub fn batch_committed(
+        &mut self,
+        store: &dyn KvStore,
+        height: i32,
+        blocks: usize,
+        transactions: usize,
+        queue_depth: usize,
+    ) {
+        self.blocks += blocks as u64;
+        self.transactions += transactions as u64;
+        let now = Instant::now();
+        if now.duration_since(self.last_log) < LOG_INTERVAL && height != self.target_height {
+            return;
+        }
+        self.last_log = now;
+
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let blocks_per_sec = self.blocks as f64 / elapsed;
+        let total = ((self.target_height - self.start_height).abs() + 1) as f64;
+        let remaining = (self.target_height - height).abs() as f64;
+        let eta = if blocks_per_sec > 0.0 {
+            format_duration(remaining / blocks_per_sec)
+        } else {
+            "unknown".to_owned()
+        };
+        let size = match store.disk_size() {
+            Ok(bytes) => format!("{:.1} GiB", bytes as f64 / (1024.0 * 1024.0 * 1024.0)),
+            Err(_) => "unknown".to_owned(),
+        };
+        tracing::info!(
+            "Height {}/{} ({:.1}%), {:.1} blocks/s, {} transactions indexed, index size {}, write queue {}, ETA {}",
+            height,
+            self.target_height,
+            100.0 * (total - remaining) / total,
+            blocks_per_sec,
+            self.transactions,
+            size,
+            queue_depth,
+            eta
+        );
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
@@ -0,0 +1,35 @@
+use bitcoin::hashes::Hash;
+use bitcoin::{MerkleBlock, Txid};
+
+use crate::store::{KvStore, Table};
+use crate::txindex::{self, TxIndexEntry};
+use crate::{headers, KorndexError};
+
+/// BIP37 merkle block proving that `txid` is in the block the index places it
+/// in, as returned by Bitcoin Core's `gettxoutproof`. `None` if the txid is
+/// not in the index.
+pub fn merkle_block(store: &dyn KvStore, txid: &Txid) -> Result<Option<MerkleBlock>, KorndexError> {
+    let Some(data) = store.get(Table::TxIndex, &txid.to_byte_array())? else {
+        return Ok(None);
+    };
+    let entry = TxIndexEntry::decode(&data)?;
+    // Both the txid list and the header come from the index, no block is read
+    let txids = txindex::read_block_txids(store, entry.block_height)?.ok_or_else(|| {
+        KorndexError::Corrupt(format!("No txid list for block {}", entry.block_height))
+    })?;
+    // Merkle trees of no transactions don't exist, building one panics
+    if txids.is_empty() {
+        return Err(KorndexError::Corrupt(format!(
+            "Empty txid list for block {}",
+            entry.block_height
+        )));
+    }
+    let header = headers::read_header(store, entry.block_height)?.ok_or_else(|| {
+        KorndexError::Corrupt(format!("No header for block {}", entry.block_height))
+    })?;
+    Ok(Some(MerkleBlock::from_header_txids_with_predicate(
+        &header,
+        &txids,
+        |candidate| candidate == txid,
+    )))
+}
@@ -0,0 +1,135 @@
+//! Python bindings for the query API, built with the `python` feature:
+//!
+//! ```python
+//! import korndex
+//! idx = korndex.open("/home/user/.bitcoin/korndex", datadir="/home/user/.bitcoin")
+//! idx.locate("f4184fc5...")
+//! for height, position, txid in idx.txids(170, 180):
+//!     ...
+//! ```
+
+use bitcoin::consensus::serialize;
+use bitcoin::Txid;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::store::Backend;
+use crate::EmbeddedIndex;
+
+create_exception!(korndex, KorndexError, PyException, "A failed index lookup.");
+
+impl From<crate::KorndexError> for PyErr {
+    fn from(e: crate::KorndexError) -> Self {
+        KorndexError::new_err(e.to_string())
+    }
+}
+
+/// An index opened read-only, with the node's chainstate if a data directory
+/// was given.
+#[pyclass(unsendable, module = "korndex")]
+pub struct Index(EmbeddedIndex);
+
+/// Opens the index below `path` for `network`. Lookups reading transactions
+/// need the node's data directory, `datadir`.
+#[pyfunction]
+#[pyo3(signature = (path, network = "mainnet", datadir = None, backend = "lmdb"))]
+fn open(path: &str, network: &str, datadir: Option<&str>, backend: &str) -> PyResult<Index> {
+    let backend = <Backend as clap::ValueEnum>::from_str(backend, true)
+        .map_err(|_| crate::KorndexError::Config(format!("Unknown storage backend {}", backend)))?;
+    let index = EmbeddedIndex::open(backend, Path::new(path), network, datadir)?;
+    Ok(Index(index))
+}
+
+fn parse_txid(txid: &str) -> Result<Txid, crate::KorndexError> {
+    Ok(Txid::from_str(txid)?)
+}
+
+#[pymethods]
+impl Index {
+    /// Where the transaction with this txid or witness transaction id was
+    /// confirmed, as a dict, or None.
+    fn locate<'py>(&self, py: Python<'py>, txid: &str) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let Some((txid, via_wtxid, entry)) = self.0.query().locate(&parse_txid(txid)?)? else {
+            return Ok(None);
+        };
+        let found = PyDict::new_bound(py);
+        found.set_item("txid", txid.to_string())?;
+        found.set_item("via_wtxid", via_wtxid)?;
+        found.set_item("block_height", entry.block_height)?;
+        found.set_item("position_in_block", entry.position_in_block)?;
+        found.set_item("fee", entry.fee)?;
+        found.set_item("vsize", entry.vsize)?;
+        found.set_item("fee_rate", entry.fee_rate())?;
+        Ok(Some(found))
+    }
+
+    /// The consensus-serialized transaction, or None. Needs the node's data
+    /// directory.
+    fn get_tx<'py>(&self, py: Python<'py>, txid: &str) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let Some(found) = self.0.query().transaction(&parse_txid(txid)?)? else {
+            return Ok(None);
+        };
+        Ok(Some(PyBytes::new_bound(py, &serialize(&found.tx))))
+    }
+
+    /// Iterates over `(height, position, txid)` of every transaction in the
+    /// blocks from `start` to `end` inclusive, a block at a time.
+    fn txids(slf: Py<Self>, start: i32, end: i32) -> TxidIter {
+        TxidIter {
+            index: slf,
+            next_height: start,
+            end,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// Iterator returned by [`Index::txids`].
+#[pyclass(unsendable, module = "korndex")]
+pub struct TxidIter {
+    index: Py<Index>,
+    next_height: i32,
+    end: i32,
+    pending: VecDeque<(i32, usize, String)>,
+}
+
+#[pymethods]
+impl TxidIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<(i32, usize, String)>> {
+        while self.pending.is_empty() && self.next_height <= self.end {
+            let height = self.next_height;
+            self.next_height += 1;
+            // Blocks outside the index are skipped
+            let index = self.index.borrow(py);
+            let Some(txids) = index.0.query().block_txids(height)? else {
+                continue;
+            };
+            self.pending.extend(
+                txids
+                    .iter()
+                    .enumerate()
+                    .map(|(position, txid)| (height, position, txid.to_string())),
+            );
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "korndex")]
+fn module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    m.add_class::<Index>()?;
+    m.add_class::<TxidIter>()?;
+    m.add("KorndexError", m.py().get_type_bound::<KorndexError>())?;
+    Ok(())
+}
@@ -0,0 +1,626 @@
+use bitcoin::bip158::FilterHeader;
+use bitcoin::block::Header;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::{BlockHash, MerkleBlock, OutPoint, Script, Transaction, TxOut, Txid};
+use libbitcoinkernel_sys::ChainstateManager;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+
+use crate::analytics::{self, ValueHistogram};
+use crate::blockstats::{self, BlockStats};
+use crate::coinbase::{self, CoinbaseEntry};
+use crate::graph::{self, GraphEntry};
+use crate::indexes::IndexKind;
+use crate::locktime::{self, LocktimeCounts};
+use crate::mempool::Mempool;
+use crate::scripthash::{self, ScriptHashEntry};
+use crate::scripttypes::{self, ScriptTypeCounts};
+use crate::spent::{self, SpendEntry};
+use crate::store::{height_key, KvStore, Table};
+use crate::txindex::{self, TxIndexEntry};
+use crate::utxo::{self, UtxoEntry};
+use crate::witness::{self, WitnessEntry};
+use crate::KorndexError;
+use crate::{headers, kernel, meta, proof, silentpayments};
+
+/// A transaction found in the index.
+#[derive(Debug)]
+pub struct TransactionLookup {
+    pub txid: Txid,
+    /// Whether the lookup went through the witness transaction id
+    pub via_wtxid: bool,
+    pub entry: TxIndexEntry,
+    pub tx: Transaction,
+    /// Header of the block confirming the transaction
+    pub header: Header,
+}
+
+/// Read-only lookups against an index, resolving positions to transactions
+/// through the kernel's block data. Without a chainstate manager only the
+/// lookups answered by the index alone work. One handle can be shared by
+/// every serve worker, each lookup runs in its own read transaction.
+pub struct QueryHandle<'a> {
+    chainman: Option<&'a ChainstateManager>,
+    store: &'a dyn KvStore,
+    /// Spent outputs of the block prevouts were last read from, so the
+    /// transactions of one block share a single undo data read
+    spent_outputs: Mutex<Option<(i32, Vec<Vec<TxOut>>)>>,
+    /// Unconfirmed transactions also returned by txid and address lookups
+    mempool: Option<&'a Mempool>,
+}
+
+// Shared across server threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<QueryHandle<'static>>();
+};
+
+impl<'a> QueryHandle<'a> {
+    pub fn new(chainman: Option<&'a ChainstateManager>, store: &'a dyn KvStore) -> Self {
+        QueryHandle {
+            chainman,
+            store,
+            spent_outputs: Mutex::new(None),
+            mempool: None,
+        }
+    }
+
+    /// Also finds unconfirmed transactions from `mempool` in
+    /// [`QueryHandle::locate`] and [`QueryHandle::address_history`], with
+    /// their block height set to [`crate::mempool::MEMPOOL_HEIGHT`].
+    pub fn with_mempool(mut self, mempool: &'a Mempool) -> Self {
+        self.mempool = Some(mempool);
+        self
+    }
+
+    /// Finds a transaction by txid or witness transaction id in the index,
+    /// returning its txid, whether `id` was a witness transaction id and its
+    /// entry.
+    pub fn locate(&self, id: &Txid) -> Result<Option<(Txid, bool, TxIndexEntry)>, KorndexError> {
+        match self.locate_confirmed(id)? {
+            Some(found) => Ok(Some(found)),
+            None => Ok(self.mempool.and_then(|mempool| mempool.locate(id))),
+        }
+    }
+
+    /// [`QueryHandle::locate`] without the mempool.
+    fn locate_confirmed(
+        &self,
+        id: &Txid,
+    ) -> Result<Option<(Txid, bool, TxIndexEntry)>, KorndexError> {
+        self.require(IndexKind::Txid)?;
+        // Resolve witness transaction ids to their txid first
+        let (txid, via_wtxid) = match self.store.get(Table::Wtxid, &id.to_byte_array())? {
+            Some(data) => (Txid::from_slice(&data)?, true),
+            None => (*id, false),
+        };
+        let Some(data) = self.store.get(Table::TxIndex, &txid.to_byte_array())? else {
+            return Ok(None);
+        };
+        Ok(Some((txid, via_wtxid, TxIndexEntry::decode(&data)?)))
+    }
+
+    /// Every location of `txid` in the index, ascending. More than one only
+    /// for a repeated coinbase txid in an index built with
+    /// `--dup-policy all`.
+    pub fn locations(&self, txid: &Txid) -> Result<Vec<TxIndexEntry>, KorndexError> {
+        self.require(IndexKind::Txid)?;
+        txindex::read_locations(self.store, txid)
+    }
+
+    /// Txids in the index starting with the hex `prefix`, at most `limit` of
+    /// them. A prefix of the raw little-endian bytes is a range lookup, a
+    /// prefix in the usual display order has to scan the whole txindex.
+    pub fn txids_with_prefix(
+        &self,
+        prefix: &str,
+        raw: bool,
+        limit: usize,
+    ) -> Result<Vec<Txid>, KorndexError> {
+        if prefix.is_empty() || prefix.len() > 64 || !prefix.bytes().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(KorndexError::InvalidInput(
+                format!("Invalid txid prefix {}", prefix).into(),
+            ));
+        }
+        self.require(IndexKind::Txid)?;
+        let prefix = prefix.to_ascii_lowercase();
+        let key_prefix = match raw {
+            true => Vec::<u8>::from_hex(&prefix[..prefix.len() / 2 * 2])?,
+            false => Vec::new(),
+        };
+        let mut txids = Vec::new();
+        let mut result = Ok(());
+        self.store.iter_prefix(
+            Table::TxIndex,
+            &key_prefix,
+            &mut |key, _| match Txid::from_slice(key) {
+                Ok(txid) => {
+                    let hex = match raw {
+                        true => key.to_lower_hex_string(),
+                        false => txid.to_string(),
+                    };
+                    if hex.starts_with(&prefix) {
+                        txids.push(txid);
+                    }
+                    txids.len() < limit
+                }
+                Err(e) => {
+                    result = Err(e.into());
+                    false
+                }
+            },
+        )?;
+        result?;
+        Ok(txids)
+    }
+
+    /// Looks up a confirmed transaction by txid or witness transaction id.
+    pub fn transaction(&self, id: &Txid) -> Result<Option<TransactionLookup>, KorndexError> {
+        let chainman = self.chainman()?;
+        let Some((txid, via_wtxid, entry)) = self.locate_confirmed(id)? else {
+            return Ok(None);
+        };
+        let Ok(ref block_index) = chainman.get_block_index_by_height(entry.block_height) else {
+            return Err(KorndexError::NotFound(format!(
+                "Transaction {} is indexed at height {}, above the node's active chain",
+                txid, entry.block_height
+            )));
+        };
+        let raw_block = kernel::read_block_data(chainman, block_index, entry.block_height)?;
+        Ok(Some(TransactionLookup {
+            txid,
+            via_wtxid,
+            tx: txindex::read_transaction(&raw_block, &entry)?,
+            entry,
+            header: kernel::header(&raw_block)?,
+        }))
+    }
+
+    /// Looks up many transactions by txid or witness transaction id, reading
+    /// the index in a single transaction and every block only once. Returns
+    /// the transactions found in block order and the ids not in the index.
+    pub fn transactions(
+        &self,
+        ids: &[Txid],
+    ) -> Result<(Vec<TransactionLookup>, Vec<Txid>), KorndexError> {
+        self.require(IndexKind::Txid)?;
+        let keys: Vec<[u8; 32]> = ids.iter().map(|id| id.to_byte_array()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let wtxid_values = self.store.get_many(Table::Wtxid, &keys)?;
+        let mut resolved = Vec::with_capacity(ids.len());
+        for (id, data) in ids.iter().zip(wtxid_values) {
+            match data {
+                Some(data) => resolved.push((Txid::from_slice(&data)?, true)),
+                None => resolved.push((*id, false)),
+            }
+        }
+
+        let keys: Vec<[u8; 32]> = resolved
+            .iter()
+            .map(|(txid, _)| txid.to_byte_array())
+            .collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let values = self.store.get_many(Table::TxIndex, &keys)?;
+        let mut by_height: BTreeMap<i32, Vec<(Txid, bool, TxIndexEntry)>> = BTreeMap::new();
+        let mut missing = Vec::new();
+        for ((id, (txid, via_wtxid)), data) in ids.iter().zip(resolved).zip(values) {
+            match data {
+                Some(data) => {
+                    let entry = TxIndexEntry::decode(&data)?;
+                    by_height
+                        .entry(entry.block_height)
+                        .or_default()
+                        .push((txid, via_wtxid, entry));
+                }
+                None => missing.push(*id),
+            }
+        }
+
+        let mut found = Vec::new();
+        for (block_height, mut txs) in by_height {
+            let block = self.block(block_height)?;
+            txs.sort_by_key(|(_, _, entry)| entry.position_in_block);
+            for (txid, via_wtxid, entry) in txs {
+                let tx = block.txdata.get(entry.position_in_block).ok_or_else(|| {
+                    KorndexError::Corrupt(format!(
+                        "Transaction {} is indexed at position {} of block {}, which has {} transactions",
+                        txid,
+                        entry.position_in_block,
+                        block_height,
+                        block.txdata.len()
+                    ))
+                })?;
+                found.push(TransactionLookup {
+                    txid,
+                    via_wtxid,
+                    tx: tx.clone(),
+                    entry,
+                    header: block.header,
+                });
+            }
+        }
+        Ok((found, missing))
+    }
+
+    /// Outputs spent by the inputs of a transaction found with
+    /// [`QueryHandle::transaction`], read from its block's undo data. Empty for
+    /// a coinbase.
+    pub fn prevouts(&self, found: &TransactionLookup) -> Result<Vec<TxOut>, KorndexError> {
+        let position = found.entry.position_in_block;
+        if position == 0 {
+            return Ok(Vec::new());
+        }
+        let block_height = found.entry.block_height;
+        let chainman = self.chainman()?;
+        let mut cached = self.spent_outputs.lock().unwrap();
+        if cached.as_ref().map(|(height, _)| *height) != Some(block_height) {
+            let block_index = chainman.get_block_index_by_height(block_height)?;
+            // The block has at least `position + 1` transactions, which is all
+            // the kernel needs to know to read its undo data
+            let spent_outputs = kernel::spent_outputs(chainman, &block_index, position + 1)?;
+            *cached = Some((block_height, spent_outputs));
+        }
+        let (_, spent_outputs) = cached.as_ref().unwrap();
+        spent_outputs.get(position - 1).cloned().ok_or_else(|| {
+            KorndexError::Corrupt(format!(
+                "No undo data for position {} of block {}",
+                position, block_height
+            ))
+        })
+    }
+
+    /// Transactions funding or spending `script`, in block order, followed by
+    /// the unconfirmed ones.
+    pub fn address_history(
+        &self,
+        script: &Script,
+    ) -> Result<Vec<(Txid, ScriptHashEntry)>, KorndexError> {
+        self.require(IndexKind::Address)?;
+        let scripthash = scripthash::script_hash(script);
+        let mut history = scripthash::history(self.store, &scripthash)?;
+        if let Some(mempool) = self.mempool {
+            // Confirmed by a block the index connected before the mempool
+            // caught up
+            let confirmed: HashSet<Txid> = history.iter().map(|(txid, _)| *txid).collect();
+            history.extend(
+                mempool
+                    .history(&scripthash)
+                    .into_iter()
+                    .filter(|(txid, _)| !confirmed.contains(txid)),
+            );
+        }
+        Ok(history)
+    }
+
+    /// Unspent outputs paying to `script`, in block order. Only needs the
+    /// index, not the blocks.
+    pub fn utxos(&self, script: &Script) -> Result<Vec<(OutPoint, UtxoEntry)>, KorndexError> {
+        self.require(IndexKind::Utxo)?;
+        utxo::unspent(self.store, &scripthash::script_hash(script))
+    }
+
+    /// The transaction spending `outpoint`, or `None` if it is unspent in the
+    /// indexed blocks.
+    pub fn spend(&self, outpoint: &OutPoint) -> Result<Option<(Txid, SpendEntry)>, KorndexError> {
+        self.require(IndexKind::Spent)?;
+        let Some(data) = self
+            .store
+            .get(Table::Spent, &spent::outpoint_key(outpoint))?
+        else {
+            return Ok(None);
+        };
+        let entry = SpendEntry::decode(&data)?;
+        let txids =
+            txindex::read_block_txids(self.store, entry.block_height)?.ok_or_else(|| {
+                KorndexError::Corrupt(format!("No txid list for block {}", entry.block_height))
+            })?;
+        let txid = txindex::txid_at(&txids, entry.block_height, entry.position_in_block as usize)?;
+        Ok(Some((txid, entry)))
+    }
+
+    /// Silent payments tweaks of the blocks in `start..=end` with their block
+    /// height.
+    pub fn tweaks(&self, start: i32, end: i32) -> Result<Vec<(i32, Vec<u8>)>, KorndexError> {
+        self.require(IndexKind::Tweaks)?;
+        self.check_indexed(start)?;
+        let mut tweaks = Vec::new();
+        for block_height in start..=end {
+            let Some(data) = self.store.get(Table::Tweaks, &height_key(block_height))? else {
+                continue;
+            };
+            tweaks.extend(
+                data.chunks_exact(silentpayments::TWEAK_SIZE)
+                    .map(|tweak| (block_height, tweak.to_vec())),
+            );
+        }
+        Ok(tweaks)
+    }
+
+    /// Stats of the blocks in `start..=end` with their block height.
+    pub fn block_stats(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> Result<Vec<(i32, BlockStats)>, KorndexError> {
+        self.require(IndexKind::BlockStats)?;
+        self.check_indexed(start)?;
+        self.check_indexed(end)?;
+        let mut stats = Vec::new();
+        for block_height in start..=end {
+            if let Some(block) = blockstats::read_block_stats(self.store, block_height)? {
+                stats.push((block_height, block));
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Output script type counts of the blocks in `start..=end` with their
+    /// block height.
+    pub fn script_types(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> Result<Vec<(i32, ScriptTypeCounts)>, KorndexError> {
+        self.require(IndexKind::ScriptTypes)?;
+        self.check_indexed(start)?;
+        self.check_indexed(end)?;
+        let mut counts = Vec::new();
+        for block_height in start..=end {
+            if let Some(block) = scripttypes::read_counts(self.store, block_height)? {
+                counts.push((block_height, block));
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Txid and coinbase entry of the block at `block_height`.
+    pub fn coinbase(
+        &self,
+        block_height: i32,
+    ) -> Result<Option<(Txid, CoinbaseEntry)>, KorndexError> {
+        self.require(IndexKind::Coinbase)?;
+        self.check_indexed(block_height)?;
+        let Some(entry) = coinbase::read_coinbase(self.store, block_height)? else {
+            return Ok(None);
+        };
+        let txid = txindex::read_block_txids(self.store, block_height)?
+            .and_then(|txids| txids.first().copied())
+            .ok_or_else(|| KorndexError::Corrupt(format!("No txids for block {}", block_height)))?;
+        Ok(Some((txid, entry)))
+    }
+
+    /// Bucket boundaries of the value histograms, and the histograms of the
+    /// blocks in `start..=end` with their block height.
+    pub fn value_distribution(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> Result<(Vec<u64>, Vec<(i32, ValueHistogram)>), KorndexError> {
+        self.require(IndexKind::Analytics)?;
+        self.check_indexed(start)?;
+        self.check_indexed(end)?;
+        let bounds = meta::read_value_buckets(self.store)?.ok_or_else(|| {
+            KorndexError::Corrupt("No value buckets recorded for the analytics index".to_owned())
+        })?;
+        let mut histograms = Vec::new();
+        for block_height in start..=end {
+            if let Some(block) = analytics::read_histogram(self.store, block_height, &bounds)? {
+                histograms.push((block_height, block));
+            }
+        }
+        Ok((bounds, histograms))
+    }
+
+    /// Lock time and sequence counts of the blocks in `start..=end` with their
+    /// block height.
+    pub fn locktimes(
+        &self,
+        start: i32,
+        end: i32,
+    ) -> Result<Vec<(i32, LocktimeCounts)>, KorndexError> {
+        self.require(IndexKind::Locktime)?;
+        self.check_indexed(start)?;
+        self.check_indexed(end)?;
+        let mut counts = Vec::new();
+        for block_height in start..=end {
+            if let Some(block) = locktime::read_counts(self.store, block_height)? {
+                counts.push((block_height, block));
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Relatives of `txid` up to `depth` steps away, see [`graph::walk`].
+    /// `None` if `txid` is not indexed.
+    pub fn graph(
+        &self,
+        txid: &Txid,
+        depth: u32,
+        direction: graph::Direction,
+    ) -> Result<Option<Vec<GraphEntry>>, KorndexError> {
+        self.require(IndexKind::Graph)?;
+        graph::walk(self.store, txid, depth, direction)
+    }
+
+    /// Taproot annexes and witness items of at least `min_size` bytes of the
+    /// blocks in `start..=end`, with the block height and txid of their
+    /// transaction. Only items of at least [`witness::MIN_ITEM_SIZE`] bytes
+    /// are indexed.
+    pub fn witness_data(
+        &self,
+        start: i32,
+        end: i32,
+        min_size: u32,
+    ) -> Result<Vec<(i32, Txid, WitnessEntry)>, KorndexError> {
+        self.require(IndexKind::Witness)?;
+        self.check_indexed(start)?;
+        self.check_indexed(end)?;
+        let mut found = Vec::new();
+        for block_height in start..=end {
+            let entries = witness::read_entries(self.store, block_height)?;
+            if entries.is_empty() {
+                continue;
+            }
+            let txids = txindex::read_block_txids(self.store, block_height)?.ok_or_else(|| {
+                KorndexError::Corrupt(format!("No txids for block {}", block_height))
+            })?;
+            for entry in entries.into_iter().filter(|entry| entry.size >= min_size) {
+                let txid = *txids.get(entry.position_in_block as usize).ok_or_else(|| {
+                    KorndexError::Corrupt(format!(
+                        "Witness entry of block {} past its last transaction",
+                        block_height
+                    ))
+                })?;
+                found.push((block_height, txid, entry));
+            }
+        }
+        Ok(found)
+    }
+
+    /// BIP37 merkle block proving the inclusion of `txid`, or `None` if it is
+    /// not in the index.
+    pub fn merkle_proof(&self, txid: &Txid) -> Result<Option<MerkleBlock>, KorndexError> {
+        self.require(IndexKind::Txid)?;
+        proof::merkle_block(self.store, txid)
+    }
+
+    /// Txids of the indexed block at `block_height`, in block order.
+    pub fn block_txids(&self, block_height: i32) -> Result<Option<Vec<Txid>>, KorndexError> {
+        self.check_indexed(block_height)?;
+        txindex::read_block_txids(self.store, block_height)
+    }
+
+    /// Height of the highest indexed block.
+    pub fn best_height(&self) -> Result<Option<i32>, KorndexError> {
+        Ok(meta::read_best_block(self.store)?.map(|best| best.height))
+    }
+
+    /// Inclusive range of heights the index covers, `None` while it is empty.
+    pub fn coverage(&self) -> Result<Option<(i32, i32)>, KorndexError> {
+        let Some(best_height) = self.best_height()? else {
+            return Ok(None);
+        };
+        let start_height = meta::read_start_height(self.store)?.unwrap_or(0);
+        Ok(Some((start_height, best_height)))
+    }
+
+    /// [`KorndexError::NotFound`] for a txid missing from the index, naming
+    /// the heights it was looked up in.
+    pub fn txid_not_found(&self, id: &Txid) -> KorndexError {
+        match self.coverage() {
+            Ok(Some((start_height, best_height))) => KorndexError::NotFound(format!(
+                "Transaction {} not found in the index (coverage: heights {}..{})",
+                id, start_height, best_height
+            )),
+            Ok(None) => {
+                KorndexError::NotFound(format!("Transaction {} not found, the index is empty", id))
+            }
+            Err(e) => e,
+        }
+    }
+
+    /// Height of the indexed block with `hash`.
+    pub fn block_height(&self, hash: &BlockHash) -> Result<Option<i32>, KorndexError> {
+        headers::read_height(self.store, hash)
+    }
+
+    /// The indexed block that was the tip at `time`, judged by median time
+    /// past, with its median time past.
+    pub fn height_at(&self, time: u32) -> Result<Option<(i32, u32)>, KorndexError> {
+        let Some(block_height) = headers::height_at(self.store, time)? else {
+            return Ok(None);
+        };
+        let median_time =
+            headers::read_median_time(self.store, block_height)?.ok_or_else(|| {
+                KorndexError::Corrupt(format!("No median time past for block {}", block_height))
+            })?;
+        Ok(Some((block_height, median_time)))
+    }
+
+    /// Header of the indexed block at `block_height`.
+    pub fn header(&self, block_height: i32) -> Result<Option<Header>, KorndexError> {
+        headers::read_header(self.store, block_height)
+    }
+
+    /// The BIP158 basic filter of the block at `block_height` and its filter
+    /// header. Indexes not starting at genesis have no filter headers.
+    pub fn filter(
+        &self,
+        block_height: i32,
+    ) -> Result<(Vec<u8>, Option<FilterHeader>), KorndexError> {
+        self.require(IndexKind::Filters)?;
+        self.check_indexed(block_height)?;
+        let filter = self
+            .store
+            .get(Table::Filters, &height_key(block_height))?
+            .ok_or_else(|| {
+                KorndexError::NotFound(format!("No filter for block {}", block_height))
+            })?;
+        let header = match self
+            .store
+            .get(Table::FilterHeaders, &height_key(block_height))?
+        {
+            Some(data) => Some(FilterHeader::from_slice(&data)?),
+            None if meta::read_start_height(self.store)?.is_some() => None,
+            None => {
+                return Err(KorndexError::NotFound(format!(
+                    "No filter header for block {}",
+                    block_height
+                )))
+            }
+        };
+        Ok((filter, header))
+    }
+
+    /// Fails with [`KorndexError::Config`] if the index was built without
+    /// `kind`.
+    fn require(&self, kind: IndexKind) -> Result<(), KorndexError> {
+        meta::built_indexes(self.store)?.require(kind)
+    }
+
+    /// Fails for heights below the start of an index that does not cover the
+    /// chain from genesis, with [`KorndexError::Pruned`] if the node no longer
+    /// has the block either, and above the end of an index built with an end
+    /// height.
+    fn check_indexed(&self, block_height: i32) -> Result<(), KorndexError> {
+        match meta::read_start_height(self.store)? {
+            Some(start_height) if block_height < start_height => {
+                if let Some(chainman) = self.chainman {
+                    kernel::block_header(chainman, block_height)?;
+                }
+                Err(KorndexError::NotFound(format!(
+                    "Block {} is below the indexed range starting at height {}, index it with korndex backfill",
+                    block_height, start_height
+                )))
+            }
+            _ => match meta::read_end_height(self.store)? {
+                Some(end_height) if block_height > end_height => {
+                    Err(KorndexError::NotFound(format!(
+                        "Block {} is above the indexed range ending at height {}",
+                        block_height, end_height
+                    )))
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn block(&self, block_height: i32) -> Result<bitcoin::Block, KorndexError> {
+        let chainman = self.chainman()?;
+        let block_index = chainman.get_block_index_by_height(block_height)?;
+        let raw_block = kernel::read_block_data(chainman, &block_index, block_height)?;
+        Ok(deserialize(&raw_block)?)
+    }
+
+    fn chainman(&self) -> Result<&'a ChainstateManager, KorndexError> {
+        self.chainman.ok_or_else(|| {
+            KorndexError::Config(
+                "This lookup reads the node's block data, which the query was opened without"
+                    .to_owned(),
+            )
+        })
+    }
+}
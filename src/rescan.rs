@@ -0,0 +1,127 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::{Block, ScriptBuf, Txid};
+use libbitcoinkernel_sys::ChainstateManager;
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+use std::collections::{BTreeSet, HashSet};
+use std::str::FromStr;
+
+use crate::indexes::IndexKind;
+use crate::store::KvStore;
+use crate::{kernel, meta, scripthash, KorndexError};
+
+/// Location of a transaction paying to or spending from a scanned script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Match {
+    pub block_height: i32,
+    pub position_in_block: u32,
+    pub txid: Txid,
+}
+
+/// scriptPubKeys of `descriptor` at the derivation indexes `start..=end`, for
+/// every path of a multipath descriptor. Descriptors without a wildcard
+/// yield their single script.
+pub fn scripts(descriptor: &str, start: u32, end: u32) -> Result<Vec<ScriptBuf>, KorndexError> {
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor)?;
+    let mut scripts = Vec::new();
+    for descriptor in descriptor.into_single_descriptors()? {
+        let indexes = match descriptor.has_wildcard() {
+            true => start..=end,
+            false => 0..=0,
+        };
+        for index in indexes {
+            scripts.push(descriptor.at_derivation_index(index)?.script_pubkey());
+        }
+    }
+    Ok(scripts)
+}
+
+/// Every transaction from `start_height` up paying to or spending from one of
+/// `scripts`, in chain order. Heights the index covers are answered from the
+/// scripthash index, blocks below an index not starting at genesis, or every
+/// block if the index was built without the address index, are read from the
+/// node.
+pub fn rescan(
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    scripts: &[ScriptBuf],
+    start_height: i32,
+) -> Result<Vec<Match>, KorndexError> {
+    if !meta::built_indexes(store)?.contains(IndexKind::Address) {
+        let best = meta::read_best_block(store)?
+            .ok_or_else(|| KorndexError::Config("The index is empty".to_owned()))?;
+        tracing::info!("Index has no address index, scanning the blocks");
+        return scan_chain(chainman, scripts, start_height, best.height);
+    }
+    let index_start = meta::read_start_height(store)?.unwrap_or(0);
+    let mut matches = Vec::new();
+    if start_height < index_start {
+        tracing::info!(
+            "Scanning blocks {} to {} below the index",
+            start_height,
+            index_start - 1
+        );
+        matches = scan_chain(chainman, scripts, start_height, index_start - 1)?;
+    }
+    matches.extend(scan_index(store, scripts, start_height.max(index_start))?);
+    Ok(matches)
+}
+
+/// Matches from `start_height` up found through the scripthash index.
+pub fn scan_index(
+    store: &dyn KvStore,
+    scripts: &[ScriptBuf],
+    start_height: i32,
+) -> Result<Vec<Match>, KorndexError> {
+    let mut matches = BTreeSet::new();
+    for script in scripts {
+        for (txid, entry) in scripthash::history(store, &scripthash::script_hash(script))? {
+            if entry.block_height >= start_height {
+                matches.insert(Match {
+                    block_height: entry.block_height,
+                    position_in_block: entry.position_in_block,
+                    txid,
+                });
+            }
+        }
+    }
+    Ok(matches.into_iter().collect())
+}
+
+/// Matches in the blocks `start..=end` found by reading each block and its
+/// undo data from the node.
+pub fn scan_chain(
+    chainman: &ChainstateManager,
+    scripts: &[ScriptBuf],
+    start: i32,
+    end: i32,
+) -> Result<Vec<Match>, KorndexError> {
+    let scripts: HashSet<&ScriptBuf> = scripts.iter().collect();
+    let mut matches = Vec::new();
+    for height in start..=end {
+        let block_index = chainman.get_block_index_by_height(height)?;
+        let raw_block = kernel::read_block_data(chainman, &block_index, height)?;
+        let block: Block = deserialize(&raw_block)?;
+        let spent_outputs = kernel::spent_outputs(chainman, &block_index, block.txdata.len())?;
+        for (position, tx) in block.txdata.iter().enumerate() {
+            let prevouts = position
+                .checked_sub(1)
+                .and_then(|i| spent_outputs.get(i))
+                .map_or(&[][..], |prevouts| &prevouts[..]);
+            let pays = tx
+                .output
+                .iter()
+                .any(|output| scripts.contains(&output.script_pubkey));
+            let spends = prevouts
+                .iter()
+                .any(|prevout| scripts.contains(&prevout.script_pubkey));
+            if pays || spends {
+                matches.push(Match {
+                    block_height: height,
+                    position_in_block: position as u32,
+                    txid: tx.compute_txid(),
+                });
+            }
+        }
+    }
+    Ok(matches)
+}
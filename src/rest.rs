@@ -0,0 +1,503 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Block, BlockHash, Network, Txid};
+use libbitcoinkernel_sys::ChainstateManager;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::access::{self, AccessControl, Denied};
+use crate::cache::{CachedBlock, CachedTx, QueryCache};
+use crate::headers;
+use crate::indexes::IndexKind;
+use crate::json;
+use crate::kernel;
+use crate::listener;
+use crate::mempool::{Mempool, MempoolTx};
+use crate::meta;
+use crate::proof;
+use crate::scripthash::{self, ScriptHashEntry};
+use crate::store::{KvStore, Table};
+use crate::tls::TlsConfig;
+use crate::txindex::{self, TxIndexEntry};
+use crate::utxo;
+use crate::{KorndexError, QueryHandle};
+
+/// Maximum number of transactions returned by `/address/:addr/txs`, matching
+/// Esplora's page size for confirmed transactions.
+const ADDRESS_TXS_LIMIT: usize = 50;
+
+/// Maximum number of headers returned by `/headers/:start/:count`, matching
+/// Bitcoin Core's REST interface.
+const HEADERS_LIMIT: u32 = 2000;
+
+enum Reply {
+    Json(Value),
+    Text(String),
+    Binary(Vec<u8>),
+    NotFound(String),
+    /// A 404 with a JSON error object, for lookups whose miss says more than
+    /// a message
+    NotFoundJson(Value),
+    BadRequest(String),
+    Unauthorized(String),
+    TooManyRequests(String),
+    ServerError(String),
+}
+
+/// Serves the Esplora-compatible subset of routes korndex can answer from its
+/// indexes, and raw blocks and headers like a node's REST interface.
+struct Api<'a> {
+    chainman: &'a ChainstateManager,
+    store: &'a dyn KvStore,
+    network: Network,
+    /// Unconfirmed transactions, also returned by the transaction and address
+    /// routes
+    mempool: Option<&'a Mempool>,
+    access: &'a AccessControl,
+    cache: &'a QueryCache,
+}
+
+/// How the REST API is served, besides where and from what.
+pub struct ServeOptions<'a> {
+    pub access: &'a AccessControl,
+    /// Requests handled at a time [default: number of CPUs]
+    pub max_requests: Option<usize>,
+    pub tls: Option<&'a TlsConfig>,
+    /// Blocks and transactions recently read for requests
+    pub cache: &'a QueryCache,
+}
+
+pub fn serve(
+    bind: &str,
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    network: Network,
+    mempool: Option<&Mempool>,
+    options: &ServeOptions,
+) -> Result<(), KorndexError> {
+    let server = match (listener::unix_path(bind), options.tls) {
+        #[cfg(unix)]
+        (Some(path), _) => Server::http_unix(path),
+        #[cfg(feature = "tls")]
+        (None, Some(tls)) => Server::https(
+            bind,
+            tiny_http::SslConfig {
+                certificate: tls.certificate.clone(),
+                private_key: tls.private_key.clone(),
+            },
+        ),
+        _ => Server::http(bind),
+    }
+    .map_err(std::io::Error::other)?;
+    let api = Api {
+        chainman,
+        store,
+        network,
+        mempool,
+        access: options.access,
+        cache: options.cache,
+    };
+    tracing::info!("Serving REST API on {}", bind);
+
+    // Each worker handles one request at a time, the others wait in line
+    let workers = options
+        .max_requests
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get()))
+        .max(1);
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                while let Ok(request) = server.recv() {
+                    api.handle(request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+impl Api<'_> {
+    fn handle(&self, request: Request) {
+        let path = request
+            .url()
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let client = request.remote_addr().map(|addr| addr.ip());
+        let reply = match self.access.check(client, api_key(&request).as_deref()) {
+            Ok(()) => self.route(&path),
+            Err(Denied::Unauthorized) => {
+                Ok(Reply::Unauthorized("Missing or unknown API key".to_owned()))
+            }
+            Err(Denied::RateLimited) => {
+                Ok(Reply::TooManyRequests("Rate limit exceeded".to_owned()))
+            }
+        };
+        let reply = reply.unwrap_or_else(|e| {
+            tracing::warn!("Failed to handle {}: {}", path, e);
+            match e {
+                KorndexError::NotFound(message) => Reply::NotFound(message),
+                KorndexError::Pruned(_) => Reply::NotFound(e.to_string()),
+                KorndexError::InvalidInput(_) => Reply::BadRequest(e.to_string()),
+                _ => Reply::ServerError(e.to_string()),
+            }
+        });
+        let (status, content_type, body) = match reply {
+            Reply::Json(value) => (200, "application/json", value.to_string().into_bytes()),
+            Reply::Text(text) => (200, "text/plain", text.into_bytes()),
+            Reply::Binary(data) => (200, "application/octet-stream", data),
+            Reply::NotFound(message) => (404, "text/plain", message.into_bytes()),
+            Reply::NotFoundJson(value) => (404, "application/json", value.to_string().into_bytes()),
+            Reply::BadRequest(message) => (400, "text/plain", message.into_bytes()),
+            Reply::Unauthorized(message) => (401, "text/plain", message.into_bytes()),
+            Reply::TooManyRequests(message) => (429, "text/plain", message.into_bytes()),
+            Reply::ServerError(message) => (500, "text/plain", message.into_bytes()),
+        };
+        let response = Response::from_data(body)
+            .with_status_code(status)
+            .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+        if let Err(e) = request.respond(response) {
+            tracing::warn!("Failed to send response for {}: {}", path, e);
+        }
+    }
+
+    fn route(&self, path: &str) -> Result<Reply, KorndexError> {
+        let Some(route) = Route::parse(path)? else {
+            return Ok(Reply::NotFound("Unknown route".to_owned()));
+        };
+        // Routes answered from an index the build may have left out
+        if let Some(kind) = route.needs() {
+            if let Err(e) = meta::built_indexes(self.store)?.require(kind) {
+                return Ok(Reply::NotFound(e.to_string()));
+            }
+        }
+        match route {
+            Route::Tx(txid) => self.tx(&txid, false),
+            Route::TxHex(txid) => self.tx(&txid, true),
+            Route::MerkleProof(txid) => self.merkle_proof(&txid),
+            Route::BlockTxids(hash) => self.block_txids(&hash),
+            Route::RawBlock(hash) => self.raw_block(&hash),
+            Route::BlockHeader(hash) => self.block_header(&hash),
+            Route::Headers { start, count } => self.headers(start, count),
+            Route::AddressTxs(address) => self.address_txs(address),
+            Route::AddressUtxo(address) => self.address_utxo(address),
+        }
+    }
+
+    fn tx(&self, txid: &Txid, hex: bool) -> Result<Reply, KorndexError> {
+        let entry: TxIndexEntry = match self.store.get(Table::TxIndex, &txid.to_byte_array())? {
+            Some(data) => TxIndexEntry::decode(&data)?,
+            None => match self.mempool.and_then(|mempool| mempool.get(txid)) {
+                Some(unconfirmed) if hex => return Ok(Reply::Text(serialize_hex(&unconfirmed.tx))),
+                Some(unconfirmed) => {
+                    return Ok(Reply::Json(self.unconfirmed_tx_json(&unconfirmed)))
+                }
+                None => return self.txid_not_found(txid),
+            },
+        };
+        let cached = self.cached_tx(entry.block_height, entry.position_in_block)?;
+        if hex {
+            return Ok(Reply::Text(serialize_hex(&cached.tx)));
+        }
+        Ok(Reply::Json(json::tx_json(
+            &cached.tx,
+            &cached.prevouts,
+            self.network,
+            entry.block_height,
+            &cached.header,
+        )))
+    }
+
+    fn merkle_proof(&self, txid: &Txid) -> Result<Reply, KorndexError> {
+        match proof::merkle_block(self.store, txid)? {
+            Some(merkle_block) => Ok(Reply::Text(serialize_hex(&merkle_block))),
+            None => self.txid_not_found(txid),
+        }
+    }
+
+    /// A 404 naming the heights the index covers, so clients can tell a
+    /// transaction outside them from one that does not exist.
+    fn txid_not_found(&self, txid: &Txid) -> Result<Reply, KorndexError> {
+        let query = QueryHandle::new(Some(self.chainman), self.store);
+        let coverage = query.coverage()?.map(|(start_height, best_height)| {
+            json!({ "start_height": start_height, "best_height": best_height })
+        });
+        Ok(Reply::NotFoundJson(json!({
+            "error": {
+                "code": 404,
+                "message": query.txid_not_found(txid).to_string(),
+                "txid": txid.to_string(),
+                "coverage": coverage,
+            }
+        })))
+    }
+
+    fn block_txids(&self, hash: &BlockHash) -> Result<Reply, KorndexError> {
+        let Some(block_height) = headers::read_height(self.store, hash)? else {
+            return Ok(Reply::NotFound("Block not found".to_owned()));
+        };
+        let txids: Vec<String> = txindex::read_block_txids(self.store, block_height)?
+            .ok_or_else(|| {
+                KorndexError::Corrupt(format!("No txid list for block {}", block_height))
+            })?
+            .iter()
+            .map(Txid::to_string)
+            .collect();
+        Ok(Reply::Json(json!(txids)))
+    }
+
+    fn raw_block(&self, hash: &BlockHash) -> Result<Reply, KorndexError> {
+        let Some(block_height) = headers::read_height(self.store, hash)? else {
+            return Ok(Reply::NotFound("Block not found".to_owned()));
+        };
+        let block_index = self
+            .chainman
+            .get_block_index_by_height(block_height)
+            .map_err(|_| KorndexError::NotFound(format!("No block at height {}", block_height)))?;
+        let raw_block = kernel::read_block_data(self.chainman, &block_index, block_height)?;
+        Ok(Reply::Binary(raw_block))
+    }
+
+    fn block_header(&self, hash: &BlockHash) -> Result<Reply, KorndexError> {
+        let Some(block_height) = headers::read_height(self.store, hash)? else {
+            return Ok(Reply::NotFound("Block not found".to_owned()));
+        };
+        let header = headers::read_header(self.store, block_height)?.ok_or_else(|| {
+            KorndexError::Corrupt(format!("No header for block {}", block_height))
+        })?;
+        Ok(Reply::Text(serialize_hex(&header)))
+    }
+
+    /// Up to `count` consecutive indexed headers from height `start`, hex
+    /// encoded back to back like Bitcoin Core's `/rest/headers`.
+    fn headers(&self, start: u32, count: u32) -> Result<Reply, KorndexError> {
+        if count == 0 || count > HEADERS_LIMIT {
+            return Err(KorndexError::InvalidInput(
+                format!("Header count must be between 1 and {}", HEADERS_LIMIT).into(),
+            ));
+        }
+        let start = i32::try_from(start)
+            .map_err(|_| KorndexError::InvalidInput("Start height out of range".into()))?;
+        let mut hex = String::new();
+        for block_height in (start..=i32::MAX).take(count as usize) {
+            match headers::read_header(self.store, block_height)? {
+                Some(header) => hex.push_str(&serialize_hex(&header)),
+                None => break,
+            }
+        }
+        if hex.is_empty() {
+            return Ok(Reply::NotFound(format!("No header at height {}", start)));
+        }
+        Ok(Reply::Text(hex))
+    }
+
+    fn address_txs(&self, address: &str) -> Result<Reply, KorndexError> {
+        let script = Address::from_str(address)?
+            .require_network(self.network)?
+            .script_pubkey();
+
+        let scripthash = scripthash::script_hash(&script);
+        let mut locations = BTreeSet::new();
+        for value in self.store.get_dups(Table::ScriptHash, &scripthash)? {
+            let entry = ScriptHashEntry::decode(&value)?;
+            locations.insert((entry.block_height, entry.position_in_block as usize));
+        }
+
+        // Unconfirmed first, as Esplora lists them, then newest first, reading
+        // each block only once
+        let mut txs = Vec::new();
+        if let Some(mempool) = self.mempool {
+            let txids: BTreeSet<Txid> = mempool
+                .history(&scripthash)
+                .into_iter()
+                .map(|(txid, _)| txid)
+                .collect();
+            for txid in txids {
+                // Confirmed by a block the index connected before the mempool
+                // caught up
+                if self
+                    .store
+                    .get(Table::TxIndex, &txid.to_byte_array())?
+                    .is_some()
+                {
+                    continue;
+                }
+                if let Some(unconfirmed) = mempool.get(&txid) {
+                    txs.push(self.unconfirmed_tx_json(&unconfirmed));
+                }
+            }
+        }
+        let mut current: Option<(i32, Arc<CachedBlock>)> = None;
+        for (block_height, position) in locations.into_iter().rev().take(ADDRESS_TXS_LIMIT) {
+            if current.as_ref().map(|(height, _)| *height) != Some(block_height) {
+                current = Some((block_height, self.read_block(block_height)?));
+            }
+            let (_, block) = current.as_ref().unwrap();
+            txs.push(self.tx_json(block, block_height, position)?);
+        }
+        Ok(Reply::Json(Value::Array(txs)))
+    }
+
+    fn address_utxo(&self, address: &str) -> Result<Reply, KorndexError> {
+        let script = Address::from_str(address)?
+            .require_network(self.network)?
+            .script_pubkey();
+        let utxos = utxo::unspent(self.store, &scripthash::script_hash(&script))?;
+
+        let mut values = Vec::with_capacity(utxos.len());
+        for (outpoint, utxo) in utxos {
+            let header = headers::read_header(self.store, utxo.block_height)?.ok_or_else(|| {
+                KorndexError::Corrupt(format!("No header for block {}", utxo.block_height))
+            })?;
+            values.push(json!({
+                "txid": outpoint.txid.to_string(),
+                "vout": outpoint.vout,
+                "status": {
+                    "confirmed": true,
+                    "block_height": utxo.block_height,
+                    "block_hash": header.block_hash().to_string(),
+                    "block_time": header.time,
+                },
+                "value": utxo.value.to_sat(),
+            }));
+        }
+        Ok(Reply::Json(Value::Array(values)))
+    }
+
+    /// Hash of the indexed block at `block_height`, which the cache is keyed
+    /// by.
+    fn block_hash(&self, block_height: i32) -> Result<BlockHash, KorndexError> {
+        let header = headers::read_header(self.store, block_height)?.ok_or_else(|| {
+            KorndexError::Corrupt(format!("No header for block {}", block_height))
+        })?;
+        Ok(header.block_hash())
+    }
+
+    /// The block at `block_height` with the outputs it spends, from the cache
+    /// or read and deserialized through the kernel.
+    fn read_block(&self, block_height: i32) -> Result<Arc<CachedBlock>, KorndexError> {
+        let hash = self.block_hash(block_height)?;
+        if let Some(block) = self.cache.blocks.get(&hash) {
+            return Ok(block);
+        }
+        let block_index = self
+            .chainman
+            .get_block_index_by_height(block_height)
+            .map_err(|_| KorndexError::NotFound(format!("No block at height {}", block_height)))?;
+        let raw_block = kernel::read_block_data(self.chainman, &block_index, block_height)?;
+        let block: Block = deserialize(&raw_block)?;
+        let spent_outputs = kernel::spent_outputs(self.chainman, &block_index, block.txdata.len())?;
+        let block = Arc::new(CachedBlock {
+            block,
+            spent_outputs,
+        });
+        self.cache.blocks.insert(hash, block.clone());
+        Ok(block)
+    }
+
+    /// The transaction at `position` of the block at `block_height`, from the
+    /// cache or its block.
+    fn cached_tx(&self, block_height: i32, position: usize) -> Result<Arc<CachedTx>, KorndexError> {
+        let key = (self.block_hash(block_height)?, position);
+        if let Some(tx) = self.cache.txs.get(&key) {
+            return Ok(tx);
+        }
+        let tx = Arc::new(self.read_block(block_height)?.tx(position)?);
+        self.cache.txs.insert(key, tx.clone());
+        Ok(tx)
+    }
+
+    fn unconfirmed_tx_json(&self, unconfirmed: &MempoolTx) -> Value {
+        json::unconfirmed_tx_json(
+            &unconfirmed.tx,
+            &unconfirmed.prevouts,
+            self.network,
+            unconfirmed.fee,
+        )
+    }
+
+    /// Renders the transaction at `position` of `block` in Esplora's JSON
+    /// shape.
+    fn tx_json(
+        &self,
+        block: &CachedBlock,
+        block_height: i32,
+        position: usize,
+    ) -> Result<Value, KorndexError> {
+        let (tx, prevouts) = block.get(position)?;
+        Ok(json::tx_json(
+            tx,
+            prevouts,
+            self.network,
+            block_height,
+            &block.block.header,
+        ))
+    }
+}
+
+/// A request path of the API with its parsed parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route<'a> {
+    Tx(Txid),
+    TxHex(Txid),
+    MerkleProof(Txid),
+    BlockTxids(BlockHash),
+    RawBlock(BlockHash),
+    BlockHeader(BlockHash),
+    Headers { start: u32, count: u32 },
+    AddressTxs(&'a str),
+    AddressUtxo(&'a str),
+}
+
+impl<'a> Route<'a> {
+    /// Parses `path`, without its query string. Returns `None` for unknown
+    /// routes and an error for malformed parameters of a known one.
+    pub fn parse(path: &'a str) -> Result<Option<Self>, KorndexError> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        Ok(Some(match segments.as_slice() {
+            ["tx", txid] => Route::Tx(Txid::from_str(txid)?),
+            ["tx", txid, "hex"] => Route::TxHex(Txid::from_str(txid)?),
+            ["tx", txid, "merkleblock-proof"] => Route::MerkleProof(Txid::from_str(txid)?),
+            ["block", hash, "txids"] => Route::BlockTxids(BlockHash::from_str(hash)?),
+            ["block", hash, "raw"] => Route::RawBlock(BlockHash::from_str(hash)?),
+            ["block", hash, "header"] => Route::BlockHeader(BlockHash::from_str(hash)?),
+            ["headers", start, count] => Route::Headers {
+                start: parse_number(start)?,
+                count: parse_number(count)?,
+            },
+            ["address", address, "txs"] => Route::AddressTxs(address),
+            ["address", address, "utxo"] => Route::AddressUtxo(address),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// The index answering the route, if the build may have left it out.
+    fn needs(&self) -> Option<IndexKind> {
+        match self {
+            Route::Tx(_) | Route::TxHex(_) | Route::MerkleProof(_) => Some(IndexKind::Txid),
+            Route::AddressTxs(_) => Some(IndexKind::Address),
+            Route::AddressUtxo(_) => Some(IndexKind::Utxo),
+            _ => None,
+        }
+    }
+}
+
+fn parse_number(segment: &str) -> Result<u32, KorndexError> {
+    segment
+        .parse()
+        .map_err(|_| KorndexError::InvalidInput(format!("Invalid number {}", segment).into()))
+}
+
+/// API key of `request`, from an `X-API-Key` or `Authorization: Bearer`
+/// header.
+fn api_key(request: &Request) -> Option<String> {
+    request.headers().iter().find_map(|header| {
+        access::header_api_key(header.field.as_str().as_str(), header.value.as_str())
+    })
+}
@@ -0,0 +1,100 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bitcoin::Network;
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::KorndexError;
+
+/// Credentials for bitcoind's RPC interface.
+#[derive(Clone, Debug)]
+pub enum RpcAuth {
+    /// The cookie file bitcoind writes on startup, read again for every call
+    /// so a restarted node is picked up
+    Cookie(PathBuf),
+    UserPass(String, String),
+}
+
+/// A minimal JSON-RPC client for bitcoind.
+pub struct RpcClient {
+    url: String,
+    auth: RpcAuth,
+}
+
+/// URL of bitcoind's RPC server for `network` on localhost, at the default
+/// port.
+pub fn default_url(network: Network) -> &'static str {
+    match network {
+        Network::Testnet => "http://127.0.0.1:18332",
+        Network::Signet => "http://127.0.0.1:38332",
+        Network::Regtest => "http://127.0.0.1:18443",
+        _ => "http://127.0.0.1:8332",
+    }
+}
+
+/// Cookie file bitcoind writes for `network` in `datadir`.
+pub fn default_cookie(datadir: &Path, network: Network) -> PathBuf {
+    let dir = match network {
+        Network::Testnet => datadir.join("testnet3"),
+        Network::Signet => datadir.join("signet"),
+        Network::Regtest => datadir.join("regtest"),
+        _ => datadir.to_path_buf(),
+    };
+    dir.join(".cookie")
+}
+
+impl RpcClient {
+    /// A client for the RPC server at `url`, e.g. http://127.0.0.1:8332.
+    pub fn new(url: &str, auth: RpcAuth) -> Self {
+        RpcClient {
+            url: url.to_owned(),
+            auth,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Calls `method` with positional `params`, returning its result.
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, KorndexError> {
+        let credentials = match self.auth {
+            RpcAuth::Cookie(ref path) => fs::read_to_string(path).map_err(|e| {
+                KorndexError::Config(format!("Failed to read {}: {}", path.display(), e))
+            })?,
+            RpcAuth::UserPass(ref user, ref password) => format!("{}:{}", user, password),
+        };
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": "korndex",
+            "method": method,
+            "params": params,
+        });
+        let response = ureq::post(&self.url)
+            .set(
+                "Authorization",
+                &format!("Basic {}", BASE64_STANDARD.encode(credentials.trim())),
+            )
+            .send_json(request);
+        // bitcoind answers failed calls with an error status and a JSON body
+        let mut response: Value = match response {
+            Ok(response) => response.into_json()?,
+            Err(ureq::Error::Status(401, _)) => {
+                return Err(KorndexError::Config(format!(
+                    "bitcoind at {} rejected the RPC credentials",
+                    self.url
+                )))
+            }
+            Err(ureq::Error::Status(_, response)) => response.into_json()?,
+            Err(e) => return Err(KorndexError::Io(io::Error::other(e))),
+        };
+        match response["error"].take() {
+            Value::Null => Ok(response["result"].take()),
+            error => Err(KorndexError::Io(io::Error::other(format!(
+                "RPC {} failed: {}",
+                method, error
+            )))),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::hex::FromHex;
+use bitcoin::{Block, BlockHash};
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::rpc::RpcClient;
+use crate::store::KvStore;
+use crate::{headers, meta, shutdown, Indexer, KorndexError};
+
+/// Blocks fetched from the node in parallel before they are indexed in order.
+const FETCH_WINDOW: usize = 16;
+
+/// Indexes the blocks of the node's active chain above the index, fetched
+/// with `getblock <hash> 0` through `client`, for machines without access to
+/// the node's data directory. Indexed blocks the node reorganized away are
+/// disconnected first. Like following over ZMQ, the outputs the blocks spend
+/// come from the UTXO index, so the index has to start at genesis.
+pub fn sync(
+    indexer: &Indexer,
+    store: &dyn KvStore,
+    client: &RpcClient,
+) -> Result<(), KorndexError> {
+    if meta::read_start_height(store)?.is_some() {
+        return Err(KorndexError::Config(
+            "Fetching blocks over RPC needs an index built from genesis".to_owned(),
+        ));
+    }
+    let tip = client
+        .call("getblockcount", json!([]))?
+        .as_i64()
+        .ok_or_else(|| KorndexError::Serialization("Unexpected getblockcount result".into()))?
+        as i32;
+    let start_height = match meta::read_best_block(store)? {
+        Some(best) => fork_point(store, client, best.height.min(tip))? + 1,
+        None => 0,
+    };
+    if start_height > tip {
+        tracing::debug!("Index is already up to date");
+        return Ok(());
+    }
+    tracing::info!("Fetching blocks {} to {} over RPC", start_height, tip);
+    let heights: Vec<i32> = (start_height..=tip).collect();
+    for window in heights.chunks(FETCH_WINDOW) {
+        if shutdown::requested() {
+            return Ok(());
+        }
+        let blocks = window
+            .par_iter()
+            .map(|block_height| fetch_block(client, *block_height))
+            .collect::<Result<Vec<Block>, KorndexError>>()?;
+        for block in blocks.iter() {
+            indexer.connect_block(block)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs [`sync`] again every `poll_interval` until shutdown is requested.
+pub fn follow(
+    indexer: &Indexer,
+    store: &dyn KvStore,
+    client: &RpcClient,
+    poll_interval: Duration,
+) -> Result<(), KorndexError> {
+    tracing::info!("Following the chain tip over RPC");
+    while !shutdown::requested() {
+        let next_poll = Instant::now() + poll_interval;
+        while Instant::now() < next_poll && !shutdown::requested() {
+            thread::sleep(Duration::from_millis(200));
+        }
+        sync(indexer, store, client)?;
+    }
+    Ok(())
+}
+
+/// Highest indexed height up to `from` whose block is still in the node's
+/// active chain.
+fn fork_point(store: &dyn KvStore, client: &RpcClient, from: i32) -> Result<i32, KorndexError> {
+    for block_height in (0..=from).rev() {
+        let header = headers::read_header(store, block_height)?.ok_or_else(|| {
+            KorndexError::Corrupt(format!("No header for block {}", block_height))
+        })?;
+        if block_hash(client, block_height)? == header.block_hash() {
+            return Ok(block_height);
+        }
+    }
+    Err(KorndexError::Config(
+        "The index shares no blocks with the node's chain, is it on another network?".to_owned(),
+    ))
+}
+
+/// Hash of the node's active block at `block_height`.
+fn block_hash(client: &RpcClient, block_height: i32) -> Result<BlockHash, KorndexError> {
+    match client.call("getblockhash", json!([block_height]))? {
+        Value::String(hash) => Ok(BlockHash::from_str(&hash)?),
+        _ => Err(KorndexError::Serialization(
+            "Unexpected getblockhash result".into(),
+        )),
+    }
+}
+
+fn fetch_block(client: &RpcClient, block_height: i32) -> Result<Block, KorndexError> {
+    let hash = block_hash(client, block_height)?;
+    match client.call("getblock", json!([hash.to_string(), 0]))? {
+        Value::String(hex) => Ok(deserialize(&Vec::<u8>::from_hex(&hex)?)?),
+        _ => Err(KorndexError::Serialization(
+            "Unexpected getblock result".into(),
+        )),
+    }
+}
@@ -0,0 +1,134 @@
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::FromHex;
+use bitcoin::{Address, Network, Script, ScriptBuf, Txid};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::store::{KvStore, Table};
+use crate::txindex;
+use crate::KorndexError;
+
+/// Whether a script was touched by a transaction output (funding) or input
+/// (spending).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Output = 0,
+    Input = 1,
+}
+
+/// One appearance of a script in the chain. Encoded big-endian into a fixed
+/// 13-byte value so LMDB keeps each script's history sorted by height.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptHashEntry {
+    pub block_height: i32,
+    pub position_in_block: u32,
+    pub direction: Direction,
+    /// vout for outputs, vin for inputs
+    pub index: u32,
+}
+
+pub const ENTRY_SIZE: usize = 13;
+
+pub fn script_hash(script: &Script) -> [u8; 32] {
+    sha256::Hash::hash(script.as_bytes()).to_byte_array()
+}
+
+/// Parses an address for `network`, or a hex-encoded scriptPubKey.
+pub fn parse_script(network: Network, address: &str) -> Result<ScriptBuf, KorndexError> {
+    match Address::from_str(address) {
+        Ok(address) => Ok(address.require_network(network)?.script_pubkey()),
+        Err(_) => Ok(ScriptBuf::from_hex(address)?),
+    }
+}
+
+impl ScriptHashEntry {
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&(self.block_height as u32).to_be_bytes());
+        buf[4..8].copy_from_slice(&self.position_in_block.to_be_bytes());
+        buf[8] = self.direction as u8;
+        buf[9..13].copy_from_slice(&self.index.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() != ENTRY_SIZE {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid scripthash entry length {}",
+                data.len()
+            )));
+        }
+        let direction = match data[8] {
+            0 => Direction::Output,
+            1 => Direction::Input,
+            other => {
+                return Err(KorndexError::Corrupt(format!(
+                    "Invalid scripthash entry direction {}",
+                    other
+                )))
+            }
+        };
+        Ok(ScriptHashEntry {
+            block_height: u32::from_be_bytes(data[0..4].try_into()?) as i32,
+            position_in_block: u32::from_be_bytes(data[4..8].try_into()?),
+            direction,
+            index: u32::from_be_bytes(data[9..13].try_into()?),
+        })
+    }
+}
+
+/// Appearances of the script with `scripthash` in the block at
+/// `block_height`, without the txids.
+pub fn block_entries(
+    store: &dyn KvStore,
+    scripthash: &[u8; 32],
+    block_height: i32,
+) -> Result<Vec<ScriptHashEntry>, KorndexError> {
+    let mut entries = Vec::new();
+    let mut result = Ok(());
+    // Entries sort by height, so the scan stops past the block
+    store.iter_dups(Table::ScriptHash, scripthash, &mut |value| {
+        match ScriptHashEntry::decode(value) {
+            Ok(entry) if entry.block_height == block_height => entries.push(entry),
+            Ok(entry) => return entry.block_height < block_height,
+            Err(e) => {
+                result = Err(e);
+                return false;
+            }
+        }
+        true
+    })?;
+    result.map(|()| entries)
+}
+
+/// Every appearance of the script with `scripthash` and the txid of the
+/// transaction it appears in, in chain order as Electrum's
+/// `blockchain.scripthash.get_history` lists it. Txids come from the stored
+/// txid lists, so no block is read.
+pub fn history(
+    store: &dyn KvStore,
+    scripthash: &[u8; 32],
+) -> Result<Vec<(Txid, ScriptHashEntry)>, KorndexError> {
+    let mut by_height: BTreeMap<i32, Vec<ScriptHashEntry>> = BTreeMap::new();
+    for value in store.get_dups(Table::ScriptHash, scripthash)? {
+        let entry = ScriptHashEntry::decode(&value)?;
+        by_height.entry(entry.block_height).or_default().push(entry);
+    }
+
+    let mut history = Vec::new();
+    for (block_height, entries) in by_height {
+        let txids = txindex::read_block_txids(store, block_height)?.ok_or_else(|| {
+            KorndexError::Corrupt(format!("No txid list for block {}", block_height))
+        })?;
+        for entry in entries {
+            let txid = *txids.get(entry.position_in_block as usize).ok_or_else(|| {
+                KorndexError::Corrupt(format!(
+                    "Block Height: {}, Block Location: {}, past the end of the txid list",
+                    block_height, entry.position_in_block
+                ))
+            })?;
+            history.push((txid, entry));
+        }
+    }
+    Ok(history)
+}
@@ -0,0 +1,143 @@
+use bitcoin::{Block, Script};
+use std::ops::AddAssign;
+
+use crate::store::{height_key, KvStore, Table};
+use crate::KorndexError;
+
+/// Kind of an output's scriptPubKey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    /// Bare multisig
+    P2ms,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    /// Witness programs of versions or lengths without a defined meaning yet,
+    /// e.g. pay-to-anchor
+    WitnessUnknown,
+    OpReturn,
+    Nonstandard,
+}
+
+impl ScriptType {
+    pub const ALL: [ScriptType; 10] = [
+        ScriptType::P2pk,
+        ScriptType::P2pkh,
+        ScriptType::P2sh,
+        ScriptType::P2ms,
+        ScriptType::P2wpkh,
+        ScriptType::P2wsh,
+        ScriptType::P2tr,
+        ScriptType::WitnessUnknown,
+        ScriptType::OpReturn,
+        ScriptType::Nonstandard,
+    ];
+
+    pub fn classify(script: &Script) -> ScriptType {
+        if script.is_p2pkh() {
+            ScriptType::P2pkh
+        } else if script.is_p2sh() {
+            ScriptType::P2sh
+        } else if script.is_p2wpkh() {
+            ScriptType::P2wpkh
+        } else if script.is_p2wsh() {
+            ScriptType::P2wsh
+        } else if script.is_p2tr() {
+            ScriptType::P2tr
+        } else if script.is_witness_program() {
+            ScriptType::WitnessUnknown
+        } else if script.is_op_return() {
+            ScriptType::OpReturn
+        } else if script.is_p2pk() {
+            ScriptType::P2pk
+        } else if script.is_multisig() {
+            ScriptType::P2ms
+        } else {
+            ScriptType::Nonstandard
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::P2ms => "p2ms",
+            ScriptType::P2wpkh => "p2wpkh",
+            ScriptType::P2wsh => "p2wsh",
+            ScriptType::P2tr => "p2tr",
+            ScriptType::WitnessUnknown => "witness_unknown",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::Nonstandard => "nonstandard",
+        }
+    }
+}
+
+/// Outputs of each [`ScriptType`] created by a block or range of blocks.
+/// A block's counts are encoded as a big-endian u32 per type, in the order of
+/// [`ScriptType::ALL`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScriptTypeCounts([u64; ScriptType::ALL.len()]);
+
+impl ScriptTypeCounts {
+    /// Counts the outputs of every transaction of `block`, the coinbase's
+    /// included.
+    pub fn of_block(block: &Block) -> Self {
+        let mut counts = ScriptTypeCounts::default();
+        for output in block.txdata.iter().flat_map(|tx| &tx.output) {
+            counts.0[ScriptType::classify(&output.script_pubkey) as usize] += 1;
+        }
+        counts
+    }
+
+    pub fn get(&self, script_type: ScriptType) -> u64 {
+        self.0[script_type as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|&count| (count as u32).to_be_bytes())
+            .collect()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() != 4 * ScriptType::ALL.len() {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid script type counts length {}",
+                data.len()
+            )));
+        }
+        let mut counts = ScriptTypeCounts::default();
+        for (count, bytes) in counts.0.iter_mut().zip(data.chunks_exact(4)) {
+            *count = u32::from_be_bytes(bytes.try_into()?) as u64;
+        }
+        Ok(counts)
+    }
+}
+
+impl AddAssign for ScriptTypeCounts {
+    fn add_assign(&mut self, other: ScriptTypeCounts) {
+        for (count, added) in self.0.iter_mut().zip(other.0) {
+            *count += added;
+        }
+    }
+}
+
+/// Script type counts of the indexed block at `block_height`.
+pub fn read_counts(
+    store: &dyn KvStore,
+    block_height: i32,
+) -> Result<Option<ScriptTypeCounts>, KorndexError> {
+    match store.get(Table::ScriptTypes, &height_key(block_height))? {
+        Some(data) => Ok(Some(ScriptTypeCounts::decode(&data)?)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,185 @@
+//! `korndex serve`: the REST API, and optionally the Electrum server,
+//! WebSocket notifications and a mempool tracker, over one index.
+
+use bitcoin::Network;
+use libbitcoinkernel_sys::ChainstateManager;
+use std::path::PathBuf;
+use std::thread;
+
+use crate::access::{AccessControl, ConnectionLimit, RateLimiter};
+use crate::cache::QueryCache;
+use crate::mempool::Mempool;
+use crate::rpc::RpcClient;
+use crate::store::KvStore;
+use crate::tls::TlsConfig;
+use crate::{electrum, listener, rest, shutdown, websocket, KorndexError};
+
+/// What `korndex serve` serves and how.
+pub struct ServeOptions {
+    /// Address of the REST API, or unix:<path>
+    pub bind: String,
+    /// Address of the Electrum server, if any
+    pub electrum: Option<String>,
+    /// Address of the WebSocket notifications, if any
+    pub websocket: Option<String>,
+    /// Client of the node whose mempool lookups also return, if any
+    pub mempool: Option<RpcClient>,
+    /// Requests per second each client IP may make to any of the servers
+    pub rate_limit: Option<f64>,
+    pub rate_burst: u32,
+    /// File of the API keys REST requests and WebSocket handshakes must
+    /// carry
+    pub api_keys: Option<PathBuf>,
+    /// REST requests handled at a time, and Electrum and WebSocket
+    /// connections open at once [default: number of CPUs]
+    pub max_requests: Option<usize>,
+    pub block_cache: usize,
+    pub tx_cache: usize,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Refuse addresses that would need DNS lookups, for a Tor hidden service
+    pub onion: bool,
+}
+
+/// The REST API and the other servers of [`ServeOptions`], with their
+/// settings checked and loaded.
+pub struct Servers {
+    options: ServeOptions,
+    access: AccessControl,
+    connections: ConnectionLimit,
+    tls: Option<TlsConfig>,
+    cache: QueryCache,
+    mempool: Option<Mempool>,
+}
+
+impl Servers {
+    /// Checks `options` and loads the API keys and TLS certificate they name,
+    /// so mistakes show before a long build.
+    pub fn new(options: ServeOptions) -> Result<Self, KorndexError> {
+        let listeners = [
+            Some(&options.bind),
+            options.electrum.as_ref(),
+            options.websocket.as_ref(),
+        ];
+        if options.onion {
+            for addr in listeners.iter().flatten() {
+                listener::check_no_dns(addr)?;
+            }
+            if let Some(ref client) = options.mempool {
+                let url = client.url();
+                let host = url.split("://").last().unwrap_or(url);
+                listener::check_no_dns(host.split('/').next().unwrap_or(host))?;
+            }
+            tracing::info!("Onion mode: no DNS lookups, no connections but to the node");
+        }
+        let unix_listener = listeners
+            .iter()
+            .flatten()
+            .any(|addr| listener::unix_path(addr).is_some());
+        if unix_listener && options.tls_cert.is_some() {
+            return Err(KorndexError::Config(
+                "TLS is not served on unix: sockets, which only local processes reach".to_owned(),
+            ));
+        }
+        if options.api_keys.is_some() && options.electrum.is_some() {
+            return Err(KorndexError::Config(
+                "Electrum clients cannot send API keys, serve Electrum without --api-keys"
+                    .to_owned(),
+            ));
+        }
+        let api_keys = match options.api_keys {
+            Some(ref path) => Some(AccessControl::load_api_keys(path)?),
+            None => None,
+        };
+        if options
+            .rate_limit
+            .is_some_and(|rate| rate <= 0.0 || rate.is_nan())
+        {
+            return Err(KorndexError::Config(
+                "--rate-limit must be above 0 requests per second".to_owned(),
+            ));
+        }
+        let limiter = options
+            .rate_limit
+            .map(|rate| RateLimiter::new(rate, options.rate_burst));
+        let tls = match (&options.tls_cert, &options.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig::load(cert, key)?),
+            _ => None,
+        };
+        let max_connections = options
+            .max_requests
+            .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get()));
+        Ok(Servers {
+            access: AccessControl::new(api_keys, limiter),
+            connections: ConnectionLimit::new(max_connections),
+            tls,
+            cache: QueryCache::new(options.block_cache, options.tx_cache),
+            mempool: options.mempool.is_some().then(Mempool::default),
+            options,
+        })
+    }
+
+    /// Serves the index in `store` until the REST API fails, running `follow`
+    /// on a thread of its own to keep the index at the tip meanwhile. As the
+    /// servers have no way to stop, the process exits once `follow` returns
+    /// on a shutdown request.
+    pub fn run<F>(
+        &self,
+        chainman: &ChainstateManager,
+        store: &dyn KvStore,
+        network: Network,
+        follow: Option<F>,
+    ) -> Result<(), KorndexError>
+    where
+        F: FnOnce() -> Result<(), KorndexError> + Send,
+    {
+        let tls = self.tls.as_ref();
+        let (access, connections) = (&self.access, &self.connections);
+        thread::scope(|s| {
+            if let Some(follow) = follow {
+                s.spawn(move || {
+                    if let Err(e) = follow() {
+                        tracing::error!("Stopped following the tip: {}", e);
+                    }
+                    if shutdown::requested() {
+                        std::process::exit(130);
+                    }
+                });
+            }
+            if let Some(ref electrum) = self.options.electrum {
+                s.spawn(move || {
+                    if let Err(e) =
+                        electrum::serve(electrum, chainman, store, tls, access, connections)
+                    {
+                        tracing::error!("Electrum server failed: {}", e);
+                    }
+                });
+            }
+            if let Some(ref websocket) = self.options.websocket {
+                s.spawn(move || {
+                    if let Err(e) =
+                        websocket::serve(websocket, store, network, tls, access, connections)
+                    {
+                        tracing::error!("WebSocket server failed: {}", e);
+                    }
+                });
+            }
+            if let (Some(mempool), Some(client)) = (&self.mempool, &self.options.mempool) {
+                s.spawn(move || mempool.run(client, store));
+            }
+            rest::serve(
+                &self.options.bind,
+                chainman,
+                store,
+                network,
+                self.mempool.as_ref(),
+                &rest::ServeOptions {
+                    access,
+                    max_requests: self.options.max_requests,
+                    tls,
+                    cache: &self.cache,
+                },
+            )
+        })
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::KorndexError;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static BUILDING: AtomicBool = AtomicBool::new(false);
+
+/// Installs the SIGINT/SIGTERM handler. Outside of a build the process exits
+/// right away. During a build the current batch is finished and checkpointed
+/// first, unless a second signal arrives.
+pub fn install() -> Result<(), KorndexError> {
+    ctrlc::set_handler(|| {
+        if !BUILDING.load(Ordering::SeqCst) || REQUESTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        tracing::info!("Shutting down after the current batch, signal again to exit immediately");
+    })
+    .map_err(|e| KorndexError::Io(std::io::Error::other(e)))
+}
+
+/// Whether a build should stop at the next checkpoint.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Defers shutdown to the build's checkpoints for as long as it is held.
+pub struct BuildGuard(());
+
+impl BuildGuard {
+    pub fn enter() -> Self {
+        BUILDING.store(true, Ordering::SeqCst);
+        BuildGuard(())
+    }
+}
+
+impl Drop for BuildGuard {
+    fn drop(&mut self) {
+        BUILDING.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,134 @@
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::{hash160, sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, Verification};
+use bitcoin::{Block, Script, Transaction, TxIn, TxOut};
+
+pub const TWEAK_SIZE: usize = 33;
+
+/// BIP341 NUMS point. Taproot script path spends using it as the internal key
+/// have no spendable key and are excluded from the shared secret.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// BIP352 public tweaks (`input_hash·A`) for every eligible transaction in the
+/// block, in block order. `spent_outputs` holds the prevouts of each
+/// non-coinbase transaction.
+pub fn block_tweaks(block: &Block, spent_outputs: &[Vec<TxOut>]) -> Vec<[u8; TWEAK_SIZE]> {
+    let secp = Secp256k1::verification_only();
+    block
+        .txdata
+        .iter()
+        .skip(1)
+        .zip(spent_outputs)
+        .filter_map(|(tx, prevouts)| tx_tweak(&secp, tx, prevouts))
+        .map(|tweak| tweak.serialize())
+        .collect()
+}
+
+fn tx_tweak<C: Verification>(
+    secp: &Secp256k1<C>,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+) -> Option<PublicKey> {
+    // Only transactions paying to taproot can contain silent payments
+    if !tx
+        .output
+        .iter()
+        .any(|output| output.script_pubkey.is_p2tr())
+    {
+        return None;
+    }
+    // Spending future segwit versions makes the transaction ineligible
+    if prevouts.iter().any(|prevout| {
+        prevout
+            .script_pubkey
+            .witness_version()
+            .map_or(false, |version| version.to_num() > 1)
+    }) {
+        return None;
+    }
+
+    let pubkeys: Vec<PublicKey> = tx
+        .input
+        .iter()
+        .zip(prevouts)
+        .filter_map(|(input, prevout)| input_pubkey(input, &prevout.script_pubkey))
+        .collect();
+    if pubkeys.is_empty() {
+        return None;
+    }
+    // Fails if the keys sum to the point at infinity
+    let a_sum = PublicKey::combine_keys(&pubkeys.iter().collect::<Vec<_>>()).ok()?;
+
+    let smallest_outpoint = tx
+        .input
+        .iter()
+        .map(|input| serialize(&input.previous_output))
+        .min()?;
+    let mut engine = tagged_engine(b"BIP0352/Inputs");
+    engine.input(&smallest_outpoint);
+    engine.input(&a_sum.serialize());
+    let input_hash = sha256::Hash::from_engine(engine);
+
+    let scalar = Scalar::from_be_bytes(input_hash.to_byte_array()).ok()?;
+    a_sum.mul_tweak(secp, &scalar).ok()
+}
+
+/// The public key an input contributes to the shared secret, if the input
+/// type is eligible.
+fn input_pubkey(input: &TxIn, script_pubkey: &Script) -> Option<PublicKey> {
+    if script_pubkey.is_p2tr() {
+        let mut witness: Vec<&[u8]> = input.witness.iter().collect();
+        if witness.len() > 1 && witness.last()?.first() == Some(&0x50) {
+            // Drop the annex
+            witness.pop();
+        }
+        if witness.len() > 1 {
+            // Script path spend, the internal key follows the control byte
+            let control_block = witness.last()?;
+            if control_block.get(1..33) == Some(&NUMS_H[..]) {
+                return None;
+            }
+        }
+        let mut key = [0x02; 33];
+        key[1..].copy_from_slice(&script_pubkey.as_bytes()[2..34]);
+        PublicKey::from_slice(&key).ok()
+    } else if script_pubkey.is_p2wpkh() {
+        compressed_pubkey(input.witness.last()?)
+    } else if script_pubkey.is_p2sh() {
+        let redeem_script = Script::from_bytes(input.script_sig.as_bytes().get(1..)?);
+        if !redeem_script.is_p2wpkh() {
+            return None;
+        }
+        compressed_pubkey(input.witness.last()?)
+    } else if script_pubkey.is_p2pkh() {
+        // Scan from the end of the scriptSig for a key matching the pubkey hash,
+        // which also covers malleated scriptSigs
+        let pubkey_hash = &script_pubkey.as_bytes()[3..23];
+        let script_sig = input.script_sig.as_bytes();
+        (33..=script_sig.len())
+            .rev()
+            .map(|end| &script_sig[end - 33..end])
+            .find(|candidate| &hash160::Hash::hash(candidate).to_byte_array()[..] == pubkey_hash)
+            .and_then(compressed_pubkey)
+    } else {
+        None
+    }
+}
+
+fn compressed_pubkey(bytes: &[u8]) -> Option<PublicKey> {
+    if bytes.len() != 33 {
+        return None;
+    }
+    PublicKey::from_slice(bytes).ok()
+}
+
+fn tagged_engine(tag: &[u8]) -> sha256::HashEngine {
+    let tag_hash = sha256::Hash::hash(tag).to_byte_array();
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash);
+    engine.input(&tag_hash);
+    engine
+}
@@ -0,0 +1,162 @@
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use std::io::{self, Read, Write};
+
+use crate::meta;
+use crate::store::{Batch, KvStore, Table};
+use crate::KorndexError;
+
+const MAGIC: &[u8; 8] = b"KORNSNAP";
+const FORMAT_VERSION: u32 = 1;
+/// Table byte marking the end of the entries, followed by the checksum.
+const END: u8 = 0xff;
+/// Entries restored per batch.
+const RESTORE_BATCH: usize = 100_000;
+
+/// Writes every entry of the index in `store` to `out` as a zstd-compressed
+/// snapshot, returning the number of entries. Writers should be stopped
+/// first, each table is read in its own transaction.
+///
+/// The uncompressed stream is the magic, the format version and the network
+/// name, then every entry as its table byte and big-endian length-prefixed
+/// key and value, then [`END`] and the SHA256 of everything before it.
+pub fn create(store: &dyn KvStore, out: impl Write) -> Result<u64, KorndexError> {
+    let network = meta::read_network(store)?
+        .ok_or_else(|| KorndexError::Config("The index has no network".to_owned()))?;
+    let mut out = HashingWriter {
+        inner: zstd::Encoder::new(out, 0)?,
+        engine: sha256::Hash::engine(),
+    };
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_be_bytes())?;
+    write_bytes(&mut out, network.as_bytes())?;
+
+    let mut entries = 0;
+    for table in Table::ALL {
+        let mut result = Ok(());
+        store.iter_prefix(table, &[], &mut |key, value| {
+            result = (|| {
+                out.write_all(&[table as u8])?;
+                write_bytes(&mut out, key)?;
+                write_bytes(&mut out, value)
+            })();
+            entries += 1;
+            result.is_ok()
+        })?;
+        result?;
+    }
+    out.write_all(&[END])?;
+    let checksum = sha256::Hash::from_engine(out.engine.clone());
+    out.inner.write_all(checksum.as_byte_array())?;
+    out.inner.finish()?.flush()?;
+    Ok(entries)
+}
+
+/// Restores a snapshot written by [`create`] into the empty index in
+/// `store`, returning the number of entries. Fails if the snapshot is for
+/// another network or its checksum does not match, in which case the index
+/// must be deleted before retrying.
+pub fn restore(store: &dyn KvStore, input: impl Read) -> Result<u64, KorndexError> {
+    if !store.is_empty(Table::TxIndex)? || meta::read_best_block(store)?.is_some() {
+        return Err(KorndexError::Config(
+            "Snapshots can only be restored into an empty index".to_owned(),
+        ));
+    }
+    let mut input = HashingReader {
+        inner: zstd::Decoder::new(input)?,
+        engine: sha256::Hash::engine(),
+    };
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    let mut version = [0u8; 4];
+    input.read_exact(&mut version)?;
+    if &magic != MAGIC || u32::from_be_bytes(version) != FORMAT_VERSION {
+        return Err(KorndexError::InvalidInput(
+            "Not a korndex snapshot, or one from an unsupported version".into(),
+        ));
+    }
+    let network = String::from_utf8(read_bytes(&mut input)?)?;
+    if let Some(indexed) = meta::read_network(store)? {
+        if indexed != network {
+            return Err(KorndexError::Config(format!(
+                "Snapshot is of a {} index but korndex is running on {}",
+                network, indexed
+            )));
+        }
+    }
+
+    let mut entries = 0;
+    let mut batch = Batch::default();
+    loop {
+        let mut table = [0u8];
+        input.read_exact(&mut table)?;
+        if table[0] == END {
+            break;
+        }
+        let table = *Table::ALL.get(table[0] as usize).ok_or_else(|| {
+            KorndexError::Corrupt(format!("Unknown table {} in snapshot", table[0]))
+        })?;
+        let key = read_bytes(&mut input)?;
+        let value = read_bytes(&mut input)?;
+        batch.put(table, key, value);
+        entries += 1;
+        if batch.ops.len() >= RESTORE_BATCH {
+            store.put_batch(&std::mem::take(&mut batch))?;
+        }
+    }
+    let expected = sha256::Hash::from_engine(input.engine.clone());
+    let mut checksum = [0u8; 32];
+    input.inner.read_exact(&mut checksum)?;
+    if checksum != expected.to_byte_array() {
+        return Err(KorndexError::Corrupt(
+            "Snapshot checksum mismatch, delete the restored index".to_owned(),
+        ));
+    }
+    store.put_batch(&batch)?;
+    store.commit()?;
+    Ok(entries)
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    out.write_all(bytes)
+}
+
+fn read_bytes(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Hashes everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    engine: sha256::HashEngine,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.engine.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    engine: sha256::HashEngine,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.engine.input(&buf[..read]);
+        Ok(read)
+    }
+}
@@ -0,0 +1,47 @@
+use bitcoin::hashes::Hash;
+use bitcoin::OutPoint;
+
+use crate::KorndexError;
+
+/// Location of the input spending an outpoint. Encoded big-endian into a
+/// fixed 12-byte value.
+#[derive(Debug, Clone, Copy)]
+pub struct SpendEntry {
+    pub block_height: i32,
+    pub position_in_block: u32,
+    pub input_index: u32,
+}
+
+pub const ENTRY_SIZE: usize = 12;
+
+/// The outpoint's txid followed by its big-endian vout.
+pub fn outpoint_key(outpoint: &OutPoint) -> [u8; 36] {
+    let mut key = [0u8; 36];
+    key[0..32].copy_from_slice(&outpoint.txid.to_byte_array());
+    key[32..36].copy_from_slice(&outpoint.vout.to_be_bytes());
+    key
+}
+
+impl SpendEntry {
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&(self.block_height as u32).to_be_bytes());
+        buf[4..8].copy_from_slice(&self.position_in_block.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.input_index.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() != ENTRY_SIZE {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid spend entry length {}",
+                data.len()
+            )));
+        }
+        Ok(SpendEntry {
+            block_height: u32::from_be_bytes(data[0..4].try_into()?) as i32,
+            position_in_block: u32::from_be_bytes(data[4..8].try_into()?),
+            input_index: u32::from_be_bytes(data[8..12].try_into()?),
+        })
+    }
+}
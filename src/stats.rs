@@ -0,0 +1,70 @@
+use crate::indexes::IndexSet;
+use crate::meta::{self, BestBlock, BuildTimes};
+use crate::store::{KvStore, Table, TableCompression};
+use crate::KorndexError;
+
+/// Size of one table of the index.
+#[derive(Debug)]
+pub struct TableStats {
+    pub table: Table,
+    /// Entries, counting each value of a dup-sorted table separately
+    pub entries: u64,
+    pub key_bytes: u64,
+    /// Before compression, for compressed tables
+    pub value_bytes: u64,
+}
+
+/// What an index covers and how much space it takes.
+#[derive(Debug)]
+pub struct IndexStats {
+    pub schema_version: Option<u32>,
+    /// Lowest indexed height
+    pub start_height: i32,
+    /// Height the index was built to end at, if any
+    pub end_height: Option<i32>,
+    pub best: Option<BestBlock>,
+    pub build_times: Option<BuildTimes>,
+    pub indexes: IndexSet,
+    /// Tables whose values are compressed
+    pub compression: Vec<TableCompression>,
+    /// Stores the txindex is split into, if more than one
+    pub txindex_shards: Option<usize>,
+    pub disk_size: u64,
+    /// See [`KvStore::fill`]
+    pub fill: Option<(u64, u64)>,
+    pub tables: Vec<TableStats>,
+}
+
+/// Collects the statistics of the index in `store`. Counting entries walks
+/// every table, which takes a while on a large index.
+pub fn collect(store: &dyn KvStore) -> Result<IndexStats, KorndexError> {
+    let mut tables = Vec::with_capacity(Table::ALL.len());
+    for table in Table::ALL {
+        let mut stats = TableStats {
+            table,
+            entries: 0,
+            key_bytes: 0,
+            value_bytes: 0,
+        };
+        store.iter_prefix(table, &[], &mut |key, value| {
+            stats.entries += 1;
+            stats.key_bytes += key.len() as u64;
+            stats.value_bytes += value.len() as u64;
+            true
+        })?;
+        tables.push(stats);
+    }
+    Ok(IndexStats {
+        schema_version: meta::read_schema_version(store)?,
+        start_height: meta::read_start_height(store)?.unwrap_or(0),
+        end_height: meta::read_end_height(store)?,
+        best: meta::read_best_block(store)?,
+        build_times: meta::read_build_times(store)?,
+        indexes: meta::built_indexes(store)?,
+        compression: meta::read_compression(store)?.unwrap_or_default(),
+        txindex_shards: meta::read_txindex_shards(store)?,
+        disk_size: store.disk_size()?,
+        fill: store.fill()?,
+        tables,
+    })
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// Longest key or value kept inline. Covers txids, script hashes, outpoints
+/// and most fixed-size entries, so a build's writes rarely allocate.
+const INLINE_LEN: usize = 38;
+
+/// A key or value of a [`super::Batch`]. Up to [`INLINE_LEN`] bytes are kept
+/// on the stack, longer ones on the heap. Serialized like a `Vec<u8>`.
+#[derive(Clone)]
+pub enum Bytes {
+    Inline(u8, [u8; INLINE_LEN]),
+    Heap(Vec<u8>),
+}
+
+impl Bytes {
+    pub fn new() -> Self {
+        Bytes::Inline(0, [0; INLINE_LEN])
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Bytes::Inline(len, buf) => &buf[..*len as usize],
+            Bytes::Heap(vec) => vec,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        self.extend_from_slice(&[byte]);
+    }
+
+    /// Appends `data`, moving to the heap once the bytes no longer fit inline.
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        match self {
+            Bytes::Inline(len, buf) if *len as usize + data.len() <= INLINE_LEN => {
+                let start = *len as usize;
+                buf[start..start + data.len()].copy_from_slice(data);
+                *len += data.len() as u8;
+            }
+            Bytes::Inline(len, buf) => {
+                let mut vec = Vec::with_capacity(*len as usize + data.len());
+                vec.extend_from_slice(&buf[..*len as usize]);
+                vec.extend_from_slice(data);
+                *self = Bytes::Heap(vec);
+            }
+            Bytes::Heap(vec) => vec.extend_from_slice(data),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Bytes::Inline(..) => self.as_slice().to_vec(),
+            Bytes::Heap(vec) => vec,
+        }
+    }
+
+    /// Heap memory held, 0 for inline bytes.
+    pub fn capacity(&self) -> usize {
+        match self {
+            Bytes::Inline(..) => 0,
+            Bytes::Heap(vec) => vec.capacity(),
+        }
+    }
+}
+
+impl Default for Bytes {
+    fn default() -> Self {
+        Bytes::new()
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Borrow<[u8]> for Bytes {
+    fn borrow(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Bytes) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Bytes {}
+
+impl Hash for Bytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_slice().hash(state)
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(data: &[u8]) -> Self {
+        let mut bytes = Bytes::new();
+        bytes.extend_from_slice(data);
+        bytes
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Bytes {
+    fn from(data: [u8; N]) -> Self {
+        Bytes::from(&data[..])
+    }
+}
+
+/// Keeps the vector's allocation rather than copying short ones inline.
+impl From<Vec<u8>> for Bytes {
+    fn from(vec: Vec<u8>) -> Self {
+        Bytes::Heap(vec)
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.as_slice())
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let vec = Vec::<u8>::deserialize(deserializer)?;
+        Ok(match vec.len() <= INLINE_LEN {
+            true => Bytes::from(&vec[..]),
+            false => Bytes::Heap(vec),
+        })
+    }
+}
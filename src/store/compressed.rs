@@ -0,0 +1,350 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use zstd::bulk::{Compressor, Decompressor};
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::KorndexError;
+
+/// Values shorter than this are stored as they are, zstd's frame header
+/// would eat most of what compressing them saves.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+/// First byte of a stored value: the value follows as it is.
+const RAW: u8 = 0;
+/// First byte of a stored value: the value's length as a little-endian
+/// `u32` follows, then a zstd frame.
+const ZSTD: u8 = 1;
+/// Like [`ZSTD`], for a frame compressed with the table's dictionary.
+const ZSTD_DICT: u8 = 2;
+
+/// How the values of one table are compressed, recorded in the index when it
+/// is created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TableCompression {
+    pub table: Table,
+    /// zstd level, higher levels compress smaller and slower
+    pub level: i32,
+    /// A zstd dictionary trained on typical values of the table, e.g. with
+    /// `zstd --train`, which compresses short values far better
+    pub dictionary: Option<Vec<u8>>,
+}
+
+impl TableCompression {
+    /// Parses `TABLE[=LEVEL]`, e.g. `undo=9`, without a dictionary.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (name, level) = match s.split_once('=') {
+            Some((name, level)) => (
+                name,
+                level
+                    .parse()
+                    .map_err(|_| format!("Invalid level {}", level))?,
+            ),
+            None => (s, zstd::DEFAULT_COMPRESSION_LEVEL),
+        };
+        let table = Table::ALL
+            .into_iter()
+            .find(|table| table.name() == name)
+            .ok_or_else(|| format!("Unknown table {}", name))?;
+        Ok(TableCompression {
+            table,
+            level,
+            dictionary: None,
+        })
+    }
+
+    /// Parses `TABLE=PATH`, a table and the file of its dictionary.
+    pub fn parse_dictionary(s: &str) -> Result<(String, PathBuf), String> {
+        match s.split_once('=') {
+            Some((table, path)) => Ok((table.to_owned(), PathBuf::from(path))),
+            None => Err("Expected TABLE=PATH".to_owned()),
+        }
+    }
+
+    /// Reads the dictionary file of each table named in `dictionaries` into
+    /// its compression in `tables`.
+    pub fn load_dictionaries(
+        tables: &mut [TableCompression],
+        dictionaries: &[(String, PathBuf)],
+    ) -> Result<(), KorndexError> {
+        for (name, path) in dictionaries {
+            let compression = tables
+                .iter_mut()
+                .find(|compression| compression.table.name() == name)
+                .ok_or_else(|| {
+                    KorndexError::Config(format!(
+                        "A dictionary is given for the {} table, which is not compressed",
+                        name
+                    ))
+                })?;
+            compression.dictionary = Some(std::fs::read(path)?);
+        }
+        Ok(())
+    }
+
+    /// Fails for tables whose values are not compressed: the metadata,
+    /// txindex entries, which SQLite stores in columns, and the values of
+    /// dup-sorted tables like the address history, which are stored sorted at
+    /// a fixed length and are too short for compressing one by one to save
+    /// anything.
+    pub fn check(tables: &[TableCompression]) -> Result<(), KorndexError> {
+        let levels = zstd::compression_level_range();
+        for (i, compression) in tables.iter().enumerate() {
+            let table = compression.table;
+            if matches!(table, Table::Meta | Table::TxIndex) {
+                return Err(KorndexError::Config(format!(
+                    "The values of the {} table cannot be compressed",
+                    table.name()
+                )));
+            }
+            if table.is_dup_sort() {
+                return Err(KorndexError::Config(format!(
+                    "The {} table holds several fixed-size values per key, which are not compressed",
+                    table.name()
+                )));
+            }
+            if !levels.contains(&compression.level) {
+                return Err(KorndexError::Config(format!(
+                    "Compression level {} of the {} table is outside {}..={}",
+                    compression.level,
+                    table.name(),
+                    levels.start(),
+                    levels.end()
+                )));
+            }
+            if tables[..i].iter().any(|other| other.table == table) {
+                return Err(KorndexError::Config(format!(
+                    "The {} table is compressed twice",
+                    table.name()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// zstd contexts kept between values, as many as threads used at once, as
+/// setting one up with a dictionary costs more than compressing a short
+/// value.
+struct Pool<T>(Mutex<Vec<T>>);
+
+impl<T> Pool<T> {
+    fn with<R>(
+        &self,
+        new: impl FnOnce() -> io::Result<T>,
+        f: impl FnOnce(&mut T) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let pooled = self.0.lock().unwrap().pop();
+        let mut context = match pooled {
+            Some(context) => context,
+            None => new()?,
+        };
+        let result = f(&mut context);
+        self.0.lock().unwrap().push(context);
+        result
+    }
+}
+
+/// Compresses one table's values.
+struct Codec {
+    table: Table,
+    level: i32,
+    dictionary: Option<Vec<u8>>,
+    compressors: Pool<Compressor<'static>>,
+    decompressors: Pool<Decompressor<'static>>,
+}
+
+impl Codec {
+    fn new(compression: &TableCompression) -> Self {
+        Codec {
+            table: compression.table,
+            level: compression.level,
+            dictionary: compression.dictionary.clone(),
+            compressors: Pool(Mutex::new(Vec::new())),
+            decompressors: Pool(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn encode(&self, value: &[u8]) -> Result<Vec<u8>, KorndexError> {
+        if value.len() >= MIN_COMPRESSED_LEN {
+            let frame = self.compressors.with(
+                || match self.dictionary {
+                    Some(ref dictionary) => Compressor::with_dictionary(self.level, dictionary),
+                    None => Compressor::new(self.level),
+                },
+                |compressor| compressor.compress(value),
+            )?;
+            if frame.len() + 4 < value.len() {
+                let mut encoded = Vec::with_capacity(frame.len() + 5);
+                encoded.push(match self.dictionary {
+                    Some(_) => ZSTD_DICT,
+                    None => ZSTD,
+                });
+                encoded.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                encoded.extend_from_slice(&frame);
+                return Ok(encoded);
+            }
+        }
+        let mut encoded = Vec::with_capacity(value.len() + 1);
+        encoded.push(RAW);
+        encoded.extend_from_slice(value);
+        Ok(encoded)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Result<Vec<u8>, KorndexError> {
+        let decoded = match encoded.split_first() {
+            Some((&RAW, value)) => Ok(value.to_vec()),
+            Some((&tag, rest)) if rest.len() >= 4 => {
+                let (len, frame) = rest.split_at(4);
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                match (tag, &self.dictionary) {
+                    (ZSTD, None) | (ZSTD_DICT, Some(_)) => self.decompressors.with(
+                        || match self.dictionary {
+                            Some(ref dictionary) => Decompressor::with_dictionary(dictionary),
+                            None => Decompressor::new(),
+                        },
+                        |decompressor| decompressor.decompress(frame, len),
+                    ),
+                    (ZSTD, Some(_)) => zstd::bulk::decompress(frame, len),
+                    _ => Err(io::Error::other(format!("unknown encoding {}", tag))),
+                }
+            }
+            _ => Err(io::Error::other("truncated value")),
+        };
+        decoded.map_err(|e| {
+            KorndexError::Corrupt(format!(
+                "Compressed value in the {} table cannot be decoded: {}",
+                self.table.name(),
+                e
+            ))
+        })
+    }
+}
+
+/// Compresses the values of selected tables with zstd before they reach
+/// `inner`, trading CPU for disk space on tables with large values like
+/// filters, undo records and block txid lists. Each value is compressed on
+/// its own, so point lookups decompress a single value. Keys stay as they
+/// are, so prefix scans and sort order are unaffected.
+pub struct CompressedStore {
+    inner: Box<dyn KvStore>,
+    /// Indexed by `table as usize`
+    codecs: Vec<Option<Codec>>,
+}
+
+impl CompressedStore {
+    pub fn new(inner: Box<dyn KvStore>, tables: &[TableCompression]) -> Self {
+        let mut codecs: Vec<Option<Codec>> = Table::ALL.iter().map(|_| None).collect();
+        for compression in tables {
+            codecs[compression.table as usize] = Some(Codec::new(compression));
+        }
+        CompressedStore { inner, codecs }
+    }
+
+    fn codec(&self, table: Table) -> Option<&Codec> {
+        self.codecs[table as usize].as_ref()
+    }
+}
+
+impl KvStore for CompressedStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        let value = self.inner.get(table, key)?;
+        match (self.codec(table), value) {
+            (Some(codec), Some(value)) => Ok(Some(codec.decode(&value)?)),
+            (_, value) => Ok(value),
+        }
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        let values = self.inner.get_many(table, keys)?;
+        let Some(codec) = self.codec(table) else {
+            return Ok(values);
+        };
+        values
+            .into_iter()
+            .map(|value| value.map(|value| codec.decode(&value)).transpose())
+            .collect()
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let Some(codec) = self.codec(table) else {
+            return self.inner.iter_prefix(table, prefix, f);
+        };
+        let mut result = Ok(());
+        self.inner
+            .iter_prefix(table, prefix, &mut |key, value| match codec.decode(value) {
+                Ok(value) => f(key, &value),
+                Err(e) => {
+                    result = Err(e);
+                    false
+                }
+            })?;
+        result
+    }
+
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.inner.iter_dups(table, key, f)
+    }
+
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        self.inner.count_dups(table, key)
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        if !batch
+            .ops
+            .iter()
+            .any(|op| matches!(op, Op::Put(table, ..) if self.codec(*table).is_some()))
+        {
+            return self.inner.put_batch(batch);
+        }
+        let ops = batch
+            .ops
+            .par_iter()
+            .map(|op| match op {
+                Op::Put(table, key, value) => match self.codec(*table) {
+                    Some(codec) => Ok(Op::Put(*table, key.clone(), codec.encode(value)?.into())),
+                    None => Ok(Op::Put(*table, key.clone(), value.clone())),
+                },
+                Op::Delete(table, key, value) => Ok(Op::Delete(*table, key.clone(), value.clone())),
+            })
+            .collect::<Result<_, KorndexError>>()?;
+        self.inner.put_batch(&Batch { ops })
+    }
+
+    fn commit(&self) -> Result<(), KorndexError> {
+        self.inner.commit()
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        self.inner.clear(table)
+    }
+
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        self.inner.compact()
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        self.inner.set_durability(durability)
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        self.inner.disk_size()
+    }
+
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        self.inner.fill()
+    }
+}
@@ -0,0 +1,470 @@
+use lmdb::{
+    Cursor, Database, DatabaseFlags, Environment, EnvironmentFlags, RwTransaction, Transaction,
+    WriteFlags,
+};
+use lmdb_sys::{MDB_cursor, MDB_txn, MDB_val};
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::{fs, ptr, slice};
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::KorndexError;
+
+/// Held shared by every transaction and exclusively while the map is resized,
+/// since LMDB forbids resizing while this process has transactions open.
+static MAP_LOCK: RwLock<()> = RwLock::new(());
+
+/// Named databases the environment can hold, room for tables added later.
+const MAX_DBS: u32 = 32;
+
+/// Reader slots in the lock table, enough for a read transaction per serve
+/// worker plus the indexer.
+const MAX_READERS: u32 = 1024;
+
+/// A read-only transaction, reset between reads and renewed for the next, and
+/// a cursor per table renewed along with it. Reusing them skips allocating a
+/// transaction and cursor on every lookup.
+struct ReadTxn {
+    txn: *mut MDB_txn,
+    cursors: [*mut MDB_cursor; Table::ALL.len()],
+}
+
+// Environments are opened with NO_TLS, so a read transaction is not bound to
+// the thread that began it and can be renewed by any worker
+unsafe impl Send for ReadTxn {}
+
+impl Drop for ReadTxn {
+    fn drop(&mut self) {
+        unsafe {
+            // Cursors of read-only transactions are not freed with them
+            for cursor in self.cursors {
+                if !cursor.is_null() {
+                    lmdb_sys::mdb_cursor_close(cursor);
+                }
+            }
+            lmdb_sys::mdb_txn_abort(self.txn);
+        }
+    }
+}
+
+pub struct LmdbStore {
+    /// Reset read transactions, declared before `env` so they are aborted
+    /// before it is closed
+    readers: Mutex<Vec<ReadTxn>>,
+    env: Environment,
+    dbs: Vec<Database>,
+    path: PathBuf,
+}
+
+impl LmdbStore {
+    /// Opens the environment at `path` and creates (or opens) every table.
+    pub fn open(path: &Path, map_size: usize) -> Result<Self, lmdb::Error> {
+        let env = Environment::new()
+            .set_flags(EnvironmentFlags::NO_TLS)
+            .set_max_dbs(MAX_DBS)
+            .set_max_readers(MAX_READERS)
+            .set_map_size(map_size)
+            .open(path)?;
+        let dbs = Table::ALL
+            .iter()
+            .map(|table| {
+                let flags = if table.is_dup_sort() {
+                    DatabaseFlags::DUP_SORT | DatabaseFlags::DUP_FIXED
+                } else {
+                    DatabaseFlags::empty()
+                };
+                env.create_db(Some(table.name()), flags)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(LmdbStore {
+            readers: Mutex::new(Vec::new()),
+            env,
+            dbs,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Opens an existing environment at `path` read-only, without creating
+    /// tables, so it can be read while another process writes to it.
+    pub fn open_read_only(path: &Path) -> Result<Self, lmdb::Error> {
+        let env = Environment::new()
+            .set_flags(EnvironmentFlags::READ_ONLY | EnvironmentFlags::NO_TLS)
+            .set_max_dbs(MAX_DBS)
+            .set_max_readers(MAX_READERS)
+            .open(path)?;
+        let dbs = Table::ALL
+            .iter()
+            .map(|table| env.open_db(Some(table.name())))
+            .collect::<Result<_, _>>()?;
+        Ok(LmdbStore {
+            readers: Mutex::new(Vec::new()),
+            env,
+            dbs,
+            path: path.to_owned(),
+        })
+    }
+
+    fn db(&self, table: Table) -> Database {
+        self.dbs[table as usize]
+    }
+
+    /// Runs `f` in a read transaction taken from the pool, or begun if every
+    /// pooled one is in use by another thread, and returns it reset.
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&mut ReadTxn) -> Result<T, lmdb::Error>,
+    ) -> Result<T, lmdb::Error> {
+        let guard = MAP_LOCK.read().unwrap();
+        let mut txn = match self.begin_read() {
+            // The writing process grew the map
+            Err(lmdb::Error::MapResized) => {
+                drop(guard);
+                self.adopt_map_size()?;
+                return self.read(f);
+            }
+            txn => txn?,
+        };
+        let result = f(&mut txn);
+        unsafe { lmdb_sys::mdb_txn_reset(txn.txn) };
+        self.readers.lock().unwrap().push(txn);
+        result
+    }
+
+    fn begin_read(&self) -> Result<ReadTxn, lmdb::Error> {
+        let pooled = self.readers.lock().unwrap().pop();
+        if let Some(txn) = pooled {
+            lmdb_result(unsafe { lmdb_sys::mdb_txn_renew(txn.txn) })?;
+            return Ok(txn);
+        }
+        let mut txn = ptr::null_mut();
+        lmdb_result(unsafe {
+            lmdb_sys::mdb_txn_begin(
+                self.env.env(),
+                ptr::null_mut(),
+                lmdb_sys::MDB_RDONLY,
+                &mut txn,
+            )
+        })?;
+        Ok(ReadTxn {
+            txn,
+            cursors: [ptr::null_mut(); Table::ALL.len()],
+        })
+    }
+
+    /// Picks up the size another process grew the map to, which LMDB only
+    /// allows while this process has no transaction open.
+    fn adopt_map_size(&self) -> Result<(), lmdb::Error> {
+        let _guard = MAP_LOCK.write().unwrap();
+        lmdb_result(unsafe { lmdb_sys::mdb_env_set_mapsize(self.env.env(), 0) })
+    }
+
+    /// The cursor over `table` of the active `txn`.
+    fn cursor(&self, txn: &mut ReadTxn, table: Table) -> Result<*mut MDB_cursor, lmdb::Error> {
+        let cursor = &mut txn.cursors[table as usize];
+        if cursor.is_null() {
+            lmdb_result(unsafe {
+                lmdb_sys::mdb_cursor_open(txn.txn, self.db(table).dbi(), cursor)
+            })?;
+        } else {
+            lmdb_result(unsafe { lmdb_sys::mdb_cursor_renew(txn.txn, *cursor) })?;
+        }
+        Ok(*cursor)
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), lmdb::Error> {
+        // Sorted writes touch the B-tree's pages sequentially. The sort is
+        // stable, so ops on the same key keep their order.
+        let mut ops: Vec<&Op> = batch.ops.iter().collect();
+        ops.sort_by(|a, b| (a.table() as usize, a.key()).cmp(&(b.table() as usize, b.key())));
+
+        let mut txn = self.env.begin_rw_txn()?;
+        // Largest key written so far in the table being written to
+        let mut last: Option<(Table, Option<Vec<u8>>)> = None;
+        for op in ops {
+            match op {
+                Op::Put(table, key, value) => {
+                    debug_assert!(!table.is_dup_sort() || value.len() == table.dup_value_len());
+                    if last.as_ref().map(|(t, _)| *t) != Some(*table) {
+                        last = Some((*table, last_key(&txn, self.db(*table))?));
+                    }
+                    let (_, table_end) = last.as_mut().unwrap();
+                    // Keys past the end of the table, like the height-keyed
+                    // tables during a build, are appended without a search
+                    let append = !table.is_dup_sort()
+                        && !matches!(table_end.as_deref(), Some(end) if key.as_slice() <= end);
+                    let flags = if append {
+                        WriteFlags::APPEND
+                    } else {
+                        WriteFlags::empty()
+                    };
+                    txn.put(self.db(*table), key, value, flags)?;
+                    if append {
+                        *table_end = Some(key.to_vec());
+                    }
+                }
+                Op::Delete(table, key, value) => {
+                    match txn.del(self.db(*table), key, value.as_deref()) {
+                        Ok(()) | Err(lmdb::Error::NotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        txn.commit()
+    }
+
+    fn map_size(&self) -> Result<usize, lmdb::Error> {
+        Ok(self.env_info()?.me_mapsize)
+    }
+
+    fn env_info(&self) -> Result<lmdb_sys::MDB_envinfo, lmdb::Error> {
+        let mut info = MaybeUninit::<lmdb_sys::MDB_envinfo>::uninit();
+        let rc = unsafe { lmdb_sys::mdb_env_info(self.env.env(), info.as_mut_ptr()) };
+        if rc != 0 {
+            return Err(lmdb::Error::from_err_code(rc));
+        }
+        Ok(unsafe { info.assume_init() })
+    }
+
+    /// Doubles the map, unless another thread already grew it past `full_size`.
+    fn grow_map(&self, full_size: usize) -> Result<(), lmdb::Error> {
+        let _guard = MAP_LOCK.write().unwrap();
+        if self.map_size()? > full_size {
+            return Ok(());
+        }
+        let new_size = full_size * 2;
+        let rc = unsafe { lmdb_sys::mdb_env_set_mapsize(self.env.env(), new_size) };
+        if rc != 0 {
+            return Err(lmdb::Error::from_err_code(rc));
+        }
+        tracing::info!(
+            "LMDB map full, grew it to {} GiB",
+            new_size / (1024 * 1024 * 1024)
+        );
+        Ok(())
+    }
+}
+
+fn last_key(txn: &RwTransaction, db: Database) -> Result<Option<Vec<u8>>, lmdb::Error> {
+    let cursor = txn.open_ro_cursor(db)?;
+    match cursor.get(None, None, lmdb_sys::MDB_LAST) {
+        Ok((key, _)) => Ok(key.map(<[u8]>::to_vec)),
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn lmdb_result(rc: i32) -> Result<(), lmdb::Error> {
+    match rc {
+        0 => Ok(()),
+        rc => Err(lmdb::Error::from_err_code(rc)),
+    }
+}
+
+fn val(bytes: &[u8]) -> MDB_val {
+    MDB_val {
+        mv_size: bytes.len(),
+        mv_data: bytes.as_ptr() as *mut _,
+    }
+}
+
+/// # Safety
+/// `val` must point into the map of a transaction that is still active.
+unsafe fn bytes<'a>(val: &MDB_val) -> &'a [u8] {
+    if val.mv_size == 0 {
+        return &[];
+    }
+    slice::from_raw_parts(val.mv_data as *const u8, val.mv_size)
+}
+
+impl KvStore for LmdbStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        Ok(self.get_many(table, &[key])?.remove(0))
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        let dbi = self.db(table).dbi();
+        Ok(self.read(|txn| {
+            keys.iter()
+                .map(|key| {
+                    let mut key = val(key);
+                    let mut value = val(&[]);
+                    match unsafe { lmdb_sys::mdb_get(txn.txn, dbi, &mut key, &mut value) } {
+                        0 => Ok(Some(unsafe { bytes(&value) }.to_vec())),
+                        lmdb_sys::MDB_NOTFOUND => Ok(None),
+                        rc => Err(lmdb::Error::from_err_code(rc)),
+                    }
+                })
+                .collect()
+        })?)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        Ok(self.read(|txn| {
+            let cursor = self.cursor(txn, table)?;
+            let mut key = val(prefix);
+            let mut value = val(&[]);
+            let mut op = if prefix.is_empty() {
+                lmdb_sys::MDB_FIRST
+            } else {
+                lmdb_sys::MDB_SET_RANGE
+            };
+            loop {
+                match unsafe { lmdb_sys::mdb_cursor_get(cursor, &mut key, &mut value, op) } {
+                    0 => {}
+                    lmdb_sys::MDB_NOTFOUND => return Ok(()),
+                    rc => return Err(lmdb::Error::from_err_code(rc)),
+                }
+                // Valid until the next cursor op, which happens after `f`
+                let (key, value) = unsafe { (bytes(&key), bytes(&value)) };
+                if !key.starts_with(prefix) || !f(key, value) {
+                    return Ok(());
+                }
+                op = lmdb_sys::MDB_NEXT;
+            }
+        })?)
+    }
+
+    /// Reads the values a page at a time, as LMDB packs the fixed-size values
+    /// of a key.
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let value_len = table.dup_value_len();
+        Ok(self.read(|txn| {
+            let cursor = self.cursor(txn, table)?;
+            let mut key = val(key);
+            let mut values = val(&[]);
+            let mut get =
+                |op| match unsafe { lmdb_sys::mdb_cursor_get(cursor, &mut key, &mut values, op) } {
+                    0 => Ok(Some(unsafe { bytes(&values) })),
+                    lmdb_sys::MDB_NOTFOUND => Ok(None),
+                    rc => Err(lmdb::Error::from_err_code(rc)),
+                };
+            if get(lmdb_sys::MDB_SET_KEY)?.is_none() {
+                return Ok(());
+            }
+            let mut op = lmdb_sys::MDB_GET_MULTIPLE;
+            // Each page is valid until the next cursor op, which happens
+            // after `f`
+            while let Some(page) = get(op)? {
+                if !page.chunks_exact(value_len).all(&mut *f) {
+                    break;
+                }
+                op = lmdb_sys::MDB_NEXT_MULTIPLE;
+            }
+            Ok(())
+        })?)
+    }
+
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        Ok(self.read(|txn| {
+            let cursor = self.cursor(txn, table)?;
+            let mut key = val(key);
+            let mut value = val(&[]);
+            let rc = unsafe {
+                lmdb_sys::mdb_cursor_get(cursor, &mut key, &mut value, lmdb_sys::MDB_SET)
+            };
+            match rc {
+                0 => {}
+                lmdb_sys::MDB_NOTFOUND => return Ok(0),
+                rc => return Err(lmdb::Error::from_err_code(rc)),
+            }
+            let mut count = 0;
+            lmdb_result(unsafe { lmdb_sys::mdb_cursor_count(cursor, &mut count) })?;
+            Ok(count)
+        })?)
+    }
+
+    /// If the map fills up the transaction is discarded, the map doubled and
+    /// the batch written again from scratch.
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        loop {
+            let (map_size, result) = {
+                let _guard = MAP_LOCK.read().unwrap();
+                (self.map_size()?, self.write(batch))
+            };
+            match result {
+                Err(lmdb::Error::MapFull) => self.grow_map(map_size)?,
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    fn commit(&self) -> Result<(), KorndexError> {
+        self.env.sync(true)?;
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        let _guard = MAP_LOCK.read().unwrap();
+        let mut txn = self.env.begin_rw_txn()?;
+        txn.clear_db(self.db(table))?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// LMDB never shrinks its data file, so the used pages are copied into a
+    /// fresh environment next to this one, whose data file then replaces it.
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        let compacted = self.path.with_extension("compact");
+        if compacted.exists() {
+            fs::remove_dir_all(&compacted)?;
+        }
+        fs::create_dir_all(&compacted)?;
+        let dest = compacted
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| {
+                KorndexError::Config(format!("Unsupported index path {}", compacted.display()))
+            })?;
+        {
+            let _guard = MAP_LOCK.read().unwrap();
+            lmdb_result(unsafe {
+                lmdb_sys::mdb_env_copy2(self.env.env(), dest.as_ptr(), lmdb_sys::MDB_CP_COMPACT)
+            })?;
+        }
+        let path = self.path.clone();
+        drop(self);
+        // The rename is atomic, a crash leaves one of the two data files
+        fs::rename(compacted.join("data.mdb"), path.join("data.mdb"))?;
+        fs::remove_dir_all(&compacted)?;
+        Ok(super::path_size(&path)?)
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        let sync_flags = lmdb_sys::MDB_NOSYNC | lmdb_sys::MDB_NOMETASYNC;
+        let flags = match durability {
+            Durability::Full => 0,
+            Durability::Batch => lmdb_sys::MDB_NOMETASYNC,
+            Durability::Async => lmdb_sys::MDB_NOSYNC,
+        };
+        let env = self.env.env();
+        lmdb_result(unsafe { lmdb_sys::mdb_env_set_flags(env, sync_flags, 0) })?;
+        if flags != 0 {
+            lmdb_result(unsafe { lmdb_sys::mdb_env_set_flags(env, flags, 1) })?;
+        }
+        Ok(())
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        Ok(super::path_size(&self.path)?)
+    }
+
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        let info = self.env_info()?;
+        let page_size = self.env.stat()?.page_size() as u64;
+        let used = (info.me_last_pgno as u64 + 1) * page_size;
+        Ok(Some((used, info.me_mapsize as u64)))
+    }
+}
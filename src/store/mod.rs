@@ -0,0 +1,328 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::KorndexError;
+
+mod bytes;
+mod compressed;
+#[cfg(feature = "lmdb")]
+mod lmdb;
+#[cfg(feature = "redb")]
+mod redb;
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+mod sharded;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod tiered;
+
+pub use self::bytes::Bytes;
+pub use self::compressed::{CompressedStore, TableCompression};
+#[cfg(feature = "lmdb")]
+pub use self::lmdb::LmdbStore;
+#[cfg(feature = "redb")]
+pub use self::redb::RedbStore;
+#[cfg(feature = "rocksdb")]
+pub use self::rocksdb::RocksDbStore;
+pub use self::sharded::{check_shards, ShardedStore, MAX_SHARDS};
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+pub use self::tiered::TieredStore;
+
+/// Storage backend holding the index.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Backend {
+    /// Requires building with the `lmdb` feature (on by default)
+    Lmdb,
+    /// Requires building with the `redb` feature
+    Redb,
+    /// Requires building with the `rocksdb` feature
+    Rocksdb,
+    /// Requires building with the `sqlite` feature
+    Sqlite,
+}
+
+/// When the batches written to a store reach the disk. Builds always end with
+/// [`KvStore::commit`], and an index left behind by a crash resumes from its
+/// last durable checkpoint.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync every batch as it is written
+    #[default]
+    Full,
+    /// Skip part of the sync of every batch. A process crash loses nothing,
+    /// a power loss or OS crash can lose the last batches but leaves the store
+    /// consistent: LMDB syncs the data pages but not the meta page pointing
+    /// at them, so it can fall back to the previous transaction; redb commits
+    /// without syncing until a fully synced commit; RocksDB writes its WAL
+    /// without syncing it; SQLite syncs its WAL only at checkpoints
+    Batch,
+    /// Sync only when a build finishes, a crash loses every batch of the
+    /// build. Fastest for an initial build
+    Async,
+}
+
+/// Identifies one of the index tables, so undo records can refer to the table
+/// an entry was written to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    TxIndex,
+    ScriptHash,
+    Spent,
+    Tweaks,
+    Wtxid,
+    Filters,
+    FilterHeights,
+    FilterHeaders,
+    Meta,
+    Undo,
+    BlockTxids,
+    Utxo,
+    Headers,
+    MedianTimes,
+    BlockStats,
+    ScriptTypes,
+    Parents,
+    Children,
+    Witness,
+    Coinbase,
+    ValueHistograms,
+    Locktimes,
+    Duplicates,
+}
+
+impl Table {
+    pub const ALL: [Table; 23] = [
+        Table::TxIndex,
+        Table::ScriptHash,
+        Table::Spent,
+        Table::Tweaks,
+        Table::Wtxid,
+        Table::Filters,
+        Table::FilterHeights,
+        Table::FilterHeaders,
+        Table::Meta,
+        Table::Undo,
+        Table::BlockTxids,
+        Table::Utxo,
+        Table::Headers,
+        Table::MedianTimes,
+        Table::BlockStats,
+        Table::ScriptTypes,
+        Table::Parents,
+        Table::Children,
+        Table::Witness,
+        Table::Coinbase,
+        Table::ValueHistograms,
+        Table::Locktimes,
+        Table::Duplicates,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Table::TxIndex => "txindex",
+            Table::ScriptHash => "scripthash",
+            Table::Spent => "spent",
+            Table::Tweaks => "tweaks",
+            Table::Wtxid => "wtxid",
+            Table::Filters => "filters",
+            Table::FilterHeights => "filter_heights",
+            Table::FilterHeaders => "filter_headers",
+            Table::Meta => "meta",
+            Table::Undo => "undo",
+            Table::BlockTxids => "block_txids",
+            Table::Utxo => "utxo",
+            Table::Headers => "headers",
+            Table::MedianTimes => "median_times",
+            Table::BlockStats => "block_stats",
+            Table::ScriptTypes => "script_types",
+            Table::Parents => "parents",
+            Table::Children => "children",
+            Table::Witness => "witness",
+            Table::Coinbase => "coinbase",
+            Table::ValueHistograms => "value_histograms",
+            Table::Locktimes => "locktimes",
+            Table::Duplicates => "duplicates",
+        }
+    }
+
+    /// Key and value lengths of the tables mapping a key to several values.
+    /// Both are fixed, so LMDB can pack a key's values into its pages and
+    /// return them a page at a time.
+    fn dup_layout(self) -> Option<(usize, usize)> {
+        match self {
+            Table::ScriptHash => Some((32, crate::scripthash::ENTRY_SIZE)),
+            // Txids of the transactions spending a transaction's outputs
+            Table::Children => Some((32, 32)),
+            _ => None,
+        }
+    }
+
+    /// Tables holding several values per key need the exact value to delete a
+    /// single entry.
+    pub fn is_dup_sort(self) -> bool {
+        self.dup_layout().is_some()
+    }
+
+    /// Key length of a dup-sorted table. Backends without native duplicates
+    /// store `key || value` and split it again at this offset.
+    pub fn dup_key_len(self) -> usize {
+        match self.dup_layout() {
+            Some((key_len, _)) => key_len,
+            None => unreachable!("{} is not dup-sorted", self.name()),
+        }
+    }
+
+    /// Length of every value of a dup-sorted table.
+    pub fn dup_value_len(self) -> usize {
+        match self.dup_layout() {
+            Some((_, value_len)) => value_len,
+            None => unreachable!("{} is not dup-sorted", self.name()),
+        }
+    }
+}
+
+/// A single write in a [`Batch`].
+pub enum Op {
+    Put(Table, Bytes, Bytes),
+    /// Deleting from a dup-sorted table needs the value, other tables ignore it.
+    Delete(Table, Bytes, Option<Bytes>),
+}
+
+impl Op {
+    pub fn table(&self) -> Table {
+        match self {
+            Op::Put(table, ..) | Op::Delete(table, ..) => *table,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Op::Put(_, key, _) | Op::Delete(_, key, _) => key,
+        }
+    }
+}
+
+/// Writes applied atomically by [`KvStore::put_batch`].
+#[derive(Default)]
+pub struct Batch {
+    pub ops: Vec<Op>,
+}
+
+impl Batch {
+    pub fn put(&mut self, table: Table, key: impl Into<Bytes>, value: impl Into<Bytes>) {
+        self.ops.push(Op::Put(table, key.into(), value.into()));
+    }
+
+    pub fn delete(&mut self, table: Table, key: impl Into<Bytes>, value: Option<Bytes>) {
+        self.ops.push(Op::Delete(table, key.into(), value));
+    }
+}
+
+/// A key-value store holding every index table.
+pub trait KvStore: Send + Sync {
+    /// The value stored under `key`, or the first one for dup-sorted tables.
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError>;
+
+    /// [`KvStore::get`] for each of `keys`, read in a single transaction where
+    /// the backend has them.
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        keys.iter().map(|key| self.get(table, key)).collect()
+    }
+
+    /// Calls `f` with every entry whose key starts with `prefix`, in key order,
+    /// until it returns false. Dup-sorted tables yield each value separately.
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError>;
+
+    /// Calls `f` with each value stored under `key` in a dup-sorted table, in
+    /// sort order, until it returns false.
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.iter_prefix(table, key, &mut |k, value| k != key || f(value))
+    }
+
+    /// Number of values stored under `key` in a dup-sorted table.
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        let mut count = 0;
+        self.iter_dups(table, key, &mut |_| {
+            count += 1;
+            true
+        })?;
+        Ok(count)
+    }
+
+    /// Applies all writes of `batch` atomically. Deleting a missing entry is
+    /// not an error.
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError>;
+
+    /// Makes every applied batch durable.
+    fn commit(&self) -> Result<(), KorndexError>;
+
+    /// Deletes every entry of `table` at once.
+    fn clear(&self, table: Table) -> Result<(), KorndexError>;
+
+    /// Rewrites the store without the space left behind by deleted entries and
+    /// closes it, returning the bytes it then occupies on disk. Nothing else
+    /// may have the store open.
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError>;
+
+    /// Sets when the batches applied from now on become durable, see
+    /// [`Durability`].
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError>;
+
+    /// Bytes the store occupies on disk.
+    fn disk_size(&self) -> Result<u64, KorndexError>;
+
+    /// Bytes in use and the current capacity of a store preallocating its
+    /// space, like LMDB's memory map. `None` for stores that grow freely.
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        Ok(None)
+    }
+}
+
+impl<'a> dyn KvStore + 'a {
+    /// All values stored under `key` in a dup-sorted table, in sort order.
+    pub fn get_dups(&self, table: Table, key: &[u8]) -> Result<Vec<Vec<u8>>, KorndexError> {
+        let mut values = Vec::new();
+        self.iter_dups(table, key, &mut |value| {
+            values.push(value.to_vec());
+            true
+        })?;
+        Ok(values)
+    }
+
+    pub fn is_empty(&self, table: Table) -> Result<bool, KorndexError> {
+        let mut is_empty = true;
+        self.iter_prefix(table, &[], &mut |_, _| {
+            is_empty = false;
+            false
+        })?;
+        Ok(is_empty)
+    }
+}
+
+/// Total size of the files at `path`, recursing into directories.
+fn path_size(path: &Path) -> std::io::Result<u64> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    fs::read_dir(path)?
+        .map(|entry| path_size(&entry?.path()))
+        .sum()
+}
+
+/// Big-endian so that height-keyed entries sort by height.
+pub fn height_key(height: i32) -> [u8; 4] {
+    (height as u32).to_be_bytes()
+}
@@ -0,0 +1,185 @@
+use redb::{
+    Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::KorndexError;
+
+/// Dup-sorted tables map to redb multimap tables, everything else to plain
+/// tables.
+pub struct RedbStore {
+    db: Database,
+    durability: Mutex<Durability>,
+    path: PathBuf,
+}
+
+fn definition(table: Table) -> TableDefinition<'static, &'static [u8], &'static [u8]> {
+    TableDefinition::new(table.name())
+}
+
+fn multimap_definition(
+    table: Table,
+) -> MultimapTableDefinition<'static, &'static [u8], &'static [u8]> {
+    MultimapTableDefinition::new(table.name())
+}
+
+impl RedbStore {
+    /// Opens the database file at `path` and creates (or opens) every table.
+    pub fn open(path: &Path) -> Result<Self, KorndexError> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        for table in Table::ALL {
+            if table.is_dup_sort() {
+                txn.open_multimap_table(multimap_definition(table))?;
+            } else {
+                txn.open_table(definition(table))?;
+            }
+        }
+        txn.commit()?;
+        Ok(RedbStore {
+            db,
+            durability: Mutex::new(Durability::Full),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl KvStore for RedbStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        Ok(self.get_many(table, &[key])?.remove(0))
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        let txn = self.db.begin_read()?;
+        let mut values = Vec::with_capacity(keys.len());
+        if table.is_dup_sort() {
+            let multimap = txn.open_multimap_table(multimap_definition(table))?;
+            for key in keys {
+                let first = multimap.get(*key)?.next().transpose()?;
+                values.push(first.map(|value| value.value().to_vec()));
+            }
+        } else {
+            let plain = txn.open_table(definition(table))?;
+            for key in keys {
+                values.push(plain.get(*key)?.map(|value| value.value().to_vec()));
+            }
+        }
+        Ok(values)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let txn = self.db.begin_read()?;
+        if table.is_dup_sort() {
+            let multimap = txn.open_multimap_table(multimap_definition(table))?;
+            for item in multimap.range(prefix..)? {
+                let (key, values) = item?;
+                if !key.value().starts_with(prefix) {
+                    break;
+                }
+                for value in values {
+                    if !f(key.value(), value?.value()) {
+                        return Ok(());
+                    }
+                }
+            }
+            return Ok(());
+        }
+        for item in txn.open_table(definition(table))?.range(prefix..)? {
+            let (key, value) = item?;
+            if !key.value().starts_with(prefix) || !f(key.value(), value.value()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(match *self.durability.lock().unwrap() {
+            Durability::Full => redb::Durability::Immediate,
+            Durability::Batch => redb::Durability::Eventual,
+            Durability::Async => redb::Durability::None,
+        });
+        // A table can only be opened once per transaction, so apply the batch
+        // table by table. Ops on different tables are independent.
+        for table in Table::ALL {
+            let ops = batch.ops.iter().filter(|op| op.table() == table);
+            if table.is_dup_sort() {
+                let mut multimap = txn.open_multimap_table(multimap_definition(table))?;
+                for op in ops {
+                    match op {
+                        Op::Put(_, key, value) => {
+                            multimap.insert(key.as_slice(), value.as_slice())?;
+                        }
+                        Op::Delete(_, key, Some(value)) => {
+                            multimap.remove(key.as_slice(), value.as_slice())?;
+                        }
+                        Op::Delete(_, key, None) => {
+                            multimap.remove_all(key.as_slice())?;
+                        }
+                    }
+                }
+            } else {
+                let mut plain = txn.open_table(definition(table))?;
+                for op in ops {
+                    match op {
+                        Op::Put(_, key, value) => {
+                            plain.insert(key.as_slice(), value.as_slice())?;
+                        }
+                        Op::Delete(_, key, _) => {
+                            plain.remove(key.as_slice())?;
+                        }
+                    }
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        let txn = self.db.begin_write()?;
+        if table.is_dup_sort() {
+            txn.delete_multimap_table(multimap_definition(table))?;
+            txn.open_multimap_table(multimap_definition(table))?;
+        } else {
+            txn.delete_table(definition(table))?;
+            txn.open_table(definition(table))?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        let mut store = *self;
+        store.db.compact()?;
+        Ok(super::path_size(&store.path)?)
+    }
+
+    /// Transactions committed with [`Durability::Full`] are durable already,
+    /// others become durable with an empty immediate commit.
+    fn commit(&self) -> Result<(), KorndexError> {
+        if *self.durability.lock().unwrap() != Durability::Full {
+            let mut txn = self.db.begin_write()?;
+            txn.set_durability(redb::Durability::Immediate);
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        *self.durability.lock().unwrap() = durability;
+        Ok(())
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        Ok(super::path_size(&self.path)?)
+    }
+}
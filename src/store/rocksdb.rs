@@ -0,0 +1,150 @@
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch,
+    WriteOptions, DB,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::KorndexError;
+
+/// One column family per table. RocksDB has no duplicate keys, so dup-sorted
+/// tables store `key || value` with an empty value instead.
+pub struct RocksDbStore {
+    db: DB,
+    durability: Mutex<Durability>,
+    path: PathBuf,
+}
+
+impl RocksDbStore {
+    /// Opens the database at `path`, creating it and any missing tables.
+    pub fn open(path: &Path) -> Result<Self, rocksdb::Error> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let cfs = Table::ALL
+            .iter()
+            .map(|table| ColumnFamilyDescriptor::new(table.name(), Options::default()));
+        Ok(RocksDbStore {
+            db: DB::open_cf_descriptors(&options, path, cfs)?,
+            durability: Mutex::new(Durability::Full),
+            path: path.to_owned(),
+        })
+    }
+
+    fn cf(&self, table: Table) -> &ColumnFamily {
+        self.db
+            .cf_handle(table.name())
+            .expect("column families are created on open")
+    }
+}
+
+fn composite_key(key: &[u8], value: &[u8]) -> Vec<u8> {
+    [key, value].concat()
+}
+
+impl KvStore for RocksDbStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        if !table.is_dup_sort() {
+            return Ok(self.db.get_cf(self.cf(table), key)?);
+        }
+        let mut first = None;
+        self.iter_prefix(table, key, &mut |k, value| {
+            if k == key {
+                first = Some(value.to_vec());
+            }
+            first.is_none()
+        })?;
+        Ok(first)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let mode = IteratorMode::From(prefix, Direction::Forward);
+        for item in self.db.iterator_cf(self.cf(table), mode) {
+            let (key, value) = item?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let more = if table.is_dup_sort() {
+                let (key, value) = key.split_at(table.dup_key_len());
+                f(key, value)
+            } else {
+                f(&key, &value)
+            };
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        let mut writes = WriteBatch::default();
+        for op in batch.ops.iter() {
+            match op {
+                Op::Put(table, key, value) if table.is_dup_sort() => {
+                    writes.put_cf(self.cf(*table), composite_key(key, value), b"")
+                }
+                Op::Put(table, key, value) => writes.put_cf(self.cf(*table), key, value),
+                Op::Delete(table, key, Some(value)) if table.is_dup_sort() => {
+                    writes.delete_cf(self.cf(*table), composite_key(key, value))
+                }
+                Op::Delete(table, key, _) => writes.delete_cf(self.cf(*table), key),
+            }
+        }
+        let mut options = WriteOptions::default();
+        match *self.durability.lock().unwrap() {
+            Durability::Full => options.set_sync(true),
+            Durability::Batch => {}
+            Durability::Async => options.disable_wal(true),
+        }
+        self.db.write_opt(writes, &options)?;
+        Ok(())
+    }
+
+    /// Writes made without the write-ahead log only become durable once the
+    /// memtables holding them are flushed.
+    fn commit(&self) -> Result<(), KorndexError> {
+        if *self.durability.lock().unwrap() == Durability::Async {
+            for table in Table::ALL {
+                self.db.flush_cf(self.cf(table))?;
+            }
+        }
+        self.db.flush_wal(true)?;
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        let cf = self.cf(table);
+        let last = self.db.iterator_cf(cf, IteratorMode::End).next();
+        if let Some((last, _)) = last.transpose()? {
+            // The end of the range is exclusive, and the last key followed by
+            // a zero byte sorts right after it
+            let end = [&last[..], &[0u8][..]].concat();
+            self.db.delete_range_cf(cf, Vec::new(), end)?;
+        }
+        Ok(())
+    }
+
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        for table in Table::ALL {
+            self.db
+                .compact_range_cf(self.cf(table), None::<&[u8]>, None::<&[u8]>);
+        }
+        Ok(super::path_size(&self.path)?)
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        *self.durability.lock().unwrap() = durability;
+        Ok(())
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        Ok(super::path_size(&self.path)?)
+    }
+}
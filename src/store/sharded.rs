@@ -0,0 +1,335 @@
+use rayon::prelude::*;
+use std::mem;
+use std::sync::Mutex;
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::meta::{self, ShardCommit};
+use crate::txindex::TxIndexEntry;
+use crate::KorndexError;
+
+/// Most shards the txindex can be split into, one per first txid byte.
+pub const MAX_SHARDS: usize = 256;
+
+/// Splits the txindex of `inner` into shards, each a store of its own, so a
+/// bulk build writes and commits them in parallel. Shard `i` holds the txids
+/// whose first byte `b` has `b * shards / 256 == i`, contiguous ranges of
+/// keys, so walking the shards in order walks the txindex in key order.
+/// Every other table stays in `inner`.
+///
+/// A commit commits the shards before `inner`, which holds the undo records
+/// and best block, so the shards never miss entries of a committed block.
+/// Each shard records with its commit the heights of the blocks it wrote and
+/// the entries it deleted, and both count their commits. A crash between the
+/// two leaves shards a commit ahead of `inner`, which [`ShardedStore::recover`]
+/// undoes when the index is next opened for writing. Until then readers can
+/// see entries of blocks past the best block.
+pub struct ShardedStore {
+    inner: Box<dyn KvStore>,
+    shards: Vec<Box<dyn KvStore>>,
+    uncommitted: Mutex<Uncommitted>,
+}
+
+/// What the shards wrote since the last commit.
+struct Uncommitted {
+    /// Commits so far
+    generation: u64,
+    /// Lowest and highest height whose undo record was written
+    heights: Option<(i32, i32)>,
+    /// Txindex entries deleted from each shard, with their values
+    deleted: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl ShardedStore {
+    pub fn new(
+        inner: Box<dyn KvStore>,
+        shards: Vec<Box<dyn KvStore>>,
+    ) -> Result<Self, KorndexError> {
+        check_shards(shards.len())?;
+        let uncommitted = Uncommitted {
+            generation: meta::read_commit_generation(&*inner)?,
+            heights: None,
+            deleted: shards.iter().map(|_| Vec::new()).collect(),
+        };
+        Ok(ShardedStore {
+            inner,
+            shards,
+            uncommitted: Mutex::new(uncommitted),
+        })
+    }
+
+    /// Undoes the last commit of each shard that is ahead of `inner`, whose
+    /// commit a crash lost: deletes the entries of the blocks it wrote and
+    /// restores those it deleted.
+    pub fn recover(&self) -> Result<(), KorndexError> {
+        let generation = meta::read_commit_generation(&*self.inner)?;
+        for (i, shard) in self.shards.iter().enumerate() {
+            let Some(commit) =
+                meta::read_shard_commit(&**shard)?.filter(|commit| commit.generation > generation)
+            else {
+                continue;
+            };
+            tracing::warn!(
+                "Txindex shard {} committed blocks the index did not, undoing its last commit",
+                i
+            );
+            let mut batch = Batch::default();
+            if let Some((lowest, highest)) = commit.heights {
+                let mut result = Ok(());
+                shard.iter_prefix(
+                    Table::TxIndex,
+                    &[],
+                    &mut |key, value| match TxIndexEntry::decode(value) {
+                        Ok(entry) => {
+                            if (lowest..=highest).contains(&entry.block_height) {
+                                batch.delete(Table::TxIndex, key.to_vec(), None);
+                            }
+                            true
+                        }
+                        Err(e) => {
+                            result = Err(e);
+                            false
+                        }
+                    },
+                )?;
+                result?;
+            }
+            for (key, value) in commit.deleted {
+                batch.put(Table::TxIndex, key, value);
+            }
+            meta::write_shard_commit(
+                &mut batch,
+                &ShardCommit {
+                    generation,
+                    ..Default::default()
+                },
+            )?;
+            shard.put_batch(&batch)?;
+            shard.commit()?;
+        }
+        Ok(())
+    }
+
+    /// The shard holding the txindex entry of `key`.
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let first = key.first().copied().unwrap_or(0) as usize;
+        first * self.shards.len() / MAX_SHARDS
+    }
+
+    fn shard(&self, key: &[u8]) -> &dyn KvStore {
+        &*self.shards[self.shard_index(key)]
+    }
+}
+
+/// Fails unless the txindex can be split into `shards` shards.
+pub fn check_shards(shards: usize) -> Result<(), KorndexError> {
+    if !(1..=MAX_SHARDS).contains(&shards) {
+        return Err(KorndexError::InvalidInput(
+            format!(
+                "The txindex is split into 1 to {} shards, not {}",
+                MAX_SHARDS, shards
+            )
+            .into(),
+        ));
+    }
+    Ok(())
+}
+
+impl KvStore for ShardedStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        match table {
+            Table::TxIndex => self.shard(key).get(table, key),
+            _ => self.inner.get(table, key),
+        }
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        if table != Table::TxIndex {
+            return self.inner.get_many(table, keys);
+        }
+        // One read of each shard for the keys it holds
+        let mut by_shard: Vec<(Vec<usize>, Vec<&[u8]>)> =
+            self.shards.iter().map(|_| Default::default()).collect();
+        for (position, key) in keys.iter().enumerate() {
+            let (positions, shard_keys) = &mut by_shard[self.shard_index(key)];
+            positions.push(position);
+            shard_keys.push(key);
+        }
+        let mut values = vec![None; keys.len()];
+        for (shard, (positions, shard_keys)) in self.shards.iter().zip(by_shard) {
+            if shard_keys.is_empty() {
+                continue;
+            }
+            let found = shard.get_many(table, &shard_keys)?;
+            for (position, value) in positions.into_iter().zip(found) {
+                values[position] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        if table != Table::TxIndex {
+            return self.inner.iter_prefix(table, prefix, f);
+        }
+        if !prefix.is_empty() {
+            return self.shard(prefix).iter_prefix(table, prefix, f);
+        }
+        let mut stopped = false;
+        for shard in &self.shards {
+            shard.iter_prefix(table, prefix, &mut |key, value| {
+                stopped = !f(key, value);
+                !stopped
+            })?;
+            if stopped {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        self.inner.iter_dups(table, key, f)
+    }
+
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        self.inner.count_dups(table, key)
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        let mut uncommitted = self.uncommitted.lock().unwrap();
+        for op in &batch.ops {
+            if let Op::Put(Table::Undo, key, _) = op {
+                let height = u32::from_be_bytes(key[..].try_into()?) as i32;
+                uncommitted.heights = Some(match uncommitted.heights {
+                    Some((lowest, highest)) => (lowest.min(height), highest.max(height)),
+                    None => (height, height),
+                });
+            }
+        }
+        if !batch.ops.iter().any(|op| op.table() == Table::TxIndex) {
+            return self.inner.put_batch(batch);
+        }
+        let mut shard_batches: Vec<Batch> = self.shards.iter().map(|_| Batch::default()).collect();
+        let mut rest = Batch::default();
+        for op in &batch.ops {
+            let op = match op {
+                Op::Put(table, key, value) => Op::Put(*table, key.clone(), value.clone()),
+                Op::Delete(table, key, value) => Op::Delete(*table, key.clone(), value.clone()),
+            };
+            match op.table() {
+                Table::TxIndex => shard_batches[self.shard_index(op.key())].ops.push(op),
+                _ => rest.ops.push(op),
+            }
+        }
+        self.shards
+            .par_iter()
+            .zip(shard_batches.par_iter())
+            .zip(uncommitted.deleted.par_iter_mut())
+            .filter(|((_, batch), _)| !batch.ops.is_empty())
+            .try_for_each(|((shard, batch), deleted)| {
+                // Deleted entries are kept to restore them if the commit of
+                // `inner` is lost
+                let keys: Vec<&[u8]> = batch
+                    .ops
+                    .iter()
+                    .filter(|op| matches!(op, Op::Delete(..)))
+                    .map(|op| op.key())
+                    .collect();
+                if !keys.is_empty() {
+                    let values = shard.get_many(Table::TxIndex, &keys)?;
+                    deleted.extend(
+                        keys.iter()
+                            .zip(values)
+                            .filter_map(|(key, value)| Some((key.to_vec(), value?))),
+                    );
+                }
+                shard.put_batch(batch)
+            })?;
+        self.inner.put_batch(&rest)
+    }
+
+    fn commit(&self) -> Result<(), KorndexError> {
+        let mut uncommitted = self.uncommitted.lock().unwrap();
+        let Uncommitted {
+            generation,
+            heights,
+            deleted,
+        } = &mut *uncommitted;
+        let next = *generation + 1;
+        self.shards
+            .par_iter()
+            .zip(deleted.par_iter_mut())
+            .try_for_each(|(shard, deleted)| {
+                let mut batch = Batch::default();
+                let commit = ShardCommit {
+                    generation: next,
+                    heights: *heights,
+                    deleted: mem::take(deleted),
+                };
+                meta::write_shard_commit(&mut batch, &commit)?;
+                shard.put_batch(&batch)?;
+                shard.commit()
+            })?;
+        let mut batch = Batch::default();
+        meta::write_commit_generation(&mut batch, next)?;
+        self.inner.put_batch(&batch)?;
+        self.inner.commit()?;
+        *generation = next;
+        *heights = None;
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        match table {
+            Table::TxIndex => self.shards.iter().try_for_each(|shard| shard.clear(table)),
+            _ => self.inner.clear(table),
+        }
+    }
+
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        let ShardedStore { inner, shards, .. } = *self;
+        let mut size = inner.compact()?;
+        for shard in shards {
+            size += shard.compact()?;
+        }
+        Ok(size)
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        for shard in &self.shards {
+            shard.set_durability(durability)?;
+        }
+        self.inner.set_durability(durability)
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        let mut size = self.inner.disk_size()?;
+        for shard in &self.shards {
+            size += shard.disk_size()?;
+        }
+        Ok(size)
+    }
+
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        let Some((mut used, mut capacity)) = self.inner.fill()? else {
+            return Ok(None);
+        };
+        for shard in &self.shards {
+            if let Some((shard_used, shard_capacity)) = shard.fill()? {
+                used += shard_used;
+                capacity += shard_capacity;
+            }
+        }
+        Ok(Some((used, capacity)))
+    }
+}
@@ -0,0 +1,248 @@
+use rusqlite::{params, Connection, Row};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::txindex::TxIndexEntry;
+use crate::KorndexError;
+
+/// The txindex gets real columns so it can be queried with plain SQL, e.g.
+/// `SELECT hex(txid), block_height, fee FROM txindex`. The other tables hold their
+/// raw keys and values as blobs.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+}
+
+fn key_column(table: Table) -> &'static str {
+    match table {
+        Table::TxIndex => "txid",
+        _ => "key",
+    }
+}
+
+fn create_table(conn: &Connection, table: Table) -> rusqlite::Result<()> {
+    let sql = match table {
+        Table::TxIndex => "CREATE TABLE IF NOT EXISTS txindex (
+            txid BLOB PRIMARY KEY,
+            block_height INTEGER NOT NULL,
+            position_in_block INTEGER NOT NULL,
+            fee INTEGER NOT NULL,
+            vsize INTEGER NOT NULL,
+            tx_offset INTEGER,
+            tx_size INTEGER
+        ) WITHOUT ROWID"
+            .to_owned(),
+        table if table.is_dup_sort() => format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                key BLOB NOT NULL,
+                value BLOB NOT NULL,
+                PRIMARY KEY (key, value)
+            ) WITHOUT ROWID",
+            table.name()
+        ),
+        table => format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                key BLOB PRIMARY KEY,
+                value BLOB NOT NULL
+            ) WITHOUT ROWID",
+            table.name()
+        ),
+    };
+    conn.execute(&sql, [])?;
+    Ok(())
+}
+
+/// Adds the byte range columns to a txindex table created before schema
+/// version 14.
+fn add_byte_range_columns(conn: &Connection) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info('txindex') WHERE name = 'tx_offset'")?
+        .exists([])?;
+    if !exists {
+        conn.execute_batch(
+            "ALTER TABLE txindex ADD COLUMN tx_offset INTEGER;
+             ALTER TABLE txindex ADD COLUMN tx_size INTEGER;",
+        )?;
+    }
+    Ok(())
+}
+
+/// Selects `(key, value columns...)` of a table, to be read back with [`value`].
+fn select(table: Table) -> String {
+    match table {
+        Table::TxIndex => {
+            "SELECT txid, block_height, position_in_block, fee, vsize, tx_offset, tx_size FROM txindex"
+                .to_owned()
+        }
+        table => format!("SELECT key, value FROM {}", table.name()),
+    }
+}
+
+/// Re-encodes a selected row into the value the rest of korndex expects.
+fn value(table: Table, row: &Row) -> Result<Vec<u8>, KorndexError> {
+    match table {
+        Table::TxIndex => {
+            let entry = TxIndexEntry {
+                block_height: row.get(1)?,
+                position_in_block: row.get::<_, i64>(2)? as usize,
+                fee: row.get::<_, i64>(3)? as u64,
+                vsize: row.get(4)?,
+                byte_range: match (row.get(5)?, row.get(6)?) {
+                    (Some(offset), Some(size)) => Some((offset, size)),
+                    _ => None,
+                },
+            };
+            Ok(entry.encode())
+        }
+        _ => Ok(row.get(1)?),
+    }
+}
+
+impl SqliteStore {
+    /// Opens the database file at `path` and creates (or opens) every table.
+    pub fn open(path: &Path) -> Result<Self, rusqlite::Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+        for table in Table::ALL {
+            create_table(&conn, table)?;
+        }
+        add_byte_range_columns(&conn)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl KvStore for SqliteStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        let conn = self.conn.lock().unwrap();
+        let sql = format!(
+            "{} WHERE {} = ?1 ORDER BY 1, 2 LIMIT 1",
+            select(table),
+            key_column(table)
+        );
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let mut rows = stmt.query([key])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(value(table, row)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let conn = self.conn.lock().unwrap();
+        // Blobs compare with memcmp, matching the byte order of the other
+        // backends
+        let sql = format!(
+            "{} WHERE {} >= ?1 ORDER BY 1, 2",
+            select(table),
+            key_column(table)
+        );
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let mut rows = stmt.query([prefix])?;
+        while let Some(row) = rows.next()? {
+            let key = row.get_ref(0)?.as_blob()?;
+            if !key.starts_with(prefix) || !f(key, &value(table, row)?) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction()?;
+        for op in batch.ops.iter() {
+            match op {
+                Op::Put(Table::TxIndex, key, value) => {
+                    let entry = TxIndexEntry::decode(value)?;
+                    txn.prepare_cached(
+                        "INSERT OR REPLACE INTO txindex
+                         (txid, block_height, position_in_block, fee, vsize, tx_offset, tx_size)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    )?
+                    .execute(params![
+                        key.as_slice(),
+                        entry.block_height,
+                        entry.position_in_block as i64,
+                        entry.fee as i64,
+                        entry.vsize,
+                        entry.byte_range.map(|(offset, _)| offset),
+                        entry.byte_range.map(|(_, size)| size)
+                    ])?;
+                }
+                Op::Put(table, key, value) => {
+                    let sql = format!(
+                        "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                        table.name()
+                    );
+                    txn.prepare_cached(&sql)?
+                        .execute(params![key.as_slice(), value.as_slice()])?;
+                }
+                Op::Delete(table, key, Some(value)) if table.is_dup_sort() => {
+                    let sql = format!("DELETE FROM {} WHERE key = ?1 AND value = ?2", table.name());
+                    txn.prepare_cached(&sql)?
+                        .execute(params![key.as_slice(), value.as_slice()])?;
+                }
+                Op::Delete(table, key, _) => {
+                    let sql = format!(
+                        "DELETE FROM {} WHERE {} = ?1",
+                        table.name(),
+                        key_column(*table)
+                    );
+                    txn.prepare_cached(&sql)?.execute(params![key.as_slice()])?;
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Checkpoints the write-ahead log into the database with a full sync,
+    /// whatever the durability of the batches in it.
+    fn commit(&self) -> Result<(), KorndexError> {
+        let conn = self.conn.lock().unwrap();
+        let synchronous: i64 = conn.query_row("PRAGMA synchronous", [], |row| row.get(0))?;
+        conn.execute_batch("PRAGMA synchronous = FULL;")?;
+        conn.query_row("PRAGMA wal_checkpoint(FULL)", [], |_| Ok(()))?;
+        conn.execute_batch(&format!("PRAGMA synchronous = {};", synchronous))?;
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(&format!("DELETE FROM {}", table.name()), [])?;
+        Ok(())
+    }
+
+    /// Rebuilds the database file with VACUUM and truncates the write-ahead
+    /// log.
+    fn compact(self: Box<Self>) -> Result<u64, KorndexError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch("VACUUM;")?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(super::path_size(&self.path)?)
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        let synchronous = match durability {
+            Durability::Full => "FULL",
+            Durability::Batch => "NORMAL",
+            Durability::Async => "OFF",
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute_batch(&format!("PRAGMA synchronous = {};", synchronous))?;
+        Ok(())
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        Ok(super::path_size(&self.path)?)
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::sync::RwLock;
+
+use super::{Batch, Durability, KvStore, Op, Table};
+use crate::{meta, KorndexError};
+
+/// Pending entries after which both generations are written out with the
+/// next batch recording a best block, whatever their height. Keeps a catch-up
+/// build from holding its batches in memory.
+const MAX_PENDING: usize = 1 << 20;
+
+/// What a generation changes about one key.
+enum Pending {
+    /// The new value of a key in a table with one value per key, `None` if
+    /// it was deleted
+    Value(Option<Vec<u8>>),
+    /// The changes to the values of a key in a dup-sorted table, applied in
+    /// this order
+    Dups {
+        /// Every value already stored was deleted
+        cleared: bool,
+        removed: BTreeSet<Vec<u8>>,
+        added: BTreeSet<Vec<u8>>,
+    },
+}
+
+impl Pending {
+    /// The values of the key after this change to `values`.
+    fn apply(&self, values: &mut Vec<Vec<u8>>) {
+        match self {
+            Pending::Value(value) => *values = value.iter().cloned().collect(),
+            Pending::Dups {
+                cleared,
+                removed,
+                added,
+            } => {
+                if *cleared {
+                    values.clear();
+                }
+                values.retain(|value| !removed.contains(value));
+                let mut merged: BTreeSet<Vec<u8>> = values.drain(..).collect();
+                merged.extend(added.iter().cloned());
+                values.extend(merged);
+            }
+        }
+    }
+}
+
+/// Writes held in memory since a commit, merged by key so that a value
+/// written and deleted again around the tip never reaches the disk.
+struct Generation {
+    /// Per table, in the order of [`Table::ALL`]
+    tables: Vec<BTreeMap<Vec<u8>, Pending>>,
+    /// Best height when the first write arrived, -1 for an empty index
+    first_height: Option<i32>,
+    len: usize,
+}
+
+impl Generation {
+    fn new() -> Self {
+        Generation {
+            tables: Table::ALL.iter().map(|_| BTreeMap::new()).collect(),
+            first_height: None,
+            len: 0,
+        }
+    }
+
+    fn pending(&self, table: Table, key: &[u8]) -> Option<&Pending> {
+        self.tables[table as usize].get(key)
+    }
+
+    fn apply(&mut self, op: &Op) {
+        let table = op.table();
+        let entry = self.tables[table as usize]
+            .entry(op.key().to_vec())
+            .or_insert_with(|| match table.is_dup_sort() {
+                true => Pending::Dups {
+                    cleared: false,
+                    removed: BTreeSet::new(),
+                    added: BTreeSet::new(),
+                },
+                false => Pending::Value(None),
+            });
+        self.len += 1;
+        match (op, entry) {
+            (Op::Put(_, _, value), Pending::Value(pending)) => *pending = Some(value.to_vec()),
+            (Op::Delete(..), Pending::Value(pending)) => *pending = None,
+            (Op::Put(_, _, value), Pending::Dups { removed, added, .. }) => {
+                removed.remove(value.as_slice());
+                added.insert(value.to_vec());
+            }
+            (Op::Delete(_, _, Some(value)), Pending::Dups { removed, added, .. }) => {
+                added.remove(value.as_slice());
+                removed.insert(value.to_vec());
+            }
+            (
+                Op::Delete(_, _, None),
+                Pending::Dups {
+                    cleared,
+                    removed,
+                    added,
+                },
+            ) => {
+                *cleared = true;
+                removed.clear();
+                added.clear();
+            }
+        }
+    }
+
+    /// The writes of this generation as a batch for the store below.
+    fn write_to(self, batch: &mut Batch) {
+        for (table, entries) in Table::ALL.into_iter().zip(self.tables) {
+            for (key, pending) in entries {
+                match pending {
+                    Pending::Value(Some(value)) => batch.put(table, key, value),
+                    Pending::Value(None) => batch.delete(table, key, None),
+                    Pending::Dups {
+                        cleared,
+                        removed,
+                        added,
+                    } => {
+                        if cleared {
+                            batch.delete(table, key.as_slice(), None);
+                        }
+                        for value in removed {
+                            batch.delete(table, key.as_slice(), Some(value.into()));
+                        }
+                        for value in added {
+                            batch.put(table, key.as_slice(), value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Tiers {
+    /// Written since the last rotation
+    young: Generation,
+    /// Written before it, and after the last blocks written to the store
+    /// below
+    old: Generation,
+    /// More than [`MAX_PENDING`] entries are pending
+    overflowed: bool,
+}
+
+/// A store keeping the entries of the most recent blocks in memory on top of
+/// another, for following the tip. The writes since the last rotation and
+/// those before it are held in two generations merged by key. A commit whose
+/// best block is `hot_blocks` above the first write of the newer generation
+/// writes the older one out in a single batch and makes the newer one older,
+/// so the last `hot_blocks` blocks stay in memory, where a reorg rewrites
+/// them without touching the disk.
+///
+/// The store below always holds a consistent prefix of the index, which a
+/// crash falls back to and other processes reading the index see, up to
+/// twice `hot_blocks` behind.
+pub struct TieredStore {
+    /// Taken by [`KvStore::compact`] only
+    inner: Option<Box<dyn KvStore>>,
+    hot_blocks: i32,
+    tiers: RwLock<Tiers>,
+}
+
+impl TieredStore {
+    pub fn new(inner: Box<dyn KvStore>, hot_blocks: usize) -> Self {
+        TieredStore {
+            inner: Some(inner),
+            hot_blocks: hot_blocks.try_into().unwrap_or(i32::MAX),
+            tiers: RwLock::new(Tiers {
+                young: Generation::new(),
+                old: Generation::new(),
+                overflowed: false,
+            }),
+        }
+    }
+
+    fn inner(&self) -> &dyn KvStore {
+        self.inner.as_deref().unwrap()
+    }
+
+    /// Writes both generations out to the store below. Only called where the
+    /// pending writes end with a best block, so the store below never holds
+    /// the entries of blocks it does not count as indexed.
+    fn flush(&self, tiers: &mut Tiers) -> Result<(), KorndexError> {
+        tiers.overflowed = false;
+        if tiers.young.len == 0 && tiers.old.len == 0 {
+            return Ok(());
+        }
+        let mut batch = Batch::default();
+        std::mem::replace(&mut tiers.old, Generation::new()).write_to(&mut batch);
+        std::mem::replace(&mut tiers.young, Generation::new()).write_to(&mut batch);
+        self.inner().put_batch(&batch)?;
+        self.inner().commit()
+    }
+
+    /// Values of `key` with the pending writes of both generations applied,
+    /// `None` if neither has any.
+    fn pending_values(
+        &self,
+        tiers: &Tiers,
+        table: Table,
+        key: &[u8],
+    ) -> Result<Option<Vec<Vec<u8>>>, KorndexError> {
+        let layers = [
+            tiers.old.pending(table, key),
+            tiers.young.pending(table, key),
+        ];
+        if layers.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        let mut values = match table.is_dup_sort() {
+            true => self.inner().get_dups(table, key)?,
+            false => self.inner().get(table, key)?.into_iter().collect(),
+        };
+        for pending in layers.into_iter().flatten() {
+            pending.apply(&mut values);
+        }
+        Ok(Some(values))
+    }
+}
+
+impl KvStore for TieredStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, KorndexError> {
+        let tiers = self.tiers.read().unwrap();
+        match self.pending_values(&tiers, table, key)? {
+            Some(values) => Ok(values.into_iter().next()),
+            None => self.inner().get(table, key),
+        }
+    }
+
+    fn get_many(&self, table: Table, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, KorndexError> {
+        let tiers = self.tiers.read().unwrap();
+        let mut values = self.inner().get_many(table, keys)?;
+        for (key, value) in keys.iter().zip(values.iter_mut()) {
+            if let Some(pending) = self.pending_values(&tiers, table, key)? {
+                *value = pending.into_iter().next();
+            }
+        }
+        Ok(values)
+    }
+
+    fn iter_prefix(
+        &self,
+        table: Table,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let tiers = self.tiers.read().unwrap();
+        let in_prefix = |generation: &Generation| -> Vec<Vec<u8>> {
+            generation.tables[table as usize]
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        let keys: BTreeSet<Vec<u8>> = in_prefix(&tiers.old)
+            .into_iter()
+            .chain(in_prefix(&tiers.young))
+            .collect();
+        if keys.is_empty() {
+            return self.inner().iter_prefix(table, prefix, f);
+        }
+
+        // Merge the pending keys into the stored entries in key order. The
+        // stored values of a pending key are collected while the store walks
+        // past it, and replaced by its merged values once it has.
+        let mut keys = keys.into_iter().peekable();
+        let mut collecting: Option<(Vec<u8>, Vec<Vec<u8>>)> = None;
+        let mut stopped = false;
+        let layers = |key: &[u8]| {
+            [
+                tiers.old.pending(table, key),
+                tiers.young.pending(table, key),
+            ]
+        };
+        let mut emit = |key: &[u8], mut values: Vec<Vec<u8>>, stopped: &mut bool| {
+            for pending in layers(key).into_iter().flatten() {
+                pending.apply(&mut values);
+            }
+            for value in values {
+                if !f(key, &value) {
+                    *stopped = true;
+                    return;
+                }
+            }
+        };
+        self.inner().iter_prefix(table, prefix, &mut |key, value| {
+            if let Some((_, values)) = collecting
+                .as_mut()
+                .filter(|(collected_key, _)| collected_key.as_slice() == key)
+            {
+                values.push(value.to_vec());
+                return true;
+            }
+            if let Some((collected_key, values)) = collecting.take() {
+                emit(&collected_key, values, &mut stopped);
+                if stopped {
+                    return false;
+                }
+            }
+            while let Some(pending_key) = keys.next_if(|pending_key| pending_key.as_slice() < key) {
+                emit(&pending_key, Vec::new(), &mut stopped);
+                if stopped {
+                    return false;
+                }
+            }
+            if keys
+                .next_if(|pending_key| pending_key.as_slice() == key)
+                .is_some()
+            {
+                collecting = Some((key.to_vec(), vec![value.to_vec()]));
+                return true;
+            }
+            emit(key, vec![value.to_vec()], &mut stopped);
+            !stopped
+        })?;
+        if stopped {
+            return Ok(());
+        }
+        if let Some((collected_key, values)) = collecting {
+            emit(&collected_key, values, &mut stopped);
+        }
+        for pending_key in keys {
+            if stopped {
+                break;
+            }
+            emit(&pending_key, Vec::new(), &mut stopped);
+        }
+        Ok(())
+    }
+
+    fn iter_dups(
+        &self,
+        table: Table,
+        key: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), KorndexError> {
+        let tiers = self.tiers.read().unwrap();
+        let Some(values) = self.pending_values(&tiers, table, key)? else {
+            return self.inner().iter_dups(table, key, f);
+        };
+        for value in values {
+            if !f(&value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn count_dups(&self, table: Table, key: &[u8]) -> Result<usize, KorndexError> {
+        let tiers = self.tiers.read().unwrap();
+        match self.pending_values(&tiers, table, key)? {
+            Some(values) => Ok(values.len()),
+            None => self.inner().count_dups(table, key),
+        }
+    }
+
+    fn put_batch(&self, batch: &Batch) -> Result<(), KorndexError> {
+        let best_height = meta::read_best_block(self)?.map_or(-1, |best| best.height);
+        let mut tiers = self.tiers.write().unwrap();
+        tiers.young.first_height.get_or_insert(best_height);
+        for op in &batch.ops {
+            tiers.young.apply(op);
+        }
+        if tiers.young.len + tiers.old.len > MAX_PENDING {
+            tiers.overflowed = true;
+        }
+        // Every change to the indexed chain records its best block last, a
+        // block's entries written without theirs would outlive a crash
+        if tiers.overflowed && meta::writes_best_block(batch) {
+            self.flush(&mut tiers)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), KorndexError> {
+        let Some(best) = meta::read_best_block(self)? else {
+            return Ok(());
+        };
+        let mut tiers = self.tiers.write().unwrap();
+        if tiers.overflowed {
+            return self.flush(&mut tiers);
+        }
+        let Some(first_height) = tiers.young.first_height else {
+            return Ok(());
+        };
+        if best.height < first_height.saturating_add(self.hot_blocks) {
+            return Ok(());
+        }
+        let old = std::mem::replace(&mut tiers.old, Generation::new());
+        if old.len > 0 {
+            let mut batch = Batch::default();
+            old.write_to(&mut batch);
+            self.inner().put_batch(&batch)?;
+            self.inner().commit()?;
+        }
+        tiers.old = std::mem::replace(&mut tiers.young, Generation::new());
+        // A generation of more blocks than are kept hot came from a batch of
+        // its own, like those of a catch-up build
+        if best.height >= first_height.saturating_add(self.hot_blocks.saturating_mul(2)) {
+            self.flush(&mut tiers)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&self, table: Table) -> Result<(), KorndexError> {
+        self.flush(&mut self.tiers.write().unwrap())?;
+        self.inner().clear(table)
+    }
+
+    fn compact(mut self: Box<Self>) -> Result<u64, KorndexError> {
+        self.flush(&mut self.tiers.write().unwrap())?;
+        self.inner.take().unwrap().compact()
+    }
+
+    fn set_durability(&self, durability: Durability) -> Result<(), KorndexError> {
+        self.inner().set_durability(durability)
+    }
+
+    fn disk_size(&self) -> Result<u64, KorndexError> {
+        self.inner().disk_size()
+    }
+
+    fn fill(&self) -> Result<Option<(u64, u64)>, KorndexError> {
+        self.inner().fill()
+    }
+}
+
+impl Drop for TieredStore {
+    fn drop(&mut self) {
+        if self.inner.is_none() {
+            return;
+        }
+        let tiers = self.tiers.get_mut().unwrap();
+        let mut tiers = std::mem::replace(
+            tiers,
+            Tiers {
+                young: Generation::new(),
+                old: Generation::new(),
+                overflowed: false,
+            },
+        );
+        if let Err(e) = self.flush(&mut tiers) {
+            tracing::error!("Failed to write the most recent blocks to the index: {}", e);
+        }
+    }
+}
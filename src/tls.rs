@@ -0,0 +1,83 @@
+//! TLS for the REST, Electrum and WebSocket listeners, with rustls. Built with
+//! the `tls` feature, without it the listeners only accept plain connections.
+
+use std::fmt;
+#[cfg(feature = "tls")]
+use std::fs;
+#[cfg(feature = "tls")]
+use std::net::TcpStream;
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use crate::listener::Stream;
+use crate::KorndexError;
+
+/// A PEM certificate chain and private key, to accept TLS connections with.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub certificate: Vec<u8>,
+    pub private_key: Vec<u8>,
+    #[cfg(feature = "tls")]
+    server: Arc<rustls::ServerConfig>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+impl TlsConfig {
+    /// Reads the certificate chain at `certificate` and the private key at
+    /// `private_key`, both PEM encoded.
+    #[cfg(feature = "tls")]
+    pub fn load(certificate: &Path, private_key: &Path) -> Result<Self, KorndexError> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|e| {
+                KorndexError::Config(format!("Failed to read {}: {}", path.display(), e))
+            })
+        };
+        let (certificate, private_key) = (read(certificate)?, read(private_key)?);
+        let chain = rustls_pemfile::certs(&mut &certificate[..]).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut &private_key[..])?
+            .ok_or_else(|| KorndexError::Config("No private key in the key file".to_owned()))?;
+        let server = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|e| KorndexError::Config(format!("Invalid certificate or key: {}", e)))?;
+        Ok(TlsConfig {
+            certificate,
+            private_key,
+            server: Arc::new(server),
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn load(_certificate: &Path, _private_key: &Path) -> Result<Self, KorndexError> {
+        Err(KorndexError::Config(
+            "korndex was built without TLS support, rebuild with --features tls".to_owned(),
+        ))
+    }
+
+    /// Starts a TLS session on the accepted connection `tcp`. The handshake
+    /// completes on the first read or write.
+    #[cfg(feature = "tls")]
+    fn accept(&self, tcp: TcpStream) -> Result<Stream, KorndexError> {
+        let connection = rustls::ServerConnection::new(self.server.clone())
+            .map_err(|e| KorndexError::Io(std::io::Error::other(e)))?;
+        Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(
+            connection, tcp,
+        ))))
+    }
+}
+
+/// Wraps the TCP connection `stream` in TLS if `tls` is set. Unix domain
+/// socket connections stay plain.
+pub fn accept(tls: Option<&TlsConfig>, stream: Stream) -> Result<Stream, KorndexError> {
+    match (tls, stream) {
+        #[cfg(feature = "tls")]
+        (Some(tls), Stream::Tcp(tcp)) => tls.accept(tcp),
+        (_, stream) => Ok(stream),
+    }
+}
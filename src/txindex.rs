@@ -0,0 +1,229 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::{Block, Transaction, TxOut, Txid};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{height_key, Bytes, KvStore, Table};
+use crate::KorndexError;
+
+/// Which location a txid keeps when a later block contains a transaction
+/// with the same txid. Before BIP30 two coinbase transactions on mainnet were
+/// repeated, see [`BIP30_DUPLICATES`], and the txid index holds a single
+/// location per txid. Recorded when the index is created, disconnecting the
+/// later block restores the earlier location.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DupPolicy {
+    /// The later transaction, whose outputs replaced the earlier one's in the
+    /// UTXO set
+    #[default]
+    Latest,
+    /// The first transaction with the txid
+    Earliest,
+    /// The later transaction, with every location of a repeated txid kept in
+    /// the duplicates table
+    All,
+}
+
+impl DupPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            DupPolicy::Latest => "latest",
+            DupPolicy::Earliest => "earliest",
+            DupPolicy::All => "all",
+        }
+    }
+}
+
+/// The mainnet coinbase txids that appear twice, with the heights of the
+/// blocks containing them.
+pub const BIP30_DUPLICATES: [(&str, [i32; 2]); 2] = [
+    (
+        "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468",
+        [91722, 91880],
+    ),
+    (
+        "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599",
+        [91812, 91842],
+    ),
+];
+
+/// Key of a location of a repeated txid in the duplicates table.
+pub fn duplicate_key(txid: &[u8; 32], block_height: i32) -> [u8; 36] {
+    let mut key = [0; 36];
+    key[..32].copy_from_slice(txid);
+    key[32..].copy_from_slice(&height_key(block_height));
+    key
+}
+
+/// Every location of `txid` in the index, ascending: those in the duplicates
+/// table for a repeated txid of an index built with [`DupPolicy::All`], else
+/// the one of the txid index.
+pub fn read_locations(store: &dyn KvStore, txid: &Txid) -> Result<Vec<TxIndexEntry>, KorndexError> {
+    let txid = txid.to_byte_array();
+    let mut values = Vec::new();
+    store.iter_prefix(Table::Duplicates, &txid, &mut |_, value| {
+        values.push(value.to_vec());
+        true
+    })?;
+    if values.is_empty() {
+        values.extend(store.get(Table::TxIndex, &txid)?);
+    }
+    values
+        .iter()
+        .map(|value| TxIndexEntry::decode(value))
+        .collect()
+}
+
+/// Location, fee and size of a transaction. Encoded as LEB128 varints in
+/// field order, so the encoding is the same on every platform, with the byte
+/// range only present if known.
+#[derive(Debug)]
+pub struct TxIndexEntry {
+    pub block_height: i32,
+    pub position_in_block: usize,
+    /// Fee in satoshis, 0 for the coinbase
+    pub fee: u64,
+    /// Virtual size in vbytes
+    pub vsize: u32,
+    /// Offset and length of the serialized transaction within its serialized
+    /// block, so it can be deserialized alone. `None` for entries written
+    /// before schema version 14
+    pub byte_range: Option<(u32, u32)>,
+}
+
+impl TxIndexEntry {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = Bytes::new();
+        write_varint(&mut buf, self.block_height as u32 as u64);
+        write_varint(&mut buf, self.position_in_block as u64);
+        write_varint(&mut buf, self.fee);
+        write_varint(&mut buf, self.vsize as u64);
+        if let Some((offset, size)) = self.byte_range {
+            write_varint(&mut buf, offset as u64);
+            write_varint(&mut buf, size as u64);
+        }
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        let mut data = data;
+        let invalid = || KorndexError::Corrupt("Invalid txindex entry".to_owned());
+        let block_height = u32::try_from(read_varint(&mut data)?).map_err(|_| invalid())? as i32;
+        let position_in_block = usize::try_from(read_varint(&mut data)?).map_err(|_| invalid())?;
+        let fee = read_varint(&mut data)?;
+        let vsize = u32::try_from(read_varint(&mut data)?).map_err(|_| invalid())?;
+        let byte_range = match data.is_empty() {
+            true => None,
+            false => {
+                let offset = u32::try_from(read_varint(&mut data)?).map_err(|_| invalid())?;
+                let size = u32::try_from(read_varint(&mut data)?).map_err(|_| invalid())?;
+                Some((offset, size))
+            }
+        };
+        if !data.is_empty() {
+            return Err(invalid());
+        }
+        Ok(TxIndexEntry {
+            block_height,
+            position_in_block,
+            fee,
+            vsize,
+            byte_range,
+        })
+    }
+
+    /// Fee rate in sat/vB.
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.vsize as f64
+    }
+}
+
+/// The transaction of `entry` from its serialized block, deserializing only
+/// the transaction's bytes if the entry has their range.
+pub fn read_transaction(
+    raw_block: &[u8],
+    entry: &TxIndexEntry,
+) -> Result<Transaction, KorndexError> {
+    let Some((offset, size)) = entry.byte_range else {
+        let mut block: Block = deserialize(raw_block)?;
+        if entry.position_in_block >= block.txdata.len() {
+            return Err(KorndexError::Corrupt(format!(
+                "Transaction at position {} is outside block {} of {} transactions",
+                entry.position_in_block,
+                entry.block_height,
+                block.txdata.len()
+            )));
+        }
+        return Ok(block.txdata.swap_remove(entry.position_in_block));
+    };
+    let range = offset as usize..offset as usize + size as usize;
+    let bytes = raw_block.get(range).ok_or_else(|| {
+        KorndexError::Corrupt(format!(
+            "Transaction at {}+{} is outside block {}",
+            offset, size, entry.block_height
+        ))
+    })?;
+    Ok(deserialize(bytes)?)
+}
+
+/// Fee paid by `tx`, whose inputs spend `prevouts`. 0 for a coinbase.
+pub fn fee(tx: &Transaction, prevouts: &[TxOut]) -> u64 {
+    if tx.is_coinbase() {
+        return 0;
+    }
+    let inputs: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+    let outputs: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+    inputs.saturating_sub(outputs)
+}
+
+/// Txids of the indexed block at `height` in block order, stored as
+/// concatenated 32-byte txids.
+pub fn read_block_txids(
+    store: &dyn KvStore,
+    height: i32,
+) -> Result<Option<Vec<Txid>>, KorndexError> {
+    let Some(data) = store.get(Table::BlockTxids, &height_key(height))? else {
+        return Ok(None);
+    };
+    let txids = data
+        .chunks_exact(32)
+        .map(Txid::from_slice)
+        .collect::<Result<_, _>>()?;
+    Ok(Some(txids))
+}
+
+/// The txid at `position` in `txids`, the txid list of the block at
+/// `block_height`.
+pub fn txid_at(txids: &[Txid], block_height: i32, position: usize) -> Result<Txid, KorndexError> {
+    txids.get(position).copied().ok_or_else(|| {
+        KorndexError::Corrupt(format!(
+            "Position {} is outside block {} of {} transactions",
+            position,
+            block_height,
+            txids.len()
+        ))
+    })
+}
+
+/// Appends `value` as an unsigned LEB128 varint: 7 bits per byte, least
+/// significant first, with the high bit set on every byte but the last.
+fn write_varint(buf: &mut Bytes, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads a varint written by [`write_varint`] from the front of `data`.
+fn read_varint(data: &mut &[u8]) -> Result<u64, KorndexError> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(KorndexError::Corrupt("Truncated varint".to_owned()))
+}
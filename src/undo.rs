@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+use crate::store::{height_key, Batch, Bytes, KvStore, Table};
+use crate::KorndexError;
+
+/// A single entry written to or deleted from one of the index databases. For
+/// written entries the value is only kept for dup-sorted tables, where it is
+/// needed to delete the entry again. Deleted entries keep the value to restore.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UndoEntry {
+    pub table: Table,
+    pub key: Bytes,
+    pub value: Option<Bytes>,
+}
+
+/// Everything written to the index for a single block, so the block can be
+/// disconnected again if it is reorged out of the active chain.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UndoRecord {
+    pub hash: [u8; 32],
+    pub entries: Vec<UndoEntry>,
+    /// Entries the block deleted, like the UTXOs it spent
+    pub deleted: Vec<UndoEntry>,
+}
+
+pub fn read_undo(store: &dyn KvStore, height: i32) -> Result<Option<UndoRecord>, KorndexError> {
+    match store.get(Table::Undo, &height_key(height))? {
+        Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_undo(batch: &mut Batch, height: i32, record: &UndoRecord) -> Result<(), KorndexError> {
+    let serialized = bincode::serialize(record)?;
+    batch.put(Table::Undo, height_key(height).to_vec(), serialized);
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use bitcoin::{Amount, OutPoint, ScriptBuf};
+
+use crate::scripthash::{self, Direction};
+use crate::spent;
+use crate::store::{Bytes, KvStore, Table};
+use crate::KorndexError;
+
+/// An unspent output, keyed by [`crate::spent::outpoint_key`]. Encoded as the
+/// big-endian height, a coinbase flag and the big-endian amount, followed by
+/// the scriptPubKey.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoEntry {
+    pub block_height: i32,
+    pub is_coinbase: bool,
+    pub value: Amount,
+    pub script_pubkey: ScriptBuf,
+}
+
+/// Length of the fixed fields preceding the scriptPubKey.
+const HEADER_SIZE: usize = 13;
+
+impl UtxoEntry {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = Bytes::new();
+        buf.extend_from_slice(&(self.block_height as u32).to_be_bytes());
+        buf.push(self.is_coinbase as u8);
+        buf.extend_from_slice(&self.value.to_sat().to_be_bytes());
+        buf.extend_from_slice(self.script_pubkey.as_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() < HEADER_SIZE {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid UTXO entry length {}",
+                data.len()
+            )));
+        }
+        Ok(UtxoEntry {
+            block_height: u32::from_be_bytes(data[0..4].try_into()?) as i32,
+            is_coinbase: data[4] != 0,
+            value: Amount::from_sat(u64::from_be_bytes(data[5..13].try_into()?)),
+            script_pubkey: ScriptBuf::from(data[HEADER_SIZE..].to_vec()),
+        })
+    }
+}
+
+/// Unspent outputs paying to the script with `scripthash`, in chain order.
+pub fn unspent(
+    store: &dyn KvStore,
+    scripthash: &[u8; 32],
+) -> Result<Vec<(OutPoint, UtxoEntry)>, KorndexError> {
+    let outpoints: Vec<OutPoint> = scripthash::history(store, scripthash)?
+        .into_iter()
+        .filter(|(_, entry)| entry.direction == Direction::Output)
+        .map(|(txid, entry)| OutPoint::new(txid, entry.index))
+        .collect();
+    let keys: Vec<[u8; 36]> = outpoints.iter().map(spent::outpoint_key).collect();
+    let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+    let mut utxos = Vec::new();
+    for (outpoint, data) in outpoints
+        .into_iter()
+        .zip(store.get_many(Table::Utxo, &keys)?)
+    {
+        if let Some(data) = data {
+            utxos.push((outpoint, UtxoEntry::decode(&data)?));
+        }
+    }
+    Ok(utxos)
+}
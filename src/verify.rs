@@ -0,0 +1,220 @@
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Block, Txid};
+use libbitcoinkernel_sys::ChainstateManager;
+use rayon::prelude::*;
+
+use crate::headers;
+use crate::indexes::{IndexKind, IndexSet};
+use crate::kernel;
+use crate::meta;
+use crate::store::{height_key, KvStore, Table};
+use crate::txindex::{self, TxIndexEntry};
+use crate::undo;
+use crate::KorndexError;
+
+/// Orphan entries printed individually before only the count is reported.
+const MAX_LISTED_ORPHANS: usize = 10;
+
+#[derive(Default)]
+struct BlockReport {
+    /// Transactions whose txindex entry points back at this block
+    confirmed: usize,
+    problems: Vec<String>,
+}
+
+/// Checks the index against the kernel's active chain: every block up to the
+/// best block must have its undo record, filter and txindex entries, and
+/// without `sample` every txindex entry must belong to one of those blocks.
+/// With `sample` only every Nth block is checked. Indexes the build left out
+/// are not checked.
+pub fn verify(
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    sample: Option<usize>,
+) -> Result<(), KorndexError> {
+    let Some(best) = meta::read_best_block(store)? else {
+        println!("Index is empty, nothing to verify");
+        return Ok(());
+    };
+    let first_height = meta::read_start_height(store)?.unwrap_or(0);
+    let indexes = meta::built_indexes(store)?;
+    let heights: Vec<i32> = (first_height..=best.height)
+        .step_by(sample.unwrap_or(1).max(1))
+        .collect();
+    tracing::info!("Verifying {} blocks", heights.len());
+    let reports: Vec<BlockReport> = heights
+        .par_iter()
+        .map(|&height| verify_block(chainman, store, height, indexes))
+        .collect::<Result<_, _>>()?;
+
+    let mut problems: Vec<String> = reports
+        .iter()
+        .flat_map(|report| report.problems.iter().cloned())
+        .collect();
+    let confirmed: usize = reports.iter().map(|report| report.confirmed).sum();
+
+    if sample.is_none() && indexes.contains(IndexKind::Txid) {
+        let mut entries = 0;
+        let mut past_best = 0;
+        store.iter_prefix(Table::TxIndex, &[], &mut |key, value| {
+            entries += 1;
+            let (Ok(txid), Ok(entry)) = (
+                Txid::from_slice(key),
+                TxIndexEntry::decode(value),
+            ) else {
+                problems.push(format!(
+                    "Undecodable txindex entry {}",
+                    key.to_lower_hex_string()
+                ));
+                return true;
+            };
+            if entry.block_height > best.height {
+                if past_best < MAX_LISTED_ORPHANS {
+                    problems.push(format!(
+                        "Transaction ID: {}, Block Height: {}, orphan entry above the best block {}",
+                        txid,
+                        entry.block_height,
+                        best.height
+                    ));
+                }
+                past_best += 1;
+            }
+            true
+        })?;
+        if entries > confirmed {
+            problems.push(format!(
+                "{} txindex entries do not belong to any indexed block ({} above the best block)",
+                entries - confirmed,
+                past_best
+            ));
+        }
+    }
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    println!(
+        "Checked {} blocks and {} transactions, found {} problems",
+        heights.len(),
+        confirmed,
+        problems.len()
+    );
+    if !problems.is_empty() {
+        return Err(KorndexError::Corrupt(format!(
+            "Index verification found {} problems",
+            problems.len()
+        )));
+    }
+    Ok(())
+}
+
+fn read_block(chainman: &ChainstateManager, height: i32) -> Result<Option<Block>, KorndexError> {
+    let Ok(block_index) = chainman.get_block_index_by_height(height) else {
+        return Ok(None);
+    };
+    let raw_block = kernel::read_block_data(chainman, &block_index, height)?;
+    Ok(Some(deserialize(&raw_block)?))
+}
+
+fn verify_block(
+    chainman: &ChainstateManager,
+    store: &dyn KvStore,
+    height: i32,
+    indexes: IndexSet,
+) -> Result<BlockReport, KorndexError> {
+    let mut report = BlockReport::default();
+    let Some(block) = read_block(chainman, height)? else {
+        report.problems.push(format!(
+            "Block Height: {}, indexed but not in the active chain",
+            height
+        ));
+        return Ok(report);
+    };
+    let hash = block.block_hash();
+
+    match undo::read_undo(store, height)? {
+        None => report
+            .problems
+            .push(format!("Block Height: {}, missing undo record", height)),
+        Some(record) if record.hash != hash.to_byte_array() => report.problems.push(format!(
+            "Block Height: {}, indexed block {} but the active chain has {}",
+            height,
+            bitcoin::BlockHash::from_byte_array(record.hash),
+            hash
+        )),
+        Some(_) => {}
+    }
+    let partial = meta::read_start_height(store)?.is_some();
+    for (table, what) in [
+        (Table::Filters, "filter"),
+        (Table::FilterHeaders, "filter header"),
+    ] {
+        // Indexes not starting at genesis have no filter headers
+        if !indexes.contains(IndexKind::Filters) || table == Table::FilterHeaders && partial {
+            continue;
+        }
+        if store.get(table, &height_key(height))?.is_none() {
+            report
+                .problems
+                .push(format!("Block Height: {}, missing {}", height, what));
+        }
+    }
+    match headers::read_header(store, height)? {
+        None => report
+            .problems
+            .push(format!("Block Height: {}, missing header", height)),
+        Some(header) if header != block.header => report.problems.push(format!(
+            "Block Height: {}, header does not match the block",
+            height
+        )),
+        Some(_) => {}
+    }
+    let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+    match txindex::read_block_txids(store, height)? {
+        None => report
+            .problems
+            .push(format!("Block Height: {}, missing txid list", height)),
+        Some(indexed) if indexed != txids => report.problems.push(format!(
+            "Block Height: {}, txid list does not match the block",
+            height
+        )),
+        Some(_) => {}
+    }
+
+    if !indexes.contains(IndexKind::Txid) {
+        return Ok(report);
+    }
+    for (position, txid) in txids.into_iter().enumerate() {
+        let Some(data) = store.get(Table::TxIndex, &txid.to_byte_array())? else {
+            // The coinbase is missing when the index was built with
+            // --skip-coinbase
+            if position != 0 {
+                report.problems.push(format!(
+                    "Transaction ID: {}, Block Height: {}, missing from the txindex",
+                    txid, height
+                ));
+            }
+            continue;
+        };
+        let entry = TxIndexEntry::decode(&data)?;
+        if (entry.block_height, entry.position_in_block) == (height, position) {
+            report.confirmed += 1;
+            continue;
+        }
+        // BIP30 duplicates share a txid with another transaction, whose
+        // location the index kept under its --dup-policy
+        let duplicate = entry.block_height != height
+            && read_block(chainman, entry.block_height)?
+                .and_then(|block| block.txdata.get(entry.position_in_block).cloned())
+                .is_some_and(|later| later.compute_txid() == txid);
+        if !duplicate {
+            report.problems.push(format!(
+                "Transaction ID: {}, Block Height: {}, Block Location: {}, indexed at Block Height: {}, Block Location: {}",
+                txid, height, position, entry.block_height, entry.position_in_block
+            ));
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,253 @@
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Network, Txid};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::indexes::IndexKind;
+use crate::scripthash::{self, Direction};
+use crate::store::KvStore;
+use crate::{headers, meta, rescan, shutdown, txindex, KorndexError};
+
+/// How often the index is checked for new blocks and the watch file for
+/// changes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Blocks remembered to detect reorgs, whose replacing blocks are notified
+/// again.
+const REORG_DEPTH: usize = 100;
+
+/// A transaction in a newly indexed block funding or spending a watched
+/// script, sent as JSON.
+#[derive(Serialize, Debug)]
+pub struct Notification {
+    /// Line of the watch file the script comes from
+    pub watch: String,
+    pub txid: Txid,
+    pub block_height: i32,
+    pub block_hash: BlockHash,
+    pub position_in_block: u32,
+    /// `funding` for an output paying to the script, `spending` for an input
+    /// spending from it
+    pub direction: &'static str,
+    /// vout for funding, vin for spending
+    pub index: u32,
+}
+
+/// Where notifications are sent.
+pub enum Sink {
+    /// POSTed to this URL
+    Webhook(String),
+    /// Published on a ZMQ PUB socket with the topic `korndexwatch`
+    #[cfg(feature = "zmq")]
+    Zmq(zmq::Socket),
+}
+
+impl Sink {
+    /// A PUB socket bound to `endpoint`, e.g. tcp://127.0.0.1:28350.
+    #[cfg(feature = "zmq")]
+    pub fn zmq(endpoint: &str) -> Result<Self, KorndexError> {
+        let zmq_error = |e: zmq::Error| KorndexError::Io(std::io::Error::other(e));
+        let socket = zmq::Context::new().socket(zmq::PUB).map_err(zmq_error)?;
+        socket.bind(endpoint).map_err(zmq_error)?;
+        Ok(Sink::Zmq(socket))
+    }
+
+    #[cfg(not(feature = "zmq"))]
+    pub fn zmq(_endpoint: &str) -> Result<Self, KorndexError> {
+        Err(KorndexError::Config(
+            "korndex was built without ZMQ support, rebuild with --features zmq".to_owned(),
+        ))
+    }
+
+    fn send(&self, body: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Sink::Webhook(url) => {
+                ureq::post(url)
+                    .set("Content-Type", "application/json")
+                    .send_string(body)?;
+            }
+            #[cfg(feature = "zmq")]
+            Sink::Zmq(socket) => {
+                socket.send_multipart([&b"korndexwatch"[..], body.as_bytes()], 0)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scripts to watch, read from a file with one address, hex-encoded
+/// scriptPubKey or output descriptor per line. Blank lines and lines starting
+/// with `#` are skipped. Ranged descriptors are watched at the derivation
+/// indexes `0..=range`. The file is read again whenever it changes, so
+/// clients register scripts by editing it.
+pub struct Watchlist {
+    path: PathBuf,
+    network: Network,
+    range: u32,
+    modified: Option<SystemTime>,
+    /// Watch file line of each watched script, by script hash
+    scripts: HashMap<[u8; 32], String>,
+}
+
+impl Watchlist {
+    pub fn new(path: PathBuf, network: Network, range: u32) -> Self {
+        Watchlist {
+            path,
+            network,
+            range,
+            modified: None,
+            scripts: HashMap::new(),
+        }
+    }
+
+    /// Reads the watch file if it changed since it was last read. A file that
+    /// fails to parse keeps the previous scripts.
+    fn refresh(&mut self) -> Result<(), KorndexError> {
+        let modified = fs::metadata(&self.path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(());
+        }
+        self.modified = Some(modified);
+        let mut scripts = HashMap::new();
+        for line in fs::read_to_string(&self.path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = match line.contains('(') {
+                true => rescan::scripts(line, 0, self.range),
+                false => scripthash::parse_script(self.network, line).map(|script| vec![script]),
+            };
+            for script in parsed? {
+                scripts.insert(scripthash::script_hash(&script), line.to_owned());
+            }
+        }
+        tracing::info!(
+            "Watching {} scripts from {}",
+            scripts.len(),
+            self.path.display()
+        );
+        self.scripts = scripts;
+        Ok(())
+    }
+}
+
+/// Sends a [`Notification`] to every sink for each transaction of a block
+/// connected to the index in `store` that funds or spends a watched script,
+/// until shutdown is requested or `stop` is set. Blocks connected by a reorg
+/// are notified too. Needs the address index.
+pub fn run(
+    store: &dyn KvStore,
+    mut watchlist: Watchlist,
+    sinks: &[Sink],
+    stop: &AtomicBool,
+) -> Result<(), KorndexError> {
+    meta::built_indexes(store)?.require(IndexKind::Address)?;
+    let mut feed = BlockFeed::new(store)?;
+    while !shutdown::requested() && !stop.load(Ordering::Relaxed) {
+        if let Err(e) = watchlist.refresh() {
+            tracing::warn!("Failed to read {}: {}", watchlist.path.display(), e);
+        }
+        for (block_height, block_hash) in feed.poll(store)? {
+            for notification in matches(store, &watchlist, block_height, block_hash)? {
+                let body = serde_json::to_string(&notification)?;
+                for sink in sinks {
+                    if let Err(e) = sink.send(&body) {
+                        tracing::warn!("Failed to send a watch notification: {}", e);
+                    }
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Follows the blocks connected to an index by another thread or process,
+/// starting from its tip when the feed is created.
+pub struct BlockFeed {
+    /// Hashes of the blocks already returned by [`BlockFeed::poll`]
+    seen: BTreeMap<i32, BlockHash>,
+}
+
+impl BlockFeed {
+    pub fn new(store: &dyn KvStore) -> Result<Self, KorndexError> {
+        let mut seen = BTreeMap::new();
+        if let Some(best) = meta::read_best_block(store)? {
+            seen.insert(best.height, BlockHash::from_byte_array(best.hash));
+        }
+        Ok(BlockFeed { seen })
+    }
+
+    /// Blocks indexed since the last poll, in chain order, including those a
+    /// reorg connected in place of blocks returned before.
+    pub fn poll(&mut self, store: &dyn KvStore) -> Result<Vec<(i32, BlockHash)>, KorndexError> {
+        let Some(best) = meta::read_best_block(store)? else {
+            return Ok(Vec::new());
+        };
+        let lowest = self.seen.keys().next().copied().unwrap_or(best.height);
+        let mut blocks = Vec::new();
+        for block_height in (lowest..=best.height).rev() {
+            let header = headers::read_header(store, block_height)?.ok_or_else(|| {
+                KorndexError::Corrupt(format!("No header for block {}", block_height))
+            })?;
+            let block_hash = header.block_hash();
+            if self.seen.get(&block_height) == Some(&block_hash) {
+                break;
+            }
+            blocks.push((block_height, block_hash));
+        }
+        blocks.reverse();
+        if let Some(&(first, _)) = blocks.first() {
+            self.seen.retain(|height, _| *height < first);
+        }
+        self.seen.extend(blocks.iter().copied());
+        while self.seen.len() > REORG_DEPTH {
+            self.seen.pop_first();
+        }
+        Ok(blocks)
+    }
+}
+
+/// Notifications for the watched scripts appearing in the block at
+/// `block_height`, found through the address index.
+fn matches(
+    store: &dyn KvStore,
+    watchlist: &Watchlist,
+    block_height: i32,
+    block_hash: BlockHash,
+) -> Result<Vec<Notification>, KorndexError> {
+    let mut found = Vec::new();
+    for (scripthash, watch) in watchlist.scripts.iter() {
+        for entry in scripthash::block_entries(store, scripthash, block_height)? {
+            found.push((watch, entry));
+        }
+    }
+    if found.is_empty() {
+        return Ok(Vec::new());
+    }
+    let txids = txindex::read_block_txids(store, block_height)?
+        .ok_or_else(|| KorndexError::Corrupt(format!("No txid list for block {}", block_height)))?;
+    found.sort_by_key(|(_, entry)| (entry.position_in_block, entry.direction as u8, entry.index));
+    found
+        .into_iter()
+        .map(|(watch, entry)| {
+            Ok(Notification {
+                watch: watch.clone(),
+                txid: txindex::txid_at(&txids, block_height, entry.position_in_block as usize)?,
+                block_height,
+                block_hash,
+                position_in_block: entry.position_in_block,
+                direction: match entry.direction {
+                    Direction::Output => "funding",
+                    Direction::Input => "spending",
+                },
+                index: entry.index,
+            })
+        })
+        .collect()
+}
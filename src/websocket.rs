@@ -0,0 +1,333 @@
+//! WebSocket push notifications for serve mode, shaped like mempool.space's
+//! WebSocket API. Clients send `{"subscribe": ["blocks", "address:<address>",
+//! "txid:<txid>"]}` or the same with `unsubscribe`, and receive:
+//!
+//! - `{"block": {...}}` for every newly indexed block
+//! - `{"address": ..., "address-transactions": [...]}` for transactions of a
+//!   new block funding or spending a subscribed address
+//! - `{"txConfirmed": ..., "block": {...}}` once a subscribed txid confirms
+
+use bitcoin::{BlockHash, Network, Txid};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::{Message, WebSocket};
+
+use crate::access::{self, AccessControl, ConnectionLimit, Denied};
+use crate::indexes::{IndexKind, IndexSet};
+use crate::listener::{Listener, Stream};
+use crate::scripthash::{self, Direction};
+use crate::store::KvStore;
+use crate::tls::{self, TlsConfig};
+use crate::watch::{BlockFeed, POLL_INTERVAL};
+use crate::{headers, meta, shutdown, txindex, KorndexError};
+
+/// How long a connection waits for a client message before sending the events
+/// of new blocks.
+const READ_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Topics a connection subscribed to.
+#[derive(Default)]
+struct Subscriptions {
+    blocks: bool,
+    /// Script hashes of the subscribed addresses, by address as given
+    addresses: BTreeMap<String, [u8; 32]>,
+    txids: HashSet<Txid>,
+}
+
+fn ws_error(e: tungstenite::Error) -> KorndexError {
+    match e {
+        tungstenite::Error::Io(e) => KorndexError::Io(e),
+        e => KorndexError::Io(io::Error::other(e)),
+    }
+}
+
+/// Serves WebSocket subscriptions on `bind` for blocks indexed into `store`
+/// by the follow thread. Address subscriptions need the address index. The
+/// handshake and every client message are checked against `access`, the API
+/// key coming from the handshake's headers, and connections beyond
+/// `connections` are turned away.
+pub fn serve(
+    bind: &str,
+    store: &dyn KvStore,
+    network: Network,
+    tls: Option<&TlsConfig>,
+    access: &AccessControl,
+    connections: &ConnectionLimit,
+) -> Result<(), KorndexError> {
+    let listener = Listener::bind(bind)?;
+    let indexes = meta::built_indexes(store)?;
+    // Each connection's queue of new blocks
+    let clients: Mutex<Vec<Sender<(i32, BlockHash)>>> = Mutex::new(Vec::new());
+    tracing::info!("Serving WebSocket notifications on {}", bind);
+
+    thread::scope(|s| {
+        let clients = &clients;
+        s.spawn(move || {
+            if let Err(e) = feed_blocks(store, clients) {
+                tracing::error!("Stopped WebSocket notifications: {}", e);
+            }
+        });
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept WebSocket connection: {}", e);
+                    continue;
+                }
+            };
+            let Some(connection) = connections.acquire() else {
+                tracing::warn!("Refused a WebSocket connection, too many are open");
+                continue;
+            };
+            let (tx, rx) = mpsc::channel();
+            clients.lock().unwrap().push(tx);
+            s.spawn(move || {
+                let _connection = connection;
+                let stream = tls::accept(tls, stream);
+                if let Err(e) = stream.and_then(|stream| {
+                    handle_connection(stream, store, network, indexes, access, rx)
+                }) {
+                    tracing::debug!("WebSocket connection closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Passes every newly indexed block to the connected clients, dropping those
+/// that disconnected.
+fn feed_blocks(
+    store: &dyn KvStore,
+    clients: &Mutex<Vec<Sender<(i32, BlockHash)>>>,
+) -> Result<(), KorndexError> {
+    let mut feed = BlockFeed::new(store)?;
+    while !shutdown::requested() {
+        for block in feed.poll(store)? {
+            clients
+                .lock()
+                .unwrap()
+                .retain(|client| client.send(block).is_ok());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    stream: Stream,
+    store: &dyn KvStore,
+    network: Network,
+    indexes: IndexSet,
+    access: &AccessControl,
+    blocks: Receiver<(i32, BlockHash)>,
+) -> Result<(), KorndexError> {
+    let client = stream.peer_ip();
+    let mut api_key = None;
+    let handshake = |request: &Request, response: Response| {
+        api_key = request
+            .headers()
+            .iter()
+            .find_map(|(name, value)| access::header_api_key(name.as_str(), value.to_str().ok()?));
+        match access.check(client, api_key.as_deref()) {
+            Ok(()) => Ok(response),
+            Err(denied) => Err(denied_response(denied)),
+        }
+    };
+    let mut socket = tungstenite::accept_hdr(stream, handshake).map_err(|e| match e {
+        tungstenite::HandshakeError::Failure(e) => ws_error(e),
+        tungstenite::HandshakeError::Interrupted(_) => {
+            KorndexError::Io(io::Error::from(ErrorKind::WouldBlock))
+        }
+    })?;
+    socket.get_ref().set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut subscriptions = Subscriptions::default();
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                let reply = match access.check(client, api_key.as_deref()) {
+                    Ok(()) => subscribe(&mut subscriptions, &text, network, indexes),
+                    Err(denied) => Err(KorndexError::InvalidInput(denied_message(denied).into())),
+                };
+                let reply = reply.unwrap_or_else(|e| json!({ "error": e.to_string() }));
+                send(&mut socket, &reply)?;
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(ws_error(e)),
+        }
+        for (block_height, block_hash) in blocks.try_iter() {
+            for event in events(store, &subscriptions, block_height, block_hash)? {
+                send(&mut socket, &event)?;
+            }
+        }
+    }
+}
+
+fn denied_message(denied: Denied) -> &'static str {
+    match denied {
+        Denied::Unauthorized => "Missing or unknown API key",
+        Denied::RateLimited => "Rate limit exceeded",
+    }
+}
+
+/// Refuses a handshake with 401 or 429, like the REST API.
+fn denied_response(denied: Denied) -> ErrorResponse {
+    let status = match denied {
+        Denied::Unauthorized => StatusCode::UNAUTHORIZED,
+        Denied::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+    };
+    let mut response = ErrorResponse::new(Some(denied_message(denied).to_owned()));
+    *response.status_mut() = status;
+    response
+}
+
+fn send(socket: &mut WebSocket<Stream>, event: &Value) -> Result<(), KorndexError> {
+    socket
+        .send(Message::Text(event.to_string().into()))
+        .map_err(ws_error)
+}
+
+/// Applies a `subscribe` or `unsubscribe` message, returning the topics now
+/// subscribed to.
+fn subscribe(
+    subscriptions: &mut Subscriptions,
+    text: &str,
+    network: Network,
+    indexes: IndexSet,
+) -> Result<Value, KorndexError> {
+    let message: Value = serde_json::from_str(text)
+        .map_err(|e| KorndexError::InvalidInput(format!("Invalid message: {}", e).into()))?;
+    let (topics, add) = match (message.get("subscribe"), message.get("unsubscribe")) {
+        (Some(topics), None) => (topics, true),
+        (None, Some(topics)) => (topics, false),
+        _ => {
+            return Err(KorndexError::InvalidInput(
+                "Expected {\"subscribe\": [...]} or {\"unsubscribe\": [...]}".into(),
+            ))
+        }
+    };
+    let topics = topics
+        .as_array()
+        .ok_or_else(|| KorndexError::InvalidInput("Topics must be an array".into()))?;
+    for topic in topics {
+        let topic = topic
+            .as_str()
+            .ok_or_else(|| KorndexError::InvalidInput("Topics must be strings".into()))?;
+        if topic == "blocks" {
+            subscriptions.blocks = add;
+        } else if let Some(address) = topic.strip_prefix("address:") {
+            if !add {
+                subscriptions.addresses.remove(address);
+                continue;
+            }
+            indexes.require(IndexKind::Address)?;
+            let script = scripthash::parse_script(network, address)?;
+            subscriptions
+                .addresses
+                .insert(address.to_owned(), scripthash::script_hash(&script));
+        } else if let Some(txid) = topic.strip_prefix("txid:") {
+            let txid = Txid::from_str(txid)?;
+            match add {
+                true => subscriptions.txids.insert(txid),
+                false => subscriptions.txids.remove(&txid),
+            };
+        } else {
+            return Err(KorndexError::InvalidInput(
+                format!("Unknown topic {}", topic).into(),
+            ));
+        }
+    }
+    let mut subscribed: Vec<String> = Vec::new();
+    if subscriptions.blocks {
+        subscribed.push("blocks".to_owned());
+    }
+    subscribed.extend(
+        subscriptions
+            .addresses
+            .keys()
+            .map(|a| format!("address:{}", a)),
+    );
+    subscribed.extend(subscriptions.txids.iter().map(|t| format!("txid:{}", t)));
+    Ok(json!({ "subscribed": subscribed }))
+}
+
+/// The events of the block at `block_height` for a connection's
+/// subscriptions.
+fn events(
+    store: &dyn KvStore,
+    subscriptions: &Subscriptions,
+    block_height: i32,
+    block_hash: BlockHash,
+) -> Result<Vec<Value>, KorndexError> {
+    let mut events = Vec::new();
+    let header = headers::read_header(store, block_height)?
+        .ok_or_else(|| KorndexError::Corrupt(format!("No header for block {}", block_height)))?;
+    if header.block_hash() != block_hash {
+        // Replaced by a reorg since, the replacing block is sent next
+        return Ok(events);
+    }
+    let txids = txindex::read_block_txids(store, block_height)?
+        .ok_or_else(|| KorndexError::Corrupt(format!("No txid list for block {}", block_height)))?;
+    let block = json!({
+        "id": block_hash.to_string(),
+        "height": block_height,
+        "version": header.version.to_consensus(),
+        "timestamp": header.time,
+        "bits": header.bits.to_consensus(),
+        "nonce": header.nonce,
+        "merkle_root": header.merkle_root.to_string(),
+        "previousblockhash": header.prev_blockhash.to_string(),
+        "tx_count": txids.len(),
+    });
+    if subscriptions.blocks {
+        events.push(json!({ "block": block }));
+    }
+    for (address, scripthash) in subscriptions.addresses.iter() {
+        let mut entries = scripthash::block_entries(store, scripthash, block_height)?;
+        if entries.is_empty() {
+            continue;
+        }
+        entries.sort_by_key(|entry| (entry.position_in_block, entry.direction as u8, entry.index));
+        let transactions: Vec<Value> = entries
+            .iter()
+            .map(|entry| {
+                let txid =
+                    txindex::txid_at(&txids, block_height, entry.position_in_block as usize)?;
+                Ok(json!({
+                    "txid": txid.to_string(),
+                    "block_height": block_height,
+                    "block_hash": block_hash.to_string(),
+                    "position_in_block": entry.position_in_block,
+                    "direction": match entry.direction {
+                        Direction::Output => "funding",
+                        Direction::Input => "spending",
+                    },
+                    "index": entry.index,
+                }))
+            })
+            .collect::<Result<_, KorndexError>>()?;
+        events.push(json!({ "address": address, "address-transactions": transactions }));
+    }
+    for txid in txids
+        .iter()
+        .filter(|txid| subscriptions.txids.contains(txid))
+    {
+        events.push(json!({
+            "txConfirmed": txid.to_string(),
+            "block": block,
+        }));
+    }
+    Ok(events)
+}
@@ -0,0 +1,130 @@
+use bitcoin::{Block, TxOut};
+
+use crate::store::{height_key, KvStore, Table};
+use crate::KorndexError;
+
+/// Witness items at least this large are indexed. Larger than any standard
+/// pushed element, so it catches tapscripts carrying data envelopes, like
+/// inscriptions, and oversized P2WSH scripts.
+pub const MIN_ITEM_SIZE: usize = 520;
+
+pub const ENTRY_SIZE: usize = 17;
+
+/// What a [`WitnessEntry`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WitnessKind {
+    /// A BIP341 annex of a taproot spend
+    Annex = 0,
+    /// A witness item of at least [`MIN_ITEM_SIZE`] bytes
+    LargeItem = 1,
+}
+
+impl WitnessKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            WitnessKind::Annex => "annex",
+            WitnessKind::LargeItem => "large-item",
+        }
+    }
+}
+
+/// A taproot annex or large witness item of an input. Encoded big-endian into
+/// a fixed 17-byte value, and stored per block, in block order.
+#[derive(Debug, Clone, Copy)]
+pub struct WitnessEntry {
+    pub position_in_block: u32,
+    pub input_index: u32,
+    pub kind: WitnessKind,
+    /// Index of the item within the input's witness
+    pub item_index: u32,
+    /// Size of the annex or item in bytes
+    pub size: u32,
+}
+
+impl WitnessEntry {
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut buf = [0u8; ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&self.position_in_block.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.input_index.to_be_bytes());
+        buf[8] = self.kind as u8;
+        buf[9..13].copy_from_slice(&self.item_index.to_be_bytes());
+        buf[13..17].copy_from_slice(&self.size.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, KorndexError> {
+        if data.len() != ENTRY_SIZE {
+            return Err(KorndexError::Corrupt(format!(
+                "Invalid witness entry length {}",
+                data.len()
+            )));
+        }
+        let kind = match data[8] {
+            0 => WitnessKind::Annex,
+            1 => WitnessKind::LargeItem,
+            other => {
+                return Err(KorndexError::Corrupt(format!(
+                    "Invalid witness entry kind {}",
+                    other
+                )))
+            }
+        };
+        Ok(WitnessEntry {
+            position_in_block: u32::from_be_bytes(data[0..4].try_into()?),
+            input_index: u32::from_be_bytes(data[4..8].try_into()?),
+            kind,
+            item_index: u32::from_be_bytes(data[9..13].try_into()?),
+            size: u32::from_be_bytes(data[13..17].try_into()?),
+        })
+    }
+}
+
+/// The annexes and large witness items of `block`, whose non-coinbase
+/// transactions spend `spent_outputs`. An annex is only recognized on inputs
+/// spending taproot outputs.
+pub fn block_entries(block: &Block, spent_outputs: &[Vec<TxOut>]) -> Vec<WitnessEntry> {
+    let mut entries = Vec::new();
+    for ((position, tx), prevouts) in block.txdata.iter().enumerate().skip(1).zip(spent_outputs) {
+        for (vin, (input, prevout)) in tx.input.iter().zip(prevouts).enumerate() {
+            let witness = &input.witness;
+            let annex = prevout
+                .script_pubkey
+                .is_p2tr()
+                .then(|| witness.taproot_annex())
+                .flatten();
+            if let Some(annex) = annex {
+                entries.push(WitnessEntry {
+                    position_in_block: position as u32,
+                    input_index: vin as u32,
+                    kind: WitnessKind::Annex,
+                    item_index: witness.len() as u32 - 1,
+                    size: annex.len() as u32,
+                });
+            }
+            for (item_index, item) in witness.iter().enumerate() {
+                if item.len() >= MIN_ITEM_SIZE {
+                    entries.push(WitnessEntry {
+                        position_in_block: position as u32,
+                        input_index: vin as u32,
+                        kind: WitnessKind::LargeItem,
+                        item_index: item_index as u32,
+                        size: item.len() as u32,
+                    });
+                }
+            }
+        }
+    }
+    entries
+}
+
+/// Annexes and large witness items of the indexed block at `block_height`,
+/// empty if it has none.
+pub fn read_entries(
+    store: &dyn KvStore,
+    block_height: i32,
+) -> Result<Vec<WitnessEntry>, KorndexError> {
+    match store.get(Table::Witness, &height_key(block_height))? {
+        Some(data) => data.chunks(ENTRY_SIZE).map(WitnessEntry::decode).collect(),
+        None => Ok(Vec::new()),
+    }
+}
@@ -0,0 +1,61 @@
+#[cfg(feature = "zmq")]
+use bitcoin::consensus::deserialize;
+#[cfg(feature = "zmq")]
+use std::io;
+
+#[cfg(feature = "zmq")]
+use crate::shutdown;
+use crate::{Indexer, KorndexError};
+
+/// How often the receive loop checks for a shutdown request.
+#[cfg(feature = "zmq")]
+const RECV_TIMEOUT_MS: i32 = 1000;
+
+/// Keeps the index at the node's tip by indexing every block bitcoind
+/// publishes on its `rawblock` ZMQ endpoint, e.g. `tcp://127.0.0.1:28332`,
+/// until shutdown is requested. Needs an index from genesis, whose UTXO set
+/// supplies the outputs the blocks spend.
+#[cfg(feature = "zmq")]
+pub fn follow(indexer: &Indexer, endpoint: &str) -> Result<(), KorndexError> {
+    let zmq_error = |e: zmq::Error| KorndexError::Io(io::Error::other(e));
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::SUB).map_err(zmq_error)?;
+    socket.set_subscribe(b"rawblock").map_err(zmq_error)?;
+    socket.set_rcvtimeo(RECV_TIMEOUT_MS).map_err(zmq_error)?;
+    socket.connect(endpoint).map_err(zmq_error)?;
+    tracing::info!("Following blocks published on {}", endpoint);
+
+    let mut next_sequence = None;
+    while !shutdown::requested() {
+        // Messages are [topic, block, little-endian sequence number]
+        let message = match socket.recv_multipart(0) {
+            Ok(message) => message,
+            Err(zmq::Error::EAGAIN) => continue,
+            Err(e) => return Err(zmq_error(e)),
+        };
+        let [_, body, sequence] = &message[..] else {
+            tracing::warn!(
+                "Ignoring malformed ZMQ message with {} parts",
+                message.len()
+            );
+            continue;
+        };
+        let sequence = u32::from_le_bytes(sequence[..].try_into()?);
+        if next_sequence.is_some_and(|next| next != sequence) {
+            tracing::warn!(
+                "Missed ZMQ block notifications before sequence {}",
+                sequence
+            );
+        }
+        next_sequence = Some(sequence.wrapping_add(1));
+        indexer.connect_block(&deserialize(body)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "zmq"))]
+pub fn follow(_indexer: &Indexer, _endpoint: &str) -> Result<(), KorndexError> {
+    Err(KorndexError::Config(
+        "korndex was built without ZMQ support, rebuild with --features zmq".to_owned(),
+    ))
+}
@@ -0,0 +1,201 @@
+//! Checks what `scan_block` finds in serialized blocks against the blocks
+//! deserialized with rust-bitcoin, and that malformed blocks are errors.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::serialize;
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::Hash;
+use bitcoin::script::Builder;
+use bitcoin::transaction;
+use bitcoin::{
+    Amount, Block, BlockHash, CompactTarget, Network, OutPoint, ScriptBuf, Sequence, Transaction,
+    TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
+
+use korndex::blockscan::scan_block;
+
+fn tx(inputs: Vec<TxIn>, outputs: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::from_consensus(7),
+        input: inputs,
+        output: outputs,
+    }
+}
+
+fn input(n: u8, script_sig: ScriptBuf, witness: Witness) -> TxIn {
+    TxIn {
+        previous_output: OutPoint::new(Txid::from_byte_array([n; 32]), n as u32),
+        script_sig,
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        witness,
+    }
+}
+
+fn output(sat: u64, script_pubkey: ScriptBuf) -> TxOut {
+    TxOut {
+        value: Amount::from_sat(sat),
+        script_pubkey,
+    }
+}
+
+fn block(txdata: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        header: Header {
+            version: Version::from_consensus(0x2000_0000),
+            prev_blockhash: BlockHash::from_byte_array([1; 32]),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1_700_000_000,
+            bits: CompactTarget::from_consensus(0x207f_ffff),
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    block
+}
+
+/// A block mixing a coinbase with the witness reserved value, legacy and
+/// segwit spends, witnesses of several items, and scripts and counts long
+/// enough for 3 byte compact sizes.
+fn segwit_block() -> Block {
+    let mut reserved = Witness::new();
+    reserved.push([0u8; 32]);
+    let coinbase = tx(
+        vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new().push_int(840_000).into_script(),
+            sequence: Sequence::MAX,
+            witness: reserved,
+        }],
+        vec![output(312_500_000, ScriptBuf::from_bytes(vec![0x6a; 38]))],
+    );
+    let legacy = tx(
+        vec![input(
+            1,
+            ScriptBuf::from_bytes(vec![0x51; 300]),
+            Witness::new(),
+        )],
+        (0..300)
+            .map(|i| output(i, ScriptBuf::from_bytes(vec![0x52; 22])))
+            .collect(),
+    );
+    let mut multisig = Witness::new();
+    multisig.push([0u8; 0]);
+    multisig.push([0x30u8; 72]);
+    multisig.push([0x30u8; 71]);
+    multisig.push([0x52u8; 500]);
+    let segwit = tx(
+        vec![
+            input(2, ScriptBuf::new(), multisig),
+            // Inputs without a witness in a transaction that has one
+            input(3, ScriptBuf::from_bytes(vec![0x00, 0x14]), Witness::new()),
+            input(4, ScriptBuf::new(), Witness::from_slice(&[[0x40u8; 64]])),
+        ],
+        vec![
+            output(1_000, ScriptBuf::from_bytes(vec![0x51, 0x20, 7])),
+            output(u64::MAX / 4, ScriptBuf::new()),
+        ],
+    );
+    // Outputs paying nothing and a transaction without outputs
+    let empty = tx(vec![input(5, ScriptBuf::new(), Witness::new())], Vec::new());
+    block(vec![coinbase, legacy, segwit, empty])
+}
+
+/// Asserts that scanning `block` serialized finds each of its transactions
+/// where rust-bitcoin serializes them, with the same hashes and sizes.
+fn assert_scans(block: &Block) {
+    let raw_block = serialize(block);
+    let scanned = scan_block(&raw_block).unwrap();
+    assert_eq!(scanned.len(), block.txdata.len());
+    let mut offset = 80 + serialize(&VarInt(block.txdata.len() as u64)).len();
+    for (tx, scanned) in block.txdata.iter().zip(&scanned) {
+        assert_eq!(scanned.txid, tx.compute_txid());
+        assert_eq!(scanned.wtxid, tx.compute_wtxid());
+        assert_eq!(scanned.offset, offset);
+        assert_eq!(scanned.size, tx.total_size());
+        assert_eq!(scanned.base_size, tx.base_size());
+        assert_eq!(scanned.weight() as u64, tx.weight().to_wu());
+        assert_eq!(scanned.vsize() as usize, tx.vsize());
+        assert_eq!(
+            scanned.is_segwit(),
+            tx.input.iter().any(|input| !input.witness.is_empty())
+        );
+        assert_eq!(scanned.inputs, tx.input.len());
+        assert_eq!(scanned.outputs, tx.output.len());
+        let output_value: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+        assert_eq!(scanned.output_value, output_value);
+        let (start, len) = scanned.byte_range();
+        assert_eq!(
+            &raw_block[start as usize..(start + len) as usize],
+            &serialize(tx)[..]
+        );
+        offset += scanned.size;
+    }
+    assert_eq!(offset, raw_block.len());
+}
+
+#[test]
+fn scans_genesis_blocks() {
+    for network in [Network::Regtest, Network::Bitcoin] {
+        assert_scans(&genesis_block(network));
+    }
+}
+
+#[test]
+fn scans_segwit_transactions() {
+    let block = segwit_block();
+    let segwit = &block.txdata[2];
+    assert_ne!(
+        segwit.compute_wtxid().to_byte_array(),
+        segwit.compute_txid().to_byte_array()
+    );
+    assert_scans(&block);
+}
+
+#[test]
+fn scans_blocks_with_many_transactions() {
+    let txs = (0..300)
+        .map(|i| {
+            tx(
+                vec![input(i as u8, ScriptBuf::new(), Witness::new())],
+                vec![output(i, ScriptBuf::new())],
+            )
+        })
+        .collect();
+    assert_scans(&block(txs));
+}
+
+#[test]
+fn truncated_blocks_are_errors() {
+    let raw_block = serialize(&segwit_block());
+    for len in 0..raw_block.len() {
+        assert!(scan_block(&raw_block[..len]).is_err(), "{} bytes", len);
+    }
+}
+
+#[test]
+fn malformed_blocks_are_errors_not_panics() {
+    let raw_block = serialize(&segwit_block());
+    // Every byte made a large compact size prefix or count in turn
+    for position in 0..raw_block.len() {
+        for byte in [0x00, 0xfd, 0xfe, 0xff] {
+            let mut malformed = raw_block.clone();
+            malformed[position] = byte;
+            let _ = scan_block(&malformed);
+        }
+    }
+    // Counts far beyond the block
+    let header = serialize(&segwit_block().header);
+    for count in [&[0xfd, 0xff, 0xff][..], &[0xff; 9][..]] {
+        assert!(scan_block(&[&header[..], count].concat()).is_err());
+    }
+    // A script length far beyond the block
+    let mut huge_script = header.clone();
+    huge_script.extend_from_slice(&[1, 2, 0, 0, 0, 1]);
+    huge_script.extend_from_slice(&[3; 36]);
+    huge_script.extend_from_slice(&[0xff; 9]);
+    assert!(scan_block(&huge_script).is_err());
+}
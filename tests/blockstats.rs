@@ -0,0 +1,51 @@
+//! Checks the totals of block stats over ranges of blocks.
+
+use korndex::blockstats::{BlockStats, BlockStatsTotal, ENTRY_SIZE};
+
+fn full_block(median_fee_rate: u64) -> BlockStats {
+    BlockStats {
+        txs: 3_000,
+        size: 2_000_000,
+        weight: 3_999_000,
+        inputs: 8_000,
+        outputs: 9_000,
+        segwit_txs: 2_500,
+        total_out: 100_000_000_000,
+        coinbase_out: 330_000_000,
+        total_fee: 17_500_000,
+        min_fee_rate: 1,
+        median_fee_rate,
+        max_fee_rate: 500,
+    }
+}
+
+#[test]
+fn totals_outgrow_a_single_block() {
+    let blocks = vec![full_block(10); 1_100];
+    let total = BlockStatsTotal::combine(&blocks);
+    assert_eq!(total.blocks, 1_100);
+    assert_eq!(total.weight, 3_999_000 * 1_100);
+    assert!(total.weight > u32::MAX as u64);
+    assert_eq!(total.txs, 3_000 * 1_100);
+    assert_eq!(total.avg_fee(), 17_500_000 / 2_999);
+    assert_eq!(
+        BlockStats::decode(&full_block(10).encode()).unwrap(),
+        full_block(10)
+    );
+    assert_eq!(full_block(10).encode().len(), ENTRY_SIZE);
+}
+
+#[test]
+fn fee_rates_skip_blocks_with_only_the_coinbase() {
+    let coinbase_only = BlockStats {
+        txs: 1,
+        ..BlockStats::default()
+    };
+    let blocks = [full_block(5), coinbase_only, full_block(20), full_block(8)];
+    let total = BlockStatsTotal::combine(&blocks);
+    assert_eq!(total.min_fee_rate, 1);
+    assert_eq!(total.median_fee_rate, 8);
+    assert_eq!(total.max_fee_rate, 500);
+    assert_eq!(total.avg_fee(), 17_500_000 * 3 / (3 * 2_999));
+    assert_eq!(BlockStatsTotal::combine(&[]).avg_fee(), 0);
+}
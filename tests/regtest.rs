@@ -0,0 +1,827 @@
+//! Builds indexes of a regtest chain mined block by block into a fresh
+//! libbitcoinkernel chainstate, and checks their lookups. Blocks and
+//! transactions are the same on every run, so are the txids asserted.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::serialize;
+use bitcoin::constants::genesis_block;
+use bitcoin::opcodes::all::{OP_DROP, OP_PUSHNUM_1};
+use bitcoin::script::Builder;
+use bitcoin::transaction;
+use bitcoin::{
+    Amount, Block, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+};
+use libbitcoinkernel_sys::{ChainType, ChainstateManager, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use korndex::meta::{self, Checkpoint, ShardCommit};
+use korndex::store::{Backend, Batch, KvStore, LmdbStore, Table, TableCompression};
+use korndex::txindex::{DupPolicy, TxIndexEntry, BIP30_DUPLICATES};
+use korndex::{kernel, Indexer, IndexerOptions, KorndexError, TxIndexStore};
+
+const MAP_SIZE: usize = 1 << 28;
+const SUBSIDY: Amount = Amount::from_sat(50 * 100_000_000);
+const FEE: Amount = Amount::from_sat(1_000);
+/// Blocks before a coinbase output can be spent
+const COINBASE_MATURITY: i32 = 100;
+
+/// A directory below the system's temporary directory, deleted when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "korndex-test-{}-{}-{}",
+            name,
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An output anyone can spend with an empty scriptSig, distinct per `tag` so
+/// every output has its own script hash.
+fn tagged_script(tag: &str) -> ScriptBuf {
+    Builder::new()
+        .push_slice(<&bitcoin::script::PushBytes>::try_from(tag.as_bytes()).unwrap())
+        .push_opcode(OP_DROP)
+        .push_opcode(OP_PUSHNUM_1)
+        .into_script()
+}
+
+fn spend(outpoints: &[OutPoint], outputs: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: outpoints
+            .iter()
+            .map(|outpoint| TxIn {
+                previous_output: *outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    }
+}
+
+/// A coinbase at `height` paying a script tagged with `tag` and the height.
+fn coinbase(height: i32, tag: &str) -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            // BIP34 height, padded to the shortest scriptSig allowed
+            script_sig: Builder::new()
+                .push_int(height as i64)
+                .push_slice(b"korndex")
+                .into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: SUBSIDY,
+            script_pubkey: tagged_script(&format!("{} {}", tag, height)),
+        }],
+    }
+}
+
+/// A block at `height` on top of `prev` with `txs` after a coinbase paying a
+/// script tagged with `tag` and the height.
+fn mine_block(prev: &Block, height: i32, tag: &str, txs: Vec<Transaction>) -> Block {
+    block_on(prev, [vec![coinbase(height, tag)], txs].concat())
+}
+
+/// A block on top of `prev` holding `txdata`.
+fn block_on(prev: &Block, txdata: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        header: Header {
+            version: Version::from_consensus(4),
+            prev_blockhash: prev.block_hash(),
+            merkle_root: prev.header.merkle_root,
+            time: prev.header.time + 600,
+            bits: prev.header.bits,
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    // About two tries at the regtest difficulty
+    while block.header.validate_pow(block.header.target()).is_err() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+/// A regtest node: a kernel chainstate in a temporary data directory, with
+/// the blocks mined into it so far.
+struct Node {
+    // Dropped in declaration order, the chainstate before its context and
+    // data directory
+    chainman: ChainstateManager,
+    _context: Context,
+    blocks: Vec<Block>,
+    _dir: TempDir,
+}
+
+impl Node {
+    fn new() -> Self {
+        let dir = TempDir::new("node");
+        let context = kernel::create_context(ChainType::REGTEST, None).unwrap();
+        let chainman = kernel::load_chainman(&context, dir.path().to_str().unwrap()).unwrap();
+        Node {
+            chainman,
+            _context: context,
+            blocks: vec![genesis_block(Network::Regtest)],
+            _dir: dir,
+        }
+    }
+
+    fn height(&self) -> i32 {
+        self.blocks.len() as i32 - 1
+    }
+
+    /// Mines a block with `txs` after its coinbase onto the tip and has the
+    /// kernel connect it. Returns the coinbase's txid.
+    fn mine(&mut self, txs: Vec<Transaction>) -> Txid {
+        let height = self.height() + 1;
+        let block = mine_block(self.blocks.last().unwrap(), height, "coinbase", txs);
+        let coinbase_txid = block.txdata[0].compute_txid();
+        let raw_block = serialize(&block);
+        let kernel_block = libbitcoinkernel_sys::Block::try_from(&raw_block[..]).unwrap();
+        let (accepted, _new_block) = self.chainman.process_block(&kernel_block);
+        assert!(accepted, "Block at height {} was rejected", height);
+        self.blocks.push(block);
+        coinbase_txid
+    }
+
+    /// Mines `count` blocks holding only their coinbase.
+    fn mine_empty(&mut self, count: usize) -> Vec<Txid> {
+        (0..count).map(|_| self.mine(Vec::new())).collect()
+    }
+
+    fn coinbase(&self, height: i32) -> Txid {
+        self.blocks[height as usize].txdata[0].compute_txid()
+    }
+}
+
+/// The transactions of [`mine_chain`] beyond the coinbases.
+struct Spends {
+    /// Spends the coinbase at height 1 into two outputs
+    split: Txid,
+    /// Spends the first output of `split` in the same block
+    child: Txid,
+    /// Spends the second output of `split` in the next block
+    grandchild: Txid,
+}
+
+/// Mines the blocks up to the first spendable coinbase, then a block spending
+/// it and one of its outputs, then a block spending the other output.
+fn mine_chain(node: &mut Node) -> Spends {
+    node.mine_empty(COINBASE_MATURITY as usize);
+    let split = spend(
+        &[OutPoint::new(node.coinbase(1), 0)],
+        vec![
+            TxOut {
+                value: (SUBSIDY - FEE) / 2,
+                script_pubkey: tagged_script("split 0"),
+            },
+            TxOut {
+                value: (SUBSIDY - FEE) / 2,
+                script_pubkey: tagged_script("split 1"),
+            },
+        ],
+    );
+    let child = spend(
+        &[OutPoint::new(split.compute_txid(), 0)],
+        vec![TxOut {
+            value: (SUBSIDY - FEE) / 2 - FEE,
+            script_pubkey: tagged_script("child"),
+        }],
+    );
+    let grandchild = spend(
+        &[OutPoint::new(split.compute_txid(), 1)],
+        vec![TxOut {
+            value: (SUBSIDY - FEE) / 2 - FEE,
+            script_pubkey: tagged_script("grandchild"),
+        }],
+    );
+    let spends = Spends {
+        split: split.compute_txid(),
+        child: child.compute_txid(),
+        grandchild: grandchild.compute_txid(),
+    };
+    node.mine(vec![split, child]);
+    node.mine(vec![grandchild]);
+    spends
+}
+
+fn open_index(dir: &TempDir) -> TxIndexStore {
+    TxIndexStore::open(Backend::Lmdb, dir.path(), Network::Regtest, MAP_SIZE).unwrap()
+}
+
+fn build(node: &Node, index: &TxIndexStore, options: IndexerOptions) -> Result<(), KorndexError> {
+    Indexer::new(&node.chainman, index, options).build()
+}
+
+/// Everything the index answers about the chain of `node`, to compare
+/// indexes built in different ways.
+fn snapshot(node: &Node, index: &TxIndexStore) -> Vec<String> {
+    let query = index.query(&node.chainman);
+    let mut lines = vec![format!("best {:?}", query.best_height().unwrap())];
+    for (height, block) in node.blocks.iter().enumerate() {
+        let height = height as i32;
+        lines.push(format!(
+            "block {} {:?}",
+            height,
+            query.block_txids(height).unwrap()
+        ));
+        for tx in &block.txdata {
+            let txid = tx.compute_txid();
+            lines.push(format!("tx {} {:?}", txid, query.locate(&txid).unwrap()));
+            for vout in 0..tx.output.len() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                lines.push(format!(
+                    "spend {} {:?}",
+                    outpoint,
+                    query.spend(&outpoint).unwrap()
+                ));
+            }
+            for output in &tx.output {
+                let script = &output.script_pubkey;
+                lines.push(format!("utxos {:?}", query.utxos(script).unwrap()));
+                lines.push(format!(
+                    "history {:?}",
+                    query.address_history(script).unwrap()
+                ));
+            }
+        }
+    }
+    lines
+}
+
+/// Every entry of the index but its build times, to compare indexes entry by
+/// entry.
+fn dump(store: &dyn KvStore) -> Vec<(Table, Vec<u8>, Vec<u8>)> {
+    let mut entries = Vec::new();
+    for table in Table::ALL {
+        store
+            .iter_prefix(table, &[], &mut |key, value| {
+                if !(table == Table::Meta && key == b"build_times") {
+                    entries.push((table, key.to_vec(), value.to_vec()));
+                }
+                true
+            })
+            .unwrap();
+    }
+    entries
+}
+
+/// Blocks forking off the chain of `node` below its last `replaced` blocks,
+/// one more than they replace so they outweigh them.
+fn fork(node: &Node, replaced: usize) -> Vec<Block> {
+    let mut prev = node.blocks[node.blocks.len() - 1 - replaced].clone();
+    let mut blocks = Vec::new();
+    for height in node.height() + 1 - replaced as i32..=node.height() + 1 {
+        let block = mine_block(&prev, height, "fork", Vec::new());
+        prev = block.clone();
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Connects `blocks` as the node announcing them would.
+fn connect(index: &TxIndexStore, blocks: &[Block]) {
+    let indexer = Indexer::without_kernel(index, IndexerOptions::default());
+    for block in blocks {
+        indexer.connect_block(block).unwrap();
+    }
+}
+
+#[test]
+fn indexes_coinbases_and_spends() {
+    let mut node = Node::new();
+    let spends = mine_chain(&mut node);
+    let dir = TempDir::new("index");
+    let index = open_index(&dir);
+    build(&node, &index, IndexerOptions::default()).unwrap();
+    let query = index.query(&node.chainman);
+
+    assert_eq!(query.best_height().unwrap(), Some(node.height()));
+    assert_eq!(
+        query.block_txids(101).unwrap(),
+        Some(vec![node.coinbase(101), spends.split, spends.child])
+    );
+
+    // A coinbase is found at position 0 of its block, and its output is
+    // spent by the first transaction it could be spent in
+    let coinbase = query.transaction(&node.coinbase(1)).unwrap().unwrap();
+    assert_eq!(coinbase.entry.block_height, 1);
+    assert_eq!(coinbase.entry.position_in_block, 0);
+    assert!(coinbase.tx.is_coinbase());
+    assert_eq!(coinbase.header.block_hash(), node.blocks[1].block_hash());
+    let (spender, entry) = query
+        .spend(&OutPoint::new(node.coinbase(1), 0))
+        .unwrap()
+        .unwrap();
+    assert_eq!(spender, spends.split);
+    assert_eq!(entry.block_height, COINBASE_MATURITY + 1);
+    assert_eq!(
+        query
+            .spend(&OutPoint::new(node.coinbase(2), 0))
+            .unwrap()
+            .map(|s| s.0),
+        None
+    );
+
+    // Spends within a block and across blocks
+    let split = query.transaction(&spends.split).unwrap().unwrap();
+    assert_eq!(split.entry.position_in_block, 1);
+    assert_eq!(split.entry.fee, FEE.to_sat());
+    let (spender, entry) = query
+        .spend(&OutPoint::new(spends.split, 0))
+        .unwrap()
+        .unwrap();
+    assert_eq!((spender, entry.block_height), (spends.child, 101));
+    let (spender, entry) = query
+        .spend(&OutPoint::new(spends.split, 1))
+        .unwrap()
+        .unwrap();
+    assert_eq!((spender, entry.block_height), (spends.grandchild, 102));
+
+    // Only the outputs left unspent are UTXOs
+    assert!(query.utxos(&tagged_script("split 0")).unwrap().is_empty());
+    assert!(query
+        .utxos(&tagged_script("coinbase 1"))
+        .unwrap()
+        .is_empty());
+    let utxos = query.utxos(&tagged_script("grandchild")).unwrap();
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos[0].0, OutPoint::new(spends.grandchild, 0));
+    assert_eq!(utxos[0].1.block_height, 102);
+    let utxos = query.utxos(&tagged_script("coinbase 2")).unwrap();
+    assert_eq!(utxos.len(), 1);
+    assert!(utxos[0].1.is_coinbase);
+    assert_eq!(utxos[0].1.value, SUBSIDY);
+
+    // The split's output script was paid once and spent once
+    assert_eq!(
+        query
+            .address_history(&tagged_script("split 1"))
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn skips_coinbases() {
+    let mut node = Node::new();
+    let spends = mine_chain(&mut node);
+    let dir = TempDir::new("index");
+    let index = open_index(&dir);
+    let options = IndexerOptions {
+        skip_coinbase: true,
+        ..IndexerOptions::default()
+    };
+    build(&node, &index, options).unwrap();
+    let query = index.query(&node.chainman);
+
+    for height in 1..=node.height() {
+        assert!(query.locate(&node.coinbase(height)).unwrap().is_none());
+    }
+    for txid in [spends.split, spends.child, spends.grandchild] {
+        assert!(query.locate(&txid).unwrap().is_some());
+    }
+    let split = query.transaction(&spends.split).unwrap().unwrap();
+    assert_eq!(split.entry.block_height, 101);
+}
+
+#[test]
+fn batch_boundaries_do_not_change_the_index() {
+    let mut node = Node::new();
+    mine_chain(&mut node);
+
+    let dir = TempDir::new("index");
+    let expected = {
+        let index = open_index(&dir);
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        snapshot(&node, &index)
+    };
+    // A batch per block, a batch ending between the block creating an output
+    // and the one spending it, and batches cut by transaction count
+    let batchings = [
+        IndexerOptions {
+            batch_blocks: 1,
+            ..IndexerOptions::default()
+        },
+        IndexerOptions {
+            batch_blocks: 34,
+            ..IndexerOptions::default()
+        },
+        IndexerOptions {
+            batch_txs: Some(2),
+            ..IndexerOptions::default()
+        },
+        IndexerOptions {
+            batch_blocks: 7,
+            prefetch_blocks: 1,
+            sequential_reads: true,
+            ..IndexerOptions::default()
+        },
+    ];
+    for options in batchings {
+        let dir = TempDir::new("index");
+        let index = open_index(&dir);
+        let batching = format!("{:?}", (options.batch_blocks, options.batch_txs));
+        build(&node, &index, options).unwrap();
+        assert_eq!(snapshot(&node, &index), expected, "batching {}", batching);
+    }
+}
+
+#[test]
+fn compression_does_not_change_the_index() {
+    let mut node = Node::new();
+    mine_chain(&mut node);
+
+    let dir = TempDir::new("index");
+    let expected = {
+        let index = open_index(&dir);
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        snapshot(&node, &index)
+    };
+    let mut tables: Vec<TableCompression> = ["undo", "block_txids", "utxo=19"]
+        .into_iter()
+        .map(|table| TableCompression::parse(table).unwrap())
+        .collect();
+    // Any bytes make a raw content dictionary
+    tables[0].dictionary = Some(serialize(&node.blocks[COINBASE_MATURITY as usize + 1]));
+    let dir = TempDir::new("index");
+    {
+        let index = open_index(&dir).compress(&tables).unwrap();
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        assert_eq!(snapshot(&node, &index), expected);
+    }
+    // Reopened indexes decompress without being asked, and keep their tables
+    let index = open_index(&dir);
+    assert_eq!(snapshot(&node, &index), expected);
+    assert!(matches!(
+        index.compress(&tables[..1]),
+        Err(KorndexError::Config(_))
+    ));
+    // The dup-sorted address history keeps its fixed-size values
+    let scripthash = TableCompression::parse("scripthash").unwrap();
+    assert!(matches!(
+        TableCompression::check(&[scripthash]),
+        Err(KorndexError::Config(_))
+    ));
+    // Nor can an index built without compression start compressing
+    let dir = TempDir::new("index");
+    let index = open_index(&dir);
+    build(&node, &index, IndexerOptions::default()).unwrap();
+    assert!(matches!(
+        index.compress(&tables),
+        Err(KorndexError::Config(_))
+    ));
+}
+
+#[test]
+fn sharding_does_not_change_the_index() {
+    let mut node = Node::new();
+    mine_chain(&mut node);
+
+    let dir = TempDir::new("index");
+    let expected = {
+        let index = open_index(&dir);
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        snapshot(&node, &index)
+    };
+    let dir = TempDir::new("index");
+    {
+        let index = open_index(&dir)
+            .shard_txindex(Backend::Lmdb, 3, MAP_SIZE)
+            .unwrap();
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        assert_eq!(snapshot(&node, &index), expected);
+    }
+    // Reopened indexes find their shards, for writing and for reading
+    let index = open_index(&dir);
+    assert_eq!(snapshot(&node, &index), expected);
+    assert!(matches!(
+        index.shard_txindex(Backend::Lmdb, 4, MAP_SIZE),
+        Err(KorndexError::Config(_))
+    ));
+    let index = TxIndexStore::open_read_only(Backend::Lmdb, dir.path(), Network::Regtest).unwrap();
+    assert_eq!(snapshot(&node, &index), expected);
+}
+
+#[test]
+fn sharded_commits_lost_to_a_crash_are_undone() {
+    let mut node = Node::new();
+    mine_chain(&mut node);
+    let dir = TempDir::new("index");
+    let expected = {
+        let index = open_index(&dir)
+            .shard_txindex(Backend::Lmdb, 2, MAP_SIZE)
+            .unwrap();
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        snapshot(&node, &index)
+    };
+
+    // A shard that committed a block and a reorg's deletion the index did not
+    let past_tip = [0; 32];
+    {
+        let shard_dir = dir.path().join("regtest/txindex-shards/0/lmdb");
+        let shard = LmdbStore::open(&shard_dir, MAP_SIZE).unwrap();
+        let mut deleted = None;
+        shard
+            .iter_prefix(Table::TxIndex, &[], &mut |key, value| {
+                deleted = Some((key.to_vec(), value.to_vec()));
+                false
+            })
+            .unwrap();
+        let deleted = deleted.unwrap();
+        let entry = TxIndexEntry {
+            block_height: node.height() + 1,
+            position_in_block: 0,
+            fee: 0,
+            vsize: 100,
+            byte_range: None,
+        };
+        let commit = meta::read_shard_commit(&shard).unwrap().unwrap();
+        let mut batch = Batch::default();
+        batch.delete(Table::TxIndex, deleted.0.clone(), None);
+        batch.put(Table::TxIndex, past_tip.to_vec(), entry.encode());
+        meta::write_shard_commit(
+            &mut batch,
+            &ShardCommit {
+                generation: commit.generation + 1,
+                heights: Some((node.height() + 1, node.height() + 1)),
+                deleted: vec![deleted],
+            },
+        )
+        .unwrap();
+        shard.put_batch(&batch).unwrap();
+        shard.commit().unwrap();
+    }
+
+    let index = open_index(&dir);
+    assert!(index.kv().get(Table::TxIndex, &past_tip).unwrap().is_none());
+    assert_eq!(snapshot(&node, &index), expected);
+}
+
+#[test]
+fn hot_blocks_rewrite_reorgs_in_memory() {
+    let mut node = Node::new();
+    mine_chain(&mut node);
+    let fork = fork(&node, 2);
+
+    let reference_dir = TempDir::new("index");
+    let reference = open_index(&reference_dir);
+    build(&node, &reference, IndexerOptions::default()).unwrap();
+    connect(&reference, &fork);
+
+    let dir = TempDir::new("index");
+    let index = open_index(&dir).tiered(10);
+    build(&node, &index, IndexerOptions::default()).unwrap();
+    connect(&index, &fork);
+    assert_eq!(dump(index.kv()), dump(reference.kv()));
+    let query = index.index_query();
+    assert_eq!(query.best_height().unwrap(), Some(node.height() + 1));
+    for height in node.height() - 1..=node.height() {
+        assert!(query.locate(&node.coinbase(height)).unwrap().is_none());
+    }
+    assert!(query
+        .locate(&fork[0].txdata[0].compute_txid())
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn hot_blocks_are_written_out_when_the_index_closes() {
+    let mut node = Node::new();
+    mine_chain(&mut node);
+    let fork = fork(&node, 2);
+    let next = mine_block(fork.last().unwrap(), node.height() + 2, "next", Vec::new());
+
+    let reference_dir = TempDir::new("index");
+    let reference = open_index(&reference_dir);
+    build(&node, &reference, IndexerOptions::default()).unwrap();
+    connect(&reference, &fork);
+
+    let dir = TempDir::new("index");
+    {
+        let index = open_index(&dir).tiered(10);
+        build(&node, &index, IndexerOptions::default()).unwrap();
+        connect(&index, &fork);
+    }
+    let index = open_index(&dir);
+    assert_eq!(dump(index.kv()), dump(reference.kv()));
+
+    // Reopened, the index follows on from where it stopped
+    let index = index.tiered(10);
+    connect(&index, &[next.clone()]);
+    connect(&reference, &[next]);
+    assert_eq!(dump(index.kv()), dump(reference.kv()));
+}
+
+/// A chain of empty blocks whose coinbases at the heights of
+/// [`BIP30_DUPLICATES`] less 91,700 repeat the earlier coinbase of their pair,
+/// like mainnet's, with the repeated txids and their heights. The kernel
+/// rejects such blocks, so they are connected without it.
+fn duplicates_chain() -> (Vec<Block>, Vec<(Txid, [i32; 2])>) {
+    let pairs: Vec<[i32; 2]> = BIP30_DUPLICATES
+        .iter()
+        .map(|(_, heights)| heights.map(|height| height - 91_700))
+        .collect();
+    let mut blocks = vec![genesis_block(Network::Regtest)];
+    for height in 1..=190 {
+        let prev = blocks.last().unwrap();
+        let block = match pairs.iter().find(|[_, later]| *later == height) {
+            Some([earlier, _]) => block_on(prev, vec![blocks[*earlier as usize].txdata[0].clone()]),
+            None => mine_block(prev, height, "coinbase", Vec::new()),
+        };
+        blocks.push(block);
+    }
+    let duplicates = pairs
+        .iter()
+        .map(|heights| {
+            (
+                blocks[heights[0] as usize].txdata[0].compute_txid(),
+                *heights,
+            )
+        })
+        .collect();
+    (blocks, duplicates)
+}
+
+#[test]
+fn dup_policies_resolve_repeated_txids_and_restore_them_on_reorgs() {
+    let (blocks, duplicates) = duplicates_chain();
+    let fork = mine_block(&blocks[130], 131, "fork", Vec::new());
+    for dup_policy in [DupPolicy::Latest, DupPolicy::Earliest, DupPolicy::All] {
+        let dir = TempDir::new("index");
+        let index = open_index(&dir);
+        let indexer = Indexer::without_kernel(
+            &index,
+            IndexerOptions {
+                dup_policy: Some(dup_policy),
+                ..Default::default()
+            },
+        );
+        for block in &blocks {
+            indexer.connect_block(block).unwrap();
+        }
+        let query = index.index_query();
+        for (txid, [earlier, later]) in &duplicates {
+            let (_, _, entry) = query.locate(txid).unwrap().unwrap();
+            let kept = match dup_policy {
+                DupPolicy::Earliest => *earlier,
+                DupPolicy::Latest | DupPolicy::All => *later,
+            };
+            assert_eq!(entry.block_height, kept, "{:?}", dup_policy);
+            assert_eq!(entry.position_in_block, 0);
+            let heights: Vec<i32> = query
+                .locations(txid)
+                .unwrap()
+                .iter()
+                .map(|entry| entry.block_height)
+                .collect();
+            match dup_policy {
+                DupPolicy::All => assert_eq!(heights, [*earlier, *later]),
+                _ => assert_eq!(heights, [kept]),
+            }
+        }
+
+        // Disconnecting both later blocks leaves every txid at its first
+        // location
+        indexer.connect_block(&fork).unwrap();
+        assert_eq!(query.best_height().unwrap(), Some(131));
+        for (txid, [earlier, _]) in &duplicates {
+            let (_, _, entry) = query.locate(txid).unwrap().unwrap();
+            assert_eq!(entry.block_height, *earlier, "{:?}", dup_policy);
+            let locations = query.locations(txid).unwrap();
+            assert_eq!(locations.len(), 1);
+            assert_eq!(locations[0].block_height, *earlier);
+        }
+        assert!(dump(index.kv())
+            .iter()
+            .all(|(table, _, _)| *table != Table::Duplicates));
+    }
+}
+
+#[test]
+fn rejects_another_dup_policy_for_an_existing_index() {
+    let (blocks, _) = duplicates_chain();
+    let dir = TempDir::new("index");
+    let index = open_index(&dir);
+    connect(&index, &blocks[..10]);
+    assert_eq!(
+        meta::read_dup_policy(index.kv()).unwrap(),
+        Some(DupPolicy::Latest)
+    );
+
+    let indexer = Indexer::without_kernel(
+        &index,
+        IndexerOptions {
+            dup_policy: Some(DupPolicy::Earliest),
+            ..Default::default()
+        },
+    );
+    assert!(matches!(
+        indexer.connect_block(&blocks[10]),
+        Err(KorndexError::Config(_))
+    ));
+    // Selecting the recorded policy, or none, keeps following
+    let indexer = Indexer::without_kernel(
+        &index,
+        IndexerOptions {
+            dup_policy: Some(DupPolicy::Latest),
+            ..Default::default()
+        },
+    );
+    indexer.connect_block(&blocks[10]).unwrap();
+    connect(&index, &blocks[11..20]);
+}
+
+#[test]
+fn extends_the_index_as_blocks_arrive() {
+    let mut node = Node::new();
+    node.mine_empty(60);
+    let dir = TempDir::new("index");
+    let index = open_index(&dir);
+    build(&node, &index, IndexerOptions::default()).unwrap();
+    assert_eq!(index.index_query().best_height().unwrap(), Some(60));
+
+    mine_chain(&mut node);
+    build(&node, &index, IndexerOptions::default()).unwrap();
+    // Building again without new blocks is a no-op
+    build(&node, &index, IndexerOptions::default()).unwrap();
+
+    let fresh_dir = TempDir::new("index");
+    let fresh = open_index(&fresh_dir);
+    build(&node, &fresh, IndexerOptions::default()).unwrap();
+    assert_eq!(snapshot(&node, &index), snapshot(&node, &fresh));
+}
+
+#[test]
+fn resumes_an_interrupted_build() {
+    let mut node = Node::new();
+    node.mine_empty(60);
+    let dir = TempDir::new("index");
+    let index = open_index(&dir);
+    build(&node, &index, IndexerOptions::default()).unwrap();
+    mine_chain(&mut node);
+
+    // What a build towards the new tip leaves behind when killed after its
+    // first batch
+    let mut batch = Batch::default();
+    meta::write_checkpoint(
+        &mut batch,
+        &Checkpoint {
+            lowest_height: 0,
+            highest_height: 60,
+            tip_hash: meta::read_best_block(index.kv()).unwrap().unwrap().hash,
+            target_height: node.height(),
+        },
+    )
+    .unwrap();
+    index.kv().put_batch(&batch).unwrap();
+    index.kv().commit().unwrap();
+
+    let err = build(&node, &index, IndexerOptions::default()).unwrap_err();
+    assert!(matches!(err, KorndexError::Config(_)), "{}", err);
+    assert_eq!(index.index_query().best_height().unwrap(), Some(60));
+
+    let options = IndexerOptions {
+        resume: true,
+        batch_blocks: 10,
+        ..IndexerOptions::default()
+    };
+    build(&node, &index, options).unwrap();
+    assert!(meta::read_checkpoint(index.kv()).unwrap().is_none());
+
+    let fresh_dir = TempDir::new("index");
+    let fresh = open_index(&fresh_dir);
+    build(&node, &fresh, IndexerOptions::default()).unwrap();
+    assert_eq!(snapshot(&node, &index), snapshot(&node, &fresh));
+}